@@ -9,7 +9,21 @@ pub fn sys_test() {
     }
 }
 
+/// Write all of `buf` to `fd`, waiting while a pipe is full. Gives up
+/// silently if the file stops taking data.
 pub fn sys_write(fd: usize, buf: &str) {
+    let mut buf = buf.as_bytes();
+    while let Ok(written) = sys_write_bytes(fd, buf)
+        && written != 0
+    {
+        buf = &buf[written..];
+    }
+}
+
+/// Write as much of `buf` to `fd` as the file takes and return how much that
+/// was, or a negative errno, e.g. `-32` (EPIPE) for a pipe without readers.
+pub fn sys_write_bytes(fd: usize, buf: &[u8]) -> Result<usize, isize> {
+    let res: isize;
     unsafe {
         core::arch::asm!(
             "int 0x80",
@@ -17,8 +31,10 @@ pub fn sys_write(fd: usize, buf: &str) {
             in("rdi") fd,
             in("rsi") buf.as_ptr(),
             in("rcx") buf.len(),
+            lateout("r10") res,
         );
     }
+    if res < 0 { Err(res) } else { Ok(res as usize) }
 }
 
 pub fn sys_fork() -> usize {
@@ -66,6 +82,15 @@ fn raw_sys_read() -> u8 {
     result as u8
 }
 
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Returned by `sys_read` once redirected input is exhausted, as a terminal
+/// does for Ctrl-D.
+pub const EOT: u8 = 0x04;
+
+/// Read a byte from `STDIN`, waiting for one to arrive.
 pub fn sys_read() -> u8 {
     let mut ch = raw_sys_read();
     while ch == 0xff {
@@ -142,6 +167,39 @@ pub fn sys_open(name: &str, do_create: bool) -> Option<usize> {
     }
 }
 
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+/// Create the file if it does not exist.
+pub const O_CREAT: usize = 0x40;
+/// With `O_CREAT`, fail if the file exists.
+pub const O_EXCL: usize = 0x80;
+/// Cut a regular file opened for writing to length 0.
+pub const O_TRUNC: usize = 0x200;
+/// Write at the end of the file, wherever the offset is.
+pub const O_APPEND: usize = 0x400;
+/// Fail reads and writes that would block instead of waiting.
+pub const O_NONBLOCK: usize = 0x800;
+/// Open a directory for `sys_getdents`.
+pub const O_DIRECTORY: usize = 0x10000;
+
+/// Open `path` with the `O_*` flags. Returns the file descriptor or a
+/// negative errno.
+pub fn sys_open2(path: &str, flags: usize) -> Result<usize, isize> {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 34,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            in("rsi") flags,
+            lateout("r10") res,
+        );
+    }
+    if res < 0 { Err(res) } else { Ok(res as usize) }
+}
+
 pub fn sys_read2(fd: usize, buf: &mut [u8]) {
     unsafe {
         core::arch::asm!(
@@ -198,29 +256,230 @@ pub fn sys_close(fd: usize) {
     }
 }
 
-pub fn sys_remove(name: &str) {
+/// Run the path syscall `num` on `path` and return 0 or a negative errno.
+fn path_syscall(num: usize, path: &str) -> isize {
+    let res: isize;
     unsafe {
         core::arch::asm!(
             "int 0x80",
-            in("rax") 16,
-            in("rdi") name.as_ptr(),
-            in("rcx") name.len(),
+            in("rax") num,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
+/// Remove a file. Returns 0 or a negative errno.
+pub fn sys_remove(name: &str) -> isize {
+    path_syscall(16, name)
+}
+
+/// Returns 0 or a negative errno.
+pub fn sys_mkdir(path: &str) -> isize {
+    path_syscall(30, path)
+}
+
+/// Remove an empty directory. Returns 0 or a negative errno.
+pub fn sys_rmdir(path: &str) -> isize {
+    path_syscall(31, path)
+}
+
+/// Move `from` to `to` on the same filesystem. Returns 0 or a negative errno.
+pub fn sys_rename(from: &str, to: &str) -> isize {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 32,
+            in("rdi") from.as_ptr(),
+            in("rcx") from.len(),
+            in("rsi") to.as_ptr(),
+            in("rdx") to.len(),
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
+/// Cut the file at `path` to `len` bytes or extend it with zeroes. Returns 0
+/// or a negative errno.
+pub fn sys_truncate(path: &str, len: usize) -> isize {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 33,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            in("rsi") len,
+            lateout("r10") res,
         );
     }
+    res
 }
 
-pub fn sys_mount(typ: usize, disk: usize, part: usize, mountpoint: &str) {
+/// Mount the filesystem on the device at `source`, such as `/dev/usb0p1`,
+/// at `mountpoint`. An empty `fs_type` lets the kernel probe for it; for
+/// `tmpfs`, `source` is only a name. `options` is a comma-separated list of
+/// `ro`, `rw`, `noexec`, `exec` and `defaults`, plus `size=` for `tmpfs`.
+/// Returns 0 or a negative errno.
+pub fn sys_mount(source: &str, mountpoint: &str, fs_type: &str, options: &str) -> isize {
+    let res: isize;
     unsafe {
         core::arch::asm!(
             "int 0x80",
             in("rax") 17,
             in("rdi") mountpoint.as_ptr(),
             in("rcx") mountpoint.len(),
-            in("rsi") typ,
-            in("rdx") disk,
-            in("r9") part,
+            in("rsi") source.as_ptr(),
+            in("rdx") source.len(),
+            in("r8") fs_type.as_ptr(),
+            in("r9") fs_type.len(),
+            in("r12") options.as_ptr(),
+            in("r13") options.len(),
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
+/// Detach the filesystem mounted at `mountpoint`. Returns 0 or a negative
+/// errno.
+pub fn sys_umount(mountpoint: &str) -> isize {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 25,
+            in("rdi") mountpoint.as_ptr(),
+            in("rcx") mountpoint.len(),
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
+/// Change the working directory, against which relative paths resolve.
+/// Returns whether `path` is a directory.
+pub fn sys_chdir(path: &str) -> bool {
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 26,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            lateout("r10") res,
+        );
+    }
+    res == 0
+}
+
+/// The working directory, or `None` if it does not fit into `buf`.
+pub fn sys_getcwd(buf: &mut [u8]) -> Option<&str> {
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 27,
+            in("rdi") buf.as_mut_ptr(),
+            in("rcx") buf.len(),
+            lateout("r10") res,
+        );
+    }
+    match res {
+        usize::MAX => None,
+        len => core::str::from_utf8(&buf[..len]).ok(),
+    }
+}
+
+pub const KIND_FILE: u32 = 1;
+pub const KIND_DIRECTORY: u32 = 2;
+pub const KIND_CHAR_DEVICE: u32 = 3;
+pub const KIND_BLOCK_DEVICE: u32 = 4;
+pub const KIND_PIPE: u32 = 5;
+pub const KIND_SYMLINK: u32 = 6;
+
+pub const ATTR_READ_ONLY: u32 = 1 << 0;
+pub const ATTR_HIDDEN: u32 = 1 << 1;
+pub const ATTR_SYSTEM: u32 = 1 << 2;
+pub const ATTR_ARCHIVE: u32 = 1 << 3;
+
+/// What `sys_stat` and `sys_fstat` return. Times are seconds since the Unix
+/// epoch, or 0 if the filesystem does not record them. `block_size` and
+/// `block_count` are only set for block devices. `mode` holds the Unix
+/// permission bits, and is 0 with `uid` and `gid` on filesystems without
+/// them.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Metadata {
+    pub kind: u32,
+    pub attributes: u32,
+    pub size: u64,
+    pub block_size: u64,
+    pub block_count: u64,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.kind == KIND_DIRECTORY
+    }
+}
+
+pub fn sys_stat(path: &str) -> Option<Metadata> {
+    let mut metadata = Metadata::default();
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 28,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            in("rsi") &raw mut metadata,
+            lateout("r10") res,
+        );
+    }
+    (res == 0).then_some(metadata)
+}
+
+/// Like `sys_stat`, but a symbolic link is described rather than followed.
+pub fn sys_lstat(path: &str) -> Option<Metadata> {
+    let mut metadata = Metadata::default();
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 42,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            in("rsi") &raw mut metadata,
+            lateout("r10") res,
+        );
+    }
+    (res == 0).then_some(metadata)
+}
+
+pub fn sys_fstat(fd: usize) -> Option<Metadata> {
+    let mut metadata = Metadata::default();
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 29,
+            in("rsi") fd,
+            in("rdi") &raw mut metadata,
+            lateout("r10") res,
         );
     }
+    (res == 0).then_some(metadata)
 }
 
 pub const DIRENT_NAME_CAP: usize = 255;
@@ -299,6 +558,138 @@ pub fn sys_closedir(fd: usize) {
     }
 }
 
+/// Create a pipe and return its read and write ends.
+pub fn sys_pipe() -> Option<(usize, usize)> {
+    let read_fd: usize;
+    let write_fd: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 23,
+            out("rsi") read_fd,
+            out("rdx") write_fd,
+        );
+    }
+    match read_fd {
+        usize::MAX => None,
+        _ => Some((read_fd, write_fd)),
+    }
+}
+
+/// Make `new_fd` refer to the file open as `old_fd`, closing whatever
+/// `new_fd` referred to.
+pub fn sys_dup2(old_fd: usize, new_fd: usize) -> Option<usize> {
+    let res: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 24,
+            in("rsi") old_fd,
+            in("rdx") new_fd,
+            out("r10") res,
+        );
+    }
+    match res {
+        usize::MAX => None,
+        v => Some(v),
+    }
+}
+
+/// Run the file descriptor syscall `num` and return the result or a
+/// negative errno.
+fn fd_syscall(num: usize, fd: usize, rdi: usize, rcx: usize, rdx: usize) -> Result<usize, isize> {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") num,
+            in("rsi") fd,
+            in("rdi") rdi,
+            in("rcx") rcx,
+            in("rdx") rdx,
+            lateout("r10") res,
+        );
+    }
+    if res < 0 { Err(res) } else { Ok(res as usize) }
+}
+
+/// Duplicate `fd` to the lowest free file descriptor. Both share the offset
+/// and the status flags.
+pub fn sys_dup(fd: usize) -> Result<usize, isize> {
+    fd_syscall(35, fd, 0, 0, 0)
+}
+
+/// `sys_fcntl`: duplicate to the lowest free descriptor at or above `arg`.
+pub const F_DUPFD: usize = 0;
+/// `sys_fcntl`: return the access mode and the status flags.
+pub const F_GETFL: usize = 3;
+/// `sys_fcntl`: set `O_APPEND` and `O_NONBLOCK` from `arg`.
+pub const F_SETFL: usize = 4;
+
+pub fn sys_fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize, isize> {
+    fd_syscall(36, fd, cmd, 0, arg)
+}
+
+/// Read from `offset` of `fd` without moving its offset. Pipes and character
+/// devices have none.
+pub fn sys_pread(fd: usize, buf: &mut [u8], offset: usize) -> Result<usize, isize> {
+    fd_syscall(37, fd, buf.as_mut_ptr() as usize, buf.len(), offset)
+}
+
+/// Write at `offset` of `fd` without moving its offset, even with `O_APPEND`.
+pub fn sys_pwrite(fd: usize, buf: &[u8], offset: usize) -> Result<usize, isize> {
+    fd_syscall(38, fd, buf.as_ptr() as usize, buf.len(), offset)
+}
+
+/// Write cached changes back to the disks. Returns 0 or a negative errno.
+pub fn sys_sync() -> isize {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 39,
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
+/// Copy the target of the symbolic link at `path` into `buf`, cut to fit.
+/// Returns its length or a negative errno.
+pub fn sys_readlink(path: &str, buf: &mut [u8]) -> Result<usize, isize> {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 40,
+            in("rdi") path.as_ptr(),
+            in("rcx") path.len(),
+            in("rsi") buf.as_mut_ptr(),
+            in("rdx") buf.len(),
+            lateout("r10") res,
+        );
+    }
+    if res < 0 { Err(res) } else { Ok(res as usize) }
+}
+
+/// Create a symbolic link at `path` pointing to `target`. Returns 0 or a
+/// negative errno.
+pub fn sys_symlink(target: &str, path: &str) -> isize {
+    let res: isize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 41,
+            in("rdi") target.as_ptr(),
+            in("rcx") target.len(),
+            in("rsi") path.as_ptr(),
+            in("rdx") path.len(),
+            lateout("r10") res,
+        );
+    }
+    res
+}
+
 pub const IPC_CMD_CREATE: usize = 0;
 pub const IPC_CMD_SEND: usize = 1;
 pub const IPC_CMD_RECV: usize = 2;
@@ -307,6 +698,64 @@ pub const IPC_CMD_DUP: usize = 4;
 pub const IPC_CMD_BIND: usize = 5;
 pub const IPC_CMD_CONNECT: usize = 6;
 pub const IPC_CMD_ACCEPT: usize = 7;
+pub const IPC_CMD_SEND_HANDLES: usize = 8;
+pub const IPC_CMD_RECV_HANDLES: usize = 9;
+pub const IPC_CMD_RECV_WAIT: usize = 10;
+pub const IPC_CMD_ACCEPT_WAIT: usize = 11;
+pub const IPC_CMD_CALL: usize = 12;
+pub const IPC_CMD_RESTRICT: usize = 13;
+pub const IPC_CMD_SEND_WAIT: usize = 14;
+
+/// Channel flag: sends may queue only part of the buffer and receives may
+/// take part of a message. Message boundaries are not kept.
+pub const IPC_CHANNEL_STREAM: usize = 1 << 0;
+/// Queue budget (in bytes) of each end of a channel created with budget 0.
+pub const IPC_DEFAULT_BUDGET: usize = 64 * 1024;
+
+/// Timeout (in timer ticks) for the blocking IPC calls that never expires.
+pub const IPC_WAIT_FOREVER: usize = usize::MAX;
+
+pub const IPC_MAX_MSG_SIZE: usize = 4096;
+pub const IPC_MAX_MSG_HANDLES: usize = 8;
+pub const IPC_HANDLE_IPC: u32 = 0;
+pub const IPC_HANDLE_FILE: u32 = 1;
+pub const IPC_HANDLE_MOVE: u32 = 1 << 0;
+
+/// A handle attached to an IPC message. `index` is a slot in the IPC handle
+/// table or the file table, depending on `kind`.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct IpcHandleDesc {
+    pub kind: u32,
+    pub flags: u32,
+    pub index: u64,
+}
+
+impl IpcHandleDesc {
+    pub fn ipc(handle: usize) -> Self {
+        Self {
+            kind: IPC_HANDLE_IPC,
+            flags: 0,
+            index: handle as u64,
+        }
+    }
+
+    pub fn file(fd: usize) -> Self {
+        Self {
+            kind: IPC_HANDLE_FILE,
+            flags: 0,
+            index: fd as u64,
+        }
+    }
+
+    /// Move the handle to the receiver instead of sharing it.
+    pub fn moved(self) -> Self {
+        Self {
+            flags: self.flags | IPC_HANDLE_MOVE,
+            ..self
+        }
+    }
+}
 
 pub fn sys_ipc(
     cmd: usize,
@@ -334,6 +783,12 @@ pub fn sys_ipc(
 }
 
 pub fn sys_ipc_create() -> Option<(usize, usize)> {
+    sys_ipc_create_with(0, 0)
+}
+
+/// Create a channel whose ends each queue up to `budget` bytes (0 for the
+/// default) with the `IPC_CHANNEL_*` `flags`.
+pub fn sys_ipc_create_with(budget: usize, flags: usize) -> Option<(usize, usize)> {
     let left: isize;
     let right: usize;
     unsafe {
@@ -341,8 +796,9 @@ pub fn sys_ipc_create() -> Option<(usize, usize)> {
             "int 0x80",
             in("rax") 21,
             in("rdi") IPC_CMD_CREATE,
+            in("rsi") budget,
+            inout("rdx") flags => right,
             lateout("rax") left,
-            lateout("rdx") right,
         );
     }
     if left < 0 {
@@ -367,6 +823,88 @@ pub fn sys_ipc_recv(handle: usize, buf: &mut [u8]) -> isize {
     )
 }
 
+pub fn sys_ipc_send_handles(handle: usize, buf: &[u8], handles: &[IpcHandleDesc]) -> isize {
+    sys_ipc(
+        IPC_CMD_SEND_HANDLES,
+        handle,
+        buf.as_ptr() as usize,
+        buf.len(),
+        handles.as_ptr() as usize,
+        handles.len(),
+    )
+}
+
+/// Receive a message and the handles attached to it. Returns the message
+/// length (or a negative error) and the number of `handles` filled in.
+pub fn sys_ipc_recv_handles(
+    handle: usize,
+    buf: &mut [u8],
+    handles: &mut [IpcHandleDesc],
+) -> (isize, usize) {
+    let ret: isize;
+    let count: usize;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            in("rax") 21,
+            in("rdi") IPC_CMD_RECV_HANDLES,
+            in("rsi") handle,
+            in("rcx") buf.len(),
+            in("r8") handles.as_mut_ptr(),
+            in("r9") handles.len(),
+            inout("rdx") buf.as_mut_ptr() as usize => count,
+            lateout("rax") ret,
+        );
+    }
+    if ret < 0 { (ret, 0) } else { (ret, count) }
+}
+
+/// Like `sys_ipc_send`, but block for up to `timeout` ticks while the peer's
+/// queue or this process's IPC budget is full.
+pub fn sys_ipc_send_wait(handle: usize, buf: &[u8], timeout: usize) -> isize {
+    sys_ipc(
+        IPC_CMD_SEND_WAIT,
+        handle,
+        buf.as_ptr() as usize,
+        buf.len(),
+        timeout,
+        0,
+    )
+}
+
+/// Like `sys_ipc_recv`, but block for up to `timeout` ticks while no message
+/// is queued.
+pub fn sys_ipc_recv_wait(handle: usize, buf: &mut [u8], timeout: usize) -> isize {
+    sys_ipc(
+        IPC_CMD_RECV_WAIT,
+        handle,
+        buf.as_mut_ptr() as usize,
+        buf.len(),
+        timeout,
+        0,
+    )
+}
+
+/// Like `sys_ipc_accept`, but block for up to `timeout` ticks while nobody
+/// connects.
+pub fn sys_ipc_accept_wait(handle: usize, timeout: usize) -> Option<usize> {
+    let res = sys_ipc(IPC_CMD_ACCEPT_WAIT, handle, 0, 0, timeout, 0);
+    if res < 0 { None } else { Some(res as usize) }
+}
+
+/// Send `buf[..request_len]` and block for up to `timeout` ticks for the
+/// reply, which overwrites `buf`. Returns the reply length.
+pub fn sys_ipc_call(handle: usize, buf: &mut [u8], request_len: usize, timeout: usize) -> isize {
+    sys_ipc(
+        IPC_CMD_CALL,
+        handle,
+        buf.as_mut_ptr() as usize,
+        request_len.min(buf.len()),
+        buf.len(),
+        timeout,
+    )
+}
+
 pub fn sys_ipc_close(handle: usize) -> isize {
     sys_ipc(IPC_CMD_CLOSE, handle, 0, 0, 0, 0)
 }
@@ -381,8 +919,33 @@ pub fn sys_ipc_bind(name: &str) -> Option<usize> {
     if res < 0 { None } else { Some(res as usize) }
 }
 
+/// Only let the program at `exe` (and others named for the same prefix) bind
+/// service names at or below `prefix`. Reserved to init.
+pub fn sys_ipc_restrict(prefix: &str, exe: &str) -> isize {
+    sys_ipc(
+        IPC_CMD_RESTRICT,
+        prefix.as_ptr() as usize,
+        prefix.len(),
+        exe.as_ptr() as usize,
+        exe.len(),
+        0,
+    )
+}
+
 pub fn sys_ipc_connect(name: &str) -> Option<usize> {
-    let res = sys_ipc(IPC_CMD_CONNECT, name.as_ptr() as usize, name.len(), 0, 0, 0);
+    sys_ipc_connect_with(name, 0, 0)
+}
+
+/// Connect to `name` over a channel configured like `sys_ipc_create_with`.
+pub fn sys_ipc_connect_with(name: &str, budget: usize, flags: usize) -> Option<usize> {
+    let res = sys_ipc(
+        IPC_CMD_CONNECT,
+        name.as_ptr() as usize,
+        name.len(),
+        budget,
+        flags,
+        0,
+    );
     if res < 0 { None } else { Some(res as usize) }
 }
 
@@ -406,7 +969,7 @@ struct Stdout;
 
 impl core::fmt::Write for Stdout {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        sys_write(1, s);
+        sys_write(STDOUT, s);
         Ok(())
     }
 }
@@ -431,7 +994,7 @@ struct Stderr;
 
 impl core::fmt::Write for Stderr {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        sys_write(0, s);
+        sys_write(STDERR, s);
         Ok(())
     }
 }
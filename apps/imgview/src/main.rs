@@ -49,9 +49,8 @@ fn get_framebuffer() -> (*mut u8, usize, usize, usize) {
 }
 
 fn read_file(path: &str) -> Option<alloc::vec::Vec<u8>> {
-    if let Some(fd) = sys_open(path, false) {
-        let size = sys_seek(fd, 0, 1);
-        sys_seek(fd, 0, 2);
+    if let Ok(fd) = sys_open2(path, O_RDONLY) {
+        let size = sys_fstat(fd).map_or(0, |metadata| metadata.size as usize);
         let mut buf = alloc::vec![0u8; size];
         sys_read2(fd, &mut buf);
         sys_close(fd);
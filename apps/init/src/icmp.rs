@@ -19,7 +19,7 @@ fn compute_checksum(data: &[u8]) -> u16 {
     !(sum as u16)
 }
 
-pub fn try_handle_ping(packet: &[u8], handle: usize) {
+pub fn try_handle_ping(packet: &[u8], conn: &mut crate::netdump::Connection) {
     let version_ihl = packet[0];
     let ihl = version_ihl & 0x0F;
     let payload_start = (ihl * 4) as usize;
@@ -69,5 +69,5 @@ pub fn try_handle_ping(packet: &[u8], handle: usize) {
     let ip_checksum = compute_checksum(&reply[..payload_start]);
     reply[ip_checksum_off..ip_checksum_off + 2].copy_from_slice(&ip_checksum.to_be_bytes());
 
-    crate::netdump::send_ipv4(&reply[..packet_len], handle);
+    conn.send_ipv4(&reply[..packet_len]);
 }
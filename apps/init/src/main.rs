@@ -17,17 +17,22 @@ static TEST: Globals = Globals {
     t: vcell::VolatileCell::new(0),
 };
 
-fn read_line(buf: &mut [u8]) -> usize {
+/// Read a line into `buf` and return its length, or `None` if the input
+/// ended before the line started.
+fn read_line(buf: &mut [u8]) -> Option<usize> {
     for (i, v) in buf.iter_mut().enumerate() {
         match dlos_app_rt::sys_read() {
-            b'\n' => return i,
+            b'\n' => return Some(i),
+            EOT if i == 0 => return None,
+            EOT => return Some(i),
             c => *v = c,
         }
     }
-    buf.len()
+    Some(buf.len())
 }
 
-fn list_dir(path: &str) {
+/// List the entries of `path`; `long` adds kind, size and modification time.
+fn list_dir(path: &str, long: bool) {
     if let Some(fd) = sys_opendir(path) {
         let mut entries = [DirEntry::empty(); 16];
         loop {
@@ -40,7 +45,9 @@ fn list_dir(path: &str) {
             }
             for entry in &entries[..count] {
                 let name = entry.name();
-                if entry.is_dir() {
+                if long {
+                    print_long_entry(path, name);
+                } else if entry.is_dir() {
                     println!("{name}/");
                 } else {
                     println!("{name}");
@@ -53,6 +60,98 @@ fn list_dir(path: &str) {
     }
 }
 
+/// One line of `ls -l`: `drwxr-xr-x 0 2024-05-01 12:00 name`. Filesystems
+/// without permissions show what their read-only attribute allows.
+fn print_long_entry(dir: &str, name: &str) {
+    let mut buf = [0u8; 512];
+    let separator = if dir.ends_with('/') { "" } else { "/" };
+    let Some(path) = concat(&mut buf, &[dir, separator, name]) else {
+        println!("?          ? ? {name}");
+        return;
+    };
+    let Some(metadata) = sys_lstat(path) else {
+        println!("?          ? ? {name}");
+        return;
+    };
+    let kind = match metadata.kind {
+        KIND_DIRECTORY => 'd',
+        KIND_CHAR_DEVICE => 'c',
+        KIND_BLOCK_DEVICE => 'b',
+        KIND_PIPE => 'p',
+        KIND_SYMLINK => 'l',
+        _ => '-',
+    };
+    let mode = if metadata.mode != 0 {
+        metadata.mode
+    } else {
+        let write = if metadata.attributes & ATTR_READ_ONLY != 0 {
+            0
+        } else {
+            0o222
+        };
+        let search = if metadata.is_dir() { 0o111 } else { 0 };
+        0o444 | write | search
+    };
+    print!("{kind}");
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        print!("{}", if mode & 0o400 >> i != 0 { c } else { '-' });
+    }
+    print!(" {:>10} ", metadata.size);
+    if metadata.modified == 0 {
+        print!("{:16} ", "-");
+    } else {
+        let days = metadata.modified / 86400;
+        let seconds = metadata.modified % 86400;
+        let (year, month, day) = civil_from_days(days);
+        print!(
+            "{year:04}-{month:02}-{day:02} {:02}:{:02} ",
+            seconds / 3600,
+            seconds / 60 % 60
+        );
+    }
+    if metadata.is_dir() {
+        println!("{name}/");
+    } else if metadata.kind == KIND_SYMLINK {
+        let mut target = [0u8; 256];
+        match sys_readlink(path, &mut target) {
+            Ok(len) => println!(
+                "{name} -> {}",
+                str::from_utf8(&target[..len]).unwrap_or("?")
+            ),
+            Err(_) => println!("{name} -> ?"),
+        }
+    } else {
+        println!("{name}");
+    }
+}
+
+/// Join `parts` in `buf`, or `None` if they do not fit.
+fn concat<'a>(buf: &'a mut [u8], parts: &[&str]) -> Option<&'a str> {
+    let mut len = 0;
+    for part in parts {
+        buf.get_mut(len..len + part.len())?
+            .copy_from_slice(part.as_bytes());
+        len += part.len();
+    }
+    str::from_utf8(&buf[..len]).ok()
+}
+
+/// The date `days` after 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
 fn print_help() {
     println!("Builtin commands:");
     println!("  help               Show this help text");
@@ -70,14 +169,26 @@ fn print_help() {
     println!("  initrd-read        Read from /dev/initrd");
     println!("  file-read <path>   Print file contents");
     println!("  file-write <path>  Write lines to a file until EOF");
-    println!("  ls [path]          List directory entries");
-    println!("  mount <ahci|nvme|usb> <disk> <partition> <path/>");
+    println!("  ls [-l] [path]     List directory entries, -l with details");
+    println!("  cd [path]          Change the working directory");
+    println!("  pwd                Print the working directory");
+    println!("  mount              List mounted filesystems");
+    println!("  mount [-t type] [-o ro,noexec] <device> <path>");
+    println!("                     Mount e.g. /dev/usb0p1, or tmpfs with -t tmpfs");
+    println!("  umount <path>      Detach a mounted filesystem");
+    println!("  sync               Write cached changes to the disks");
     println!("  file-rm            Remove /test.txt");
+    println!("  mkdir <path>       Create a directory");
+    println!("  mv <from> <to>     Move or rename a file or directory");
+    println!("  ln -s <target> <path>  Create a symbolic link");
+    println!("  rm [-r] <path>     Remove a file, -r a directory and its contents");
     println!("  beep <freq>        Play a beep");
     println!("  poweroff           Power off the machine");
     println!("  reboot             Reboot the machine");
     println!("  netdump            Dump recieved packets from upppd");
     println!();
+    println!("Commands can be joined with `|` and redirected with `< path` and `> path`.");
+    println!();
     println!("External commands:");
     println!("  /bin/<name>        Execute a command from /bin");
     println!("  exiter             Do nothing");
@@ -96,201 +207,533 @@ fn print_help() {
     println!("  upppd              PPPoS user-space network service over /dev/serial");
 }
 
-fn shell_main_loop() {
-    let mut buf = [0u8; 128];
-    loop {
-        print!("[User@DoglinkOS-2nd /]$ ");
-        let len = read_line(&mut buf);
-        let cmd = str::from_utf8(&buf[..len]).unwrap();
-        if cmd.is_empty() {
-            continue;
-        } else if cmd == "help" {
-            print_help();
-        } else if cmd == "panic-test" {
-            panic!("panic test");
-        } else if cmd == "exit" {
-            break;
-        } else if cmd == "sysinfo" {
-            println!("DoglinkOS-2nd version 1.4.1");
-            println!("DoglinkOS Shell version 1.4.1");
-            println!("In user mode");
-            println!(
-                "Console: {} rows, {} cols",
-                sys_info(1).unwrap(),
-                sys_info(0).unwrap()
-            );
-            println!(
-                "Framebuffer: {} x {}, pitch {}",
-                sys_info(6).unwrap(),
-                sys_info(7).unwrap(),
-                sys_info(9).unwrap()
-            );
-            println!("Current shell PID: {}", sys_info(2).unwrap());
-            println!("Current kernel ticks: {}", sys_info(3).unwrap());
-        } else if let Some(content) = cmd.strip_prefix("echo ") {
-            println!("{content}");
-        } else if cmd == "clear" {
-            print!("\x1b[H\x1b[2J\x1b[3J");
-        } else if cmd == "disk-read" {
-            if let Some(fd) = sys_open("/dev/disk0", false) {
-                let mut content = [0; 512];
-                sys_read2(fd, &mut content);
-                println!("{content:?}");
-                sys_seek(fd, 0, SEEK_SET);
-                sys_read2(fd, &mut content[..100]);
-                sys_read2(fd, &mut content[100..]);
-                println!("{content:?}");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/disk0");
-            }
-        } else if cmd == "nvme-read" {
-            if let Some(fd) = sys_open("/dev/nvme0-0", false) {
-                let mut content = [0; 512];
-                sys_read2(fd, &mut content);
-                println!("{content:?}");
-                sys_seek(fd, 0, SEEK_SET);
-                sys_read2(fd, &mut content[..100]);
-                sys_read2(fd, &mut content[100..]);
-                println!("{content:?}");
+enum Builtin {
+    Done,
+    Exit,
+    /// Not a builtin; run it from `/bin`.
+    External,
+}
+
+fn run_builtin(cmd: &str) -> Builtin {
+    if cmd == "help" {
+        print_help();
+    } else if cmd == "panic-test" {
+        panic!("panic test");
+    } else if cmd == "exit" {
+        return Builtin::Exit;
+    } else if cmd == "sysinfo" {
+        println!("DoglinkOS-2nd version 1.4.1");
+        println!("DoglinkOS Shell version 1.4.1");
+        println!("In user mode");
+        println!(
+            "Console: {} rows, {} cols",
+            sys_info(1).unwrap(),
+            sys_info(0).unwrap()
+        );
+        println!(
+            "Framebuffer: {} x {}, pitch {}",
+            sys_info(6).unwrap(),
+            sys_info(7).unwrap(),
+            sys_info(9).unwrap()
+        );
+        println!("Current shell PID: {}", sys_info(2).unwrap());
+        println!("Current kernel ticks: {}", sys_info(3).unwrap());
+    } else if let Some(content) = cmd.strip_prefix("echo ") {
+        println!("{content}");
+    } else if cmd == "clear" {
+        print!("\x1b[H\x1b[2J\x1b[3J");
+    } else if cmd == "disk-read" {
+        if let Some(fd) = sys_open("/dev/disk0", false) {
+            let mut content = [0; 512];
+            sys_read2(fd, &mut content);
+            println!("{content:?}");
+            sys_seek(fd, 0, SEEK_SET);
+            sys_read2(fd, &mut content[..100]);
+            sys_read2(fd, &mut content[100..]);
+            println!("{content:?}");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/disk0");
+        }
+    } else if cmd == "nvme-read" {
+        if let Some(fd) = sys_open("/dev/nvme0-0", false) {
+            let mut content = [0; 512];
+            sys_read2(fd, &mut content);
+            println!("{content:?}");
+            sys_seek(fd, 0, SEEK_SET);
+            sys_read2(fd, &mut content[..100]);
+            sys_read2(fd, &mut content[100..]);
+            println!("{content:?}");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/nvme0-0");
+        }
+    } else if cmd == "usb-read" {
+        if let Some(fd) = sys_open("/dev/usb0", false) {
+            let mut content = [0; 512];
+            sys_read2(fd, &mut content);
+            println!("{content:?}");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/usb0");
+        }
+    } else if cmd == "disk-size" {
+        if let Some(fd) = sys_open("/dev/disk0", false) {
+            let sz = sys_seek(fd, 0, SEEK_END);
+            println!("/dev/disk0 is {sz:?} bytes");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/disk0");
+        }
+    } else if cmd == "nvme-size" {
+        if let Some(fd) = sys_open("/dev/nvme0-0", false) {
+            let sz = sys_seek(fd, 0, SEEK_END);
+            println!("/dev/nvme0-0 is {sz:?} bytes");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/nvme0-0");
+        }
+    } else if cmd == "usb-size" {
+        if let Some(fd) = sys_open("/dev/usb0", false) {
+            let sz = sys_seek(fd, 0, SEEK_END);
+            println!("/dev/usb0 is {sz:?} bytes");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/usb0");
+        }
+    } else if cmd == "initrd-read" {
+        if let Some(fd) = sys_open("/dev/initrd", false) {
+            let mut content = [0; 512];
+            sys_read2(fd, &mut content);
+            println!("{content:?}");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/initrd");
+        }
+    } else if let Some(file_name) = cmd.strip_prefix("file-read ") {
+        print_file(file_name);
+    } else if let Some(file_name) = cmd.strip_prefix("file-write ") {
+        match sys_open2(file_name, O_WRONLY | O_CREAT | O_TRUNC) {
+            Ok(fd) => {
+                let mut line_buf = [0u8; 128];
+                while line_buf[0] != b'E' || line_buf[1] != b'O' || line_buf[2] != b'F' {
+                    let Some(len) = read_line(&mut line_buf) else {
+                        break;
+                    };
+                    sys_write(fd, str::from_utf8(&line_buf[..len]).unwrap());
+                    sys_write(fd, "\n");
+                }
                 sys_close(fd);
-            } else {
-                println!("error while opening /dev/nvme0-0");
             }
-        } else if cmd == "usb-read" {
-            if let Some(fd) = sys_open("/dev/usb0", false) {
-                let mut content = [0; 512];
-                sys_read2(fd, &mut content);
-                println!("{content:?}");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/usb0");
+            Err(errno) => println!("cannot open {file_name}: {}", vfs_error(errno)),
+        }
+    } else if cmd == "ls" || cmd.starts_with("ls ") {
+        let args = cmd[2..].trim();
+        let (long, path) = match args.strip_prefix("-l") {
+            Some(path) if path.is_empty() || path.starts_with(' ') => (true, path.trim()),
+            _ => (false, args),
+        };
+        list_dir(if path.is_empty() { "." } else { path }, long);
+    } else if cmd == "pwd" {
+        let mut buf = [0u8; 256];
+        match sys_getcwd(&mut buf) {
+            Some(cwd) => println!("{cwd}"),
+            None => eprintln!("pwd: path too long"),
+        }
+    } else if cmd == "cd" || cmd.starts_with("cd ") {
+        let path = cmd[2..].trim();
+        let path = if path.is_empty() { "/" } else { path };
+        if !sys_chdir(path) {
+            eprintln!("cd: {path}: no such directory");
+        }
+    } else if cmd == "mount" {
+        print_file("/proc/mounts");
+    } else if let Some(params) = cmd.strip_prefix("mount ") {
+        mount_command(params);
+    } else if let Some(mountpoint) = cmd.strip_prefix("umount ") {
+        let res = sys_umount(mountpoint.trim());
+        if res < 0 {
+            eprintln!("umount: {}", mount_error(res, "not a mount point"));
+        }
+    } else if cmd == "sync" {
+        let res = sys_sync();
+        if res < 0 {
+            eprintln!("sync: {}", vfs_error(res));
+        }
+    } else if let Some(path) = cmd.strip_prefix("mkdir ") {
+        let path = path.trim();
+        let res = sys_mkdir(path);
+        if res < 0 {
+            eprintln!("mkdir: {path}: {}", vfs_error(res));
+        }
+    } else if let Some(args) = cmd.strip_prefix("mv ") {
+        let mut it = args.split_ascii_whitespace();
+        let (Some(from), Some(to), None) = (it.next(), it.next(), it.next()) else {
+            eprintln!("usage: mv <from> <to>");
+            return Builtin::Done;
+        };
+        let res = sys_rename(from, to);
+        if res < 0 {
+            eprintln!("mv: {from}: {}", vfs_error(res));
+        }
+    } else if let Some(args) = cmd.strip_prefix("ln ") {
+        let mut it = args.split_ascii_whitespace();
+        let (Some("-s"), Some(target), Some(path), None) =
+            (it.next(), it.next(), it.next(), it.next())
+        else {
+            eprintln!("usage: ln -s <target> <path>");
+            return Builtin::Done;
+        };
+        let res = sys_symlink(target, path);
+        if res < 0 {
+            eprintln!("ln: {path}: {}", vfs_error(res));
+        }
+    } else if let Some(args) = cmd.strip_prefix("rm ") {
+        let (recursive, path) = match args.trim().strip_prefix("-r ") {
+            Some(path) => (true, path.trim()),
+            None => (false, args.trim()),
+        };
+        let res = if recursive {
+            remove_tree(path)
+        } else {
+            sys_remove(path)
+        };
+        if res < 0 {
+            eprintln!("rm: {path}: {}", vfs_error(res));
+        }
+    } else if cmd.starts_with("file-rm") {
+        sys_remove("/test.txt");
+    } else if let Some(freq) = cmd.strip_prefix("beep ") {
+        if let Some(fd) = sys_open("/dev/pcspk", false) {
+            sys_write(fd, freq);
+            let start = sys_getticks();
+            while sys_getticks() < start + 50 {}
+            sys_write(fd, "stop");
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/pcspk");
+        }
+    } else if cmd == "poweroff" || cmd == "reboot" {
+        if let Some(fd) = sys_open("/dev/power", false) {
+            sys_write(fd, cmd);
+            sys_close(fd);
+        } else {
+            println!("error while opening /dev/power");
+        }
+    } else if let Some(cnt) = cmd
+        .strip_prefix("netdump")
+        .map(|x| x.trim().parse().unwrap_or(4))
+    {
+        netdump::main(cnt);
+    } else {
+        return Builtin::External;
+    }
+    Builtin::Done
+}
+
+fn print_file(file_name: &str) {
+    if let Ok(fd) = sys_open2(file_name, O_RDONLY) {
+        let mut remaining_size = sys_fstat(fd).map_or(0, |metadata| metadata.size as usize);
+        let mut buf = [0; 512];
+        while remaining_size > 0 {
+            let will_read = core::cmp::min(remaining_size, 512);
+            sys_read2(fd, &mut buf[..will_read]);
+            sys_write(STDOUT, str::from_utf8(&buf[..will_read]).unwrap());
+            remaining_size -= will_read;
+        }
+        sys_close(fd);
+    } else {
+        println!("file {file_name} not found");
+    }
+}
+
+/// `mount [-t <type>] [-o <options>] <device> <path>`
+fn mount_command(params: &str) {
+    let mut it = params.split_ascii_whitespace();
+    let (mut fs_type, mut options) = ("", "");
+    let mut operands = [""; 2];
+    let mut count = 0;
+    while let Some(arg) = it.next() {
+        let value = match arg {
+            "-t" => &mut fs_type,
+            "-o" => &mut options,
+            _ if count < operands.len() => {
+                operands[count] = arg;
+                count += 1;
+                continue;
             }
-        } else if cmd == "disk-size" {
-            if let Some(fd) = sys_open("/dev/disk0", false) {
-                let sz = sys_seek(fd, 0, SEEK_END);
-                println!("/dev/disk0 is {sz:?} bytes");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/disk0");
+            _ => {
+                count += 1;
+                break;
             }
-        } else if cmd == "nvme-size" {
-            if let Some(fd) = sys_open("/dev/nvme0-0", false) {
-                let sz = sys_seek(fd, 0, SEEK_END);
-                println!("/dev/nvme0-0 is {sz:?} bytes");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/nvme0-0");
+        };
+        let Some(next) = it.next() else {
+            count = 0;
+            break;
+        };
+        *value = next;
+    }
+    if count != operands.len() {
+        eprintln!("usage: mount [-t <type>] [-o <options>] <device> <path>");
+        return;
+    }
+    let res = sys_mount(operands[0], operands[1], fs_type, options);
+    if res < 0 {
+        eprintln!(
+            "mount: {}",
+            mount_error(res, "invalid mount point or option")
+        );
+    }
+}
+
+/// Remove `path` and, if it is a directory, everything in it. Symbolic links
+/// are removed, not followed. Returns 0 or the negative errno of the first
+/// removal that failed.
+fn remove_tree(path: &str) -> isize {
+    match sys_lstat(path) {
+        None => return -2,
+        Some(metadata) if !metadata.is_dir() => return sys_remove(path),
+        Some(_) => {}
+    }
+    // directory handles are snapshots, so read a batch, remove it and look
+    // again until only `.` and `..` are left
+    loop {
+        let Some(fd) = sys_opendir(path) else {
+            return -2;
+        };
+        let mut entries = [DirEntry::empty(); 8];
+        let count = sys_getdents(fd, &mut entries).unwrap_or(0);
+        sys_closedir(fd);
+        let mut removed = 0;
+        for entry in &entries[..count] {
+            let name = entry.name();
+            if name == "." || name == ".." {
+                continue;
             }
-        } else if cmd == "usb-size" {
-            if let Some(fd) = sys_open("/dev/usb0", false) {
-                let sz = sys_seek(fd, 0, SEEK_END);
-                println!("/dev/usb0 is {sz:?} bytes");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/usb0");
+            let mut buf = [0u8; 512];
+            let Some(child) = concat(&mut buf, &[path, "/", name]) else {
+                return -36;
+            };
+            let res = remove_tree(child);
+            if res < 0 {
+                return res;
             }
-        } else if cmd == "initrd-read" {
-            if let Some(fd) = sys_open("/dev/initrd", false) {
-                let mut content = [0; 512];
-                sys_read2(fd, &mut content);
-                println!("{content:?}");
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/initrd");
+            removed += 1;
+        }
+        if removed == 0 {
+            break;
+        }
+    }
+    sys_rmdir(path)
+}
+
+fn vfs_error(errno: isize) -> &'static str {
+    match errno {
+        -1 => "operation not supported",
+        -2 => "no such file or directory",
+        -5 => "I/O error",
+        -9 => "bad file descriptor",
+        -16 => "mount point busy",
+        -17 => "already exists",
+        -18 => "cannot move between filesystems",
+        -20 => "not a directory",
+        -21 => "is a directory",
+        -22 => "invalid argument",
+        -24 => "too many open files",
+        -28 => "no space left",
+        -29 => "not seekable",
+        -30 => "read-only filesystem",
+        -36 => "path too long",
+        -39 => "directory not empty",
+        -40 => "too many levels of symbolic links",
+        _ => "unknown error",
+    }
+}
+
+/// Describe an errno of `sys_mount` or `sys_umount`; `einval` depends on the
+/// call.
+fn mount_error(errno: isize, einval: &'static str) -> &'static str {
+    match errno {
+        -5 => "no supported filesystem on the device",
+        -16 => "mount point busy",
+        -19 => "no such device or filesystem type",
+        -22 => einval,
+        _ => "unknown error",
+    }
+}
+
+/// Replace the current process with `/bin/<cmd>`, or `cmd` if it is a path.
+fn exec_command(cmd: &str) -> ! {
+    let mut path = [0u8; 256];
+    let len = if cmd.starts_with('/') {
+        path[..cmd.len()].copy_from_slice(cmd.as_bytes());
+        cmd.len()
+    } else {
+        path[0..5].copy_from_slice(b"/bin/");
+        path[5..(5 + cmd.len())].copy_from_slice(cmd.as_bytes());
+        cmd.len() + 5
+    };
+    sys_exec(unsafe { core::str::from_utf8_unchecked(&path[..len]) });
+    eprintln!("unknown command");
+    sys_exit();
+}
+
+/// One command of a pipeline with its redirections.
+struct Stage<'a> {
+    command: &'a str,
+    input: Option<&'a str>,
+    output: Option<&'a str>,
+}
+
+impl<'a> Stage<'a> {
+    /// Parse `command [< input] [> output]`.
+    fn parse(text: &'a str) -> Option<Self> {
+        let end = text.find(['<', '>']).unwrap_or(text.len());
+        let mut stage = Stage {
+            command: text[..end].trim(),
+            input: None,
+            output: None,
+        };
+        let mut rest = &text[end..];
+        while let Some(op) = rest.chars().next() {
+            let after = rest[1..].trim_start();
+            let path_end = after
+                .find(|c: char| c.is_whitespace() || c == '<' || c == '>')
+                .unwrap_or(after.len());
+            let path = &after[..path_end];
+            if path.is_empty() {
+                return None;
             }
-        } else if let Some(file_name) = cmd.strip_prefix("file-read ") {
-            if let Some(fd) = sys_open(file_name, false) {
-                let mut remaining_size = sys_seek(fd, 0, SEEK_END);
-                sys_seek(fd, 0, SEEK_SET);
-                let mut buf = [0; 512];
-                while remaining_size > 0 {
-                    let will_read = core::cmp::min(remaining_size, 512);
-                    sys_read2(fd, &mut buf[..will_read]);
-                    sys_write(1, str::from_utf8(&buf[..will_read]).unwrap());
-                    remaining_size -= will_read;
-                }
-                sys_close(fd);
+            if op == '<' {
+                stage.input = Some(path);
             } else {
-                println!("file {file_name} not found");
+                stage.output = Some(path);
             }
-        } else if let Some(file_name) = cmd.strip_prefix("file-write ") {
-            if let Some(fd) = sys_open(file_name, true) {
-                let mut line_buf = [0u8; 128];
-                while line_buf[0] != b'E' || line_buf[1] != b'O' || line_buf[2] != b'F' {
-                    let len = read_line(&mut line_buf);
-                    sys_write(fd, str::from_utf8(&line_buf[..len]).unwrap());
-                    sys_write(fd, "\n");
-                }
-                sys_close(fd);
-            } else {
-                println!("error while opening {file_name}");
+            rest = after[path_end..].trim_start();
+            if !rest.is_empty() && !rest.starts_with(['<', '>']) {
+                return None;
             }
-        } else if cmd == "ls" {
-            list_dir("/");
-        } else if let Some(path) = cmd.strip_prefix("ls ") {
-            list_dir(path);
-        } else if let Some(params) = cmd.strip_prefix("mount ") {
-            let mut it = params.split(' ');
-            let typs = it.next().unwrap();
-            let typ = if typs == "ahci" {
-                0
-            } else if typs == "nvme" {
-                1
-            } else if typs == "usb" {
-                2
-            } else {
+        }
+        (!stage.command.is_empty()).then_some(stage)
+    }
+
+    /// Apply the redirections and run the command. Called in the forked
+    /// child, whose fds 0 and 1 already are the pipe ends.
+    fn run(&self) -> ! {
+        let redirections = [
+            (self.input, 0, O_RDONLY),
+            (self.output, 1, O_WRONLY | O_CREAT | O_TRUNC),
+        ];
+        for (path, fd, flags) in redirections {
+            let Some(path) = path else {
                 continue;
             };
-            let disk: usize = it.next().unwrap().parse().unwrap();
-            let part: usize = it.next().unwrap().parse().unwrap();
-            let mountpoint = it.next().unwrap();
-            if !mountpoint.ends_with('/') {
-                eprintln!("a mount point must end with /");
-            } else {
-                sys_mount(typ, disk, part, mountpoint);
-            }
-        } else if cmd.starts_with("file-rm") {
-            sys_remove("/test.txt");
-        } else if let Some(freq) = cmd.strip_prefix("beep ") {
-            if let Some(fd) = sys_open("/dev/pcspk", false) {
-                sys_write(fd, freq);
-                let start = sys_getticks();
-                while sys_getticks() < start + 50 {}
-                sys_write(fd, "stop");
+            let file = match sys_open2(path, flags) {
+                Ok(file) => file,
+                Err(errno) => {
+                    eprintln!("cannot open {path}: {}", vfs_error(errno));
+                    sys_exit();
+                }
+            };
+            sys_dup2(file, fd);
+            sys_close(file);
+        }
+        if let Builtin::External = run_builtin(self.command) {
+            exec_command(self.command);
+        }
+        sys_exit();
+    }
+}
+
+const MAX_STAGES: usize = 8;
+
+/// Run `a | b | ...`, each command in its own process, builtins included.
+fn run_pipeline(line: &str) {
+    let mut pids = [0usize; MAX_STAGES];
+    let mut count = 0;
+    // read end of the pipe from the previous stage
+    let mut input = None;
+    let mut stages = line.split('|').peekable();
+    while let Some(text) = stages.next() {
+        let Some(stage) = Stage::parse(text) else {
+            eprintln!("syntax error in `{}`", text.trim());
+            break;
+        };
+        if count == MAX_STAGES {
+            eprintln!("too many commands in a pipeline");
+            break;
+        }
+        let pipe = if stages.peek().is_some() {
+            let Some(pipe) = sys_pipe() else {
+                eprintln!("cannot create a pipe");
+                break;
+            };
+            Some(pipe)
+        } else {
+            None
+        };
+        let pid = sys_fork();
+        if pid == 0 {
+            if let Some(fd) = input {
+                sys_dup2(fd, 0);
                 sys_close(fd);
-            } else {
-                println!("error while opening /dev/pcspk");
             }
-        } else if cmd == "poweroff" || cmd == "reboot" {
-            if let Some(fd) = sys_open("/dev/power", false) {
-                sys_write(fd, cmd);
-                sys_close(fd);
-            } else {
-                println!("error while opening /dev/power");
+            if let Some((read_fd, write_fd)) = pipe {
+                sys_close(read_fd);
+                sys_dup2(write_fd, 1);
+                sys_close(write_fd);
             }
-        } else if let Some(cnt) = cmd
-            .strip_prefix("netdump")
-            .map(|x| x.trim().parse().unwrap_or(4))
-        {
-            netdump::main(cnt);
-        } else {
-            let mut buf2 = [0u8; 128];
-            let len2 = if buf[0] != b'/' {
-                buf2[0..5].copy_from_slice(b"/bin/");
-                buf2[5..(5 + len)].copy_from_slice(&buf[..len]);
-                len + 5
-            } else {
-                buf2[..len].copy_from_slice(&buf[..len]);
-                len
-            };
-            let fork_result = sys_fork();
-            if fork_result == 0 {
-                sys_exec(unsafe { core::str::from_utf8_unchecked(&buf2[..len2]) });
-                eprintln!("unknown command");
-                sys_exit();
-            } else if cmd != "upppd" {
-                sys_waitpid(fork_result);
+            stage.run();
+        }
+        if let Some(fd) = input {
+            sys_close(fd);
+        }
+        // the shell must not keep a write end open, or the reader never sees
+        // end of file
+        input = pipe.map(|(read_fd, write_fd)| {
+            sys_close(write_fd);
+            read_fd
+        });
+        pids[count] = pid;
+        count += 1;
+    }
+    if let Some(fd) = input {
+        sys_close(fd);
+    }
+    for pid in &pids[..count] {
+        sys_waitpid(*pid);
+    }
+}
+
+fn shell_main_loop() {
+    let mut buf = [0u8; 128];
+    loop {
+        let mut cwd = [0u8; 256];
+        print!(
+            "[User@DoglinkOS-2nd {}]$ ",
+            sys_getcwd(&mut cwd).unwrap_or("?")
+        );
+        let Some(len) = read_line(&mut buf) else {
+            continue;
+        };
+        let cmd = str::from_utf8(&buf[..len]).unwrap();
+        if cmd.is_empty() {
+            continue;
+        }
+        if cmd.contains(['|', '<', '>']) {
+            run_pipeline(cmd);
+            continue;
+        }
+        match run_builtin(cmd) {
+            Builtin::Done => {}
+            Builtin::Exit => break,
+            Builtin::External => {
+                let fork_result = sys_fork();
+                if fork_result == 0 {
+                    exec_command(cmd);
+                } else if cmd != "upppd" {
+                    sys_waitpid(fork_result);
+                }
             }
         }
     }
@@ -298,7 +741,9 @@ fn shell_main_loop() {
 
 #[unsafe(no_mangle)]
 extern "C" fn _start() -> ! {
-    sys_write(0, "\n\nDoglinkOS Shell v1.4.1\n");
+    sys_write(STDOUT, "\n\nDoglinkOS Shell v1.4.1\n");
+    // keep the network service names for the real network daemon
+    sys_ipc_restrict("net", "/bin/upppd");
     shell_main_loop();
     if sys_fork() == 0 {
         // child
@@ -1,14 +1,20 @@
 use dlos_app_rt::*;
+use dlos_ipc_proto::ppp::{Event, Request, SERVICE_NAME, Status};
+use dlos_ipc_proto::{Correlator, Frame};
 
-const IPC_EAGAIN: isize = -11;
+/// A connection to the PPP service.
+pub struct Connection {
+    handle: usize,
+    ids: Correlator,
+}
 
-fn recv_poll(handle: usize, buf: &mut [u8]) -> isize {
-    loop {
-        let recv_len = sys_ipc_recv(handle, buf);
-        if recv_len != IPC_EAGAIN {
-            return recv_len;
+impl Connection {
+    pub fn send_ipv4(&mut self, packet: &[u8]) {
+        let id = self.ids.next_id();
+        let request = Request::SendIpv4(packet);
+        if let Err(err) = dlos_ipc_proto::send_wait(self.handle, id, &request, IPC_WAIT_FOREVER) {
+            println!("netdump: send failed: {err:?}");
         }
-        core::hint::spin_loop();
     }
 }
 
@@ -22,21 +28,34 @@ fn connect_poll(name: &str) -> usize {
 }
 
 pub fn main(mut cnt: usize) {
-    let handle = connect_poll("upppd");
-    let mut buf = [0u8; 4096];
+    let mut conn = Connection {
+        handle: connect_poll(SERVICE_NAME),
+        ids: Correlator::new(),
+    };
+    let mut buf = [0u8; dlos_ipc_proto::MAX_FRAME_LEN];
     loop {
-        let msg_len = recv_poll(handle, &mut buf) as usize;
-        let msg = &mut buf[..msg_len];
-        match msg[0] {
-            0x81 => handle_inbound_ipv4(&msg[1..], handle),
-            0x82 => handle_status(msg),
-            _ => continue,
+        match dlos_ipc_proto::recv_wait::<Event>(conn.handle, &mut buf, IPC_WAIT_FOREVER) {
+            Ok(Frame {
+                message: Event::ReceivedIpv4(packet),
+                ..
+            }) => handle_inbound_ipv4(packet, &mut conn),
+            Ok(Frame {
+                message: Event::Status(status),
+                ..
+            }) => handle_status(&status),
+            Ok(_) => continue,
+            Err(dlos_ipc_proto::Error::Closed) => break,
+            Err(err) => {
+                println!("netdump: bad message: {err:?}");
+                continue;
+            }
         }
         cnt -= 1;
         if cnt == 0 {
             break;
         }
     }
+    let _ = sys_ipc_close(conn.handle);
 }
 
 fn print_ipv4_header(packet: &[u8]) -> Option<usize> {
@@ -148,31 +167,20 @@ fn print_ipv4_addr(addr: &[u8]) {
     }
 }
 
-pub fn send_ipv4(packet: &[u8], handle: usize) {
-    let mut buf = [0u8; 4096];
-    buf[0] = 1;
-    buf[1..(packet.len() + 1)].copy_from_slice(packet);
-    sys_ipc_send(handle, &buf[..packet.len() + 1]);
-}
-
-fn handle_inbound_ipv4(packet: &[u8], handle: usize) {
-    crate::icmp::try_handle_ping(packet, handle);
+fn handle_inbound_ipv4(packet: &[u8], conn: &mut Connection) {
+    crate::icmp::try_handle_ping(packet, conn);
     println!("---------- BEGIN IPV4 PACKET ----------");
     print_ipv4_header(packet);
     println!("----------- END IPV4 PACKET -----------");
 }
 
-fn handle_status(status: &[u8]) {
-    let phase = status[1];
-    let local_addr = core::net::Ipv4Addr::new(status[2], status[3], status[4], status[5]);
-    let peer_addr = core::net::Ipv4Addr::new(status[6], status[7], status[8], status[9]);
-    let dns1 = core::net::Ipv4Addr::new(status[10], status[11], status[12], status[13]);
-    let dns2 = core::net::Ipv4Addr::new(status[14], status[15], status[16], status[17]);
+fn handle_status(status: &Status) {
+    let unknown = core::net::Ipv4Addr::UNSPECIFIED;
     println!("--------- BEGIN STATUS REPORT ---------");
-    println!("Phase: {phase}");
-    println!("Local Address: {local_addr}");
-    println!("Peer Address: {peer_addr}");
-    println!("DNS 1: {dns1}");
-    println!("DNS 2: {dns2}");
+    println!("Phase: {:?}", status.phase);
+    println!("Local Address: {}", status.address.unwrap_or(unknown));
+    println!("Peer Address: {}", status.peer_address.unwrap_or(unknown));
+    println!("DNS 1: {}", status.dns_servers[0].unwrap_or(unknown));
+    println!("DNS 2: {}", status.dns_servers[1].unwrap_or(unknown));
     println!("---------- END STATUS REPORT ----------");
 }
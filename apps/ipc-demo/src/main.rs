@@ -6,16 +6,6 @@ use dlos_app_rt::*;
 const IPC_EAGAIN: isize = -11;
 const NAMED_CHANNEL: &str = "ipc-demo.named";
 
-fn recv_poll(handle: usize, buf: &mut [u8]) -> isize {
-    loop {
-        let recv_len = sys_ipc_recv(handle, buf);
-        if recv_len != IPC_EAGAIN {
-            return recv_len;
-        }
-        core::hint::spin_loop();
-    }
-}
-
 fn connect_poll(name: &str) -> usize {
     loop {
         if let Some(handle) = sys_ipc_connect(name) {
@@ -25,15 +15,6 @@ fn connect_poll(name: &str) -> usize {
     }
 }
 
-fn accept_poll(listener: usize) -> usize {
-    loop {
-        if let Some(handle) = sys_ipc_accept(listener) {
-            return handle;
-        }
-        core::hint::spin_loop();
-    }
-}
-
 fn run_named_client(client_id: usize) -> ! {
     let handle = connect_poll(NAMED_CHANNEL);
     println!("ipc-demo client {client_id} connected");
@@ -45,15 +26,11 @@ fn run_named_client(client_id: usize) -> ! {
         outbound[msg.len()] = b'0' + client_id as u8;
         msg.len() + 1
     };
-    let send_len = sys_ipc_send(handle, &outbound[..outbound_len]);
-    if send_len < 0 {
-        eprintln!("ipc-demo client {client_id}: send failed {}", send_len);
-    }
-
     let mut buf = [0u8; 128];
-    let recv_len = recv_poll(handle, &mut buf);
+    buf[..outbound_len].copy_from_slice(&outbound[..outbound_len]);
+    let recv_len = sys_ipc_call(handle, &mut buf, outbound_len, IPC_WAIT_FOREVER);
     if recv_len < 0 {
-        eprintln!("ipc-demo client {client_id}: recv failed {}", recv_len);
+        eprintln!("ipc-demo client {client_id}: call failed {}", recv_len);
     } else {
         let msg = core::str::from_utf8(&buf[..recv_len as usize]).unwrap_or("<invalid utf8>");
         println!("ipc-demo client {client_id} received: {msg}");
@@ -71,9 +48,12 @@ fn run_named_server() -> ! {
     println!("ipc-demo server listening on {NAMED_CHANNEL}");
 
     for client_id in 1..=3 {
-        let conn = accept_poll(listener);
+        let Some(conn) = sys_ipc_accept_wait(listener, IPC_WAIT_FOREVER) else {
+            eprintln!("ipc-demo server: accept failed");
+            break;
+        };
         let mut buf = [0u8; 128];
-        let recv_len = recv_poll(conn, &mut buf);
+        let recv_len = sys_ipc_recv_wait(conn, &mut buf, IPC_WAIT_FOREVER);
         if recv_len < 0 {
             eprintln!(
                 "ipc-demo server: recv from client {client_id} failed {}",
@@ -103,6 +83,126 @@ fn run_named_server() -> ! {
     sys_exit();
 }
 
+fn recv_handles_poll(
+    handle: usize,
+    buf: &mut [u8],
+    handles: &mut [IpcHandleDesc],
+) -> (isize, usize) {
+    loop {
+        let res = sys_ipc_recv_handles(handle, buf, handles);
+        if res.0 != IPC_EAGAIN {
+            return res;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Hand a child a dedicated channel over a control channel.
+fn run_handle_passing() {
+    let Some((control_parent, control_child)) = sys_ipc_create() else {
+        eprintln!("ipc-demo: ipc_create failed");
+        return;
+    };
+    let pid = sys_fork();
+    if pid == 0 {
+        let _ = sys_ipc_close(control_parent);
+        let mut buf = [0u8; 128];
+        let mut handles = [IpcHandleDesc::default(); 1];
+        let (recv_len, count) = recv_handles_poll(control_child, &mut buf, &mut handles);
+        if recv_len < 0 || count != 1 {
+            eprintln!(
+                "ipc-demo child: handle recv failed {} ({count} handles)",
+                recv_len
+            );
+            sys_exit();
+        }
+        let data = handles[0].index as usize;
+        let recv_len = sys_ipc_recv_wait(data, &mut buf, IPC_WAIT_FOREVER);
+        if recv_len < 0 {
+            eprintln!("ipc-demo child: recv on passed channel failed {}", recv_len);
+        } else {
+            let msg = core::str::from_utf8(&buf[..recv_len as usize]).unwrap_or("<invalid utf8>");
+            println!("ipc-demo child received over passed channel: {msg}");
+        }
+        let _ = sys_ipc_close(data);
+        let _ = sys_ipc_close(control_child);
+        sys_exit();
+    }
+    let _ = sys_ipc_close(control_child);
+
+    let Some((data_parent, data_child)) = sys_ipc_create() else {
+        eprintln!("ipc-demo parent: ipc_create failed");
+        return;
+    };
+    let send_len = sys_ipc_send_handles(
+        control_parent,
+        b"your channel",
+        &[IpcHandleDesc::ipc(data_child).moved()],
+    );
+    if send_len < 0 {
+        eprintln!("ipc-demo parent: handle send failed {}", send_len);
+    }
+    let send_len = sys_ipc_send(data_parent, b"hello over a passed channel");
+    if send_len < 0 {
+        eprintln!(
+            "ipc-demo parent: send on passed channel failed {}",
+            send_len
+        );
+    }
+    sys_waitpid(pid);
+    let _ = sys_ipc_close(data_parent);
+    let _ = sys_ipc_close(control_parent);
+}
+
+/// Push 64 KiB through a stream channel whose ends only queue 8 KiB, so the
+/// sender keeps blocking on the reader.
+fn run_stream_transfer() {
+    const TOTAL: usize = 64 * 1024;
+    let Some((reader, writer)) = sys_ipc_create_with(8 * 1024, IPC_CHANNEL_STREAM) else {
+        eprintln!("ipc-demo: stream ipc_create failed");
+        return;
+    };
+
+    let pid = sys_fork();
+    if pid == 0 {
+        let _ = sys_ipc_close(reader);
+        let mut chunk = [0u8; 4096];
+        let mut sent = 0;
+        while sent < TOTAL {
+            for (idx, byte) in chunk.iter_mut().enumerate() {
+                *byte = (sent + idx) as u8;
+            }
+            let len = chunk.len().min(TOTAL - sent);
+            let res = sys_ipc_send_wait(writer, &chunk[..len], IPC_WAIT_FOREVER);
+            if res < 0 {
+                eprintln!("ipc-demo stream writer: send failed {}", res);
+                break;
+            }
+            sent += res as usize;
+        }
+        let _ = sys_ipc_close(writer);
+        sys_exit();
+    }
+
+    let _ = sys_ipc_close(writer);
+    let mut buf = [0u8; 1000];
+    let mut received = 0;
+    let mut intact = true;
+    loop {
+        let res = sys_ipc_recv_wait(reader, &mut buf, IPC_WAIT_FOREVER);
+        if res <= 0 {
+            break;
+        }
+        for (idx, byte) in buf[..res as usize].iter().enumerate() {
+            intact &= *byte == (received + idx) as u8;
+        }
+        received += res as usize;
+    }
+    println!("ipc-demo stream received {received} of {TOTAL} bytes, intact: {intact}");
+    let _ = sys_ipc_close(reader);
+    sys_waitpid(pid);
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn _start() -> ! {
     let Some((parent_end, child_end)) = sys_ipc_create() else {
@@ -115,7 +215,7 @@ extern "C" fn _start() -> ! {
         let _ = sys_ipc_close(parent_end);
 
         let mut buf = [0u8; 128];
-        let recv_len = recv_poll(child_end, &mut buf);
+        let recv_len = sys_ipc_recv_wait(child_end, &mut buf, IPC_WAIT_FOREVER);
         if recv_len < 0 {
             eprintln!("ipc-demo child: recv failed {}", recv_len);
         } else {
@@ -146,7 +246,7 @@ extern "C" fn _start() -> ! {
     }
 
     let mut buf = [0u8; 128];
-    let recv_len = recv_poll(parent_end, &mut buf);
+    let recv_len = sys_ipc_recv_wait(parent_end, &mut buf, IPC_WAIT_FOREVER);
     if recv_len < 0 {
         eprintln!("ipc-demo parent: recv failed {}", recv_len);
     } else {
@@ -157,6 +257,9 @@ extern "C" fn _start() -> ! {
     let _ = sys_ipc_close(parent_end);
     sys_waitpid(pid);
 
+    run_handle_passing();
+    run_stream_transfer();
+
     let server_pid = sys_fork();
     if server_pid == 0 {
         run_named_server();
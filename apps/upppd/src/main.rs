@@ -5,29 +5,19 @@ extern crate alloc;
 
 use alloc::vec::Vec;
 use dlos_app_rt::*;
+use dlos_ipc_proto::ppp::{ErrorCode, Event, Phase, Request, SERVICE_NAME, Status};
+use dlos_ipc_proto::{Frame, UNSOLICITED};
 use good_memory_allocator::SpinLockedAllocator;
+use ppproto::Config;
 use ppproto::pppos::{PPPoS, PPPoSAction};
-use ppproto::{Config, Phase};
 
 #[global_allocator]
 static ALLOCATOR: SpinLockedAllocator = SpinLockedAllocator::empty();
 
 const SERIAL_PATH: &str = "/dev/serial";
-const SERVICE_NAME: &str = "upppd";
-
-const IPC_EAGAIN: isize = -11;
-
-const OP_SEND_IPV4: u8 = 1;
-const OP_QUERY_STATUS: u8 = 2;
-
-const EVT_ACK: u8 = 0x80;
-const EVT_RX_IPV4: u8 = 0x81;
-const EVT_STATUS: u8 = 0x82;
-const EVT_ERROR: u8 = 0xFF;
 
 const SERIAL_RX_BUF_SIZE: usize = 2048;
 const PPP_TX_BUF_SIZE: usize = 2304;
-const IPC_BUF_SIZE: usize = 4096;
 
 #[unsafe(no_mangle)]
 extern "C" fn _start() -> ! {
@@ -81,13 +71,13 @@ fn main() {
     let mut serial_in = [0u8; 256];
     let mut serial_rx_buf = [0u8; SERIAL_RX_BUF_SIZE];
     let mut tx_buf = [0u8; PPP_TX_BUF_SIZE];
-    let mut ipc_buf = [0u8; IPC_BUF_SIZE];
+    let mut ipc_buf = [0u8; dlos_ipc_proto::MAX_FRAME_LEN];
     let mut status_phase = Phase::Dead;
 
     loop {
         while let Some(handle) = sys_ipc_accept(listener) {
-            let status = build_status_event(ppp.status().phase, ppp.status().ipv4.as_ref());
-            let _ = sys_ipc_send(handle, &status);
+            let status = Event::Status(status(&ppp));
+            let _ = dlos_ipc_proto::send(handle, UNSOLICITED, &status);
             clients.push(handle);
         }
 
@@ -124,46 +114,46 @@ fn main() {
             &mut clients,
         ) {}
 
-        let current_status = ppp.status();
+        let current_status = status(&ppp);
         if current_status.phase != status_phase {
             status_phase = current_status.phase;
-            let event = build_status_event(current_status.phase, current_status.ipv4.as_ref());
-            broadcast(&mut clients, &event);
+            broadcast(&mut clients, &Event::Status(current_status));
         }
 
         let mut idx = 0;
         while idx < clients.len() {
             let handle = clients[idx];
-            let recv_len = sys_ipc_recv(handle, &mut ipc_buf);
-            if recv_len == IPC_EAGAIN {
-                idx += 1;
-                continue;
-            }
-            if recv_len <= 0 {
-                let _ = sys_ipc_close(handle);
-                clients.swap_remove(idx);
-                continue;
-            }
-
-            let reply =
-                process_client_request(&mut ppp, &ipc_buf[..recv_len as usize], &mut tx_buf);
-            match reply {
-                ClientReply::Immediate(buf) => {
-                    if sys_ipc_send(handle, &buf) < 0 {
-                        let _ = sys_ipc_close(handle);
-                        clients.swap_remove(idx);
-                        continue;
-                    }
+            let (id, reply) = match dlos_ipc_proto::recv::<Request>(handle, &mut ipc_buf) {
+                Ok(Frame { id, message }) => {
+                    (id, process_client_request(&mut ppp, message, &mut tx_buf))
+                }
+                Err(dlos_ipc_proto::Error::WouldBlock) => {
+                    idx += 1;
+                    continue;
+                }
+                Err(dlos_ipc_proto::Error::Closed | dlos_ipc_proto::Error::Ipc(_)) => {
+                    let _ = sys_ipc_close(handle);
+                    clients.swap_remove(idx);
+                    continue;
                 }
+                // a frame that does not decode only costs the client an error
+                Err(_) => (
+                    UNSOLICITED,
+                    ClientReply::Immediate(Event::Error(ErrorCode::BadRequest)),
+                ),
+            };
+
+            let event = match reply {
+                ClientReply::Immediate(event) => event,
                 ClientReply::Transmit(len) => {
                     write_raw(serial_fd, &tx_buf[..len]);
-                    let ack = build_ack(0);
-                    if sys_ipc_send(handle, &ack) < 0 {
-                        let _ = sys_ipc_close(handle);
-                        clients.swap_remove(idx);
-                        continue;
-                    }
+                    Event::Ack
                 }
+            };
+            if dlos_ipc_proto::send(handle, id, &event).is_err() {
+                let _ = sys_ipc_close(handle);
+                clients.swap_remove(idx);
+                continue;
             }
             idx += 1;
         }
@@ -173,27 +163,17 @@ fn main() {
 }
 
 enum ClientReply {
-    Immediate([u8; 32]),
+    Immediate(Event<'static>),
     Transmit(usize),
 }
 
-fn process_client_request(ppp: &mut PPPoS<'_>, req: &[u8], tx_buf: &mut [u8]) -> ClientReply {
-    if req.is_empty() {
-        return ClientReply::Immediate(build_error(1));
-    }
-    match req[0] {
-        OP_SEND_IPV4 => {
-            let payload = &req[1..];
-            match ppp.send(payload, tx_buf) {
-                Ok(len) => ClientReply::Transmit(len),
-                Err(_) => ClientReply::Immediate(build_error(2)),
-            }
-        }
-        OP_QUERY_STATUS => {
-            let status = ppp.status();
-            ClientReply::Immediate(build_status_event(status.phase, status.ipv4.as_ref()))
-        }
-        _ => ClientReply::Immediate(build_error(3)),
+fn process_client_request(ppp: &mut PPPoS<'_>, req: Request, tx_buf: &mut [u8]) -> ClientReply {
+    match req {
+        Request::SendIpv4(payload) => match ppp.send(payload, tx_buf) {
+            Ok(len) => ClientReply::Transmit(len),
+            Err(_) => ClientReply::Immediate(Event::Error(ErrorCode::SendFailed)),
+        },
+        Request::QueryStatus => ClientReply::Immediate(Event::Status(status(ppp))),
     }
 }
 
@@ -211,20 +191,20 @@ fn handle_ppp_actions(
             true
         }
         PPPoSAction::Received(range) => {
-            let mut out = Vec::with_capacity(1 + range.len());
-            out.push(EVT_RX_IPV4);
-            out.extend_from_slice(&rx_buf[range]);
-            broadcast(clients, &out);
+            broadcast(clients, &Event::ReceivedIpv4(&rx_buf[range]));
             true
         }
     }
 }
 
-fn broadcast(clients: &mut Vec<usize>, payload: &[u8]) {
+/// Send `payload` to every client. A client whose queue is full misses it,
+/// one that hung up is dropped.
+fn broadcast(clients: &mut Vec<usize>, event: &Event) {
     let mut idx = 0;
     while idx < clients.len() {
         let handle = clients[idx];
-        if sys_ipc_send(handle, payload) < 0 {
+        let sent = dlos_ipc_proto::send(handle, UNSOLICITED, event);
+        if sent.is_err() && sent != Err(dlos_ipc_proto::Error::WouldBlock) {
             let _ = sys_ipc_close(handle);
             clients.swap_remove(idx);
         } else {
@@ -233,45 +213,22 @@ fn broadcast(clients: &mut Vec<usize>, payload: &[u8]) {
     }
 }
 
-fn build_ack(code: u8) -> [u8; 32] {
-    let mut buf = [0u8; 32];
-    buf[0] = EVT_ACK;
-    buf[1] = code;
-    buf
-}
-
-fn build_error(code: u8) -> [u8; 32] {
-    let mut buf = [0u8; 32];
-    buf[0] = EVT_ERROR;
-    buf[1] = code;
-    buf
-}
-
-fn build_status_event(phase: Phase, ipv4: Option<&ppproto::Ipv4Status>) -> [u8; 32] {
-    let mut buf = [0u8; 32];
-    buf[0] = EVT_STATUS;
-    buf[1] = match phase {
-        Phase::Dead => 0,
-        Phase::Establish => 1,
-        Phase::Auth => 2,
-        Phase::Network => 3,
-        Phase::Open => 4,
+fn status(ppp: &PPPoS<'_>) -> Status {
+    let status = ppp.status();
+    let phase = match status.phase {
+        ppproto::Phase::Dead => Phase::Dead,
+        ppproto::Phase::Establish => Phase::Establish,
+        ppproto::Phase::Auth => Phase::Auth,
+        ppproto::Phase::Network => Phase::Network,
+        ppproto::Phase::Open => Phase::Open,
     };
-    if let Some(ipv4) = ipv4 {
-        if let Some(address) = ipv4.address {
-            buf[2..6].copy_from_slice(&address.octets());
-        }
-        if let Some(peer) = ipv4.peer_address {
-            buf[6..10].copy_from_slice(&peer.octets());
-        }
-        if let Some(dns1) = ipv4.dns_servers[0] {
-            buf[10..14].copy_from_slice(&dns1.octets());
-        }
-        if let Some(dns2) = ipv4.dns_servers[1] {
-            buf[14..18].copy_from_slice(&dns2.octets());
-        }
+    let ipv4 = status.ipv4.as_ref();
+    Status {
+        phase,
+        address: ipv4.and_then(|ipv4| ipv4.address),
+        peer_address: ipv4.and_then(|ipv4| ipv4.peer_address),
+        dns_servers: ipv4.map_or([None; 2], |ipv4| ipv4.dns_servers),
     }
-    buf
 }
 
 fn write_raw(fd: usize, buf: &[u8]) {
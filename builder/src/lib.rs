@@ -4,7 +4,7 @@ use gpt::GptConfig;
 use gpt::disk::LogicalBlockSize;
 use gpt::mbr::ProtectiveMBR;
 use gpt::partition_types::EFI;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
@@ -103,6 +103,128 @@ impl FatBuilder {
     }
 }
 
+/// The directories an archive needs before `files`, parents first.
+fn archive_directories(files: &Files) -> BTreeSet<String> {
+    files
+        .keys()
+        .flat_map(|path| Path::new(path).ancestors().skip(1))
+        .map(|dir| dir.to_string_lossy().trim_start_matches('/').to_owned())
+        .filter(|dir| !dir.is_empty())
+        .collect()
+}
+
+/// Writes a newc CPIO archive, which the kernel unpacks into a tmpfs root.
+pub struct CpioBuilder;
+
+impl CpioBuilder {
+    pub fn create(files: Files, out_path: &Path) -> anyhow::Result<()> {
+        let mut out = Vec::new();
+        // sorted, so parents come before their children
+        for dir in archive_directories(&files) {
+            Self::add_entry(&mut out, &dir, 0o040755, &[]);
+        }
+        for (target_path, source) in &files {
+            let data = fs::read(source)
+                .with_context(|| format!("failed to read `{}`", source.display()))?;
+            Self::add_entry(
+                &mut out,
+                target_path.trim_start_matches('/'),
+                0o100755,
+                &data,
+            );
+        }
+        Self::add_entry(&mut out, "TRAILER!!!", 0, &[]);
+        fs::write(out_path, out)
+            .with_context(|| format!("failed to write file to `{}`", out_path.display()))
+    }
+
+    fn add_entry(out: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let ino = out.len() as u32;
+        let name_size = name.len() as u32 + 1;
+        // magic, ino, mode, uid, gid, nlink, mtime, filesize, devmajor,
+        // devminor, rdevmajor, rdevminor, namesize, check
+        let fields = [
+            ino,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name_size,
+            0,
+        ];
+        out.extend_from_slice(b"070701");
+        for field in fields {
+            out.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+        out.extend_from_slice(data);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+}
+
+/// Writes a ustar archive, which the kernel unpacks into a tmpfs root.
+pub struct TarBuilder;
+
+impl TarBuilder {
+    pub fn create(files: Files, out_path: &Path) -> anyhow::Result<()> {
+        let mut out = Vec::new();
+        for dir in archive_directories(&files) {
+            Self::add_entry(&mut out, &format!("{dir}/"), b'5', 0o755, &[])?;
+        }
+        for (target_path, source) in &files {
+            let data = fs::read(source)
+                .with_context(|| format!("failed to read `{}`", source.display()))?;
+            Self::add_entry(
+                &mut out,
+                target_path.trim_start_matches('/'),
+                b'0',
+                0o755,
+                &data,
+            )?;
+        }
+        // the end of the archive
+        out.resize(out.len() + 1024, 0);
+        fs::write(out_path, out)
+            .with_context(|| format!("failed to write file to `{}`", out_path.display()))
+    }
+
+    fn add_entry(
+        out: &mut Vec<u8>,
+        name: &str,
+        typeflag: u8,
+        mode: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(name.len() <= 100, "`{name}` is too long for a tar header");
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(format!("{mode:07o}").as_bytes());
+        header[108..115].copy_from_slice(b"0000000");
+        header[116..123].copy_from_slice(b"0000000");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // the checksum is taken with its own field filled with spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&c| c as u32).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(data);
+        out.resize(out.len().next_multiple_of(512), 0);
+        Ok(())
+    }
+}
+
 struct DiskCreator;
 
 impl DiskCreator {
@@ -1,5 +1,5 @@
 use argh::FromArgs;
-use builder::{FatBuilder, ImageBuilder, build_usb_storage_image};
+use builder::{CpioBuilder, FatBuilder, ImageBuilder, TarBuilder, build_usb_storage_image};
 use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
@@ -41,6 +41,12 @@ struct Args {
     #[argh(description = "use nvme disk instead of ahci disk")]
     nvme: bool,
 
+    #[argh(option)]
+    #[argh(
+        description = "attach a raw disk image, e.g. a GPT disk with an ext2 partition, as the second disk"
+    )]
+    data_disk: Option<PathBuf>,
+
     #[argh(switch, short = 's')]
     #[argh(description = "enable sound card")]
     sound: bool,
@@ -85,11 +91,42 @@ struct Args {
     #[argh(switch)]
     #[argh(description = "emulate a Realtek RTL8139 network card instead of a default Intel one")]
     rtl_nic: bool,
+
+    #[argh(switch)]
+    #[argh(description = "add an Intel VT-d IOMMU and boot the kernel with iommu=vtd")]
+    iommu: bool,
+
+    #[argh(option)]
+    #[argh(default = "InitrdFormat::Fat")]
+    #[argh(description = "format of initrd.img: fat (default), cpio or tar")]
+    initrd_format: InitrdFormat,
+}
+
+#[derive(Clone, Copy)]
+enum InitrdFormat {
+    Fat,
+    Cpio,
+    Tar,
+}
+
+impl std::str::FromStr for InitrdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fat" => Ok(Self::Fat),
+            "cpio" => Ok(Self::Cpio),
+            "tar" => Ok(Self::Tar),
+            _ => Err(format!(
+                "unknown initrd format `{s}`, expected fat, cpio or tar"
+            )),
+        }
+    }
 }
 
 fn main() {
     let args: Args = argh::from_env();
-    let img_path = build_img(args.serial_console);
+    let img_path = build_img(args.serial_console, args.iommu, args.initrd_format);
     let usb_storage_path = args.usb_storage_image.clone().unwrap_or_else(|| {
         Path::new(env!("CARGO_MANIFEST_DIR"))
             .parent()
@@ -114,6 +151,10 @@ fn main() {
         cmd.arg("-m").arg(args.memory.as_deref().unwrap_or("256m"));
         cmd.arg("-smp").arg(format!("cores={}", args.cores));
         cmd.arg("-cpu").arg("qemu64,+x2apic");
+        if args.iommu {
+            // QEMU wants the IOMMU created before the devices behind it.
+            cmd.arg("-device").arg("intel-iommu,intremap=off");
+        }
 
         if args.sound
             && let Some(backend) = match std::env::consts::OS {
@@ -137,6 +178,17 @@ fn main() {
         }
         let drive_config = format!("if=none,format=raw,id=disk1,file={}", img_path.display());
         cmd.arg("-drive").arg(drive_config);
+        if let Some(data_disk) = &args.data_disk {
+            // on the same kind of controller as the boot disk, so it is disk 1
+            // of that type in the kernel
+            if args.nvme {
+                cmd.arg("-device").arg("nvme,drive=disk2,serial=deadbeef2");
+            } else {
+                cmd.arg("-device").arg("ide-hd,drive=disk2,bus=ahci.1");
+            }
+            let drive_config = format!("if=none,format=raw,id=disk2,file={}", data_disk.display());
+            cmd.arg("-drive").arg(drive_config);
+        }
 
         if args.kvm {
             cmd.arg("--enable-kvm");
@@ -200,7 +252,7 @@ fn main() {
     }
 }
 
-fn build_img(serial_console: bool) -> PathBuf {
+fn build_img(serial_console: bool, iommu: bool, initrd_format: InitrdFormat) -> PathBuf {
     let doglinked_path = Path::new(env!("CARGO_BIN_FILE_DOGLINKED"));
     let t_path = Path::new(env!("CARGO_BIN_FILE_INFINITE_LOOP"));
     let imgview_path = Path::new(env!("CARGO_BIN_FILE_IMGVIEW"));
@@ -235,23 +287,44 @@ fn build_img(serial_console: bool) -> PathBuf {
         ("/res/demo2.avi", assets_dir.join("demo2.avi")),
     ]);
     let initrd_path = manifest_dir.parent().unwrap().join("initrd.img");
-    FatBuilder::create(initrd_files, &initrd_path).expect("failed to build initrd.img");
+    match initrd_format {
+        InitrdFormat::Fat => FatBuilder::create(initrd_files, &initrd_path),
+        InitrdFormat::Cpio => CpioBuilder::create(initrd_files, &initrd_path),
+        InitrdFormat::Tar => TarBuilder::create(initrd_files, &initrd_path),
+    }
+    .expect("failed to build initrd.img");
     println!("Created initrd.img at {:#?}", initrd_path);
 
     let kernel_path = Path::new(env!("CARGO_BIN_FILE_DOGLINKOS_2ND"));
     println!("Building UEFI disk image for kernel at {:#?}", kernel_path);
 
+    let mut limine_conf = assets_dir.join(if serial_console {
+        "limine-serial.conf"
+    } else {
+        "limine.conf"
+    });
+    if iommu {
+        let conf = std::fs::read_to_string(&limine_conf).expect("failed to read limine.conf");
+        let conf: String = conf
+            .lines()
+            .map(|line| {
+                if line.trim_start().starts_with("cmdline:") {
+                    format!("{line} iommu=vtd\n")
+                } else {
+                    format!("{line}\n")
+                }
+            })
+            .collect();
+        let out_dir = manifest_dir.parent().unwrap().join("target");
+        std::fs::create_dir_all(&out_dir).expect("failed to create the build directory");
+        limine_conf = out_dir.join("limine-iommu.conf");
+        std::fs::write(&limine_conf, conf).expect("failed to write limine-iommu.conf");
+    }
+
     let files = BTreeMap::from([
         ("kernel", kernel_path.to_path_buf()),
         ("efi/boot/bootx64.efi", assets_dir.join("BOOTX64.EFI")),
-        (
-            "limine.conf",
-            assets_dir.join(if serial_console {
-                "limine-serial.conf"
-            } else {
-                "limine.conf"
-            }),
-        ),
+        ("limine.conf", limine_conf),
         ("initrd.img", initrd_path.to_path_buf()),
     ]);
 
@@ -0,0 +1,258 @@
+//! Typed messages for IPC services.
+//!
+//! Every message travels as one IPC message: an 8-byte header followed by
+//! the payload of the message type.
+//!
+//! ```text
+//! +----------+---------+------+----------------+---------+
+//! | 0..2     | 2       | 3    | 4..8           | 8..     |
+//! +----------+---------+------+----------------+---------+
+//! | protocol | version | kind | correlation id | payload |
+//! +----------+---------+------+----------------+---------+
+//! ```
+//!
+//! `protocol` and `version` come from the `Protocol` a message belongs to,
+//! and a frame whose protocol or version differs from the receiver's is
+//! rejected. Bump `Protocol::VERSION` on every change of the wire format.
+//!
+//! A reply carries the correlation ID of its request; messages nobody asked
+//! for use `UNSOLICITED`. Integers are little-endian.
+
+#![no_std]
+
+pub mod ppp;
+
+use core::net::Ipv4Addr;
+
+use dlos_app_rt::*;
+
+pub const HEADER_LEN: usize = 8;
+/// Largest encoded message, header included.
+pub const MAX_FRAME_LEN: usize = IPC_MAX_MSG_SIZE;
+/// Correlation ID of events that do not answer a request.
+pub const UNSOLICITED: u32 = 0;
+
+const IPC_EAGAIN: isize = -11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The frame ends before the message does.
+    Truncated,
+    /// The message does not fit into the buffer.
+    TooLong,
+    /// The frame belongs to another protocol.
+    Protocol(u16),
+    /// The peer speaks another version of the protocol.
+    Version(u8),
+    /// The message kind is not part of the protocol.
+    UnknownKind(u8),
+    /// A field holds a value outside its range.
+    Invalid,
+    /// Nothing is queued on a non-blocking receive, or no space is left on a
+    /// non-blocking send.
+    WouldBlock,
+    /// The peer closed the channel.
+    Closed,
+    /// Any other IPC error, as returned by the kernel.
+    Ipc(isize),
+}
+
+pub trait Protocol {
+    const ID: u16;
+    const VERSION: u8;
+}
+
+/// One direction of a protocol, e.g. its requests or its events.
+pub trait Message<'a>: Sized {
+    type Protocol: Protocol;
+
+    fn kind(&self) -> u8;
+    fn encode_payload(&self, writer: &mut Writer<'_>) -> Result<(), Error>;
+    fn decode_payload(kind: u8, reader: &mut Reader<'a>) -> Result<Self, Error>;
+}
+
+/// A decoded message with its correlation ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<M> {
+    pub id: u32,
+    pub message: M,
+}
+
+/// Hands out correlation IDs for requests, never `UNSOLICITED`.
+pub struct Correlator {
+    last: u32,
+}
+
+impl Correlator {
+    pub const fn new() -> Self {
+        Self { last: UNSOLICITED }
+    }
+
+    pub fn next_id(&mut self) -> u32 {
+        self.last = self.last.wrapping_add(1);
+        if self.last == UNSOLICITED {
+            self.last = 1;
+        }
+        self.last
+    }
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(Error::TooLong)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn put_u8(&mut self, value: u8) -> Result<(), Error> {
+        self.put_bytes(&[value])
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> Result<(), Error> {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    /// A presence byte followed by the address, or by zeroes for `None`.
+    pub fn put_ipv4(&mut self, addr: Option<Ipv4Addr>) -> Result<(), Error> {
+        self.put_u8(addr.is_some() as u8)?;
+        self.put_bytes(&addr.unwrap_or(Ipv4Addr::UNSPECIFIED).octets())
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    /// Everything left in the frame.
+    pub fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.buf)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn ipv4(&mut self) -> Result<Option<Ipv4Addr>, Error> {
+        let present = self.u8()?;
+        let octets: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+        match present {
+            0 => Ok(None),
+            1 => Ok(Some(Ipv4Addr::from(octets))),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
+
+/// Encode `message` with correlation ID `id` into `buf` and return the frame
+/// length.
+pub fn encode<'a, M: Message<'a>>(id: u32, message: &M, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut writer = Writer { buf, pos: 0 };
+    writer.put_bytes(&M::Protocol::ID.to_le_bytes())?;
+    writer.put_u8(M::Protocol::VERSION)?;
+    writer.put_u8(message.kind())?;
+    writer.put_u32(id)?;
+    message.encode_payload(&mut writer)?;
+    Ok(writer.pos)
+}
+
+pub fn decode<'a, M: Message<'a>>(frame: &'a [u8]) -> Result<Frame<M>, Error> {
+    let mut reader = Reader { buf: frame };
+    let protocol = u16::from_le_bytes(reader.bytes(2)?.try_into().unwrap());
+    if protocol != M::Protocol::ID {
+        return Err(Error::Protocol(protocol));
+    }
+    let version = reader.u8()?;
+    if version != M::Protocol::VERSION {
+        return Err(Error::Version(version));
+    }
+    let kind = reader.u8()?;
+    let id = reader.u32()?;
+    let message = M::decode_payload(kind, &mut reader)?;
+    if !reader.rest().is_empty() {
+        return Err(Error::Invalid);
+    }
+    Ok(Frame { id, message })
+}
+
+fn check_send(ret: isize) -> Result<(), Error> {
+    match ret {
+        IPC_EAGAIN => Err(Error::WouldBlock),
+        ret if ret < 0 => Err(Error::Ipc(ret)),
+        _ => Ok(()),
+    }
+}
+
+fn check_recv(ret: isize) -> Result<usize, Error> {
+    match ret {
+        0 => Err(Error::Closed),
+        IPC_EAGAIN => Err(Error::WouldBlock),
+        ret if ret < 0 => Err(Error::Ipc(ret)),
+        len => Ok(len as usize),
+    }
+}
+
+/// Send `message` on `handle`, failing with `WouldBlock` if the peer's queue
+/// is full.
+pub fn send<'a, M: Message<'a>>(handle: usize, id: u32, message: &M) -> Result<(), Error> {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = encode(id, message, &mut buf)?;
+    check_send(sys_ipc_send(handle, &buf[..len]))
+}
+
+/// Send `message` on `handle`, waiting up to `timeout` ticks for queue space.
+pub fn send_wait<'a, M: Message<'a>>(
+    handle: usize,
+    id: u32,
+    message: &M,
+    timeout: usize,
+) -> Result<(), Error> {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = encode(id, message, &mut buf)?;
+    check_send(sys_ipc_send_wait(handle, &buf[..len], timeout))
+}
+
+/// Receive the next message on `handle` into `buf` without blocking.
+pub fn recv<'a, M: Message<'a>>(handle: usize, buf: &'a mut [u8]) -> Result<Frame<M>, Error> {
+    let len = check_recv(sys_ipc_recv(handle, buf))?;
+    decode(&buf[..len])
+}
+
+/// Receive the next message on `handle` into `buf`, waiting up to `timeout`
+/// ticks for one to arrive.
+pub fn recv_wait<'a, M: Message<'a>>(
+    handle: usize,
+    buf: &'a mut [u8],
+    timeout: usize,
+) -> Result<Frame<M>, Error> {
+    let len = check_recv(sys_ipc_recv_wait(handle, buf, timeout))?;
+    decode(&buf[..len])
+}
@@ -0,0 +1,167 @@
+//! The protocol of the PPP network service (`net/ppp0`, served by `upppd`).
+//!
+//! Clients send `Request`s; the service answers each with an `Event` carrying
+//! the request's correlation ID and broadcasts received packets and phase
+//! changes as unsolicited events.
+
+use core::net::Ipv4Addr;
+
+use crate::{Error, Message, Protocol, Reader, Writer};
+
+pub const SERVICE_NAME: &str = "net/ppp0";
+
+pub struct Ppp;
+
+impl Protocol for Ppp {
+    const ID: u16 = u16::from_le_bytes(*b"PP");
+    const VERSION: u8 = 1;
+}
+
+const REQ_SEND_IPV4: u8 = 0x01;
+const REQ_QUERY_STATUS: u8 = 0x02;
+
+const EVT_ACK: u8 = 0x80;
+const EVT_RX_IPV4: u8 = 0x81;
+const EVT_STATUS: u8 = 0x82;
+const EVT_ERROR: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// Send a complete IPv4 packet over the link.
+    SendIpv4(&'a [u8]),
+    QueryStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// The packet of a `SendIpv4` request went out.
+    Ack,
+    /// A complete IPv4 packet arrived on the link. Broadcast to every client.
+    ReceivedIpv4(&'a [u8]),
+    /// Sent on connect, on every phase change and for `QueryStatus`.
+    Status(Status),
+    Error(ErrorCode),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Phase {
+    Dead = 0,
+    Establish = 1,
+    Auth = 2,
+    Network = 3,
+    Open = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub phase: Phase,
+    pub address: Option<Ipv4Addr>,
+    pub peer_address: Option<Ipv4Addr>,
+    pub dns_servers: [Option<Ipv4Addr>; 2],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// The request could not be decoded.
+    BadRequest = 1,
+    /// PPP could not frame the packet.
+    SendFailed = 2,
+}
+
+impl<'a> Message<'a> for Request<'a> {
+    type Protocol = Ppp;
+
+    fn kind(&self) -> u8 {
+        match self {
+            Request::SendIpv4(_) => REQ_SEND_IPV4,
+            Request::QueryStatus => REQ_QUERY_STATUS,
+        }
+    }
+
+    fn encode_payload(&self, writer: &mut Writer<'_>) -> Result<(), Error> {
+        match self {
+            Request::SendIpv4(packet) => writer.put_bytes(packet),
+            Request::QueryStatus => Ok(()),
+        }
+    }
+
+    fn decode_payload(kind: u8, reader: &mut Reader<'a>) -> Result<Self, Error> {
+        match kind {
+            REQ_SEND_IPV4 => Ok(Request::SendIpv4(reader.rest())),
+            REQ_QUERY_STATUS => Ok(Request::QueryStatus),
+            kind => Err(Error::UnknownKind(kind)),
+        }
+    }
+}
+
+impl<'a> Message<'a> for Event<'a> {
+    type Protocol = Ppp;
+
+    fn kind(&self) -> u8 {
+        match self {
+            Event::Ack => EVT_ACK,
+            Event::ReceivedIpv4(_) => EVT_RX_IPV4,
+            Event::Status(_) => EVT_STATUS,
+            Event::Error(_) => EVT_ERROR,
+        }
+    }
+
+    fn encode_payload(&self, writer: &mut Writer<'_>) -> Result<(), Error> {
+        match self {
+            Event::Ack => Ok(()),
+            Event::ReceivedIpv4(packet) => writer.put_bytes(packet),
+            Event::Status(status) => {
+                writer.put_u8(status.phase as u8)?;
+                writer.put_ipv4(status.address)?;
+                writer.put_ipv4(status.peer_address)?;
+                writer.put_ipv4(status.dns_servers[0])?;
+                writer.put_ipv4(status.dns_servers[1])
+            }
+            Event::Error(code) => writer.put_u8(*code as u8),
+        }
+    }
+
+    fn decode_payload(kind: u8, reader: &mut Reader<'a>) -> Result<Self, Error> {
+        match kind {
+            EVT_ACK => Ok(Event::Ack),
+            EVT_RX_IPV4 => Ok(Event::ReceivedIpv4(reader.rest())),
+            EVT_STATUS => Ok(Event::Status(Status {
+                phase: Phase::try_from(reader.u8()?)?,
+                address: reader.ipv4()?,
+                peer_address: reader.ipv4()?,
+                dns_servers: [reader.ipv4()?, reader.ipv4()?],
+            })),
+            EVT_ERROR => Ok(Event::Error(ErrorCode::try_from(reader.u8()?)?)),
+            kind => Err(Error::UnknownKind(kind)),
+        }
+    }
+}
+
+impl TryFrom<u8> for Phase {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Phase::Dead),
+            1 => Ok(Phase::Establish),
+            2 => Ok(Phase::Auth),
+            3 => Ok(Phase::Network),
+            4 => Ok(Phase::Open),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            1 => Ok(ErrorCode::BadRequest),
+            2 => Ok(ErrorCode::SendFailed),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::mm::phys_to_virt;
 use crate::println;
+use acpi::AcpiTable;
 use acpi::AcpiTables;
 use acpi::PciConfigRegions;
 use acpi::fadt::Fadt;
@@ -9,6 +10,7 @@ use acpi::madt::Madt;
 use acpi::madt::MadtEntry;
 use acpi::mcfg::PciConfigEntry;
 use acpi::platform::interrupt::InterruptModel;
+use acpi::sdt::{SdtHeader, Signature};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use aml::AmlContext;
@@ -161,3 +163,36 @@ pub static PCI_CONFIG_REGIONS: Lazy<Vec<PciConfigEntry>> = Lazy::new(|| {
     let res = PciConfigRegions::new(&acpi).unwrap();
     res.iter().collect()
 });
+
+/// DMA Remapping Reporting table, see the Intel VT-d specification.
+#[repr(C, packed)]
+pub struct Dmar {
+    header: SdtHeader,
+    pub host_address_width: u8,
+    pub flags: u8,
+    _reserved: [u8; 10],
+}
+
+unsafe impl AcpiTable for Dmar {
+    const SIGNATURE: Signature = Signature::DMAR;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+/// Return the whole DMAR table, remapping structures included, if the
+/// firmware provides one.
+pub fn dmar_table() -> Option<&'static [u8]> {
+    let acpi =
+        unsafe { AcpiTables::from_rsdp(Handler, *RSDP_PA - (phys_to_virt(0) as usize)).ok()? };
+    let dmar = acpi.find_table::<Dmar>().ok()?;
+    let length = dmar.get().header.length as usize;
+    // the handler maps through the HHDM, which outlives the mapping object
+    Some(unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(dmar.physical_start() as u64) as *const u8,
+            length,
+        )
+    })
+}
@@ -2,30 +2,44 @@ use alloc::vec::Vec;
 use bit_field::BitField;
 use x86_64::VirtAddr;
 
-use crate::mm::page_alloc::dealloc_physical_page;
+use crate::mm::dma::{DmaBuffer, DmaDevice};
 
 use super::cmd::{CommandHeader, CommandTable, FisRegH2D};
-use super::hba::{HbaMemory, HbaPort};
+use super::hba::{HbaMemory, HbaPort, spin_until};
 use super::identify::{Identify, IdentifyData};
 
 pub const BLOCK_SIZE: usize = 512;
+/// How many sectors one command moves, as many as fit the data buffer.
+pub const MAX_SECTORS: usize = 8;
 const FIS_TYPE_REG_H2D: u8 = 0x27;
 const CMD_READ_DMA_EXT: u8 = 0x25;
 const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
 const CMD_IDENTIFY_DEVICE: u8 = 0xEC;
+/// The bit of `CommandHeader::flags` for commands that send data.
+const HEADER_WRITE: usize = 6;
+/// Task file error status in `HbaPort::interrupt_status`.
+const PORT_IS_TFES: usize = 30;
+/// The error bit of the status in `HbaPort::task_file_data`.
+const TFD_ERR: usize = 0;
 
 pub struct Ahci {
     pub data: &'static mut [u8],
     pub port: &'static HbaPort,
-    pub cmd_list: &'static [CommandHeader],
+    pub cmd_list: &'static mut [CommandHeader],
     pub cmd_table: &'static mut CommandTable,
     pub recieved_fis: &'static mut [u8],
+    /// Owns the memory behind the slices above.
+    pub(super) _buffers: [DmaBuffer; 4],
+    /// Set while the port does not restart, which the next command tries
+    /// first.
+    pub(super) failed: bool,
 }
 
 unsafe impl Send for Ahci {}
 
 impl Ahci {
-    pub fn new(address: VirtAddr) -> Vec<Self> {
+    pub fn new(address: VirtAddr, device: DmaDevice) -> Vec<Self> {
         let hba_memory = unsafe { &*address.as_mut_ptr::<HbaMemory>() };
 
         hba_memory.enable_ahci();
@@ -34,35 +48,57 @@ impl Ahci {
         (0..hba_memory.support_port_count())
             .filter(|&port_num| hba_memory.port_active(port_num))
             .flat_map(|port_num| hba_memory.get_port(port_num))
-            .map(|port| unsafe { port.init_ahci() })
+            .map(|port| unsafe { port.init_ahci(device) })
             .collect()
     }
 
     pub fn identity(&mut self) -> IdentifyData {
         unsafe {
             // crate::println!("[DEBUG] ahci/driver.rs: Ahci::identity() called");
-            self.execute_command(CMD_IDENTIFY_DEVICE, 0);
+            // a drive that fails it is left with what was in the buffer
+            let _ = self.execute_command(CMD_IDENTIFY_DEVICE, 0, 1);
             // crate::println!("[DEBUG] ahci/driver.rs: Ahci::identity() returned");
             (&*(self.data.as_ptr() as *const Identify)).into()
         }
     }
 
-    pub fn read_block(&mut self, start_sector: u64, buffer: &mut [u8]) {
-        self.execute_command(CMD_READ_DMA_EXT, start_sector);
-        let length = buffer.len().min(BLOCK_SIZE);
-        buffer.copy_from_slice(&self.data[..length]);
+    /// Read the sectors from `start_sector` on into `buffer`, which holds up
+    /// to `MAX_SECTORS` whole sectors, with one command.
+    pub fn read_blocks(&mut self, start_sector: u64, buffer: &mut [u8]) -> Result<(), ()> {
+        let count = buffer.len() / BLOCK_SIZE;
+        if count == 0 || count > MAX_SECTORS {
+            return Err(());
+        }
+        self.execute_command(CMD_READ_DMA_EXT, start_sector, count)?;
+        let length = count * BLOCK_SIZE;
+        buffer[..length].copy_from_slice(&self.data[..length]);
+        Ok(())
     }
 
-    pub fn write_block(&mut self, start_sector: u64, buffer: &[u8]) {
-        let length = buffer.len().min(BLOCK_SIZE);
+    /// Write `buffer`, which holds up to `MAX_SECTORS` whole sectors, from
+    /// `start_sector` on with one command.
+    pub fn write_blocks(&mut self, start_sector: u64, buffer: &[u8]) -> Result<(), ()> {
+        let count = buffer.len() / BLOCK_SIZE;
+        if count == 0 || count > MAX_SECTORS {
+            return Err(());
+        }
+        let length = count * BLOCK_SIZE;
         self.data[..length].copy_from_slice(&buffer[..length]);
-        self.execute_command(CMD_WRITE_DMA_EXT, start_sector);
+        self.execute_command(CMD_WRITE_DMA_EXT, start_sector, count)
     }
 
-    fn execute_command(&mut self, command: u8, start_sector: u64) {
+    /// Have the drive write its volatile write cache to the medium.
+    pub fn flush_cache(&mut self) -> Result<(), ()> {
+        self.execute_command(CMD_FLUSH_CACHE_EXT, 0, 0)
+    }
+
+    fn execute_command(&mut self, command: u8, start_sector: u64, count: usize) -> Result<(), ()> {
         // crate::println!(
         //     "[DEBUG] ahci/driver.rs: Ahci::execute_command({command},{start_sector}) called"
         // );
+        if self.failed {
+            self.recover()?;
+        }
         let cmd_table = &mut *self.cmd_table;
         let fis = unsafe { &mut *(cmd_table.cfis.as_mut_ptr() as *mut FisRegH2D) };
         *fis = unsafe { core::mem::zeroed() };
@@ -75,21 +111,46 @@ impl Ahci {
             _ => 0,
         };
 
-        fis.sector_count = if command == CMD_IDENTIFY_DEVICE { 0 } else { 1 };
+        fis.sector_count = match command {
+            CMD_READ_DMA_EXT | CMD_WRITE_DMA_EXT => count as u16,
+            _ => 0,
+        };
         fis.set_lba(start_sector);
+        cmd_table.prdt[0].byte_count_i = (count.max(1) * BLOCK_SIZE - 1) as u32;
+        self.cmd_list[0]
+            .flags
+            .set_bit(HEADER_WRITE, command == CMD_WRITE_DMA_EXT);
 
+        // the bits are cleared by writing ones
+        self.port.interrupt_status.set(u32::MAX);
         self.port.command_issue.set(1 << 0);
-        while self.port.command_issue.get().get_bit(0) {}
+        // the port stops on an error without finishing the command
+        let port = self.port;
+        let done = spin_until(|| {
+            !port.command_issue.get().get_bit(0)
+                || port.interrupt_status.get().get_bit(PORT_IS_TFES)
+        });
+        if done.is_err() {
+            let _ = self.recover();
+            return Err(());
+        }
         // crate::println!(
         //     "[DEBUG] ahci/driver.rs: Ahci::execute_command({command},{start_sector}) returned"
         // );
+        if self.port.interrupt_status.get().get_bit(PORT_IS_TFES)
+            || self.port.task_file_data.get().get_bit(TFD_ERR)
+        {
+            let _ = self.recover();
+            return Err(());
+        }
+        Ok(())
     }
-}
 
-impl Drop for Ahci {
-    fn drop(&mut self) {
-        dealloc_physical_page(self.cmd_list.as_ptr() as u64);
-        dealloc_physical_page(self.cmd_table as *const _ as u64);
-        dealloc_physical_page(self.data.as_ptr() as u64);
+    /// Restart the port after an error or a command that never finished. A
+    /// port that does not restart is marked failed and tried again on the
+    /// next command.
+    fn recover(&mut self) -> Result<(), ()> {
+        self.failed = self.port.recover().is_err();
+        if self.failed { Err(()) } else { Ok(()) }
     }
 }
@@ -8,13 +8,17 @@ use x86_64::structures::paging::{
 };
 
 use super::cmd::{CommandHeader, CommandTable, FisRegH2D};
-use super::driver::Ahci;
-use crate::mm::{page_alloc::alloc_physical_page, phys_to_virt};
+use super::driver::{Ahci, MAX_SECTORS};
+use crate::mm::dma::{DmaBuffer, DmaDevice};
+use crate::mm::mmio::CacheMode;
+use crate::mm::phys_to_virt;
 
 const BLOCK_SIZE: usize = 512;
 const SATA_SIG_ATAPI: u32 = 0xEB140101;
 const SATA_SIG_SEMB: u32 = 0xC33C0101;
 const SATA_SIG_PM: u32 = 0x96690101;
+/// How many times a port register is polled before the port is given up on.
+const SPIN_LIMIT: usize = 10_000_000;
 
 #[repr(C)]
 pub struct HbaMemory {
@@ -79,18 +83,20 @@ pub struct HbaPort {
 }
 
 impl HbaPort {
-    pub fn start_cmd(&self) {
+    pub fn start_cmd(&self) -> Result<(), ()> {
         let command = &self.command;
-        while command.get().get_bit(15) {}
+        spin_until(|| !command.get().get_bit(15))?;
         command.set(*command.get().set_bit(4, true));
         command.set(*command.get().set_bit(0, true));
+        Ok(())
     }
 
     pub fn stop_cmd_and_reset(&self) {
         let command = &self.command;
         command.set(*command.get().set_bit(0, false));
         command.set(*command.get().set_bit(4, false));
-        while command.get().get_bit(15) || command.get().get_bit(14) {}
+        // a port that never stops is reset anyway
+        let _ = spin_until(|| !command.get().get_bit(15) && !command.get().get_bit(14));
         let sata_control = &self.sata_control;
         sata_control.set((sata_control.get() & !0xf) | 1);
         for _ in 0..1000000 {
@@ -101,6 +107,16 @@ impl HbaPort {
         sata_control.set(sata_control.get() & !0xf);
     }
 
+    /// Start the port again after a task file error, which stops it.
+    pub fn recover(&self) -> Result<(), ()> {
+        let command = &self.command;
+        command.set(*command.get().set_bit(0, false));
+        spin_until(|| !command.get().get_bit(15))?;
+        self.sata_error.set(u32::MAX);
+        self.interrupt_status.set(u32::MAX);
+        self.start_cmd()
+    }
+
     pub fn is_sata_device(&self) -> bool {
         !matches!(
             self.signature.get(),
@@ -115,17 +131,16 @@ impl HbaPort {
 }
 
 impl HbaPort {
-    pub(super) unsafe fn init_ahci(&'static self) -> Ahci {
+    pub(super) unsafe fn init_ahci(&'static self, device: DmaDevice) -> Ahci {
         self.stop_cmd_and_reset();
 
-        let cmd_list_pa = alloc_physical_page().unwrap();
-        let cmd_list_va = phys_to_virt(cmd_list_pa);
-        let cmd_table_pa = alloc_physical_page().unwrap();
-        let cmd_table_va = phys_to_virt(cmd_table_pa);
-        let data_pa = alloc_physical_page().unwrap();
-        let data_va = phys_to_virt(data_pa);
-        let fis_pa = alloc_physical_page().unwrap();
-        let fis_va = phys_to_virt(fis_pa);
+        let buffers = [(); 4].map(|_| DmaBuffer::new(device, 4096, 4096).unwrap());
+        let [cmd_list, cmd_table, data, fis] = &buffers;
+        let (cmd_list_va, cmd_list_da) = (cmd_list.virtual_address(), cmd_list.device_address());
+        let (cmd_table_va, cmd_table_da) =
+            (cmd_table.virtual_address(), cmd_table.device_address());
+        let (data_va, data_da) = (data.virtual_address(), data.device_address());
+        let (fis_va, fis_da) = (fis.virtual_address(), fis.device_address());
 
         unsafe {
             let mut pgt = OffsetPageTable::new(
@@ -135,31 +150,39 @@ impl HbaPort {
             let _ = pgt
                 .update_flags(
                     Page::<Size4KiB>::containing_address(VirtAddr::new(cmd_list_va)),
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+                    PageTableFlags::WRITABLE
+                        | PageTableFlags::PRESENT
+                        | CacheMode::Uncacheable.page_flags(),
                 )
                 .map(|u| u.flush());
             let _ = pgt
                 .update_flags(
                     Page::<Size4KiB>::containing_address(VirtAddr::new(cmd_table_va)),
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+                    PageTableFlags::WRITABLE
+                        | PageTableFlags::PRESENT
+                        | CacheMode::Uncacheable.page_flags(),
                 )
                 .map(|u| u.flush());
             let _ = pgt
                 .update_flags(
                     Page::<Size4KiB>::containing_address(VirtAddr::new(fis_va)),
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+                    PageTableFlags::WRITABLE
+                        | PageTableFlags::PRESENT
+                        | CacheMode::Uncacheable.page_flags(),
                 )
                 .map(|u| u.flush());
             let _ = pgt
                 .update_flags(
                     Page::<Size4KiB>::containing_address(VirtAddr::new(data_va)),
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_CACHE,
+                    PageTableFlags::WRITABLE
+                        | PageTableFlags::PRESENT
+                        | CacheMode::Uncacheable.page_flags(),
                 )
                 .map(|u| u.flush());
         }
 
-        self.command_list_base_address.set(cmd_list_pa);
-        self.fis_base_address.set(fis_pa);
+        self.command_list_base_address.set(cmd_list_da);
+        self.fis_base_address.set(fis_da);
 
         self.command_issue.set(0);
 
@@ -169,20 +192,21 @@ impl HbaPort {
 
         let cmd_header = &mut cmd_list[0];
         *cmd_header = unsafe { core::mem::zeroed() };
-        cmd_header.command_table_base_address = cmd_table_pa;
+        cmd_header.command_table_base_address = cmd_table_da;
         cmd_header.flags = (size_of::<FisRegH2D>() / size_of::<u32>()) as u16;
         cmd_header.prdt_length = 1;
 
         let cmd_table = unsafe { &mut *(cmd_table_va as *mut CommandTable) };
         *cmd_table = unsafe { core::mem::zeroed() };
         let prdt = &mut cmd_table.prdt[0];
-        prdt.data_base_address = data_pa;
+        prdt.data_base_address = data_da;
         prdt.byte_count_i = (BLOCK_SIZE - 1) as u32;
 
-        let data = unsafe { slice::from_raw_parts_mut(data_va as *mut _, BLOCK_SIZE) };
+        let data =
+            unsafe { slice::from_raw_parts_mut(data_va as *mut _, MAX_SECTORS * BLOCK_SIZE) };
         data.fill(0);
 
-        self.start_cmd();
+        let failed = self.start_cmd().is_err();
 
         Ahci {
             cmd_list,
@@ -190,6 +214,19 @@ impl HbaPort {
             data,
             port: self,
             recieved_fis: unsafe { slice::from_raw_parts_mut(fis_va as *mut u8, 0x100) },
+            _buffers: buffers,
+            failed,
+        }
+    }
+}
+
+/// Poll until `done`, or fail once `SPIN_LIMIT` polls are used up.
+pub(super) fn spin_until(mut done: impl FnMut() -> bool) -> Result<(), ()> {
+    for _ in 0..SPIN_LIMIT {
+        if done() {
+            return Ok(());
         }
+        core::hint::spin_loop();
     }
+    Err(())
 }
@@ -1,20 +1,18 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use identify::IdentifyData;
 use spin::{Lazy, Mutex};
-use x86_64::{
-    VirtAddr,
-    registers::control::Cr3,
-    structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB},
-};
+use x86_64::VirtAddr;
 
-use crate::mm::phys_to_virt;
+use crate::blockdev::cache::{self, BlockDevice, DeviceId};
+use crate::mm::dma::DmaDevice;
+use crate::mm::mmio::{self, CacheMode};
 
 pub mod cmd;
 pub mod driver;
 pub mod hba;
 pub mod identify;
 
-pub use driver::{Ahci, BLOCK_SIZE};
+pub use driver::{Ahci, BLOCK_SIZE, MAX_SECTORS};
 pub use hba::HbaMemory;
 
 #[derive(Clone)]
@@ -30,26 +28,29 @@ impl fatfs::IoBase for AhciBlockDevice {
 
 impl fatfs::Read for AhciBlockDevice {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let mut device = self.device.lock();
-        let size = buf.len();
-        let sector = self.cur_pos / BLOCK_SIZE;
-        let mut buf2 = [0; BLOCK_SIZE];
-        device.read_block(sector as u64, &mut buf2);
-        let t1 = self.cur_pos % BLOCK_SIZE;
-        let will_read = core::cmp::min(size, BLOCK_SIZE - t1);
-        buf[..will_read].copy_from_slice(&buf2[t1..(t1 + will_read)]);
+        let size = self.identify.block_count as usize * BLOCK_SIZE;
+        let pos = self.cur_pos;
+        let will_read = buf.len().min(size.saturating_sub(pos));
+        cache::read_bytes(self, pos, &mut buf[..will_read])?;
         self.cur_pos += will_read;
         Ok(will_read)
     }
 }
 
 impl fatfs::Write for AhciBlockDevice {
-    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
-        Err(())
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = self.identify.block_count as usize * BLOCK_SIZE;
+        let pos = self.cur_pos;
+        let will_write = buf.len().min(size.saturating_sub(pos));
+        cache::write_bytes(self, pos, &buf[..will_write])?;
+        self.cur_pos += will_write;
+        Ok(will_write)
     }
 
+    /// Write the cached blocks of the disk back, which also empties the
+    /// disk's own write cache.
     fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        cache::sync_device(self.device_id())
     }
 }
 
@@ -71,17 +72,51 @@ impl fatfs::Seek for AhciBlockDevice {
     }
 }
 
+impl BlockDevice for AhciBlockDevice {
+    fn device_id(&self) -> DeviceId {
+        DeviceId::Ahci(Arc::as_ptr(&self.device) as usize)
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_blocks_uncached(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let mut device = self.device.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * BLOCK_SIZE).enumerate() {
+            device.read_blocks(lba + (i * MAX_SECTORS) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks_uncached(&mut self, lba: u64, buf: &[u8]) -> Result<(), ()> {
+        let mut device = self.device.lock();
+        for (i, chunk) in buf.chunks(MAX_SECTORS * BLOCK_SIZE).enumerate() {
+            device.write_blocks(lba + (i * MAX_SECTORS) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn clone_device(&self) -> Box<dyn BlockDevice> {
+        Box::new(self.clone())
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.device.lock().flush_cache()
+    }
+}
+
 impl crate::vfs::VfsFile for AhciBlockDevice {
     fn size(&mut self) -> usize {
         self.identify.block_count as usize * 512
     }
 
     fn read(&mut self, buf: &mut [u8]) -> usize {
-        <Self as fatfs::Read>::read(self, buf).unwrap()
+        <Self as fatfs::Read>::read(self, buf).unwrap_or(0)
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
-        <Self as fatfs::Write>::write(self, buf).unwrap()
+    fn write(&mut self, buf: &[u8]) -> Result<usize, crate::vfs::VfsError> {
+        <Self as fatfs::Write>::write(self, buf).map_err(|_| crate::vfs::VfsError::Io)
     }
 
     fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
@@ -93,7 +128,15 @@ impl crate::vfs::VfsFile for AhciBlockDevice {
                 crate::vfs::SeekFrom::Start(x) => fatfs::SeekFrom::Start(x as u64),
             },
         )
-        .unwrap() as usize
+        .map_or(self.cur_pos, |pos| pos as usize)
+    }
+
+    fn metadata(&mut self) -> crate::vfs::Metadata {
+        crate::vfs::Metadata::block_device(BLOCK_SIZE as u64, self.identify.block_count)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        <Self as fatfs::Write>::flush(self).map_err(|_| ())
     }
 }
 
@@ -120,32 +163,23 @@ pub static AHCI: Lazy<AhciManager> = Lazy::new(|| {
 
     let mut connections = Vec::new();
 
-    crate::pcie::enumrate::doit(|_, _, _, device| {
+    crate::pcie::enumrate::doit(|bus, dev, function, device| {
         if device.class_code == 1 && device.subclass == 6 {
-            let virtual_address = phys_to_virt((device.bar[5] & 0xfffffff0u32) as u64);
-            unsafe {
-                let mut pgt = OffsetPageTable::new(
-                    &mut *(phys_to_virt(Cr3::read().0.start_address().as_u64()) as *mut PageTable),
-                    VirtAddr::new(crate::mm::phys_to_virt(0)),
-                );
-                let _ = pgt
-                    .update_flags(
-                        Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_address)),
-                        PageTableFlags::WRITABLE
-                            | PageTableFlags::PRESENT
-                            | PageTableFlags::NO_CACHE,
-                    )
-                    .map(|u| u.flush());
-                let _ = pgt
-                    .update_flags(
-                        Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_address + 4096)),
-                        PageTableFlags::WRITABLE
-                            | PageTableFlags::PRESENT
-                            | PageTableFlags::NO_CACHE,
-                    )
-                    .map(|u| u.flush());
-            }
-            for ahci_device in Ahci::new(VirtAddr::new(virtual_address)) {
+            let Ok(registers) = (unsafe {
+                mmio::map(
+                    (device.bar[5] & 0xfffffff0u32) as u64,
+                    2 * 4096,
+                    CacheMode::Uncacheable,
+                )
+            }) else {
+                return;
+            };
+            // the ports keep `'static` references into the HBA registers
+            let virtual_address = registers.leak() as u64;
+            for ahci_device in Ahci::new(
+                VirtAddr::new(virtual_address),
+                DmaDevice::new(bus, dev, function),
+            ) {
                 connections.push(Arc::new(Mutex::new(ahci_device)));
             }
         }
@@ -157,7 +191,7 @@ pub static AHCI: Lazy<AhciManager> = Lazy::new(|| {
 });
 
 pub fn init() {
-    for ahci in AHCI.iter() {
+    for (idx, ahci) in AHCI.iter().enumerate() {
         // println!("[DEBUG] ahci: loop in init() begin");
         let res = crate::mm::convert_unit(ahci.identify.block_count * BLOCK_SIZE as u64);
         crate::println!(
@@ -166,6 +200,7 @@ pub fn init() {
             res.0,
             res.1
         );
+        crate::blockdev::partition::table::scan(&alloc::format!("disk{idx}"), ahci);
         // println!("[DEBUG] ahci: loop in init() end");
     }
 }
@@ -0,0 +1,459 @@
+//! The block cache shared by all block devices.
+//!
+//! Blocks are cached by device and LBA and evicted least recently used
+//! first. A miss reads a run of the blocks after it too. Writes only change
+//! the cached block and mark it dirty; it reaches the device on `sync`, every
+//! `WRITE_BACK_TICKS` from the idle loop, or when the cache is full of dirty
+//! blocks and the oldest ones are written back to make room. Only clean
+//! blocks are evicted, and a block that failed to be written stays dirty.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Lazy, Mutex};
+
+use crate::task::sched::TOTAL_TICKS;
+
+/// How many blocks the cache holds, whatever their size.
+pub const CACHE_BLOCKS: usize = 2048;
+
+/// Identifies a device in the cache. Every handle opened on a device has the
+/// same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceId {
+    /// The address of the port's driver state.
+    Ahci(usize),
+    /// The index of the controller and of the namespace on it.
+    Nvme(usize, usize),
+    Usb(usize),
+}
+
+/// A device read and written in whole blocks, bypassing the cache.
+pub trait BlockDevice: Send {
+    fn device_id(&self) -> DeviceId;
+    fn block_size(&self) -> usize;
+    /// Read the blocks from `lba` on into `buf`, which holds a whole number
+    /// of them.
+    fn read_blocks_uncached(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()>;
+    /// Write `buf`, which holds a whole number of blocks, from `lba` on.
+    fn write_blocks_uncached(&mut self, lba: u64, buf: &[u8]) -> Result<(), ()>;
+    /// A handle the cache keeps to write dirty blocks back later.
+    fn clone_device(&self) -> Box<dyn BlockDevice>;
+    /// Make the blocks written so far durable, e.g. by emptying the disk's
+    /// own write cache. Called by `sync` after the write-back.
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// The key of the block in `BlockCache::lru`.
+    last_use: u64,
+    /// The clock when the block was last changed, which tells a write-back
+    /// whether it wrote the latest data.
+    modified: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    write_backs: u64,
+}
+
+struct BlockCache {
+    blocks: BTreeMap<(DeviceId, u64), CachedBlock>,
+    /// Blocks by the time of their last use, oldest first.
+    lru: BTreeMap<u64, (DeviceId, u64)>,
+    clock: u64,
+    dirty_blocks: usize,
+    /// Handles of the devices that have dirty blocks.
+    writers: BTreeMap<DeviceId, Box<dyn BlockDevice>>,
+    counters: Counters,
+}
+
+/// A copy of a dirty block to write to its device.
+struct WriteBack {
+    key: (DeviceId, u64),
+    modified: u64,
+    data: Box<[u8]>,
+}
+
+/// How many dirty blocks are written back at once to make room.
+const WRITE_BACK_BATCH: usize = 64;
+
+/// How many blocks a miss reads, counting the one asked for.
+const READ_AHEAD: usize = 8;
+
+/// How many ticks the idle loop waits between write-backs of all dirty
+/// blocks.
+const WRITE_BACK_TICKS: usize = 500;
+
+/// The tick of the last periodic write-back.
+static LAST_WRITE_BACK: AtomicUsize = AtomicUsize::new(0);
+
+static CACHE: Lazy<Mutex<BlockCache>> = Lazy::new(|| {
+    Mutex::new(BlockCache {
+        blocks: BTreeMap::new(),
+        lru: BTreeMap::new(),
+        clock: 0,
+        dirty_blocks: 0,
+        writers: BTreeMap::new(),
+        counters: Counters::default(),
+    })
+});
+
+/// Held while dirty blocks are written back, so two write-backs of a block
+/// cannot reach the device out of order. It is taken before `CACHE`, which
+/// is never held during device I/O.
+static WRITE_BACK: Mutex<()> = Mutex::new(());
+
+impl BlockCache {
+    /// The cached block at `key`, if there is one, counted as a hit.
+    fn lookup(&mut self, key: (DeviceId, u64)) -> Option<&mut CachedBlock> {
+        if !self.blocks.contains_key(&key) {
+            return None;
+        }
+        self.counters.hits += 1;
+        self.touch(key)
+    }
+
+    /// Mark the block at `key` as just used.
+    fn touch(&mut self, key: (DeviceId, u64)) -> Option<&mut CachedBlock> {
+        self.clock += 1;
+        let now = self.clock;
+        let block = self.blocks.get_mut(&key)?;
+        self.lru.remove(&block.last_use);
+        self.lru.insert(now, key);
+        block.last_use = now;
+        Some(block)
+    }
+
+    /// Cache `data`, just read from the device, as the block at `key`. If
+    /// the block was cached meanwhile, that copy is newer and kept instead.
+    fn insert(&mut self, key: (DeviceId, u64), data: Box<[u8]>) -> &mut CachedBlock {
+        if self.blocks.contains_key(&key) {
+            return self.touch(key).unwrap();
+        }
+        // with only dirty blocks left the cache grows past its size until
+        // the next write-back
+        while self.blocks.len() >= CACHE_BLOCKS && self.evict_clean() {}
+        self.clock += 1;
+        let now = self.clock;
+        self.lru.insert(now, key);
+        self.blocks.entry(key).or_insert(CachedBlock {
+            data,
+            dirty: false,
+            last_use: now,
+            modified: 0,
+        })
+    }
+
+    fn full_of_dirty(&self) -> bool {
+        self.blocks.len() >= CACHE_BLOCKS && self.dirty_blocks >= self.blocks.len()
+    }
+
+    /// Drop the least recently used clean block. False if all are dirty.
+    fn evict_clean(&mut self) -> bool {
+        let Some((&last_use, &key)) = self.lru.iter().find(|(_, key)| !self.blocks[*key].dirty)
+        else {
+            return false;
+        };
+        self.lru.remove(&last_use);
+        self.blocks.remove(&key);
+        true
+    }
+
+    /// Copies of the blocks at `keys` that are still dirty.
+    fn write_backs(&self, keys: &[(DeviceId, u64)]) -> Vec<WriteBack> {
+        keys.iter()
+            .filter_map(|key| {
+                let block = self.blocks.get(key).filter(|block| block.dirty)?;
+                Some(WriteBack {
+                    key: *key,
+                    modified: block.modified,
+                    data: block.data.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Handles to write the blocks at `keys` back with.
+    fn writers(&self, keys: &[(DeviceId, u64)]) -> BTreeMap<DeviceId, Box<dyn BlockDevice>> {
+        keys.iter()
+            .filter_map(|(device, _)| Some((*device, self.writers.get(device)?.clone_device())))
+            .collect()
+    }
+
+    /// Record that a copy of a block was written back. A block that could
+    /// not be written is left dirty, so the next write-back tries again.
+    fn finish(&mut self, write: &WriteBack) {
+        self.counters.write_backs += 1;
+        let Some(block) = self.blocks.get_mut(&write.key) else {
+            return;
+        };
+        // changed again since the copy was taken, so still dirty
+        if block.dirty && block.modified == write.modified {
+            block.dirty = false;
+            self.dirty_blocks -= 1;
+        }
+    }
+}
+
+/// Write copies of the blocks at `keys` back to their devices, a run of
+/// consecutive blocks with one request. Fails if any of them could not be
+/// written.
+fn write_back(keys: &[(DeviceId, u64)]) -> Result<(), ()> {
+    let (mut writes, mut writers) = {
+        let cache = CACHE.lock();
+        (cache.write_backs(keys), cache.writers(keys))
+    };
+    writes.sort_by_key(|write| write.key);
+    let runs: Vec<_> = writes
+        .chunk_by(|a, b| a.key.0 == b.key.0 && a.key.1 + 1 == b.key.1)
+        .collect();
+    let results: Vec<_> = runs
+        .iter()
+        .map(|run| {
+            let (device, lba) = run[0].key;
+            let data: Vec<u8> = run
+                .iter()
+                .flat_map(|write| write.data.iter().copied())
+                .collect();
+            writers
+                .get_mut(&device)
+                .ok_or(())
+                .and_then(|writer| writer.write_blocks_uncached(lba, &data))
+        })
+        .collect();
+    let mut cache = CACHE.lock();
+    let mut failed = false;
+    for (run, written) in runs.iter().zip(results) {
+        match written {
+            Ok(()) => run.iter().for_each(|write| cache.finish(write)),
+            Err(()) => failed = true,
+        }
+    }
+    if failed { Err(()) } else { Ok(()) }
+}
+
+/// When the cache is full of dirty blocks, write the oldest ones back so
+/// that clean ones can be evicted. Fails if one of them could not be written.
+fn make_room() -> Result<(), ()> {
+    if !CACHE.lock().full_of_dirty() {
+        return Ok(());
+    }
+    let _write_back = WRITE_BACK.lock();
+    let oldest: Vec<_> = {
+        let cache = CACHE.lock();
+        // another write-back may have made room meanwhile
+        if !cache.full_of_dirty() {
+            return Ok(());
+        }
+        cache
+            .lru
+            .values()
+            .filter(|key| cache.blocks[*key].dirty)
+            .take(WRITE_BACK_BATCH)
+            .copied()
+            .collect()
+    };
+    write_back(&oldest)
+}
+
+/// Read block `lba` of `device` and up to `READ_AHEAD` blocks in all that
+/// follow it and are not cached yet, with one request to the device.
+fn read_run(device: &mut dyn BlockDevice, lba: u64) -> Result<Vec<Box<[u8]>>, ()> {
+    let id = device.device_id();
+    let count = {
+        let cache = CACHE.lock();
+        (1..READ_AHEAD as u64)
+            .take_while(|i| !cache.blocks.contains_key(&(id, lba + i)))
+            .count()
+            + 1
+    };
+    let block_size = device.block_size();
+    let mut data = vec![0; count * block_size];
+    // the run may reach past the end of the device
+    if count > 1 && device.read_blocks_uncached(lba, &mut data).is_ok() {
+        return Ok(data.chunks(block_size).map(Box::from).collect());
+    }
+    data.truncate(block_size);
+    device.read_blocks_uncached(lba, &mut data)?;
+    Ok(vec![data.into_boxed_slice()])
+}
+
+/// Call `f` with the cached block `lba` of `device`, read from the device
+/// on a miss. The cache is not locked while the device is read.
+fn with_cached<R>(
+    device: &mut dyn BlockDevice,
+    lba: u64,
+    f: impl FnOnce(&mut BlockCache, (DeviceId, u64)) -> R,
+) -> Result<R, ()> {
+    let key = (device.device_id(), lba);
+    {
+        let mut cache = CACHE.lock();
+        if cache.lookup(key).is_some() {
+            return Ok(f(&mut cache, key));
+        }
+        cache.counters.misses += 1;
+    }
+    let run = read_run(device, lba)?;
+    // a block that cannot be written back stays cached, and is reported to
+    // the writers that need the room
+    let _ = make_room();
+    let mut cache = CACHE.lock();
+    for (i, data) in run.into_iter().enumerate().rev() {
+        cache.insert((key.0, lba + i as u64), data);
+    }
+    Ok(f(&mut cache, key))
+}
+
+/// Call `f` with block `lba` of `device`.
+pub fn with_block<R>(
+    device: &mut dyn BlockDevice,
+    lba: u64,
+    f: impl FnOnce(&[u8]) -> R,
+) -> Result<R, ()> {
+    with_cached(device, lba, |cache, key| f(&cache.blocks[&key].data))
+}
+
+/// Call `f` to change block `lba` of `device`, which is written back later.
+pub fn with_block_mut<R>(
+    device: &mut dyn BlockDevice,
+    lba: u64,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> Result<R, ()> {
+    let id = device.device_id();
+    if !CACHE.lock().writers.contains_key(&id) {
+        let writer = device.clone_device();
+        CACHE.lock().writers.entry(id).or_insert(writer);
+    }
+    make_room()?;
+    with_cached(device, lba, |cache, key| {
+        cache.clock += 1;
+        let now = cache.clock;
+        let block = cache.blocks.get_mut(&key).unwrap();
+        let was_dirty = core::mem::replace(&mut block.dirty, true);
+        block.modified = now;
+        let res = f(&mut block.data);
+        if !was_dirty {
+            cache.dirty_blocks += 1;
+        }
+        res
+    })
+}
+
+/// Write the dirty blocks of the devices `filter` accepts back. Fails if
+/// any of them could not be written; those stay dirty.
+fn sync_where(filter: impl Fn(DeviceId) -> bool) -> Result<(), ()> {
+    let _write_back = WRITE_BACK.lock();
+    let dirty: Vec<_> = CACHE
+        .lock()
+        .blocks
+        .iter()
+        .filter(|(key, block)| block.dirty && filter(key.0))
+        .map(|(key, _)| *key)
+        .collect();
+    let mut failed = false;
+    for keys in dirty.chunks(WRITE_BACK_BATCH) {
+        failed |= write_back(keys).is_err();
+    }
+    let writers: Vec<_> = CACHE
+        .lock()
+        .writers
+        .iter()
+        .filter(|(device, _)| filter(**device))
+        .map(|(_, writer)| writer.clone_device())
+        .collect();
+    // the devices are flushed without the cache lock, like the write-back
+    for mut writer in writers {
+        failed |= writer.flush().is_err();
+    }
+    if failed { Err(()) } else { Ok(()) }
+}
+
+/// Write every dirty block back to its device.
+pub fn sync() -> Result<(), ()> {
+    sync_where(|_| true)
+}
+
+/// Write the dirty blocks of `device` back.
+pub fn sync_device(device: DeviceId) -> Result<(), ()> {
+    sync_where(|id| id == device)
+}
+
+/// Write every dirty block back if `WRITE_BACK_TICKS` passed since the last
+/// time. Called from the idle loop, so writes reach the disk without a
+/// `sync`.
+pub fn write_back_periodically() {
+    let now = TOTAL_TICKS.load(Ordering::Relaxed);
+    let last = LAST_WRITE_BACK.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) < WRITE_BACK_TICKS
+        || LAST_WRITE_BACK
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    if CACHE.lock().dirty_blocks > 0 {
+        // the blocks that failed stay dirty for the next time
+        let _ = sync();
+    }
+}
+
+/// The line of `/proc/cache` for the block cache.
+pub fn stats() -> String {
+    let cache = CACHE.lock();
+    let dirty = cache.dirty_blocks;
+    format!(
+        "block {} {} {} {} {} {}\n",
+        cache.counters.hits,
+        cache.counters.misses,
+        cache.blocks.len(),
+        CACHE_BLOCKS,
+        dirty,
+        cache.counters.write_backs
+    )
+}
+
+/// Fill `buf` from byte `offset` of `device` on, block by block. The caller
+/// keeps the read inside the device.
+pub fn read_bytes(device: &mut dyn BlockDevice, offset: usize, buf: &mut [u8]) -> Result<(), ()> {
+    let block_size = device.block_size();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done;
+        let within = pos % block_size;
+        let take = (block_size - within).min(buf.len() - done);
+        with_block(device, (pos / block_size) as u64, |block| {
+            buf[done..done + take].copy_from_slice(&block[within..within + take]);
+        })?;
+        done += take;
+    }
+    Ok(())
+}
+
+/// Copy `buf` to byte `offset` of `device` on, block by block. A block that
+/// is only partly written is read first. The caller keeps the write inside
+/// the device.
+pub fn write_bytes(device: &mut dyn BlockDevice, offset: usize, buf: &[u8]) -> Result<(), ()> {
+    let block_size = device.block_size();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done;
+        let within = pos % block_size;
+        let take = (block_size - within).min(buf.len() - done);
+        with_block_mut(device, (pos / block_size) as u64, |block| {
+            block[within..within + take].copy_from_slice(&buf[done..done + take]);
+        })?;
+        done += take;
+    }
+    Ok(())
+}
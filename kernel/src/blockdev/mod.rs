@@ -1,4 +1,5 @@
 pub mod ahci;
+pub mod cache;
 pub mod nvme;
 pub mod partition;
 pub mod ramdisk;
@@ -1,38 +1,88 @@
 use core::alloc::Layout;
 
 use alloc::alloc::{alloc, dealloc};
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use nvme::{Allocator, Device, IoQueuePair, Namespace};
 use spin::{Lazy, Mutex};
+use x86_64::VirtAddr;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{
-    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
-};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{OffsetPageTable, PageTable, Translate};
 
-use crate::mm::page_alloc::{DLOSFrameAllocator, dealloc_physical_page, find_continuous_mem};
+use crate::blockdev::cache::{self, BlockDevice, DeviceId};
+use crate::mm::dma::{self, DmaDevice, DmaMapping};
+use crate::mm::mmio::{self, CacheMode};
+use crate::mm::page_alloc::{PAGE_SIZE, dealloc_physical_page, find_continuous_mem};
 use crate::mm::phys_to_virt;
 use crate::pcie::enumrate::doit;
 
 type SharedNvmeDevice = Arc<Mutex<Device<NvmeAllocator>>>;
 type LockedQueuePair = Mutex<IoQueuePair<NvmeAllocator>>;
 
-pub struct NvmeAllocator;
+/// Hands the `nvme` crate memory the controller can reach.
+///
+/// The crate asks for device addresses of arbitrary kernel buffers through
+/// `translate` and never says when the controller is done with them, so every
+/// page it sees is mapped for the controller once and stays mapped, unless it
+/// was handed out by `allocate`: those pages are unmapped again when they are
+/// freed. Device addresses equal physical addresses even with VT-d enabled,
+/// which keeps the crate's PRP arithmetic valid.
+pub struct NvmeAllocator {
+    device: DmaDevice,
+    mapped: Mutex<BTreeMap<u64, DmaMapping>>,
+    /// Pages of each allocation, by its physical address.
+    allocations: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl NvmeAllocator {
+    fn new(device: DmaDevice) -> Self {
+        Self {
+            device,
+            mapped: Mutex::new(BTreeMap::new()),
+            allocations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn map_for_device(&self, phys: u64, len: usize) -> u64 {
+        let mut mapped = self.mapped.lock();
+        let first = phys & !(PAGE_SIZE as u64 - 1);
+        for page in (first..phys + len as u64).step_by(PAGE_SIZE) {
+            mapped.entry(page).or_insert_with(|| {
+                let mapping =
+                    dma::map(self.device, page, PAGE_SIZE).expect("nvme: DMA mapping failed");
+                assert_eq!(mapping.device_address(), page);
+                mapping
+            });
+        }
+        phys
+    }
+}
 
 impl Allocator for NvmeAllocator {
     unsafe fn allocate(&self, size: usize) -> usize {
         // println!("[DEBUG] NvmeAllocator got a request of {size} bytes");
-        let res = phys_to_virt(
-            find_continuous_mem(size.div_ceil(4096)).expect("nvme: DMA allocation failed"),
-        ) as usize;
+        let pages = size.div_ceil(4096);
+        let phys = find_continuous_mem(pages).expect("nvme: DMA allocation failed");
+        self.map_for_device(phys, pages * PAGE_SIZE);
+        self.allocations.lock().insert(phys, pages);
+        let res = phys_to_virt(phys) as usize;
         unsafe { (res as *mut u8).write_bytes(0, 4096) };
         res
     }
 
     unsafe fn deallocate(&self, addr: usize) {
-        dealloc_physical_page(addr as u64 - phys_to_virt(0));
+        let phys = addr as u64 - phys_to_virt(0);
+        let pages = self.allocations.lock().remove(&phys).unwrap_or(1);
+        let mut mapped = self.mapped.lock();
+        for page in 0..pages {
+            let page = phys + (page * PAGE_SIZE) as u64;
+            // dropping the mapping takes the page out of the controller's
+            // IOMMU domain and invalidates the IOTLB
+            mapped.remove(&page);
+            dealloc_physical_page(page);
+        }
     }
 
     fn translate(&self, addr: usize) -> usize {
@@ -42,9 +92,11 @@ impl Allocator for NvmeAllocator {
                 VirtAddr::new(crate::mm::phys_to_virt(0)),
             )
         };
-        pgt.translate_addr(VirtAddr::new(addr as u64))
+        let phys = pgt
+            .translate_addr(VirtAddr::new(addr as u64))
             .unwrap()
-            .as_u64() as usize
+            .as_u64();
+        self.map_for_device(phys, 1) as usize
     }
 }
 
@@ -53,6 +105,10 @@ pub struct NvmeBlockDevice {
     pub namespace: Namespace,
     pub qpairs: BTreeMap<u16, Arc<LockedQueuePair>>,
     pub model_number: alloc::string::String,
+    /// Index of the controller in `NVME` and of the namespace on it, which
+    /// identify the namespace in the block cache.
+    controller: usize,
+    namespace_index: usize,
     cur_pos: usize,
 }
 
@@ -62,26 +118,12 @@ impl fatfs::IoBase for NvmeBlockDevice {
 
 impl fatfs::Read for NvmeBlockDevice {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let t = self.qpairs.first_entry().ok_or(())?;
-        let mut qp = t.get().lock();
-        let block_size = self.namespace.block_size() as usize;
-        let size = buf.len();
-        let sector = self.cur_pos / block_size;
-        let buf2 =
-            unsafe { alloc(Layout::from_size_align(block_size, block_size).map_err(|_| ())?) };
-        qp.read(buf2, block_size, sector as u64).map_err(|_| ())?;
-        qp.flush().map_err(|_| ())?;
-        let t1 = self.cur_pos % block_size;
-        let will_read = core::cmp::min(size, block_size - t1);
-        let buf2_slice = unsafe { core::slice::from_raw_parts(buf2, block_size) };
-        buf[..will_read].copy_from_slice(&buf2_slice[t1..(t1 + will_read)]);
+        let pos = self.cur_pos;
+        let will_read = buf
+            .len()
+            .min(crate::vfs::VfsFile::size(self).saturating_sub(pos));
+        cache::read_bytes(self, pos, &mut buf[..will_read])?;
         self.cur_pos += will_read;
-        unsafe {
-            dealloc(
-                buf2,
-                Layout::from_size_align(block_size, block_size).map_err(|_| ())?,
-            );
-        }
         Ok(will_read)
     }
 }
@@ -120,6 +162,48 @@ impl fatfs::Seek for NvmeBlockDevice {
     }
 }
 
+impl BlockDevice for NvmeBlockDevice {
+    fn device_id(&self) -> DeviceId {
+        DeviceId::Nvme(self.controller, self.namespace_index)
+    }
+
+    fn block_size(&self) -> usize {
+        self.namespace.block_size() as usize
+    }
+
+    fn read_blocks_uncached(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let t = self.qpairs.first_entry().ok_or(())?;
+        let mut qp = t.get().lock();
+        let block_size = self.namespace.block_size() as usize;
+        let size = buf.len() / block_size * block_size;
+        if size == 0 {
+            return Err(());
+        }
+        let layout = Layout::from_size_align(size, block_size).map_err(|_| ())?;
+        let buf2 = unsafe { alloc(layout) };
+        if buf2.is_null() {
+            return Err(());
+        }
+        let res = qp
+            .read(buf2, size, lba)
+            .and_then(|()| qp.flush())
+            .map_err(|_| ());
+        if res.is_ok() {
+            unsafe { core::ptr::copy_nonoverlapping(buf2, buf.as_mut_ptr(), size) };
+        }
+        unsafe { dealloc(buf2, layout) };
+        res
+    }
+
+    fn write_blocks_uncached(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn clone_device(&self) -> Box<dyn BlockDevice> {
+        Box::new(self.clone())
+    }
+}
+
 impl crate::vfs::VfsFile for NvmeBlockDevice {
     fn size(&mut self) -> usize {
         self.namespace.block_count() as usize * self.namespace.block_size() as usize
@@ -129,8 +213,8 @@ impl crate::vfs::VfsFile for NvmeBlockDevice {
         <Self as fatfs::Read>::read(self, buf).unwrap()
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
-        <Self as fatfs::Write>::write(self, buf).unwrap()
+    fn write(&mut self, buf: &[u8]) -> Result<usize, crate::vfs::VfsError> {
+        <Self as fatfs::Write>::write(self, buf).map_err(|_| crate::vfs::VfsError::Io)
     }
 
     fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
@@ -142,7 +226,18 @@ impl crate::vfs::VfsFile for NvmeBlockDevice {
                 crate::vfs::SeekFrom::Start(x) => fatfs::SeekFrom::Start(x as u64),
             },
         )
-        .unwrap() as usize
+        .map_or(self.cur_pos, |pos| pos as usize)
+    }
+
+    fn metadata(&mut self) -> crate::vfs::Metadata {
+        crate::vfs::Metadata::block_device(
+            self.namespace.block_size(),
+            self.namespace.block_count(),
+        )
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        <Self as fatfs::Write>::flush(self).map_err(|_| ())
     }
 }
 
@@ -150,11 +245,11 @@ pub struct NvmeManager(Vec<SharedNvmeDevice>);
 
 impl NvmeManager {
     pub fn iter(&self) -> impl Iterator<Item = Vec<NvmeBlockDevice>> + use<'_> {
-        self.0.iter().map(|device| {
+        self.0.iter().enumerate().map(|(controller_index, device)| {
             let mut controller = device.lock();
             let namespaces = controller.identify_namespaces(0).unwrap();
 
-            let mapper = |namespace: Namespace| {
+            let mapper = |(namespace_index, namespace): (usize, Namespace)| {
                 // Some(NvmeBlockDevice {
                 //     namespace,
                 //     qpairs: BTreeMap::new(),
@@ -168,11 +263,17 @@ impl NvmeManager {
                     namespace,
                     qpairs: BTreeMap::from([(*qpair.id(), Arc::new(Mutex::new(qpair)))]),
                     model_number: controller.controller_data().model_number.clone(),
+                    controller: controller_index,
+                    namespace_index,
                     cur_pos: 0,
                 })
             };
 
-            namespaces.into_iter().filter_map(mapper).collect()
+            namespaces
+                .into_iter()
+                .enumerate()
+                .filter_map(mapper)
+                .collect()
         })
     }
 }
@@ -182,38 +283,23 @@ pub static NVME: Lazy<NvmeManager> = Lazy::new(|| {
 
     let mut connections = Vec::new();
 
-    doit(|_, _, _, config| {
+    doit(|bus, dev, function, config| {
         if config.class_code == 1 && config.subclass == 8 {
             let physical_address =
                 (config.bar[0] & 0xfffffff0u32) as u64 + ((config.bar[1] as u64) << 32);
-            let virtual_address = phys_to_virt(physical_address);
-            let mut pgt = unsafe {
-                OffsetPageTable::new(
-                    &mut *(phys_to_virt(Cr3::read().0.start_address().as_u64()) as *mut PageTable),
-                    VirtAddr::new(crate::mm::phys_to_virt(0)),
-                )
+            let Ok(registers) =
+                (unsafe { mmio::map(physical_address, 32 * 4096, CacheMode::Uncacheable) })
+            else {
+                return;
             };
-            for pg in 0..32 {
-                unsafe {
-                    let _ = pgt
-                        .map_to(
-                            Page::<Size4KiB>::containing_address(VirtAddr::new(
-                                virtual_address + pg * 4096,
-                            )),
-                            PhysFrame::containing_address(PhysAddr::new(
-                                physical_address + pg * 4096,
-                            )),
-                            PageTableFlags::WRITABLE
-                                | PageTableFlags::PRESENT
-                                | PageTableFlags::NO_CACHE,
-                            &mut DLOSFrameAllocator,
-                        )
-                        .map(|f| f.flush());
-                }
-            }
 
-            let virtual_address = virtual_address as usize;
-            let device = Device::init(virtual_address, NvmeAllocator).unwrap();
+            // the controller is never torn down, neither is its mapping
+            let virtual_address = registers.leak() as usize;
+            let device = Device::init(
+                virtual_address,
+                NvmeAllocator::new(DmaDevice::new(bus, dev, function)),
+            )
+            .unwrap();
             connections.push(Arc::new(Mutex::new(device)));
         }
     });
@@ -224,8 +310,8 @@ pub static NVME: Lazy<NvmeManager> = Lazy::new(|| {
 });
 
 pub fn init() {
-    for device in NVME.iter() {
-        for namespace in device.iter() {
+    for (device_idx, device) in NVME.iter().enumerate() {
+        for (namespace_idx, namespace) in device.iter().enumerate() {
             let res = crate::mm::convert_unit(
                 namespace.namespace.block_count() * namespace.namespace.block_size(),
             );
@@ -235,6 +321,10 @@ pub fn init() {
                 res.0,
                 res.1
             );
+            crate::blockdev::partition::table::scan(
+                &alloc::format!("nvme{device_idx}-{namespace_idx}"),
+                namespace.clone(),
+            );
         }
     }
 }
@@ -23,22 +23,21 @@ impl BlockIo for crate::blockdev::ahci::AhciBlockDevice {
         {
             return Err(true);
         }
-        let mut device = self.device.lock();
-        for (sector, chunk) in (start_lba.to_u64()..).zip(
-            output
-                .as_chunks_mut::<{ crate::blockdev::ahci::BLOCK_SIZE }>()
-                .0
-                .iter_mut(),
-        ) {
-            device.read_block(sector, chunk);
-        }
-        Ok(())
+        let offset = start_lba.to_u64() as usize * crate::blockdev::ahci::BLOCK_SIZE;
+        crate::blockdev::cache::read_bytes(self, offset, output).map_err(|()| true)
     }
 
-    fn write_blocks(&mut self, _start_lba: Lba, _input: &[u8]) -> Result<(), Self::Error> {
-        Err(true)
+    fn write_blocks(&mut self, start_lba: Lba, input: &[u8]) -> Result<(), Self::Error> {
+        if !input
+            .len()
+            .is_multiple_of(crate::blockdev::ahci::BLOCK_SIZE)
+        {
+            return Err(true);
+        }
+        let offset = start_lba.to_u64() as usize * crate::blockdev::ahci::BLOCK_SIZE;
+        crate::blockdev::cache::write_bytes(self, offset, input).map_err(|()| true)
     }
     fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        fatfs::Write::flush(self).map_err(|()| true)
     }
 }
@@ -1,21 +1,27 @@
-//! Common GPT partition adapter for every block device.
+//! Common partition adapter for every block device.
 
 pub mod ahci;
 pub mod nvme;
+pub mod table;
 pub mod usb;
 
+use alloc::sync::Arc;
 use core::fmt;
 
 use fatfs::SeekFrom;
-use gpt_disk_io::{BlockIo, Disk};
+use gpt_disk_io::BlockIo;
+use spin::Mutex;
 
-/// A bounded, byte-addressable view of one GPT partition.
+use crate::vfs::VfsFile;
+
+/// A bounded, byte-addressable view of one partition.
 ///
 /// The underlying device remains responsible for reporting removal.  In
 /// particular, a USB device returns its I/O error after it has gone offline;
 /// the FAT layer therefore never accesses xHCI DMA memory after unplug.
 pub struct Partition<T> {
     block_device: T,
+    block_size: u64,
     start: u64,
     end: u64,
     position: u64,
@@ -23,8 +29,8 @@ pub struct Partition<T> {
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum PartitionError {
-    InvalidGpt,
-    PartitionNotFound,
+    NoPartitionTable,
+    ReadFailed,
     UnsupportedBlockSize,
     OffsetOverflow,
     SeekFailed,
@@ -33,8 +39,8 @@ pub enum PartitionError {
 impl fmt::Display for PartitionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(match self {
-            Self::InvalidGpt => "invalid GPT",
-            Self::PartitionNotFound => "GPT partition not found",
+            Self::NoPartitionTable => "no valid GPT or MBR partition table",
+            Self::ReadFailed => "failed to read the disk",
             Self::UnsupportedBlockSize => "unsupported block size",
             Self::OffsetOverflow => "partition offset overflow",
             Self::SeekFailed => "failed to seek to partition",
@@ -46,43 +52,18 @@ impl<T> Partition<T>
 where
     T: BlockIo + Clone + fatfs::ReadWriteSeek,
 {
-    pub fn new(mut block_device: T, part_number: usize) -> Result<Self, PartitionError> {
+    /// The bytes from `start` to `end` of `block_device`, as found by
+    /// `table::scan`.
+    pub fn new(mut block_device: T, start: u64, end: u64) -> Result<Self, PartitionError> {
         let block_size = block_device
             .block_size()
             .to_usize()
             .ok_or(PartitionError::UnsupportedBlockSize)? as u64;
-        let mut disk = Disk::new(block_device.clone()).map_err(|_| PartitionError::InvalidGpt)?;
-        let mut block_buf = [0; 4096];
-        let header = disk
-            .read_primary_gpt_header(&mut block_buf)
-            .map_err(|_| PartitionError::InvalidGpt)?;
-        if !header.is_signature_valid() {
-            return Err(PartitionError::InvalidGpt);
-        }
-        let layout = header
-            .get_partition_entry_array_layout()
-            .map_err(|_| PartitionError::InvalidGpt)?;
-        let partition_entry = disk
-            .gpt_partition_entry_array_iter(layout, &mut block_buf)
-            .map_err(|_| PartitionError::InvalidGpt)?
-            .nth(part_number)
-            .ok_or(PartitionError::PartitionNotFound)?
-            .map_err(|_| PartitionError::InvalidGpt)?;
-        let start = partition_entry
-            .starting_lba
-            .to_u64()
-            .checked_mul(block_size)
-            .ok_or(PartitionError::OffsetOverflow)?;
-        let end = partition_entry
-            .ending_lba
-            .to_u64()
-            .checked_add(1)
-            .and_then(|lba| lba.checked_mul(block_size))
-            .ok_or(PartitionError::OffsetOverflow)?;
         fatfs::Seek::seek(&mut block_device, SeekFrom::Start(start))
             .map_err(|_| PartitionError::SeekFailed)?;
         Ok(Self {
             block_device,
+            block_size,
             start,
             end,
             position: 0,
@@ -120,7 +101,14 @@ where
     T: BlockIo + Clone + fatfs::ReadWriteSeek,
 {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        fatfs::Write::write(&mut self.block_device, buf).map_err(fatfs::Error::Io)
+        let remaining = self
+            .end
+            .saturating_sub(self.start.saturating_add(self.position));
+        let write_len = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+        let written = fatfs::Write::write(&mut self.block_device, &buf[..write_len])
+            .map_err(fatfs::Error::Io)?;
+        self.position = self.position.saturating_add(written as u64);
+        Ok(written)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
@@ -153,3 +141,71 @@ where
         Ok(next)
     }
 }
+
+impl<T> VfsFile for Partition<T>
+where
+    T: BlockIo + Clone + fatfs::ReadWriteSeek + Send,
+{
+    fn size(&mut self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        fatfs::Read::read(self, buf).unwrap_or(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, crate::vfs::VfsError> {
+        fatfs::Write::write(self, buf).map_err(|_| crate::vfs::VfsError::Io)
+    }
+
+    fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
+        fatfs::Seek::seek(
+            self,
+            match pos {
+                crate::vfs::SeekFrom::Start(value) => SeekFrom::Start(value as u64),
+                crate::vfs::SeekFrom::End(value) => SeekFrom::End(value as i64),
+                crate::vfs::SeekFrom::Current(value) => SeekFrom::Current(value as i64),
+            },
+        )
+        .map_or(self.position as usize, |value| value as usize)
+    }
+
+    fn metadata(&mut self) -> crate::vfs::Metadata {
+        crate::vfs::Metadata::block_device(
+            self.block_size,
+            (self.end - self.start) / self.block_size,
+        )
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        <Self as fatfs::Write>::flush(self).map_err(|_| ())
+    }
+}
+
+/// The devfs node for `block_device`, whose own node is `disk`, or for its
+/// partition `part` if the node name has a `p<part>` suffix.
+pub fn open_node<T>(
+    block_device: T,
+    disk: &str,
+    part: Option<&str>,
+) -> Result<Arc<Mutex<dyn VfsFile>>, ()>
+where
+    T: BlockIo + Clone + fatfs::ReadWriteSeek + VfsFile + 'static,
+{
+    let Some(part) = part else {
+        return Ok(Arc::new(Mutex::new(block_device)));
+    };
+    let number = part.parse().map_err(|_| ())?;
+    let info = table::find(disk, number).ok_or(())?;
+    let partition = Partition::new(block_device, info.start, info.end).map_err(|_| ())?;
+    Ok(Arc::new(Mutex::new(partition)))
+}
+
+/// Split a devfs node name into the name of the disk and the partition
+/// number, if there is one: `nvme0-0p2` into `nvme0-0` and `2`.
+pub fn split_node_name(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once('p') {
+        Some((disk, part)) => (disk, Some(part)),
+        None => (name, None),
+    }
+}
@@ -1,7 +1,5 @@
 pub type NvmePartition = super::Partition<crate::blockdev::nvme::NvmeBlockDevice>;
 
-use alloc::alloc::{alloc, dealloc};
-use core::alloc::Layout;
 use gpt_disk_io::{
     BlockIo,
     gpt_disk_types::{BlockSize, Lba},
@@ -23,31 +21,8 @@ impl BlockIo for crate::blockdev::nvme::NvmeBlockDevice {
         if !output.len().is_multiple_of(block_size) {
             return Err(true);
         }
-        let Some(entry) = self.qpairs.first_entry() else {
-            return Err(true);
-        };
-        let layout = Layout::from_size_align(block_size, block_size).map_err(|_| true)?;
-        let buffer = unsafe { alloc(layout) };
-        if buffer.is_null() {
-            return Err(true);
-        }
-        let mut qp = entry.get().lock();
-        let mut sector = start_lba.to_u64();
-        let result = (|| {
-            for chunk in output.chunks_exact_mut(block_size) {
-                qp.read(buffer, block_size, sector).map_err(|_| true)?;
-                qp.flush().map_err(|_| true)?;
-                unsafe {
-                    core::ptr::copy_nonoverlapping(buffer, chunk.as_mut_ptr(), block_size);
-                }
-                sector += 1;
-            }
-            Ok(())
-        })();
-        unsafe {
-            dealloc(buffer, layout);
-        }
-        result
+        let offset = start_lba.to_u64() as usize * block_size;
+        crate::blockdev::cache::read_bytes(self, offset, output).map_err(|()| true)
     }
 
     fn write_blocks(&mut self, _start_lba: Lba, _input: &[u8]) -> Result<(), Self::Error> {
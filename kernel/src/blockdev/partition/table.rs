@@ -0,0 +1,324 @@
+//! The partitions found on every disk.
+//!
+//! A disk's table is read once, when the disk is probed: GPT if its header
+//! is valid, a legacy MBR otherwise. GPT partitions are numbered by their
+//! entry from 1; MBR ones are 1 to 4 for the primary entries and 5 on for
+//! the logical partitions in an extended one, as elsewhere.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use gpt_disk_io::gpt_disk_types::{Guid, Lba};
+use gpt_disk_io::{BlockIo, Disk};
+use spin::Mutex;
+
+use super::PartitionError;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_DISK_SIGNATURE: usize = 440;
+const MBR_RECORDS: usize = 446;
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// How many logical partitions are looked for, so a looping chain of
+/// extended boot records ends.
+const MAX_LOGICAL: usize = 64;
+
+#[derive(Clone)]
+pub enum Scheme {
+    Gpt {
+        type_guid: Guid,
+        guid: Guid,
+        label: String,
+    },
+    Mbr {
+        system_id: u8,
+        disk_signature: u32,
+    },
+}
+
+#[derive(Clone)]
+pub struct PartitionInfo {
+    /// The devfs node of the whole disk, like `disk0`.
+    pub disk: String,
+    pub number: usize,
+    /// In bytes from the start of the disk.
+    pub start: u64,
+    pub end: u64,
+    pub scheme: Scheme,
+}
+
+impl PartitionInfo {
+    /// The devfs node of the partition, like `disk0p1`.
+    pub fn name(&self) -> String {
+        format!("{}p{}", self.disk, self.number)
+    }
+
+    /// The type GUID, or the MBR system ID as in `0x0c`.
+    pub fn type_name(&self) -> String {
+        match &self.scheme {
+            Scheme::Gpt { type_guid, .. } => type_guid.to_string(),
+            Scheme::Mbr { system_id, .. } => format!("{system_id:#04x}"),
+        }
+    }
+
+    /// The partition GUID, or for MBR the disk signature and the number, as
+    /// Linux makes up a `PARTUUID` for them.
+    pub fn uuid(&self) -> String {
+        match &self.scheme {
+            Scheme::Gpt { guid, .. } => guid.to_string(),
+            Scheme::Mbr { disk_signature, .. } => {
+                format!("{disk_signature:08x}-{:02x}", self.number)
+            }
+        }
+    }
+
+    /// The GPT partition name. MBR partitions have none.
+    pub fn label(&self) -> &str {
+        match &self.scheme {
+            Scheme::Gpt { label, .. } => label,
+            Scheme::Mbr { .. } => "",
+        }
+    }
+}
+
+static PARTITIONS: Mutex<Vec<PartitionInfo>> = Mutex::new(Vec::new());
+
+/// Read the partition table of `device`, the disk with the devfs node
+/// `disk`, and replace what was known about its partitions. False if the
+/// disk could not be read, e.g. because it is not ready yet; what was known
+/// is kept then.
+pub fn scan<T: BlockIo + Clone>(disk: &str, device: T) -> bool {
+    let found = match read(disk, device) {
+        Ok(found) => found,
+        Err(PartitionError::ReadFailed) => return false,
+        Err(_) => Vec::new(),
+    };
+    for partition in &found {
+        let size = crate::mm::convert_unit(partition.end - partition.start);
+        crate::println!(
+            "[INFO] blockdev: {}: {} {}, type {}",
+            partition.name(),
+            size.0,
+            size.1,
+            partition.type_name()
+        );
+    }
+    let mut partitions = PARTITIONS.lock();
+    partitions.retain(|partition| partition.disk != disk);
+    partitions.extend(found);
+    true
+}
+
+/// Forget the partitions of `disk`, which went away.
+pub fn remove(disk: &str) {
+    PARTITIONS.lock().retain(|partition| partition.disk != disk);
+}
+
+/// Partition `number` of `disk`.
+pub fn find(disk: &str, number: usize) -> Option<PartitionInfo> {
+    crate::blockdev::usb::scan_partitions();
+    PARTITIONS
+        .lock()
+        .iter()
+        .find(|partition| partition.disk == disk && partition.number == number)
+        .cloned()
+}
+
+/// The partitions of `disk`, or of every disk for `None`.
+pub fn list(disk: Option<&str>) -> Vec<PartitionInfo> {
+    crate::blockdev::usb::scan_partitions();
+    PARTITIONS
+        .lock()
+        .iter()
+        .filter(|partition| disk.is_none_or(|disk| partition.disk == disk))
+        .cloned()
+        .collect()
+}
+
+fn read<T: BlockIo + Clone>(
+    disk: &str,
+    mut device: T,
+) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let block_size = device
+        .block_size()
+        .to_usize()
+        .ok_or(PartitionError::UnsupportedBlockSize)? as u64;
+    // tell a disk that cannot be read from one without a table
+    let mut block = vec![0; block_size as usize];
+    device
+        .read_blocks(Lba(0), &mut block)
+        .map_err(|_| PartitionError::ReadFailed)?;
+    let mut partitions = match read_gpt(device.clone(), block_size) {
+        Ok(partitions) => partitions,
+        Err(_) => read_mbr(device, block_size)?,
+    };
+    for partition in &mut partitions {
+        partition.disk = String::from(disk);
+    }
+    Ok(partitions)
+}
+
+fn read_gpt<T: BlockIo>(
+    mut device: T,
+    block_size: u64,
+) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let disk_blocks = device
+        .num_blocks()
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    let mut disk = Disk::new(device).map_err(|_| PartitionError::NoPartitionTable)?;
+    let mut block_buf = [0; 4096];
+    let header = disk
+        .read_primary_gpt_header(&mut block_buf)
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    if !header.is_signature_valid() {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let layout = header
+        .get_partition_entry_array_layout()
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    let mut partitions = Vec::new();
+    let entries = disk
+        .gpt_partition_entry_array_iter(layout, &mut block_buf)
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    for (index, entry) in entries.enumerate() {
+        let entry = entry.map_err(|_| PartitionError::NoPartitionTable)?;
+        // an entry that ends before it starts or past the disk would
+        // make its size underflow or reach beyond the device
+        let (first, last) = (entry.starting_lba.to_u64(), entry.ending_lba.to_u64());
+        if !entry.is_used() || last < first || last >= disk_blocks {
+            continue;
+        }
+        let start = first
+            .checked_mul(block_size)
+            .ok_or(PartitionError::OffsetOverflow)?;
+        let end = last
+            .checked_add(1)
+            .and_then(|lba| lba.checked_mul(block_size))
+            .ok_or(PartitionError::OffsetOverflow)?;
+        partitions.push(PartitionInfo {
+            disk: String::new(),
+            number: index + 1,
+            start,
+            end,
+            scheme: Scheme::Gpt {
+                type_guid: entry.partition_type_guid.0,
+                guid: entry.unique_partition_guid,
+                label: entry.name.to_string(),
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+struct MbrRecord {
+    status: u8,
+    system_id: u8,
+    /// In blocks from the start of the table the record is in, or for the
+    /// link to the next extended boot record, of the extended partition.
+    start: u64,
+    blocks: u64,
+}
+
+/// The four records of the (extended) boot record in `block`, if it has the
+/// signature of one.
+fn mbr_records(block: &[u8]) -> Option<[MbrRecord; 4]> {
+    if block.get(510..512)? != MBR_SIGNATURE {
+        return None;
+    }
+    Some(core::array::from_fn(|index| {
+        let record = &block[MBR_RECORDS + index * 16..MBR_RECORDS + (index + 1) * 16];
+        let field = |offset: usize| {
+            u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap()) as u64
+        };
+        MbrRecord {
+            status: record[0],
+            system_id: record[4],
+            start: field(8),
+            blocks: field(12),
+        }
+    }))
+}
+
+fn read_mbr<T: BlockIo>(
+    mut device: T,
+    block_size: u64,
+) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let disk_blocks = device
+        .num_blocks()
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    let mut block = vec![0; block_size as usize];
+    device
+        .read_blocks(Lba(0), &mut block)
+        .map_err(|_| PartitionError::NoPartitionTable)?;
+    let records = mbr_records(&block).ok_or(PartitionError::NoPartitionTable)?;
+    // a FAT boot sector without a partition table has the same signature,
+    // but then these bytes are boot code
+    let is_table = records.iter().all(|record| {
+        matches!(record.status, 0x00 | 0x80)
+            && (record.system_id == 0 || record.start + record.blocks <= disk_blocks)
+    });
+    // a protective MBR whose GPT was not valid
+    let is_protective = records
+        .iter()
+        .any(|record| record.system_id == MBR_PROTECTIVE);
+    if !is_table || is_protective {
+        return Err(PartitionError::NoPartitionTable);
+    }
+    let disk_signature = u32::from_le_bytes(
+        block[MBR_DISK_SIGNATURE..MBR_DISK_SIGNATURE + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let partition = |number, system_id, start: u64, blocks: u64| PartitionInfo {
+        disk: String::new(),
+        number,
+        start: start * block_size,
+        end: (start + blocks) * block_size,
+        scheme: Scheme::Mbr {
+            system_id,
+            disk_signature,
+        },
+    };
+    let mut partitions = Vec::new();
+    let mut logical_number = 5;
+    for (index, record) in records.iter().enumerate() {
+        if record.system_id == 0 {
+            continue;
+        }
+        partitions.push(partition(
+            index + 1,
+            record.system_id,
+            record.start,
+            record.blocks,
+        ));
+        if !MBR_EXTENDED.contains(&record.system_id) {
+            continue;
+        }
+        // each extended boot record holds one logical partition and a link
+        // to the next one
+        let mut ebr = record.start;
+        for _ in 0..MAX_LOGICAL {
+            if ebr >= disk_blocks || device.read_blocks(Lba(ebr), &mut block).is_err() {
+                break;
+            }
+            let Some([logical, next, ..]) = mbr_records(&block) else {
+                break;
+            };
+            if logical.system_id != 0 && ebr + logical.start + logical.blocks <= disk_blocks {
+                partitions.push(partition(
+                    logical_number,
+                    logical.system_id,
+                    ebr + logical.start,
+                    logical.blocks,
+                ));
+                logical_number += 1;
+            }
+            if !MBR_EXTENDED.contains(&next.system_id) {
+                break;
+            }
+            ebr = record.start + next.start;
+        }
+    }
+    Ok(partitions)
+}
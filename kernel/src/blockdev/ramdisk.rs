@@ -81,8 +81,8 @@ impl crate::vfs::VfsFile for RamDisk {
         <Self as fatfs::Read>::read(self, buf).unwrap()
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
-        <Self as fatfs::Write>::write(self, buf).unwrap()
+    fn write(&mut self, buf: &[u8]) -> Result<usize, crate::vfs::VfsError> {
+        <Self as fatfs::Write>::write(self, buf).map_err(|_| crate::vfs::VfsError::Io)
     }
 
     fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
@@ -94,6 +94,14 @@ impl crate::vfs::VfsFile for RamDisk {
                 crate::vfs::SeekFrom::Start(x) => fatfs::SeekFrom::Start(x as u64),
             },
         )
-        .unwrap() as usize
+        .map_or(self.cur_pos, |pos| pos as usize)
+    }
+
+    fn metadata(&mut self) -> crate::vfs::Metadata {
+        crate::vfs::Metadata::block_device(512, self.size_in_blocks as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        <Self as fatfs::Write>::flush(self).map_err(|_| ())
     }
 }
@@ -1,6 +1,6 @@
 //! Read-only USB mass-storage block devices exposed through devfs.
 
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
 use core::fmt;
 use gpt_disk_io::{
     BlockIo,
@@ -8,6 +8,9 @@ use gpt_disk_io::{
 };
 use spin::{Lazy, Mutex};
 
+use crate::blockdev::cache::{self, BlockDevice, DeviceId};
+use crate::blockdev::partition;
+
 const BLOCK_SIZE: usize = 512;
 
 /// A device removal or transport failure observed by the USB block layer.
@@ -51,6 +54,8 @@ struct Record {
     id: usize,
     blocks: u64,
     online: bool,
+    /// Whether the partition table has been read.
+    scanned: bool,
 }
 
 static DEVICES: Lazy<Mutex<Vec<Record>>> = Lazy::new(|| Mutex::new(Vec::new()));
@@ -66,6 +71,7 @@ pub fn register(blocks: u64) -> usize {
         id,
         blocks,
         online: true,
+        scanned: false,
     });
     crate::println!(
         "[INFO] blockdev: usb{} registered, blocks {}, block size {}",
@@ -81,6 +87,32 @@ pub fn offline(id: usize) {
         device.online = false;
         crate::println!("[INFO] blockdev: usb{} offline", id);
     }
+    partition::table::remove(&name(id));
+}
+
+/// Read the partition tables of the devices whose table has not been read
+/// yet.
+///
+/// xHCI registers a device while it is still setting up the port, before it
+/// can read from it, so the table is read on a later partition lookup, once
+/// the device answers.
+pub fn scan_partitions() {
+    let new: Vec<usize> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.online && !device.scanned)
+        .map(|device| device.id)
+        .collect();
+    for id in new {
+        let Some(device) = UsbBlockDevice::open(id) else {
+            continue;
+        };
+        if partition::table::scan(&name(id), device)
+            && let Some(device) = DEVICES.lock().iter_mut().find(|device| device.id == id)
+        {
+            device.scanned = true;
+        }
+    }
 }
 
 pub fn online_devices() -> Vec<(usize, u64)> {
@@ -138,6 +170,8 @@ impl UsbBlockDevice {
         }
         let mut done = 0;
         while done < output.len() {
+            // ids are not reused, so the cached blocks of a device that went
+            // away are never looked up again and just age out
             if !self.is_online() {
                 return Err(UsbError::Offline);
             }
@@ -145,15 +179,16 @@ impl UsbBlockDevice {
             let lba = (offset / BLOCK_SIZE) as u64;
             let within = offset % BLOCK_SIZE;
             let take = (BLOCK_SIZE - within).min(output.len() - done);
-            let mut sector = [0u8; BLOCK_SIZE];
-            if !crate::xhci::read_usb_blocks(self.id, lba, &mut sector) {
+            let copy = |sector: &[u8]| {
+                output[done..done + take].copy_from_slice(&sector[within..within + take]);
+            };
+            if cache::with_block(self, lba, copy).is_err() {
                 return Err(if self.is_online() {
                     UsbError::ReadFailed
                 } else {
                     UsbError::Offline
                 });
             }
-            output[done..done + take].copy_from_slice(&sector[within..within + take]);
             done += take;
         }
         self.position = end;
@@ -161,6 +196,32 @@ impl UsbBlockDevice {
     }
 }
 
+impl BlockDevice for UsbBlockDevice {
+    fn device_id(&self) -> DeviceId {
+        DeviceId::Usb(self.id)
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn read_blocks_uncached(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ()> {
+        if crate::xhci::read_usb_blocks(self.id, lba, buf) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    fn write_blocks_uncached(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn clone_device(&self) -> Box<dyn BlockDevice> {
+        Box::new(self.clone())
+    }
+}
+
 impl fatfs::IoBase for UsbBlockDevice {
     type Error = UsbError;
 }
@@ -255,8 +316,8 @@ impl crate::vfs::VfsFile for UsbBlockDevice {
         fatfs::Read::read(self, output).unwrap_or(0)
     }
 
-    fn write(&mut self, _buf: &[u8]) -> usize {
-        0
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, crate::vfs::VfsError> {
+        Err(crate::vfs::VfsError::ReadOnly)
     }
 
     fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
@@ -268,18 +329,26 @@ impl crate::vfs::VfsFile for UsbBlockDevice {
                 crate::vfs::SeekFrom::Current(value) => fatfs::SeekFrom::Current(value as i64),
             },
         )
-        .map(|value| value as usize)
-        .unwrap_or(0)
+        .map_or(self.position, |value| value as usize)
+    }
+
+    fn metadata(&mut self) -> crate::vfs::Metadata {
+        crate::vfs::Metadata::block_device(BLOCK_SIZE as u64, self.blocks)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        <Self as fatfs::Write>::flush(self).map_err(|_| ())
     }
 }
 
 pub fn open(path: &str) -> Result<Arc<Mutex<dyn crate::vfs::VfsFile>>, ()> {
-    let id = path
-        .strip_prefix("/usb")
+    let (disk, part) = partition::split_node_name(path.strip_prefix('/').ok_or(())?);
+    let id = disk
+        .strip_prefix("usb")
         .ok_or(())?
         .parse::<usize>()
         .map_err(|_| ())?;
-    Ok(Arc::new(Mutex::new(UsbBlockDevice::open(id).ok_or(())?)))
+    partition::open_node(UsbBlockDevice::open(id).ok_or(())?, disk, part)
 }
 
 pub fn name(id: usize) -> String {
@@ -12,7 +12,20 @@ use spin::{Lazy, Mutex};
 pub static FRAMEBUFFER: Lazy<FrameBuffer> = Lazy::new(|| {
     let framebuffer_response = FRAMEBUFFER_REQUEST.response().unwrap();
     let framebuffer = framebuffer_response.framebuffers()[0];
-    FrameBuffer::from_limine(framebuffer)
+    let mut framebuffer = FrameBuffer::from_limine(framebuffer);
+    // The framebuffer is only ever written, so write-combining lets redraws
+    // go out in bursts instead of one uncached store per pixel.
+    let physical_address = framebuffer.addr as u64 - crate::mm::phys_to_virt(0);
+    if let Ok(region) = unsafe {
+        crate::mm::mmio::map(
+            physical_address,
+            framebuffer.pitch * framebuffer.height,
+            crate::mm::mmio::CacheMode::WriteCombining,
+        )
+    } {
+        framebuffer.addr = region.leak() as usize;
+    }
+    framebuffer
 });
 
 pub static TERMINAL: Lazy<Mutex<Terminal<FrameBuffer>>> = Lazy::new(|| {
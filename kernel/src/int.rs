@@ -2,6 +2,7 @@ use crate::println;
 use core::arch::naked_asm;
 use spin::Lazy;
 use x86_64::PrivilegeLevel;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
@@ -24,6 +25,11 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
         .set_privilege_level(PrivilegeLevel::Ring3);
     temp.page_fault.set_handler_fn(handler5);
     temp.general_protection_fault.set_handler_fn(handler6);
+    unsafe {
+        temp.double_fault
+            .set_handler_fn(handler7)
+            .set_stack_index(crate::task::DOUBLE_FAULT_IST_INDEX);
+    }
     temp
 });
 
@@ -114,3 +120,23 @@ pub extern "x86-interrupt" fn handler6(f: InterruptStackFrame, c: u64) {
     );
     loop {}
 }
+
+/// Runs on its own IST stack, so it still works when a kernel stack overflow
+/// hit a guard page and the page fault frame could not be pushed.
+#[allow(clippy::empty_loop)]
+pub extern "x86-interrupt" fn handler7(f: InterruptStackFrame, _code: u64) -> ! {
+    let overflowed = crate::task::kstack::guard_owner(Cr2::read_raw())
+        .or_else(|| crate::task::kstack::guard_owner(f.stack_pointer.as_u64()));
+    if let Some(pid) = overflowed {
+        println!(
+            "kernel stack overflow in pid {pid}, caused by instruction at {:?}",
+            f.instruction_pointer
+        );
+    } else {
+        println!(
+            "double fault, caused by instruction at {:?}",
+            f.instruction_pointer
+        );
+    }
+    loop {}
+}
@@ -0,0 +1,62 @@
+//! DMA remapping.
+//!
+//! Drivers never talk to this module directly; they go through `mm::dma`,
+//! which asks it for the address a device has to use for a physical range.
+//! Without a remapping unit that address is the physical address itself.
+//!
+//! The Intel VT-d backend is opt-in (`iommu=vtd` on the kernel command line)
+//! because enabling translation blocks DMA from every device that has no
+//! driver going through `mm::dma`, e.g. a GPU scanning out the framebuffer.
+
+pub mod vtd;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::mm::dma::DmaDevice;
+use crate::println;
+
+static VTD_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    if !crate::cmdline::CMDLINE
+        .split_ascii_whitespace()
+        .any(|arg| arg == "iommu=vtd")
+    {
+        return;
+    }
+    match vtd::init() {
+        Ok(units) => {
+            println!("[INFO] iommu: VT-d enabled on {units} remapping unit(s)");
+            VTD_ENABLED.store(true, Ordering::Release);
+        }
+        Err(error) => println!("[WARN] iommu: VT-d not enabled: {error}"),
+    }
+}
+
+pub fn enabled() -> bool {
+    VTD_ENABLED.load(Ordering::Acquire)
+}
+
+/// Make `pages` pages starting at `phys` visible to `device` and return the
+/// address the device has to use for `phys`.
+pub(crate) fn map(device: DmaDevice, phys: u64, pages: usize) -> Result<u64, ()> {
+    if enabled() {
+        vtd::map(device, phys, pages)
+    } else {
+        Ok(phys)
+    }
+}
+
+/// Revoke a mapping returned by `map`.
+pub(crate) fn unmap(device: DmaDevice, device_address: u64, pages: usize) {
+    if enabled() {
+        vtd::unmap(device, device_address, pages);
+    }
+}
+
+/// Log and clear the DMA faults recorded since the last call.
+pub fn report_faults() {
+    if enabled() {
+        vtd::report_faults();
+    }
+}
@@ -0,0 +1,518 @@
+//! Intel VT-d DMA remapping.
+//!
+//! Every device gets its own domain whose second-level page table maps
+//! exactly the buffers handed to it through `mm::dma`. Inside a domain a
+//! device address equals the physical address, so offsets a driver or device
+//! computes within a buffer stay valid; what changes is that every address
+//! outside the mapped buffers faults instead of reaching memory. RMRR regions
+//! reported by the firmware are identity-mapped for the devices they name.
+//!
+//! Only endpoint device scopes are matched. Devices behind a bridge scope are
+//! left to a unit with the INCLUDE_PCI_ALL flag, if the firmware has one.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::mm::dma::DmaDevice;
+use crate::mm::mmio::{self, CacheMode};
+use crate::mm::page_alloc::{PAGE_SIZE, alloc_physical_page, dealloc_physical_page};
+use crate::mm::phys_to_virt;
+use crate::pcie::enumrate::Bdf;
+use crate::println;
+
+const DMAR_HEADER_LEN: usize = 48;
+const TYPE_DRHD: u16 = 0;
+const TYPE_RMRR: u16 = 1;
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+const SCOPE_ENDPOINT: u8 = 1;
+
+const REG_CAP: usize = 0x08;
+const REG_ECAP: usize = 0x10;
+const REG_GCMD: usize = 0x18;
+const REG_GSTS: usize = 0x1c;
+const REG_RTADDR: usize = 0x20;
+const REG_CCMD: usize = 0x28;
+const REG_FSTS: usize = 0x34;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_WBF: u32 = 1 << 27;
+/// GSTS bits that are safe to write back to GCMD (one-shot bits cleared).
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+const CAP_RWBF: u64 = 1 << 4;
+const ECAP_COHERENT: u64 = 1 << 0;
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const FSTS_PFO: u32 = 1 << 0;
+const FRCD_F: u64 = 1 << 63;
+const FRCD_READ: u64 = 1 << 62;
+
+const PTE_READ: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const SPIN_LIMIT: usize = 10_000_000;
+
+struct Rmrr {
+    segment: u16,
+    base: u64,
+    limit: u64,
+    devices: Vec<Bdf>,
+}
+
+struct Domain {
+    table: u64,
+    /// Per-page mapping counts, keyed by physical (= device) address.
+    refs: BTreeMap<u64, u32>,
+}
+
+struct Unit {
+    registers: *mut u8,
+    segment: u16,
+    include_all: bool,
+    devices: Vec<Bdf>,
+    root_table: u64,
+    levels: u32,
+    address_width: u64,
+    coherent: bool,
+    write_buffer_flush: bool,
+    iotlb: usize,
+    fault_records: usize,
+    fault_record_count: usize,
+    max_domains: usize,
+    domains: BTreeMap<u16, Domain>,
+}
+
+unsafe impl Send for Unit {}
+
+struct State {
+    units: Vec<Unit>,
+    rmrrs: Vec<Rmrr>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    units: Vec::new(),
+    rmrrs: Vec::new(),
+});
+
+impl Unit {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.registers.add(offset) as *const u32) }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(self.registers.add(offset) as *mut u32, value) }
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        unsafe { core::ptr::read_volatile(self.registers.add(offset) as *const u64) }
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { core::ptr::write_volatile(self.registers.add(offset) as *mut u64, value) }
+    }
+
+    /// Issue a global command and wait for the status bit to follow.
+    fn command(&self, bit: u32, set: bool) -> Result<(), &'static str> {
+        let status = self.read32(REG_GSTS) & GSTS_PERSISTENT;
+        self.write32(REG_GCMD, if set { status | bit } else { status & !bit });
+        spin_until(|| (self.read32(REG_GSTS) & bit != 0) == set)
+    }
+
+    fn covers(&self, device: DmaDevice) -> bool {
+        self.segment == device.segment && self.devices.contains(&device.bdf)
+    }
+
+    fn flush_entry(&self, entry: *const u64) {
+        if !self.coherent {
+            unsafe { core::arch::asm!("clflush [{}]", in(reg) entry) };
+        }
+    }
+
+    fn flush_write_buffer(&self) {
+        if self.write_buffer_flush {
+            let status = self.read32(REG_GSTS) & GSTS_PERSISTENT;
+            self.write32(REG_GCMD, status | GCMD_WBF);
+            let _ = spin_until(|| self.read32(REG_GSTS) & GCMD_WBF == 0);
+        }
+    }
+
+    fn invalidate_context_cache(&self) {
+        self.flush_write_buffer();
+        self.write64(REG_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        let _ = spin_until(|| self.read64(REG_CCMD) & CCMD_ICC == 0);
+    }
+
+    fn invalidate_iotlb(&self) {
+        self.flush_write_buffer();
+        self.write64(self.iotlb + 8, IOTLB_IVT | IOTLB_GLOBAL);
+        let _ = spin_until(|| self.read64(self.iotlb + 8) & IOTLB_IVT == 0);
+    }
+
+    /// Create the domain for `device` on first use, RMRR ranges included.
+    fn ensure_domain(&mut self, device: DmaDevice, rmrrs: &[Rmrr]) -> Result<(), ()> {
+        let source_id = device.source_id();
+        if self.domains.contains_key(&source_id) {
+            return Ok(());
+        }
+        // domain 0 is reserved when the unit runs in caching mode
+        let id = self.domains.len() + 1;
+        if id >= self.max_domains {
+            return Err(());
+        }
+        let mut domain = Domain {
+            table: zeroed_page().ok_or(())?,
+            refs: BTreeMap::new(),
+        };
+        let installed = rmrrs
+            .iter()
+            .filter(|rmrr| rmrr.segment == device.segment && rmrr.devices.contains(&device.bdf))
+            .try_for_each(|rmrr| {
+                let mut page = rmrr.base & PTE_ADDR_MASK;
+                while page <= rmrr.limit {
+                    self.map_page(&mut domain, page)?;
+                    page += PAGE_SIZE as u64;
+                }
+                Ok(())
+            })
+            .and_then(|()| self.install_context(device, id as u16, domain.table));
+        if installed.is_err() {
+            self.clear_context(device);
+            self.free_table(domain.table, self.levels);
+            return Err(());
+        }
+        self.domains.insert(source_id, domain);
+        Ok(())
+    }
+
+    /// Free the page table at `table` of the given level and the tables
+    /// below it, but not the pages they map.
+    fn free_table(&self, table: u64, level: u32) {
+        if level > 1 {
+            for index in 0..512 {
+                let entry = unsafe { *(phys_to_virt(table) as *const u64).add(index) };
+                if entry & (PTE_READ | PTE_WRITE) != 0 {
+                    self.free_table(entry & PTE_ADDR_MASK, level - 1);
+                }
+            }
+        }
+        dealloc_physical_page(table);
+    }
+
+    /// Clear the context entry of `device`, if its bus has a context table.
+    fn clear_context(&self, device: DmaDevice) {
+        let root_entry =
+            (phys_to_virt(self.root_table) as *mut u64).wrapping_add(2 * device.bdf.bus as usize);
+        if unsafe { *root_entry } & 1 == 0 {
+            return;
+        }
+        let context_table = unsafe { *root_entry } & PTE_ADDR_MASK;
+        let devfn = (device.bdf.device as usize) << 3 | device.bdf.function as usize;
+        let entry = (phys_to_virt(context_table) as *mut u64).wrapping_add(2 * devfn);
+        unsafe {
+            *entry = 0;
+            *entry.add(1) = 0;
+        }
+        self.flush_entry(entry);
+        self.invalidate_context_cache();
+        self.invalidate_iotlb();
+    }
+
+    fn install_context(&self, device: DmaDevice, id: u16, table: u64) -> Result<(), ()> {
+        let root_entry =
+            (phys_to_virt(self.root_table) as *mut u64).wrapping_add(2 * device.bdf.bus as usize);
+        let mut context_table = unsafe { *root_entry } & PTE_ADDR_MASK;
+        if unsafe { *root_entry } & 1 == 0 {
+            context_table = zeroed_page().ok_or(())?;
+            unsafe { *root_entry = context_table | 1 };
+            self.flush_entry(root_entry);
+        }
+        let devfn = (device.bdf.device as usize) << 3 | device.bdf.function as usize;
+        let entry = (phys_to_virt(context_table) as *mut u64).wrapping_add(2 * devfn);
+        // AW encodes the page table depth: 1 for 3 levels, 2 for 4 levels
+        let address_width = (self.levels - 2) as u64;
+        unsafe {
+            *entry.add(1) = address_width | (id as u64) << 8;
+            // translation type 0: untranslated requests only
+            *entry = table | 1;
+        }
+        self.flush_entry(entry);
+        self.invalidate_context_cache();
+        self.invalidate_iotlb();
+        Ok(())
+    }
+
+    /// Walk `domain`'s page table down to the leaf entry for `address`,
+    /// creating intermediate tables when `create` is set.
+    fn leaf(&self, domain: &Domain, address: u64, create: bool) -> Option<*mut u64> {
+        let mut table = domain.table;
+        for level in (2..=self.levels).rev() {
+            let index = (address >> (12 + 9 * (level - 1))) as usize & 511;
+            let entry = (phys_to_virt(table) as *mut u64).wrapping_add(index);
+            if unsafe { *entry } & (PTE_READ | PTE_WRITE) == 0 {
+                if !create {
+                    return None;
+                }
+                let next = zeroed_page()?;
+                unsafe { *entry = next | PTE_READ | PTE_WRITE };
+                self.flush_entry(entry);
+            }
+            table = unsafe { *entry } & PTE_ADDR_MASK;
+        }
+        let index = (address >> 12) as usize & 511;
+        Some((phys_to_virt(table) as *mut u64).wrapping_add(index))
+    }
+
+    fn map_page(&self, domain: &mut Domain, page: u64) -> Result<(), ()> {
+        if page >= self.address_width {
+            return Err(());
+        }
+        let refs = domain.refs.entry(page).or_insert(0);
+        *refs += 1;
+        if *refs == 1 {
+            let leaf = self.leaf(domain, page, true).ok_or(())?;
+            unsafe { *leaf = page | PTE_READ | PTE_WRITE };
+            self.flush_entry(leaf);
+        }
+        Ok(())
+    }
+
+    fn unmap_page(&self, domain: &mut Domain, page: u64) {
+        let Some(refs) = domain.refs.get_mut(&page) else {
+            return;
+        };
+        *refs -= 1;
+        if *refs == 0 {
+            domain.refs.remove(&page);
+            if let Some(leaf) = self.leaf(domain, page, false) {
+                unsafe { *leaf = 0 };
+                self.flush_entry(leaf);
+            }
+        }
+    }
+}
+
+fn spin_until(mut done: impl FnMut() -> bool) -> Result<(), &'static str> {
+    for _ in 0..SPIN_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("remapping unit did not respond")
+}
+
+fn zeroed_page() -> Option<u64> {
+    let page = alloc_physical_page()?;
+    unsafe { core::ptr::write_bytes(phys_to_virt(page) as *mut u8, 0, PAGE_SIZE) };
+    Some(page)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Collect the endpoints named by the device scope entries in `scopes`.
+fn parse_scopes(scopes: &[u8]) -> Vec<Bdf> {
+    let mut devices = Vec::new();
+    let mut offset = 0;
+    while offset + 6 <= scopes.len() {
+        let kind = scopes[offset];
+        let length = scopes[offset + 1] as usize;
+        if length < 6 || offset + length > scopes.len() {
+            break;
+        }
+        // a path of exactly one (device, function) pair names a device on
+        // the start bus itself
+        if kind == SCOPE_ENDPOINT
+            && length == 8
+            && let Some(bdf) = Bdf::new(scopes[offset + 5], scopes[offset + 6], scopes[offset + 7])
+        {
+            devices.push(bdf);
+        }
+        offset += length;
+    }
+    devices
+}
+
+fn probe_unit(
+    segment: u16,
+    base: u64,
+    include_all: bool,
+    devices: Vec<Bdf>,
+) -> Result<Unit, &'static str> {
+    let registers = unsafe { mmio::map(base, PAGE_SIZE, CacheMode::Uncacheable) }
+        .map_err(|_| "unable to map remapping unit registers")?
+        .leak();
+    let mut unit = Unit {
+        registers,
+        segment,
+        include_all,
+        devices,
+        root_table: 0,
+        levels: 0,
+        address_width: 0,
+        coherent: false,
+        write_buffer_flush: false,
+        iotlb: 0,
+        fault_records: 0,
+        fault_record_count: 0,
+        max_domains: 0,
+        domains: BTreeMap::new(),
+    };
+    let cap = unit.read64(REG_CAP);
+    let ecap = unit.read64(REG_ECAP);
+    let sagaw = (cap >> 8) & 0x1f;
+    unit.levels = if sagaw & (1 << 2) != 0 {
+        4
+    } else if sagaw & (1 << 1) != 0 {
+        3
+    } else {
+        return Err("no supported page table depth");
+    };
+    unit.address_width = 1 << (12 + 9 * unit.levels);
+    unit.coherent = ecap & ECAP_COHERENT != 0;
+    unit.write_buffer_flush = cap & CAP_RWBF != 0;
+    unit.iotlb = ((ecap >> 8) & 0x3ff) as usize * 16;
+    unit.fault_records = ((cap >> 24) & 0x3ff) as usize * 16;
+    unit.fault_record_count = ((cap >> 40) & 0xff) as usize + 1;
+    unit.max_domains = 1 << (4 + 2 * (cap & 0x7));
+    unit.root_table = zeroed_page().ok_or("out of memory")?;
+
+    if unit.read32(REG_GSTS) & GCMD_TE != 0 {
+        unit.command(GCMD_TE, false)?;
+    }
+    unit.write64(REG_RTADDR, unit.root_table);
+    unit.command(GCMD_SRTP, true)?;
+    unit.invalidate_context_cache();
+    unit.invalidate_iotlb();
+    unit.command(GCMD_TE, true)?;
+    Ok(unit)
+}
+
+pub(super) fn init() -> Result<usize, &'static str> {
+    let table = crate::acpi::dmar_table().ok_or("no DMAR table")?;
+    let mut state = STATE.lock();
+    let mut offset = DMAR_HEADER_LEN;
+    while offset + 4 <= table.len() {
+        let kind = read_u16(table, offset);
+        let length = read_u16(table, offset + 2) as usize;
+        if length < 4 || offset + length > table.len() {
+            return Err("malformed DMAR table");
+        }
+        let entry = &table[offset..offset + length];
+        match kind {
+            TYPE_DRHD if length >= 16 => {
+                let include_all = entry[4] & DRHD_INCLUDE_PCI_ALL != 0;
+                let segment = read_u16(entry, 6);
+                let base = read_u64(entry, 8);
+                let unit = probe_unit(segment, base, include_all, parse_scopes(&entry[16..]))?;
+                state.units.push(unit);
+            }
+            TYPE_RMRR if length >= 24 => state.rmrrs.push(Rmrr {
+                segment: read_u16(entry, 6),
+                base: read_u64(entry, 8),
+                limit: read_u64(entry, 16),
+                devices: parse_scopes(&entry[24..]),
+            }),
+            _ => {}
+        }
+        offset += length;
+    }
+    if state.units.is_empty() {
+        return Err("no remapping hardware units");
+    }
+    Ok(state.units.len())
+}
+
+fn unit_index(units: &[Unit], device: DmaDevice) -> Option<usize> {
+    units
+        .iter()
+        .position(|unit| unit.covers(device))
+        .or_else(|| {
+            units
+                .iter()
+                .position(|unit| unit.include_all && unit.segment == device.segment)
+        })
+}
+
+pub(super) fn map(device: DmaDevice, phys: u64, pages: usize) -> Result<u64, ()> {
+    let mut state = STATE.lock();
+    let State { units, rmrrs } = &mut *state;
+    let Some(index) = unit_index(units, device) else {
+        // not behind any remapping unit, so its requests are not translated
+        return Ok(phys);
+    };
+    let unit = &mut units[index];
+    unit.ensure_domain(device, rmrrs)?;
+    // domains are only ever added, so the count doubles as the next id and
+    // taking one out for the walk below cannot hand its id out twice
+    let mut domain = unit.domains.remove(&device.source_id()).unwrap();
+    let mut result = Ok(phys);
+    for page in 0..pages {
+        let address = phys + (page * PAGE_SIZE) as u64;
+        if unit.map_page(&mut domain, address).is_err() {
+            for mapped in 0..page {
+                unit.unmap_page(&mut domain, phys + (mapped * PAGE_SIZE) as u64);
+            }
+            result = Err(());
+            break;
+        }
+    }
+    unit.domains.insert(device.source_id(), domain);
+    unit.invalidate_iotlb();
+    result
+}
+
+pub(super) fn unmap(device: DmaDevice, device_address: u64, pages: usize) {
+    let mut state = STATE.lock();
+    let Some(index) = unit_index(&state.units, device) else {
+        return;
+    };
+    let unit = &mut state.units[index];
+    let Some(mut domain) = unit.domains.remove(&device.source_id()) else {
+        return;
+    };
+    for page in 0..pages {
+        unit.unmap_page(&mut domain, device_address + (page * PAGE_SIZE) as u64);
+    }
+    unit.domains.insert(device.source_id(), domain);
+    unit.invalidate_iotlb();
+}
+
+pub(super) fn report_faults() {
+    let state = STATE.lock();
+    for unit in &state.units {
+        for record in 0..unit.fault_record_count {
+            let offset = unit.fault_records + record * 16;
+            let high = unit.read64(offset + 8);
+            if high & FRCD_F == 0 {
+                continue;
+            }
+            let address = unit.read64(offset) & PTE_ADDR_MASK;
+            let source_id = high as u16;
+            println!(
+                "[WARN] iommu: blocked DMA {} by {:02x}:{:02x}.{} at {:#x} (reason {:#x})",
+                if high & FRCD_READ != 0 {
+                    "read"
+                } else {
+                    "write"
+                },
+                source_id >> 8,
+                (source_id >> 3) & 0x1f,
+                source_id & 0x7,
+                address,
+                (high >> 32) & 0xff,
+            );
+            unit.write64(offset + 8, FRCD_F);
+        }
+        unit.write32(REG_FSTS, FSTS_PFO);
+    }
+}
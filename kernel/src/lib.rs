@@ -1,6 +1,5 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
-#![feature(str_from_raw_parts)]
 #![allow(non_snake_case)]
 #![allow(clippy::result_unit_err)]
 #![allow(clippy::len_without_is_empty)]
@@ -16,6 +15,7 @@ pub mod console;
 pub mod cpu;
 pub mod inputdev;
 pub mod int;
+pub mod iommu;
 pub mod mm;
 pub mod net;
 pub mod pcie;
@@ -11,6 +11,7 @@ use DoglinkOS_2nd::inputdev::init as init_inputdev;
 use DoglinkOS_2nd::int::init as init_interrupt;
 use DoglinkOS_2nd::mm::dma::test as test_dma;
 use DoglinkOS_2nd::mm::init as init_mm;
+use DoglinkOS_2nd::mm::mmio::test as test_mmio;
 use DoglinkOS_2nd::mm::page_alloc::test as test_page_alloc;
 use DoglinkOS_2nd::net::init as init_net;
 use DoglinkOS_2nd::pcie::enumrate::doit;
@@ -18,6 +19,7 @@ use DoglinkOS_2nd::pcie::enumrate::test as test_pcie;
 use DoglinkOS_2nd::println;
 use DoglinkOS_2nd::task::{init as init_task, init_sse, reset_gdt};
 use DoglinkOS_2nd::vfs::init as init_vfs;
+use DoglinkOS_2nd::vfs::path::test as test_path;
 use DoglinkOS_2nd::xhci::init as init_xhci;
 use DoglinkOS_2nd::xhci::test as test_xhci;
 use core::arch::asm;
@@ -58,6 +60,7 @@ extern "C" fn kmain() -> ! {
     init_ioapic(parse_madt(), lapic_id);
     init_inputdev();
     DoglinkOS_2nd::inputdev::test();
+    DoglinkOS_2nd::iommu::init();
     init_ahci();
     init_nvme();
     show_cpu_info();
@@ -65,6 +68,8 @@ extern "C" fn kmain() -> ! {
     test_pcie();
     test_page_alloc();
     test_dma();
+    test_mmio();
+    test_path();
     test_xhci();
     init_xhci();
     init_net();
@@ -134,6 +139,8 @@ fn idle() -> ! {
     loop {
         DoglinkOS_2nd::net::poll();
         DoglinkOS_2nd::xhci::poll();
+        DoglinkOS_2nd::iommu::report_faults();
+        DoglinkOS_2nd::blockdev::cache::write_back_periodically();
         // Polling consumes only a bounded event batch.  Sleeping until the
         // next hardware interrupt avoids burning a core when no USB device is
         // present; USB event delivery remains polling-only until MSI support.
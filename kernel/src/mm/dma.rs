@@ -1,76 +1,74 @@
-//! DMA and MMIO helpers for devices using the HHDM direct map.
+//! DMA helpers for devices using the HHDM direct map. MMIO mappings live in
+//! `mm::mmio`.
 //!
-//! There is no IOMMU support yet. DMA addresses are therefore physical
-//! addresses and device drivers must keep a `DmaBuffer` alive until hardware
-//! has stopped accessing it.
+//! Every buffer and mapping is made on behalf of a `DmaDevice`. The address
+//! handed to hardware is `device_address()`, which goes through `iommu` and
+//! only equals the physical address while no remapping unit is enabled.
+//! Device drivers must keep a `DmaBuffer` or `DmaMapping` alive until
+//! hardware has stopped accessing it; dropping it revokes the device's
+//! access.
 
+use alloc::vec::Vec;
 use core::ptr::NonNull;
 
-use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{
-    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
-    mapper::FlagUpdateError,
-};
-use x86_64::{PhysAddr, VirtAddr};
-
-use crate::mm::page_alloc::{
-    DLOSFrameAllocator, PAGE_SIZE, dealloc_continuous_mem, find_aligned_continuous_mem,
-};
+use crate::iommu;
+use crate::mm::page_alloc::{PAGE_SIZE, dealloc_continuous_mem, find_aligned_continuous_mem};
 use crate::mm::phys_to_virt;
+use crate::pcie::enumrate::Bdf;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum DmaError {
     Empty,
     InvalidAlignment,
     OutOfMemory,
+    MappingFailed,
 }
 
+/// A PCI function issuing DMA, identified the way remapping hardware sees it.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum MmioError {
-    Empty,
-    AddressOverflow,
-    MappingMissing,
+pub struct DmaDevice {
+    pub segment: u16,
+    pub bdf: Bdf,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-struct PageRange {
-    base: u64,
-    pages: usize,
-    offset: usize,
+impl DmaDevice {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment: 0,
+            bdf: Bdf::new(bus, device, function).expect("mm: invalid DMA requester"),
+        }
+    }
+
+    /// The PCI requester ID the device puts on its DMA transactions.
+    pub fn source_id(&self) -> u16 {
+        (self.bdf.bus as u16) << 8 | (self.bdf.device as u16) << 3 | self.bdf.function as u16
+    }
 }
 
-impl PageRange {
-    fn new(address: u64, len: usize) -> Result<Self, MmioError> {
-        if len == 0 {
-            return Err(MmioError::Empty);
-        }
-        let base = address & !(PAGE_SIZE as u64 - 1);
-        let offset = (address - base) as usize;
-        let span = offset.checked_add(len).ok_or(MmioError::AddressOverflow)?;
-        let pages = span
-            .checked_add(PAGE_SIZE - 1)
-            .ok_or(MmioError::AddressOverflow)?
-            / PAGE_SIZE;
-        base.checked_add((pages - 1) as u64 * PAGE_SIZE as u64)
-            .ok_or(MmioError::AddressOverflow)?;
-        Ok(Self {
-            base,
-            pages,
-            offset,
-        })
+fn page_span(phys: u64, len: usize) -> Result<(u64, usize), DmaError> {
+    if len == 0 {
+        return Err(DmaError::Empty);
     }
+    let base = phys & !(PAGE_SIZE as u64 - 1);
+    let end = phys
+        .checked_add(len as u64 + PAGE_SIZE as u64 - 1)
+        .ok_or(DmaError::MappingFailed)?
+        & !(PAGE_SIZE as u64 - 1);
+    Ok((base, ((end - base) / PAGE_SIZE as u64) as usize))
 }
 
 /// Physically contiguous, zeroed memory suitable for DMA.
 pub struct DmaBuffer {
+    device: DmaDevice,
     physical_address: u64,
+    device_address: u64,
     virtual_address: NonNull<u8>,
     pages: usize,
     alignment: usize,
 }
 
 impl DmaBuffer {
-    pub fn new(len: usize, alignment: usize) -> Result<Self, DmaError> {
+    pub fn new(device: DmaDevice, len: usize, alignment: usize) -> Result<Self, DmaError> {
         if len == 0 {
             return Err(DmaError::Empty);
         }
@@ -87,8 +85,14 @@ impl DmaBuffer {
         let virtual_address = NonNull::new(phys_to_virt(physical_address) as *mut u8)
             .expect("HHDM virtual address must not be null");
         unsafe { core::ptr::write_bytes(virtual_address.as_ptr(), 0, pages * PAGE_SIZE) };
+        let Ok(device_address) = iommu::map(device, physical_address, pages) else {
+            dealloc_continuous_mem(physical_address, pages);
+            return Err(DmaError::MappingFailed);
+        };
         Ok(Self {
+            device,
             physical_address,
+            device_address,
             virtual_address,
             pages,
             alignment,
@@ -99,6 +103,15 @@ impl DmaBuffer {
         self.physical_address
     }
 
+    /// The address `device` has to use to reach the buffer.
+    pub fn device_address(&self) -> u64 {
+        self.device_address
+    }
+
+    pub fn device(&self) -> DmaDevice {
+        self.device
+    }
+
     pub fn virtual_address(&self) -> u64 {
         self.virtual_address.as_ptr() as u64
     }
@@ -122,123 +135,113 @@ impl DmaBuffer {
 
 impl Drop for DmaBuffer {
     fn drop(&mut self) {
+        iommu::unmap(self.device, self.device_address, self.pages);
         dealloc_continuous_mem(self.physical_address, self.pages);
     }
 }
 
-/// An HHDM MMIO range with all constituent pages marked uncacheable.
-pub struct MmioMapping {
-    physical_address: u64,
-    virtual_address: NonNull<u8>,
-    len: usize,
-    page_base: u64,
+/// Device access to memory owned by someone else, e.g. a caller's buffer.
+pub struct DmaMapping {
+    device: DmaDevice,
+    device_address: u64,
     pages: usize,
 }
 
-impl MmioMapping {
-    /// # Safety
-    ///
-    /// `physical_address..physical_address + len` must identify a device MMIO
-    /// range. It remains mapped until the kernel changes the active page table.
-    pub unsafe fn map(physical_address: u64, len: usize) -> Result<Self, MmioError> {
-        let range = PageRange::new(physical_address, len)?;
-        let mut mapper = unsafe {
-            OffsetPageTable::new(
-                &mut *(phys_to_virt(Cr3::read().0.start_address().as_u64()) as *mut PageTable),
-                VirtAddr::new(phys_to_virt(0)),
-            )
-        };
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
-        for page_index in 0..range.pages {
-            let virtual_address = phys_to_virt(range.base + page_index as u64 * PAGE_SIZE as u64);
-            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virtual_address));
-            let result = unsafe { mapper.update_flags(page, flags) };
-            match result {
-                Ok(flush) => flush.flush(),
-                // Limine commonly maps the HHDM with 2 MiB pages. A 4 KiB
-                // flag update cannot split such a mapping, so mark the
-                // containing huge page uncached instead.
-                Err(FlagUpdateError::ParentEntryHugePage) => {
-                    let huge = Page::<Size2MiB>::containing_address(VirtAddr::new(virtual_address));
-                    unsafe { mapper.update_flags(huge, flags) }
-                        .map_err(|_| MmioError::MappingMissing)?
-                        .flush();
-                }
-                Err(FlagUpdateError::PageNotMapped) => {
-                    let physical_address = range.base + page_index as u64 * PAGE_SIZE as u64;
-                    unsafe {
-                        mapper
-                            .map_to(
-                                page,
-                                PhysFrame::containing_address(PhysAddr::new(physical_address)),
-                                flags,
-                                &mut DLOSFrameAllocator,
-                            )
-                            .map_err(|_| MmioError::MappingMissing)?
-                            .flush();
-                    }
-                }
-            }
-        }
-        let virtual_address = NonNull::new(phys_to_virt(physical_address) as *mut u8)
-            .expect("HHDM virtual address must not be null");
-        Ok(Self {
-            physical_address,
-            virtual_address,
-            len,
-            page_base: range.base,
-            pages: range.pages,
-        })
-    }
+/// Let `device` access `len` bytes starting at `phys`.
+///
+/// The pages containing the range are mapped as a whole, so the device can
+/// also reach whatever shares the first and last page with it.
+pub fn map(device: DmaDevice, phys: u64, len: usize) -> Result<DmaMapping, DmaError> {
+    let (base, pages) = page_span(phys, len)?;
+    let page_address = iommu::map(device, base, pages).map_err(|_| DmaError::MappingFailed)?;
+    Ok(DmaMapping {
+        device,
+        device_address: page_address + (phys - base),
+        pages,
+    })
+}
 
-    pub fn physical_address(&self) -> u64 {
-        self.physical_address
+impl DmaMapping {
+    pub fn device_address(&self) -> u64 {
+        self.device_address
     }
 
-    pub fn virtual_address(&self) -> u64 {
-        self.virtual_address.as_ptr() as u64
+    pub fn pages(&self) -> usize {
+        self.pages
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.len
+impl Drop for DmaMapping {
+    fn drop(&mut self) {
+        iommu::unmap(
+            self.device,
+            self.device_address & !(PAGE_SIZE as u64 - 1),
+            self.pages,
+        );
     }
+}
 
-    pub fn pages(&self) -> usize {
-        self.pages
+/// One contiguous run of device addresses in a `ScatterList`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DmaSegment {
+    pub device_address: u64,
+    pub len: usize,
+}
+
+/// Device access to a list of physical ranges.
+pub struct ScatterList {
+    mappings: Vec<DmaMapping>,
+    segments: Vec<DmaSegment>,
+}
+
+/// Map every `(phys, len)` range for `device`. Ranges that end up adjacent
+/// in device address space are merged into one segment.
+pub fn map_sg(device: DmaDevice, ranges: &[(u64, usize)]) -> Result<ScatterList, DmaError> {
+    let mut mappings = Vec::with_capacity(ranges.len());
+    let mut segments: Vec<DmaSegment> = Vec::with_capacity(ranges.len());
+    for &(phys, len) in ranges {
+        let mapping = map(device, phys, len)?;
+        let device_address = mapping.device_address();
+        mappings.push(mapping);
+        if let Some(last) = segments.last_mut()
+            && last.device_address + last.len as u64 == device_address
+        {
+            last.len += len;
+        } else {
+            segments.push(DmaSegment {
+                device_address,
+                len,
+            });
+        }
     }
+    Ok(ScatterList { mappings, segments })
+}
 
-    pub fn page_base(&self) -> u64 {
-        self.page_base
+impl ScatterList {
+    pub fn segments(&self) -> &[DmaSegment] {
+        &self.segments
     }
 
-    pub fn as_ptr(&self) -> *mut u8 {
-        self.virtual_address.as_ptr()
+    pub fn pages(&self) -> usize {
+        self.mappings.iter().map(DmaMapping::pages).sum()
     }
 }
 
 pub fn test() {
-    assert_eq!(
-        PageRange::new(0x1234, PAGE_SIZE).unwrap(),
-        PageRange {
-            base: 0x1000,
-            pages: 2,
-            offset: 0x234,
-        }
-    );
-    assert_eq!(PageRange::new(0, 0), Err(MmioError::Empty));
-    assert_eq!(
-        PageRange::new(u64::MAX - 1, PAGE_SIZE),
-        Err(MmioError::AddressOverflow)
-    );
-    assert!(matches!(DmaBuffer::new(0, PAGE_SIZE), Err(DmaError::Empty)));
+    let device = DmaDevice::new(0, 0, 0);
+    assert_eq!(DmaDevice::new(0x23, 4, 2).source_id(), 0x2322);
+    assert!(matches!(
+        DmaBuffer::new(device, 0, PAGE_SIZE),
+        Err(DmaError::Empty)
+    ));
     assert!(matches!(
-        DmaBuffer::new(PAGE_SIZE, 3),
+        DmaBuffer::new(device, PAGE_SIZE, 3),
         Err(DmaError::InvalidAlignment)
     ));
 
     let alignment = PAGE_SIZE * 2;
-    let dma =
-        DmaBuffer::new(PAGE_SIZE + 1, alignment).expect("mm: DMA self-test allocation failed");
+    let dma = DmaBuffer::new(device, PAGE_SIZE + 1, alignment)
+        .expect("mm: DMA self-test allocation failed");
     assert_eq!(dma.pages(), 2);
     assert_eq!(dma.len(), 2 * PAGE_SIZE);
     assert_eq!(dma.physical_address() as usize % alignment, 0);
@@ -247,12 +250,35 @@ pub fn test() {
             .iter()
             .all(|byte| *byte == 0)
     );
+    if !iommu::enabled() {
+        assert_eq!(dma.device_address(), dma.physical_address());
+    }
     let physical_address = dma.physical_address();
+
+    let sg = map_sg(
+        device,
+        &[
+            (physical_address + 16, PAGE_SIZE - 16),
+            (physical_address + PAGE_SIZE as u64, 32),
+        ],
+    )
+    .expect("mm: DMA self-test scatter mapping failed");
+    assert_eq!(sg.pages(), 2);
+    if !iommu::enabled() {
+        assert_eq!(
+            sg.segments(),
+            &[DmaSegment {
+                device_address: physical_address + 16,
+                len: PAGE_SIZE + 16,
+            }]
+        );
+    }
+    drop(sg);
     drop(dma);
 
-    let replacement =
-        DmaBuffer::new(PAGE_SIZE + 1, alignment).expect("mm: DMA self-test re-allocation failed");
+    let replacement = DmaBuffer::new(device, PAGE_SIZE + 1, alignment)
+        .expect("mm: DMA self-test re-allocation failed");
     assert_eq!(replacement.physical_address(), physical_address);
     drop(replacement);
-    crate::println!("[INFO] mm: DMA/MMIO self-test passed");
+    crate::println!("[INFO] mm: DMA self-test passed");
 }
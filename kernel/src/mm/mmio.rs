@@ -0,0 +1,423 @@
+//! Device memory mappings with explicit caching attributes.
+//!
+//! MMIO ranges are mapped at their HHDM address, so a device register is
+//! reachable through a single virtual alias. The PAT is reprogrammed at boot
+//! so that every `CacheMode` can be selected with the PWT/PCD bits alone:
+//!
+//! | PCD | PWT | type |
+//! |-----|-----|------|
+//! |  0  |  0  | WB   |
+//! |  0  |  1  | WC   |
+//! |  1  |  0  | WT   |
+//! |  1  |  1  | UC   |
+//!
+//! The PAT bit is left clear, which keeps 2 MiB pages usable for every mode
+//! (their PAT bit would otherwise overlap the frame address).
+//!
+//! A huge page that is already mapped, as Limine maps the HHDM, is split
+//! into 4 KiB pages before a range in it changes mode, so the memory around
+//! the range keeps its own mode.
+//!
+//! Mappings are reference counted: mapping the same range with the same mode
+//! again returns another handle to it, and the original page table state is
+//! restored once the last handle is dropped.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::mm::page_alloc::{DLOSFrameAllocator, PAGE_SIZE};
+use crate::mm::paging::{self, HUGE_PAGE_SIZE};
+use crate::mm::phys_to_virt;
+
+const IA32_PAT: u32 = 0x277;
+const PAT_WB: u64 = 0x06;
+const PAT_WT: u64 = 0x04;
+const PAT_WC: u64 = 0x01;
+const PAT_UC: u64 = 0x00;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    Uncacheable,
+}
+
+impl CacheMode {
+    /// The PWT/PCD bits selecting this mode under the kernel's PAT layout.
+    pub fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::NO_CACHE,
+            CacheMode::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MmioError {
+    Empty,
+    AddressOverflow,
+    MappingMissing,
+    /// The range overlaps an existing mapping with another mode or extent.
+    Conflict,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PageRange {
+    base: u64,
+    pages: usize,
+    offset: usize,
+}
+
+impl PageRange {
+    fn new(address: u64, len: usize) -> Result<Self, MmioError> {
+        if len == 0 {
+            return Err(MmioError::Empty);
+        }
+        let base = address & !(PAGE_SIZE as u64 - 1);
+        let offset = (address - base) as usize;
+        let span = offset.checked_add(len).ok_or(MmioError::AddressOverflow)?;
+        let pages = span
+            .checked_add(PAGE_SIZE - 1)
+            .ok_or(MmioError::AddressOverflow)?
+            / PAGE_SIZE;
+        base.checked_add((pages - 1) as u64 * PAGE_SIZE as u64)
+            .ok_or(MmioError::AddressOverflow)?;
+        Ok(Self {
+            base,
+            pages,
+            offset,
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.base + (self.pages * PAGE_SIZE) as u64
+    }
+}
+
+/// How to put a page table entry back when a mapping goes away.
+enum Undo {
+    Unmap4KiB(Page<Size4KiB>),
+    Unmap2MiB(Page<Size2MiB>),
+    Restore4KiB(Page<Size4KiB>, PageTableFlags),
+}
+
+struct Record {
+    range: PageRange,
+    mode: CacheMode,
+    refs: usize,
+    undo: Vec<Undo>,
+}
+
+static MAPPINGS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+/// A live MMIO mapping. Dropping the last handle to a range unmaps it.
+pub struct MmioRegion {
+    physical_address: u64,
+    virtual_address: NonNull<u8>,
+    len: usize,
+    page_base: u64,
+    mode: CacheMode,
+}
+
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
+
+impl MmioRegion {
+    pub fn physical_address(&self) -> u64 {
+        self.physical_address
+    }
+
+    pub fn virtual_address(&self) -> u64 {
+        self.virtual_address.as_ptr() as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virtual_address.as_ptr()
+    }
+
+    /// Keep the mapping for the rest of the kernel's lifetime.
+    pub fn leak(self) -> *mut u8 {
+        let ptr = self.as_ptr();
+        core::mem::forget(self);
+        ptr
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let mut mappings = MAPPINGS.lock();
+        let Some(index) = mappings
+            .iter()
+            .position(|record| record.range.base == self.page_base)
+        else {
+            return;
+        };
+        mappings[index].refs -= 1;
+        if mappings[index].refs == 0 {
+            let record = mappings.swap_remove(index);
+            unsafe { undo(&mut paging::active_mapper(), record.undo) };
+        }
+    }
+}
+
+/// Program the PAT with the layout described in the module documentation.
+///
+/// Runs before the console exists. On a CPU without PAT the power-on layout
+/// stays in place, under which WC degrades to WT and WT to UC-.
+pub fn init() {
+    if !CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_pat())
+    {
+        return;
+    }
+    let entries = [PAT_WB, PAT_WC, PAT_WT, PAT_UC];
+    let value = entries
+        .iter()
+        .chain(entries.iter())
+        .enumerate()
+        .fold(0u64, |value, (index, entry)| value | entry << (index * 8));
+    unsafe {
+        core::arch::asm!("wbinvd");
+        Msr::new(IA32_PAT).write(value);
+    }
+    let (frame, flags) = Cr3::read();
+    unsafe { Cr3::write(frame, flags) };
+}
+
+/// Map `phys..phys + len` with the given caching mode.
+///
+/// # Safety
+///
+/// `phys..phys + len` must identify device memory (or memory that is
+/// otherwise safe to access with `mode`). The mapping lives in the shared
+/// kernel half, so every address space sees it.
+pub unsafe fn map(phys: u64, len: usize, mode: CacheMode) -> Result<MmioRegion, MmioError> {
+    let range = PageRange::new(phys, len)?;
+    let mut mappings = MAPPINGS.lock();
+    let page_base = if let Some(record) = mappings
+        .iter_mut()
+        .find(|record| record.range.base < range.end() && range.base < record.range.end())
+    {
+        if record.mode != mode || range.base < record.range.base || range.end() > record.range.end()
+        {
+            return Err(MmioError::Conflict);
+        }
+        record.refs += 1;
+        record.range.base
+    } else {
+        let mut mapper = unsafe { paging::active_mapper() };
+        let undo_log = unsafe { apply(&mut mapper, &range, mode) }?;
+        mappings.push(Record {
+            range,
+            mode,
+            refs: 1,
+            undo: undo_log,
+        });
+        range.base
+    };
+    Ok(MmioRegion {
+        physical_address: phys,
+        virtual_address: NonNull::new(phys_to_virt(phys) as *mut u8)
+            .expect("HHDM virtual address must not be null"),
+        len,
+        page_base,
+        mode,
+    })
+}
+
+unsafe fn apply(
+    mapper: &mut OffsetPageTable,
+    range: &PageRange,
+    mode: CacheMode,
+) -> Result<Vec<Undo>, MmioError> {
+    let cache_bits = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | mode.page_flags();
+    let huge = HUGE_PAGE_SIZE as u64;
+    let mut log = Vec::new();
+    let mut changed_existing = false;
+    let mut pa = range.base;
+    while pa < range.end() {
+        let va = VirtAddr::new(phys_to_virt(pa));
+        let step = match mapper.translate(va) {
+            TranslateResult::NotMapped => {
+                let Ok(size) =
+                    (unsafe { paging::map_page(mapper, va, pa, range.end() - pa, flags) })
+                else {
+                    unsafe { undo(mapper, log) };
+                    return Err(MmioError::MappingMissing);
+                };
+                log.push(if size == huge {
+                    Undo::Unmap2MiB(Page::containing_address(va))
+                } else {
+                    Undo::Unmap4KiB(Page::containing_address(va))
+                });
+                size
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                flags: old,
+                ..
+            } => {
+                // bit 7 is the PAT bit in a 4 KiB entry
+                let new = (old - cache_bits - PageTableFlags::HUGE_PAGE) | flags;
+                let page = Page::<Size4KiB>::containing_address(va);
+                unsafe { mapper.update_flags(page, new) }
+                    .map_err(|_| MmioError::MappingMissing)?
+                    .flush();
+                log.push(Undo::Restore4KiB(page, old));
+                changed_existing = true;
+                PAGE_SIZE as u64
+            }
+            // look at the same address again once it is mapped by 4 KiB pages
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                if let Err(err) = unsafe { split_huge_page(mapper, va, false) } {
+                    unsafe { undo(mapper, log) };
+                    return Err(err);
+                }
+                0
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                if let Err(err) = unsafe { split_huge_page(mapper, va, true) } {
+                    unsafe { undo(mapper, log) };
+                    return Err(err);
+                }
+                0
+            }
+            TranslateResult::InvalidFrameAddress(_) => {
+                unsafe { undo(mapper, log) };
+                return Err(MmioError::MappingMissing);
+            }
+        };
+        pa += step;
+    }
+    if changed_existing {
+        // cached lines of the old memory type must not linger
+        unsafe { core::arch::asm!("wbinvd") };
+    }
+    Ok(log)
+}
+
+/// Replace the huge page mapping `va` by a table of pages of the next size
+/// down with the same flags: 2 MiB pages for a 1 GiB page if `gib`, 4 KiB
+/// pages for a 2 MiB page otherwise.
+unsafe fn split_huge_page(
+    mapper: &mut OffsetPageTable,
+    va: VirtAddr,
+    gib: bool,
+) -> Result<(), MmioError> {
+    let next = |entry: &mut x86_64::structures::paging::page_table::PageTableEntry| {
+        let table = phys_to_virt(entry.addr().as_u64()) as *mut PageTable;
+        unsafe { &mut *table }
+    };
+    let level_3 = next(&mut mapper.level_4_table_mut()[va.p4_index()]);
+    let entry = if gib {
+        &mut level_3[va.p3_index()]
+    } else {
+        &mut next(&mut level_3[va.p3_index()])[va.p2_index()]
+    };
+    let old = entry.flags();
+    if !old.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MmioError::MappingMissing);
+    }
+    let (size, child_flags) = if gib {
+        (Size2MiB::SIZE, old)
+    } else {
+        // bit 7 is the PAT bit in a 4 KiB entry, and the kernel's PAT bits
+        // are clear
+        (Size4KiB::SIZE, old - PageTableFlags::HUGE_PAGE)
+    };
+    let base = entry.addr().as_u64() & !(size * 512 - 1);
+    let frame: PhysFrame<Size4KiB> = DLOSFrameAllocator
+        .allocate_frame()
+        .ok_or(MmioError::MappingMissing)?;
+    let table = unsafe { &mut *(phys_to_virt(frame.start_address().as_u64()) as *mut PageTable) };
+    for (index, child) in table.iter_mut().enumerate() {
+        child.set_addr(PhysAddr::new(base + index as u64 * size), child_flags);
+    }
+    // these bits mean nothing, or something else, in a table entry
+    let table_flags = old
+        - PageTableFlags::HUGE_PAGE
+        - PageTableFlags::GLOBAL
+        - PageTableFlags::DIRTY
+        - PageTableFlags::WRITE_THROUGH
+        - PageTableFlags::NO_CACHE;
+    entry.set_addr(frame.start_address(), table_flags);
+    x86_64::instructions::tlb::flush_all();
+    Ok(())
+}
+
+unsafe fn undo(mapper: &mut OffsetPageTable, log: Vec<Undo>) {
+    for step in log.into_iter().rev() {
+        unsafe {
+            match step {
+                Undo::Unmap4KiB(page) => {
+                    if let Ok((_, flush)) = mapper.unmap(page) {
+                        flush.flush();
+                    }
+                }
+                Undo::Unmap2MiB(page) => {
+                    if let Ok((_, flush)) = mapper.unmap(page) {
+                        flush.flush();
+                    }
+                }
+                Undo::Restore4KiB(page, flags) => {
+                    if let Ok(flush) = mapper.update_flags(page, flags) {
+                        flush.flush();
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn test() {
+    assert_eq!(
+        PageRange::new(0x1234, PAGE_SIZE).unwrap(),
+        PageRange {
+            base: 0x1000,
+            pages: 2,
+            offset: 0x234,
+        }
+    );
+    assert_eq!(PageRange::new(0, 0), Err(MmioError::Empty));
+    assert_eq!(
+        PageRange::new(u64::MAX - 1, PAGE_SIZE),
+        Err(MmioError::AddressOverflow)
+    );
+    assert_eq!(CacheMode::WriteBack.page_flags(), PageTableFlags::empty());
+    assert!(
+        CacheMode::Uncacheable
+            .page_flags()
+            .contains(PageTableFlags::NO_CACHE)
+    );
+    crate::println!("[INFO] mm: MMIO self-test passed");
+}
@@ -1,5 +1,6 @@
 pub mod bitmap;
 pub mod dma;
+pub mod mmio;
 pub mod page_alloc;
 pub mod paging;
 
@@ -22,6 +23,7 @@ pub fn init() {
         *OFFSET.lock() = res.offset;
     }
     self::page_alloc::init();
+    self::mmio::init();
     let heap_start_address = phys_to_virt(
         self::page_alloc::find_continuous_mem(2048)
             .expect("mm: unable to reserve initial kernel heap"),
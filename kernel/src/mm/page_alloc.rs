@@ -1,5 +1,6 @@
 use super::phys_to_virt;
 use crate::mm::bitmap::PageMan;
+use crate::mm::paging::{HUGE_PAGE_SIZE, PAGES_PER_HUGE_PAGE, map_user_huge};
 use crate::println;
 use limine::request::MemmapRequest;
 use spin::{Lazy, Mutex};
@@ -9,9 +10,13 @@ use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::paging::FrameAllocator;
 use x86_64::structures::paging::FrameDeallocator;
 use x86_64::structures::paging::Mapper;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::structures::paging::PageSize;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::paging::Size2MiB;
 use x86_64::structures::paging::Size4KiB;
+use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult};
 use x86_64::structures::paging::page::Page;
 
 pub const PAGE_SIZE: usize = 4096;
@@ -219,6 +224,15 @@ pub fn do_user_page_fault(ip: x86_64::VirtAddr, code: PageFaultErrorCode) {
     let task = tasks[current].as_mut().unwrap();
     let pgt = &mut task.page_table;
     if within_stack_range(addr) || addr.as_u64() < task.brk {
+        // a heap that covers the whole 2 MiB block around the fault gets a
+        // huge page, anything else is demand-paged 4 KiB at a time
+        let huge_page = Page::<Size2MiB>::containing_address(addr);
+        if !within_stack_range(addr)
+            && huge_page.start_address().as_u64() + Size2MiB::SIZE <= task.brk
+            && map_user_huge(pgt, huge_page)
+        {
+            return;
+        }
         let new_page_pa = alloc_physical_page().unwrap();
         page_incref(new_page_pa);
         unsafe {
@@ -262,15 +276,25 @@ fn is_write_protection_fault(code: PageFaultErrorCode) -> bool {
 /// present user page.
 fn resolve_current_user_cow_fault() {
     let addr = Cr2::read().unwrap();
-    let page = Page::<Size4KiB>::containing_address(addr);
     let current = crate::task::sched::CURRENT_TASK_ID.load(core::sync::atomic::Ordering::Relaxed);
     let mut tasks = crate::task::process::TASKS.lock();
     let task = tasks[current].as_mut().unwrap();
     let pgt = &mut task.page_table;
-    let old_page_pa = pgt.translate_page(page).unwrap().start_address().as_u64();
     let writable_user_flags =
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
+    if let TranslateResult::Mapped {
+        frame: MappedFrame::Size2MiB(frame),
+        ..
+    } = pgt.translate(addr)
+    {
+        resolve_huge_cow_fault(pgt, Page::containing_address(addr), frame);
+        return;
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let old_page_pa = pgt.translate_page(page).unwrap().start_address().as_u64();
+
     if page_getref(old_page_pa) > 1 {
         let new_page_pa = alloc_physical_page().unwrap();
         unsafe {
@@ -298,6 +322,45 @@ fn resolve_current_user_cow_fault() {
     }
 }
 
+/// Same as the 4 KiB path, but the copy is a whole 2 MiB page so the huge
+/// mapping survives the fault.
+fn resolve_huge_cow_fault(
+    pgt: &mut OffsetPageTable,
+    page: Page<Size2MiB>,
+    old_frame: PhysFrame<Size2MiB>,
+) {
+    let writable_user_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let old_pa = old_frame.start_address().as_u64();
+
+    if page_getref(old_pa) > 1 {
+        let new_pa = find_aligned_continuous_mem(PAGES_PER_HUGE_PAGE, HUGE_PAGE_SIZE)
+            .expect("mm: unable to allocate huge page for CoW");
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(old_pa) as *const u8,
+                phys_to_virt(new_pa) as *mut u8,
+                HUGE_PAGE_SIZE,
+            );
+            page_decref(old_pa);
+            pgt.unmap(page).unwrap().1.flush();
+            pgt.map_to(
+                page,
+                PhysFrame::from_start_address(PhysAddr::new(new_pa)).unwrap(),
+                writable_user_flags,
+                &mut DLOSFrameAllocator,
+            )
+            .unwrap()
+            .flush();
+            page_incref(new_pa);
+        }
+    } else {
+        unsafe {
+            pgt.update_flags(page, writable_user_flags).unwrap().flush();
+        }
+    }
+}
+
 fn within_stack_range(addr: x86_64::VirtAddr) -> bool {
     const STACK_BEGIN: u64 = 1 << 47;
     const STACK_END: u64 = STACK_BEGIN - (1 << 23);
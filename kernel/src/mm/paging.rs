@@ -1 +1,182 @@
+//! Page table helpers shared by the kernel mappings and process address
+//! spaces.
+//!
+//! The upper half of the address space belongs to the kernel and the lower
+//! half to the process. Every upper-half PML4 slot of the bootloader's page
+//! table points at a page directory pointer table that all address spaces
+//! reference, so a kernel mapping made through any of them is visible in all
+//! of them. Only the lower half is private to a process.
+//!
+//! Mappings use 2 MiB pages wherever the virtual and physical addresses are
+//! both 2 MiB aligned and a whole huge page fits, and fall back to 4 KiB
+//! pages for the unaligned head and tail. `map_page` makes that choice for a
+//! single page; `mm::mmio` maps device memory through it.
 
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB,
+    Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::mm::page_alloc::{
+    DLOSFrameAllocator, PAGE_SIZE, alloc_physical_page, dealloc_continuous_mem,
+    find_aligned_continuous_mem, page_incref,
+};
+use crate::mm::phys_to_virt;
+
+pub const HUGE_PAGE_SIZE: usize = Size2MiB::SIZE as usize;
+pub const PAGES_PER_HUGE_PAGE: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+/// First PML4 slot of the kernel half.
+pub const KERNEL_PML4_START: usize = 256;
+
+/// Turn the kernel half of the active (bootloader) page table into the
+/// template shared by every address space.
+///
+/// Empty kernel slots get a zeroed PDPT so that later mappings never need a
+/// new PML4 entry. Task 0 briefly runs kernel code in Ring 3 before it
+/// switches itself back to Ring 0, so the existing kernel mappings are made
+/// user-accessible, as the per-process copies used to be.
+///
+/// Must run before the first process page table is created.
+pub fn init_kernel_half() {
+    let mut mapper = unsafe { active_mapper() };
+    for entry in mapper
+        .level_4_table_mut()
+        .iter_mut()
+        .skip(KERNEL_PML4_START)
+    {
+        if entry.is_unused() {
+            let pdpt_pa = alloc_physical_page().expect("mm: unable to allocate kernel PDPT");
+            unsafe { core::ptr::write_bytes(phys_to_virt(pdpt_pa) as *mut u8, 0, PAGE_SIZE) };
+            entry.set_addr(
+                PhysAddr::new(pdpt_pa),
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE,
+            );
+        } else {
+            set_user_accessible(entry, 4);
+        }
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+fn set_user_accessible(entry: &mut PageTableEntry, level: u8) {
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+    if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    let table = unsafe { &mut *(phys_to_virt(entry.addr().as_u64()) as *mut PageTable) };
+    for entry in table.iter_mut() {
+        set_user_accessible(entry, level - 1);
+    }
+}
+
+/// Point the kernel half of `p4t` at the shared kernel tables of `template`.
+pub fn share_kernel_half(template: &PageTable, p4t: &mut PageTable) {
+    for index in KERNEL_PML4_START..512 {
+        p4t[index] = template[index].clone();
+    }
+}
+
+/// Return a mapper over the page table that is currently loaded in CR3.
+///
+/// # Safety
+///
+/// The caller must not keep the mapper across a page table switch.
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    unsafe {
+        OffsetPageTable::new(
+            &mut *(phys_to_virt(Cr3::read().0.start_address().as_u64()) as *mut PageTable),
+            VirtAddr::new(phys_to_virt(0)),
+        )
+    }
+}
+
+/// Map the unmapped address `va` to `pa` with `flags` and return the size of
+/// the page used: 2 MiB if both addresses are 2 MiB aligned, `len` covers a
+/// whole huge page and nothing is mapped inside it yet, 4 KiB otherwise.
+///
+/// # Safety
+///
+/// The caller must ensure the new mapping does not alias memory in a way that
+/// breaks Rust's aliasing rules, e.g. by mapping allocator-owned RAM.
+pub unsafe fn map_page(
+    mapper: &mut OffsetPageTable,
+    va: VirtAddr,
+    pa: u64,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<u64, ()> {
+    let huge = HUGE_PAGE_SIZE as u64;
+    if pa.is_multiple_of(huge) && va.is_aligned(huge) && len >= huge {
+        let page = Page::<Size2MiB>::containing_address(va);
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(pa));
+        if let Ok(flush) = unsafe { mapper.map_to(page, frame, flags, &mut DLOSFrameAllocator) } {
+            flush.flush();
+            return Ok(huge);
+        }
+    }
+    let page = Page::<Size4KiB>::containing_address(va);
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(pa));
+    unsafe { mapper.map_to(page, frame, flags, &mut DLOSFrameAllocator) }
+        .map_err(|_| ())?
+        .flush();
+    Ok(PAGE_SIZE as u64)
+}
+
+/// Back `page` with a fresh, zeroed 2 MiB user page.
+///
+/// Returns `false` without touching the page table when no aligned physical
+/// block is free or something is already mapped inside `page`; the caller
+/// should fall back to 4 KiB pages then. The head frame carries the
+/// reference count of the whole huge page.
+pub fn map_user_huge(pgt: &mut OffsetPageTable, page: Page<Size2MiB>) -> bool {
+    if !huge_slot_free(pgt, page) {
+        return false;
+    }
+    let Some(pa) = find_aligned_continuous_mem(PAGES_PER_HUGE_PAGE, HUGE_PAGE_SIZE) else {
+        return false;
+    };
+    unsafe { core::ptr::write_bytes(phys_to_virt(pa) as *mut u8, 0, HUGE_PAGE_SIZE) };
+    let result = unsafe {
+        pgt.map_to(
+            page,
+            PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(pa)),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+            &mut DLOSFrameAllocator,
+        )
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            page_incref(pa);
+            true
+        }
+        Err(_) => {
+            dealloc_continuous_mem(pa, PAGES_PER_HUGE_PAGE);
+            false
+        }
+    }
+}
+
+/// Check whether the page directory entry for `page` is still unused.
+fn huge_slot_free(pgt: &OffsetPageTable, page: Page<Size2MiB>) -> bool {
+    let mut table = pgt.level_4_table();
+    for index in [page.p4_index(), page.p3_index()] {
+        let entry = &table[index];
+        if entry.is_unused() {
+            return true;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        }
+        table = unsafe { &*(phys_to_virt(entry.addr().as_u64()) as *const PageTable) };
+    }
+    table[page.p2_index()].is_unused()
+}
@@ -2,7 +2,10 @@ use alloc::boxed::Box;
 use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
 
 use crate::{
-    mm::{dma::DmaBuffer, page_alloc::PAGE_SIZE},
+    mm::{
+        dma::{DmaBuffer, DmaDevice},
+        page_alloc::PAGE_SIZE,
+    },
     net::Nic,
     pcie::enumrate::PCIConfigSpace,
     println,
@@ -16,17 +19,17 @@ struct Rtl8139 {
 }
 
 impl Rtl8139 {
-    pub fn new(config: &PCIConfigSpace) -> Self {
+    pub fn new(config: &PCIConfigSpace, dma: DmaDevice) -> Self {
         // enable Bus Mastering
         let command = config.read_u16(4);
         unsafe { config.write_u16(4, command | (1 << 2)) }
 
         // BAR0 is I/O Space BAR
         let io_base = (config.bar[0] & !0b11) as u16;
-        Self::from_io_base(io_base)
+        Self::from_io_base(io_base, dma)
     }
 
-    fn from_io_base(io_base: u16) -> Self {
+    fn from_io_base(io_base: u16, dma: DmaDevice) -> Self {
         // read MAC address
         let mut mac = [0u8; 6];
         for i in 0..6 {
@@ -40,9 +43,9 @@ impl Rtl8139 {
         while unsafe { PortReadOnly::<u8>::new(io_base + 0x37).read() } & 0x10 != 0 {}
 
         // init receive buffer
-        let rx_buffer = DmaBuffer::new(8208, PAGE_SIZE).unwrap();
+        let rx_buffer = DmaBuffer::new(dma, 8208, PAGE_SIZE).unwrap();
         let phys_addr: u32 = rx_buffer
-            .physical_address()
+            .device_address()
             .try_into()
             .expect("DMA buffer address cannot fit in 32-bit");
         unsafe {
@@ -118,7 +121,7 @@ pub(super) fn init() {
     crate::pcie::enumrate::doit(|bus, device, function, config| {
         if config.vendor_id == 0x10ec && config.device_id == 0x8139 {
             println!("[INFO] rtl8139: found at {bus:02x}:{device:02x}.{function}");
-            let nic = Rtl8139::new(config);
+            let nic = Rtl8139::new(config, DmaDevice::new(bus, device, function));
             println!("[INFO] rtl8139: physical address is {}", nic.format_mac());
             super::NICS.lock().push(Box::new(nic));
         }
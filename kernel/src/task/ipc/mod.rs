@@ -1,17 +1,30 @@
-use crate::task::process::{ProcessContext, TASKS};
-use crate::task::sched::CURRENT_TASK_ID;
+pub mod registry;
+
+use crate::task::process::{Process, ProcessContext, TASKS, WaitReason};
+use crate::task::sched::{self, CURRENT_TASK_ID, TOTAL_TICKS};
 use alloc::borrow::ToOwned;
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeSet, VecDeque};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::min;
-use core::sync::atomic::Ordering;
-use spin::{Lazy, Mutex};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
 
 pub const IPC_MAX_HANDLES: usize = 64;
 pub const IPC_MAX_MSG_SIZE: usize = 4096;
-pub const IPC_QUEUE_DEPTH: usize = 4096;
+/// Bytes charged for every queued message on top of its payload, so that
+/// empty messages cannot be queued without bound.
+pub const IPC_MSG_OVERHEAD: usize = 64;
+/// Queue budget of an endpoint when the creator asks for none.
+pub const IPC_DEFAULT_BUDGET: usize = 64 * 1024;
+/// Smallest endpoint budget; every message fits into an empty queue.
+pub const IPC_MIN_BUDGET: usize = IPC_MAX_MSG_SIZE + IPC_MSG_OVERHEAD;
+pub const IPC_MAX_BUDGET: usize = 1024 * 1024;
+/// Bytes a process may have queued on all channels together.
+pub const IPC_PROCESS_BUDGET: usize = 4 * 1024 * 1024;
+/// Maximum number of handles attached to a single message.
+pub const IPC_MAX_MSG_HANDLES: usize = 8;
 
 pub const IPC_CMD_CREATE: usize = 0;
 pub const IPC_CMD_SEND: usize = 1;
@@ -21,6 +34,27 @@ pub const IPC_CMD_DUP: usize = 4;
 pub const IPC_CMD_BIND: usize = 5;
 pub const IPC_CMD_CONNECT: usize = 6;
 pub const IPC_CMD_ACCEPT: usize = 7;
+pub const IPC_CMD_SEND_HANDLES: usize = 8;
+pub const IPC_CMD_RECV_HANDLES: usize = 9;
+pub const IPC_CMD_RECV_WAIT: usize = 10;
+pub const IPC_CMD_ACCEPT_WAIT: usize = 11;
+pub const IPC_CMD_CALL: usize = 12;
+pub const IPC_CMD_RESTRICT: usize = 13;
+pub const IPC_CMD_SEND_WAIT: usize = 14;
+
+/// Channel flag: a send queues as much as fits and a receive may take part
+/// of a message, leaving the rest queued. Message boundaries are not kept.
+pub const IPC_CHANNEL_STREAM: usize = 1 << 0;
+
+/// Timeout argument of the blocking commands that never gives up.
+pub const IPC_WAIT_FOREVER: usize = usize::MAX;
+
+/// `IpcHandleDesc::kind` of an IPC channel end or listener.
+pub const IPC_HANDLE_IPC: u32 = 0;
+/// `IpcHandleDesc::kind` of a VFS file descriptor.
+pub const IPC_HANDLE_FILE: u32 = 1;
+/// Move the handle out of the sender's table instead of duplicating it.
+pub const IPC_HANDLE_MOVE: u32 = 1 << 0;
 
 const IPC_OK: isize = 0;
 const IPC_EINVAL: isize = -22;
@@ -30,11 +64,31 @@ const IPC_EMFILE: isize = -24;
 const IPC_EPIPE: isize = -32;
 const IPC_ENOENT: isize = -2;
 const IPC_EEXIST: isize = -17;
+const IPC_EACCES: isize = -13;
 const IPC_EMSGSIZE: isize = -90;
+const IPC_ETIMEDOUT: isize = -110;
+/// Returned by a command that blocked the caller. The syscall frame then
+/// belongs to whichever task runs next and must be left alone.
+const IPC_BLOCKED: isize = isize::MIN;
 const IPC_MAX_NAME_LEN: usize = 128;
 
+/// Number given to the request of the next `IPC_CMD_CALL`.
+static NEXT_CALL: AtomicU64 = AtomicU64::new(1);
+
 pub type IpcHandle = Arc<Mutex<IpcHandleState>>;
 
+/// A handle attached to a message, as seen by user space.
+///
+/// On send `index` names a slot in the sender's table selected by `kind`; on
+/// receive the kernel fills in the slot the handle was installed at.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct IpcHandleDesc {
+    pub kind: u32,
+    pub flags: u32,
+    pub index: u64,
+}
+
 pub struct IpcHandleState {
     object: IpcHandleObject,
 }
@@ -47,10 +101,6 @@ enum IpcHandleObject {
     Listener(Arc<Mutex<IpcListener>>),
 }
 
-type NamedEndpoint = (String, Arc<Mutex<IpcListener>>);
-
-static NAMED_ENDPOINTS: Lazy<Mutex<Vec<NamedEndpoint>>> = Lazy::new(|| Mutex::new(Vec::new()));
-
 struct IpcListener {
     refs: usize,
     pending: VecDeque<IpcHandle>,
@@ -59,31 +109,169 @@ struct IpcListener {
 struct IpcChannel {
     refs: [usize; 2],
     endpoints: [IpcEndpoint; 2],
+    stream: bool,
 }
 
 struct IpcEndpoint {
     queue: VecDeque<IpcMessage>,
     closed: bool,
+    /// Bytes the queue may hold, overhead included.
+    budget: usize,
+    /// Bytes charged for the messages in the queue.
+    queued: usize,
+    /// Calls received from this queue and not answered yet, oldest first.
+    answering: VecDeque<u64>,
 }
 
 struct IpcMessage {
     data: Vec<u8>,
+    handles: Vec<IpcTransfer>,
+    charge: IpcCharge,
+    /// The number of the call this message is the request of.
+    call: Option<u64>,
+    /// The number of the call this message answers.
+    reply_to: Option<u64>,
+}
+
+/// The bytes a process has queued on all channels. Messages keep the account
+/// of their sender alive, so the charge outlives the sender if need be.
+pub struct IpcAccount {
+    queued: AtomicUsize,
+    /// A sender is blocked until some of the account is paid back.
+    starved: AtomicBool,
+}
+
+/// The part of a sender's account paid for one queued message.
+struct IpcCharge {
+    account: Arc<IpcAccount>,
+    bytes: usize,
+}
+
+/// A handle in flight. It owns one reference, which is either installed in
+/// the receiver's tables or released when the message is dropped unread.
+enum IpcTransfer {
+    Ipc(IpcHandle),
+    File(Arc<crate::vfs::OpenFile>),
+}
+
+impl IpcMessage {
+    fn release(self) {
+        release_transfers(self.handles);
+    }
+}
+
+fn release_transfers(handles: Vec<IpcTransfer>) {
+    for handle in handles {
+        if let IpcTransfer::Ipc(handle) = handle {
+            close_handle_ref(handle);
+        }
+    }
+}
+
+impl IpcAccount {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            queued: AtomicUsize::new(0),
+            starved: AtomicBool::new(false),
+        })
+    }
+
+    /// Bytes currently queued by the owner.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn key(self: &Arc<Self>) -> usize {
+        Arc::as_ptr(self) as usize
+    }
+
+    /// Charge `bytes` unless that would exceed the process budget.
+    fn charge(self: &Arc<Self>, bytes: usize) -> Option<IpcCharge> {
+        self.queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued + bytes <= IPC_PROCESS_BUDGET).then_some(queued + bytes)
+            })
+            .ok()?;
+        Some(IpcCharge {
+            account: self.clone(),
+            bytes,
+        })
+    }
+}
+
+impl IpcCharge {
+    /// Move all but `bytes` of the charge into a new one.
+    fn split_off(&mut self, bytes: usize) -> IpcCharge {
+        let rest = self.bytes - bytes;
+        self.bytes = bytes;
+        IpcCharge {
+            account: self.account.clone(),
+            bytes: rest,
+        }
+    }
+}
+
+/// Paying back wakes the senders blocked on the account, so a charge must
+/// not be dropped with a channel or the task list locked.
+impl Drop for IpcCharge {
+    fn drop(&mut self) {
+        self.account.queued.fetch_sub(self.bytes, Ordering::Relaxed);
+        if self.account.starved.swap(false, Ordering::Relaxed) {
+            wake_senders(self.account.key());
+        }
+    }
 }
 
 impl IpcEndpoint {
-    fn new() -> Self {
+    fn new(budget: usize) -> Self {
         Self {
             queue: VecDeque::new(),
             closed: false,
+            budget,
+            queued: 0,
+            answering: VecDeque::new(),
+        }
+    }
+
+    fn push_back(&mut self, message: IpcMessage) {
+        self.queued += message.charge.bytes;
+        self.queue.push_back(message);
+    }
+
+    fn push_front(&mut self, message: IpcMessage) {
+        self.queued += message.charge.bytes;
+        self.queue.push_front(message);
+    }
+
+    /// Take the next message. In a stream channel at most `len` bytes are
+    /// taken and the rest of the message stays at the front of the queue.
+    fn pop_front(&mut self, len: usize, stream: bool) -> Option<IpcMessage> {
+        let mut message = self.queue.pop_front()?;
+        if stream && message.data.len() > len {
+            let rest = IpcMessage {
+                data: message.data.split_off(len),
+                handles: Vec::new(),
+                charge: message.charge.split_off(len),
+                call: None,
+                reply_to: None,
+            };
+            self.queue.push_front(rest);
         }
+        self.queued -= message.charge.bytes;
+        Some(message)
     }
 }
 
 impl IpcChannel {
-    fn new() -> Self {
+    fn new(budget: usize, flags: usize) -> Self {
+        let budget = match budget {
+            0 => IPC_DEFAULT_BUDGET,
+            budget => budget.clamp(IPC_MIN_BUDGET, IPC_MAX_BUDGET),
+        };
         Self {
             refs: [0, 0],
-            endpoints: [IpcEndpoint::new(), IpcEndpoint::new()],
+            endpoints: [IpcEndpoint::new(budget), IpcEndpoint::new(budget)],
+            stream: flags & IPC_CHANNEL_STREAM != 0,
         }
     }
 }
@@ -112,13 +300,218 @@ pub fn syscall(args: &mut ProcessContext) {
         IPC_CMD_BIND => sys_bind(args),
         IPC_CMD_CONNECT => sys_connect(args),
         IPC_CMD_ACCEPT => sys_accept(args),
+        IPC_CMD_SEND_HANDLES => sys_send_handles(args),
+        IPC_CMD_RECV_HANDLES => sys_recv_handles(args),
+        IPC_CMD_RECV_WAIT => sys_recv_wait(args),
+        IPC_CMD_ACCEPT_WAIT => sys_accept_wait(args),
+        IPC_CMD_CALL => sys_call(args),
+        IPC_CMD_RESTRICT => sys_restrict(args),
+        IPC_CMD_SEND_WAIT => sys_send_wait(args),
         _ => IPC_EINVAL,
     };
-    args.rax = ret as u64;
+    if ret != IPC_BLOCKED {
+        args.rax = ret as u64;
+    }
+}
+
+/// Block the caller until it is woken, then retry the command.
+fn block_and_restart(args: &mut ProcessContext, reason: WaitReason) -> isize {
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    if let Some(task) = TASKS.lock()[current].as_mut() {
+        task.ipc_deadline = reason.deadline();
+    }
+    sched::block_and_restart(args, reason);
+    IPC_BLOCKED
+}
+
+/// Finish the blocking command of `task`, whose deadline has passed. Its
+/// saved context was rewound by `block_and_restart`.
+pub(crate) fn complete_timed_out(task: &mut Process) {
+    task.ipc_deadline = None;
+    task.ipc_call = None;
+    task.context.rip += sched::SYSCALL_INSN_LEN;
+    task.context.rax = IPC_ETIMEDOUT as u64;
+}
+
+/// The deadline of a wait for up to `timeout` ticks. A restarted wait keeps
+/// the one it blocked with, so this must be called on every entry of a
+/// command that can block, before it can return.
+fn deadline(timeout: usize) -> Option<usize> {
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let restarted = TASKS.lock()[current]
+        .as_mut()
+        .and_then(|task| task.ipc_deadline.take());
+    restarted.or_else(|| {
+        (timeout != IPC_WAIT_FOREVER)
+            .then(|| TOTAL_TICKS.load(Ordering::Relaxed).saturating_add(timeout))
+    })
+}
+
+fn endpoint_key(channel: &Arc<Mutex<IpcChannel>>, side: usize) -> usize {
+    Arc::as_ptr(channel) as usize + side
+}
+
+fn listener_key(listener: &Arc<Mutex<IpcListener>>) -> usize {
+    Arc::as_ptr(listener) as usize
+}
+
+/// Wake the tasks waiting for a message on `key`.
+fn wake_receivers(key: usize) {
+    sched::wake(|reason| {
+        matches!(
+            *reason,
+            WaitReason::IpcRecv { key: waiting, .. } | WaitReason::IpcCall { key: waiting, .. }
+                if waiting == key
+        )
+    });
+}
+
+/// Wake the tasks waiting for queue space on the endpoint or account `key`.
+fn wake_senders(key: usize) {
+    sched::wake(
+        |reason| matches!(*reason, WaitReason::IpcSend { key: waiting, .. } if waiting == key),
+    );
+}
+
+fn current_account() -> Arc<IpcAccount> {
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let tasks = TASKS.lock();
+    tasks[current].as_ref().unwrap().ipc_account.clone()
+}
+
+/// Why a message could not be queued.
+struct SendError {
+    err: isize,
+    /// What to wait for before retrying, if the queue or account was full.
+    wait_key: Option<usize>,
+    handles: Vec<IpcTransfer>,
+}
+
+/// Queue `data` and `handles` for the peer of `side`, charged to the current
+/// task, and return the number of bytes queued. `call` numbers a request.
+///
+/// The whole message has to fit into both the peer's queue budget and the
+/// sender's process budget, except in a stream channel, where a prefix of
+/// `data` is queued if that is all that fits. A queued message answers the
+/// oldest call `side` received and has not answered yet.
+fn enqueue(
+    channel: &Arc<Mutex<IpcChannel>>,
+    side: usize,
+    data: &[u8],
+    handles: Vec<IpcTransfer>,
+    call: Option<u64>,
+) -> Result<usize, SendError> {
+    let account = current_account();
+    let dest = side ^ 1;
+    let mut locked = channel.lock();
+    if locked.endpoints[side].closed || locked.endpoints[dest].closed {
+        return Err(SendError {
+            err: IPC_EPIPE,
+            wait_key: None,
+            handles,
+        });
+    }
+    let stream = locked.stream;
+    let [first, second] = &mut locked.endpoints;
+    let (own, endpoint) = if side == 0 {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let queue_room = endpoint.budget.saturating_sub(endpoint.queued);
+    let account_room = IPC_PROCESS_BUDGET.saturating_sub(account.queued());
+    let room = min(queue_room, account_room);
+    let len = if stream {
+        min(data.len(), room.saturating_sub(IPC_MSG_OVERHEAD))
+    } else {
+        data.len()
+    };
+    let fits = len + IPC_MSG_OVERHEAD <= room && (len != 0 || data.is_empty());
+    let charge = fits
+        .then(|| account.charge(len + IPC_MSG_OVERHEAD))
+        .flatten();
+    let Some(charge) = charge else {
+        let wait_key = if queue_room < account_room {
+            endpoint_key(channel, dest)
+        } else {
+            account.starved.store(true, Ordering::Relaxed);
+            account.key()
+        };
+        return Err(SendError {
+            err: IPC_EAGAIN,
+            wait_key: Some(wait_key),
+            handles,
+        });
+    };
+    endpoint.push_back(IpcMessage {
+        data: data[..len].to_vec(),
+        handles,
+        charge,
+        call,
+        reply_to: own.answering.pop_front(),
+    });
+    drop(locked);
+    wake_receivers(endpoint_key(channel, dest));
+    Ok(len)
+}
+
+/// Take the next message for `side`. Senders blocked on the freed queue
+/// space are woken. A request is answered by the next message `side` sends.
+fn dequeue(
+    channel: &Arc<Mutex<IpcChannel>>,
+    side: usize,
+    len: usize,
+    max_handles: usize,
+) -> Result<IpcMessage, isize> {
+    let mut locked = channel.lock();
+    let peer_closed = locked.endpoints[side ^ 1].closed;
+    let stream = locked.stream;
+    let endpoint = &mut locked.endpoints[side];
+    let message = match endpoint.queue.front() {
+        Some(message) if message.handles.len() > max_handles => return Err(IPC_EMSGSIZE),
+        Some(_) => endpoint.pop_front(len, stream).unwrap(),
+        None if peer_closed || endpoint.closed => return Err(IPC_OK),
+        None => return Err(IPC_EAGAIN),
+    };
+    endpoint.answering.extend(message.call);
+    drop(locked);
+    wake_senders(endpoint_key(channel, side));
+    Ok(message)
+}
+
+/// Take the reply to `call` for `side`, leaving the messages before it
+/// queued. Senders blocked on the freed queue space are woken.
+fn take_reply(
+    channel: &Arc<Mutex<IpcChannel>>,
+    side: usize,
+    call: u64,
+) -> Result<IpcMessage, isize> {
+    let mut locked = channel.lock();
+    let peer_closed = locked.endpoints[side ^ 1].closed;
+    let endpoint = &mut locked.endpoints[side];
+    let position = endpoint
+        .queue
+        .iter()
+        .position(|message| message.reply_to == Some(call));
+    let Some(position) = position else {
+        return Err(if peer_closed || endpoint.closed {
+            IPC_EPIPE
+        } else {
+            IPC_EAGAIN
+        });
+    };
+    let message = endpoint.queue.remove(position).unwrap();
+    endpoint.queued -= message.charge.bytes;
+    drop(locked);
+    wake_senders(endpoint_key(channel, side));
+    Ok(message)
 }
 
 fn sys_create(args: &mut ProcessContext) -> isize {
-    let channel = Arc::new(Mutex::new(IpcChannel::new()));
+    let channel = Arc::new(Mutex::new(IpcChannel::new(
+        args.rsi as usize,
+        args.rdx as usize,
+    )));
     let handle0 = new_channel_handle(channel.clone(), 0);
     let handle1 = new_channel_handle(channel, 1);
     {
@@ -151,17 +544,68 @@ fn sys_send(args: &mut ProcessContext) -> isize {
     let Some((channel, side)) = current_handle(handle_id) else {
         return IPC_EBADF;
     };
-    let dest = side ^ 1;
-    let mut locked = channel.lock();
-    if locked.endpoints[side].closed || locked.endpoints[dest].closed {
-        return IPC_EPIPE;
+    match enqueue(&channel, side, buf, Vec::new(), None) {
+        Ok(sent) => sent as isize,
+        Err(error) => error.err,
     }
-    let endpoint = &mut locked.endpoints[dest];
-    if endpoint.queue.len() >= IPC_QUEUE_DEPTH {
-        return IPC_EAGAIN;
+}
+
+/// `sys_send` that blocks for up to `r8` ticks while the peer's queue or the
+/// caller's process budget is full.
+fn sys_send_wait(args: &mut ProcessContext) -> isize {
+    let handle_id = args.rsi as usize;
+    let ptr = args.rdx as *const u8;
+    let len = args.rcx as usize;
+    let timeout = args.r8 as usize;
+    let deadline = deadline(timeout);
+    if len > IPC_MAX_MSG_SIZE {
+        return IPC_EMSGSIZE;
+    }
+    let buf = unsafe { core::slice::from_raw_parts(ptr, len) };
+    let Some((channel, side)) = current_handle(handle_id) else {
+        return IPC_EBADF;
+    };
+    match enqueue(&channel, side, buf, Vec::new(), None) {
+        Ok(sent) => sent as isize,
+        Err(SendError {
+            wait_key: Some(key),
+            ..
+        }) if timeout != 0 => {
+            let reason = WaitReason::IpcSend { key, deadline };
+            block_and_restart(args, reason)
+        }
+        Err(error) => error.err,
+    }
+}
+
+fn sys_send_handles(args: &mut ProcessContext) -> isize {
+    let handle_id = args.rsi as usize;
+    let ptr = args.rdx as *const u8;
+    let len = args.rcx as usize;
+    let descs_ptr = args.r8 as *const IpcHandleDesc;
+    let descs_len = args.r9 as usize;
+    if len > IPC_MAX_MSG_SIZE || descs_len > IPC_MAX_MSG_HANDLES {
+        return IPC_EMSGSIZE;
+    }
+    if descs_len != 0 && descs_ptr.is_null() {
+        return IPC_EINVAL;
+    }
+    let buf = unsafe { core::slice::from_raw_parts(ptr, len) };
+    let descs = unsafe { core::slice::from_raw_parts(descs_ptr, descs_len) };
+    let Some((channel, side)) = current_handle(handle_id) else {
+        return IPC_EBADF;
+    };
+    let handles = match take_current_handles(&channel, descs) {
+        Ok(handles) => handles,
+        Err(err) => return err,
+    };
+    match enqueue(&channel, side, buf, handles, None) {
+        Ok(sent) => sent as isize,
+        Err(error) => {
+            restore_current_handles(descs, error.handles);
+            error.err
+        }
     }
-    endpoint.queue.push_back(IpcMessage { data: buf.to_vec() });
-    len as isize
 }
 
 fn sys_recv(args: &mut ProcessContext) -> isize {
@@ -171,19 +615,189 @@ fn sys_recv(args: &mut ProcessContext) -> isize {
     let Some((channel, side)) = current_handle(handle_id) else {
         return IPC_EBADF;
     };
-    let mut locked = channel.lock();
-    let peer_closed = locked.endpoints[side ^ 1].closed;
-    let endpoint = &mut locked.endpoints[side];
-    if let Some(message) = endpoint.queue.pop_front() {
-        let copy_len = min(len, message.data.len());
-        unsafe {
-            core::ptr::copy_nonoverlapping(message.data.as_ptr(), ptr, copy_len);
-        }
-        copy_len as isize
-    } else if peer_closed || endpoint.closed {
-        IPC_OK
-    } else {
-        IPC_EAGAIN
+    match dequeue(&channel, side, len, usize::MAX) {
+        Ok(message) => {
+            let copy_len = min(len, message.data.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(message.data.as_ptr(), ptr, copy_len);
+            }
+            // a plain receive has nowhere to put attached handles
+            message.release();
+            copy_len as isize
+        }
+        Err(err) => err,
+    }
+}
+
+fn sys_recv_handles(args: &mut ProcessContext) -> isize {
+    let handle_id = args.rsi as usize;
+    let ptr = args.rdx as *mut u8;
+    let len = args.rcx as usize;
+    let descs_ptr = args.r8 as *mut IpcHandleDesc;
+    let descs_len = args.r9 as usize;
+    if descs_len != 0 && descs_ptr.is_null() {
+        return IPC_EINVAL;
+    }
+    let Some((channel, side)) = current_handle(handle_id) else {
+        return IPC_EBADF;
+    };
+    let message = match dequeue(&channel, side, len, descs_len) {
+        Ok(message) => message,
+        Err(IPC_OK) => {
+            args.rdx = 0;
+            return IPC_OK;
+        }
+        Err(err) => return err,
+    };
+    let IpcMessage {
+        data,
+        handles,
+        charge,
+        call,
+        reply_to,
+    } = message;
+    let handles = match install_current_transfers(handles) {
+        Ok(slots) => slots,
+        Err(handles) => {
+            // put the message back so the receiver can retry after closing
+            // some handles
+            let mut locked = channel.lock();
+            let endpoint = &mut locked.endpoints[side];
+            endpoint
+                .answering
+                .retain(|&answering| Some(answering) != call);
+            endpoint.push_front(IpcMessage {
+                data,
+                handles,
+                charge,
+                call,
+                reply_to,
+            });
+            drop(locked);
+            return IPC_EMFILE;
+        }
+    };
+    drop(charge);
+    let descs = unsafe { core::slice::from_raw_parts_mut(descs_ptr, descs_len) };
+    for (desc, (kind, slot)) in descs.iter_mut().zip(handles.iter()) {
+        *desc = IpcHandleDesc {
+            kind: *kind,
+            flags: 0,
+            index: *slot as u64,
+        };
+    }
+    let copy_len = min(len, data.len());
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, copy_len);
+    }
+    args.rdx = handles.len() as u64;
+    copy_len as isize
+}
+
+/// `sys_recv` that blocks for up to `r8` ticks while the queue is empty.
+fn sys_recv_wait(args: &mut ProcessContext) -> isize {
+    let timeout = args.r8 as usize;
+    let deadline = deadline(timeout);
+    let ret = sys_recv(args);
+    if ret != IPC_EAGAIN || timeout == 0 {
+        return ret;
+    }
+    let Some((channel, side)) = current_handle(args.rsi as usize) else {
+        return IPC_EBADF;
+    };
+    let reason = WaitReason::IpcRecv {
+        key: endpoint_key(&channel, side),
+        deadline,
+    };
+    block_and_restart(args, reason)
+}
+
+/// `sys_accept` that blocks for up to `r8` ticks while nobody connects.
+fn sys_accept_wait(args: &mut ProcessContext) -> isize {
+    let timeout = args.r8 as usize;
+    let deadline = deadline(timeout);
+    let ret = sys_accept(args);
+    if ret != IPC_EAGAIN || timeout == 0 {
+        return ret;
+    }
+    let Some(listener) = current_listener(args.rsi as usize) else {
+        return IPC_EBADF;
+    };
+    let reason = WaitReason::IpcAccept {
+        key: listener_key(&listener),
+        deadline,
+    };
+    block_and_restart(args, reason)
+}
+
+/// Send the request in `rdx[..rcx]` and wait up to `r9` ticks for the reply,
+/// which is stored in `rdx[..r8]`.
+///
+/// The kernel numbers every request. Its reply is the first message the
+/// server sends on the channel after receiving it; other messages stay
+/// queued for a plain receive. While the request cannot be queued the caller
+/// waits within the same timeout. A restart after the request was sent finds
+/// its number in `ipc_call` and only waits for the reply.
+fn sys_call(args: &mut ProcessContext) -> isize {
+    let (capacity, timeout) = (args.r8 as usize, args.r9 as usize);
+    let deadline = deadline(timeout);
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let sent = TASKS.lock()[current]
+        .as_mut()
+        .and_then(|task| task.ipc_call.take());
+    let Some((channel, side)) = current_handle(args.rsi as usize) else {
+        return IPC_EBADF;
+    };
+    // a partially sent request would never be answered
+    if channel.lock().stream {
+        return IPC_EINVAL;
+    }
+    let call = match sent {
+        Some(call) => call,
+        None => {
+            let len = args.rcx as usize;
+            if len > IPC_MAX_MSG_SIZE {
+                return IPC_EMSGSIZE;
+            }
+            let buf = unsafe { core::slice::from_raw_parts(args.rdx as *const u8, len) };
+            let call = NEXT_CALL.fetch_add(1, Ordering::Relaxed);
+            match enqueue(&channel, side, buf, Vec::new(), Some(call)) {
+                Ok(_) => call,
+                Err(SendError {
+                    wait_key: Some(key),
+                    ..
+                }) if timeout != 0 => {
+                    let reason = WaitReason::IpcSend { key, deadline };
+                    return block_and_restart(args, reason);
+                }
+                Err(error) => return error.err,
+            }
+        }
+    };
+    match take_reply(&channel, side, call) {
+        Ok(message) => {
+            let copy_len = min(capacity, message.data.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    message.data.as_ptr(),
+                    args.rdx as *mut u8,
+                    copy_len,
+                );
+            }
+            message.release();
+            copy_len as isize
+        }
+        Err(IPC_EAGAIN) if timeout != 0 => {
+            if let Some(task) = TASKS.lock()[current].as_mut() {
+                task.ipc_call = Some(call);
+            }
+            let reason = WaitReason::IpcCall {
+                key: endpoint_key(&channel, side),
+                deadline,
+            };
+            block_and_restart(args, reason)
+        }
+        Err(err) => err,
     }
 }
 
@@ -244,38 +858,49 @@ fn sys_bind(args: &mut ProcessContext) -> isize {
         object: IpcHandleObject::Listener(listener.clone()),
     }));
 
-    {
-        let mut named = NAMED_ENDPOINTS.lock();
-        if named.iter().any(|(entry_name, _)| entry_name == &name) {
-            drop(named);
-            close_handle_ref(local);
-            return IPC_EEXIST;
-        }
-        named.push((name, listener));
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let exe = {
+        let tasks = TASKS.lock();
+        tasks[current].as_ref().unwrap().exe_path.clone()
+    };
+    if let Err(err) = registry::register(name, listener, current, exe.as_deref()) {
+        close_handle_ref(local);
+        return err;
     }
 
     match install_current_handle(local) {
         Ok(slot) => slot as isize,
         Err(handle) => {
-            unregister_listener_by_name(&name_key);
+            registry::unregister_name(&name_key);
             close_handle_ref(handle);
             IPC_EMFILE
         }
     }
 }
 
+/// Restrict binding below the service prefix `rsi[..rdx]` to the program at
+/// `rcx[..r8]`. Only init may do this.
+fn sys_restrict(args: &mut ProcessContext) -> isize {
+    let Some(prefix) = copy_name_arg(args.rsi as *const u8, args.rdx as usize) else {
+        return IPC_EINVAL;
+    };
+    let Some(exe) = copy_name_arg(args.rcx as *const u8, args.r8 as usize) else {
+        return IPC_EINVAL;
+    };
+    registry::restrict(CURRENT_TASK_ID.load(Ordering::Relaxed), prefix, exe)
+}
+
 fn sys_connect(args: &mut ProcessContext) -> isize {
     let Some(name) = copy_name_arg(args.rsi as *const u8, args.rdx as usize) else {
         return IPC_EINVAL;
     };
-    let listener = {
-        let named = NAMED_ENDPOINTS.lock();
-        let Some((_, listener)) = named.iter().find(|(entry_name, _)| entry_name == &name) else {
-            return IPC_ENOENT;
-        };
-        listener.clone()
+    let Some(listener) = registry::lookup(&name) else {
+        return IPC_ENOENT;
     };
-    let channel = Arc::new(Mutex::new(IpcChannel::new()));
+    let channel = Arc::new(Mutex::new(IpcChannel::new(
+        args.rcx as usize,
+        args.r8 as usize,
+    )));
     let client = new_channel_handle(channel.clone(), 0);
     let server = new_channel_handle(channel, 1);
     {
@@ -289,8 +914,11 @@ fn sys_connect(args: &mut ProcessContext) -> isize {
 
     match install_current_handle(client) {
         Ok(slot) => {
-            let mut locked = listener.lock();
-            locked.pending.push_back(server);
+            listener.lock().pending.push_back(server);
+            let key = listener_key(&listener);
+            sched::wake(
+                |reason| matches!(*reason, WaitReason::IpcAccept { key: waiting, .. } if waiting == key),
+            );
             slot as isize
         }
         Err(handle) => {
@@ -341,6 +969,166 @@ fn current_listener(handle_id: usize) -> Option<Arc<Mutex<IpcListener>>> {
     }
 }
 
+/// Collect the handles named by `descs` from the current task's tables.
+///
+/// Either every descriptor is valid and its handle is taken (moved out of the
+/// table or duplicated), or nothing changes. An IPC handle from which
+/// `channel` can be reached cannot be sent over it, as the message would
+/// close a cycle that keeps the channels alive once every process closed
+/// them.
+fn take_current_handles(
+    channel: &Arc<Mutex<IpcChannel>>,
+    descs: &[IpcHandleDesc],
+) -> Result<Vec<IpcTransfer>, isize> {
+    let mut taken = Vec::with_capacity(descs.len());
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    {
+        let mut tasks = TASKS.lock();
+        let task = tasks[current].as_mut().unwrap();
+        for (idx, desc) in descs.iter().enumerate() {
+            let index = desc.index as usize;
+            // a moved handle must not be named twice
+            let conflict = descs.iter().enumerate().any(|(other_idx, other)| {
+                other_idx != idx
+                    && other.kind == desc.kind
+                    && other.index == desc.index
+                    && (desc.flags | other.flags) & IPC_HANDLE_MOVE != 0
+            });
+            if desc.flags & !IPC_HANDLE_MOVE != 0 || conflict {
+                return Err(IPC_EINVAL);
+            }
+            let handle = match desc.kind {
+                IPC_HANDLE_IPC => {
+                    let Some(Some(handle)) = task.ipc_handles.get(index) else {
+                        return Err(IPC_EBADF);
+                    };
+                    if reaches(handle, channel) {
+                        return Err(IPC_EINVAL);
+                    }
+                    IpcTransfer::Ipc(handle.clone())
+                }
+                IPC_HANDLE_FILE => {
+                    let Some(Some(file)) = task.files.get(index) else {
+                        return Err(IPC_EBADF);
+                    };
+                    IpcTransfer::File(file.clone())
+                }
+                _ => return Err(IPC_EINVAL),
+            };
+            taken.push(handle);
+        }
+        for desc in descs
+            .iter()
+            .filter(|desc| desc.flags & IPC_HANDLE_MOVE != 0)
+        {
+            let index = desc.index as usize;
+            match desc.kind {
+                IPC_HANDLE_IPC => task.ipc_handles[index] = None,
+                _ => task.files[index] = None,
+            }
+        }
+    }
+    // a moved handle carries the table's reference, a duplicated one a new one
+    for (desc, handle) in descs.iter().zip(taken.iter()) {
+        if let IpcTransfer::Ipc(handle) = handle
+            && desc.flags & IPC_HANDLE_MOVE == 0
+        {
+            dup_handle_ref(handle);
+        }
+    }
+    Ok(taken)
+}
+
+/// Whether `channel` is the channel of `handle` or can be reached from it
+/// through the handles queued on channels and pending on listeners.
+fn reaches(handle: &IpcHandle, channel: &Arc<Mutex<IpcChannel>>) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut pending = alloc::vec![handle.clone()];
+    while let Some(handle) = pending.pop() {
+        let object = match &handle.lock().object {
+            IpcHandleObject::Channel { channel, .. } => Ok(channel.clone()),
+            IpcHandleObject::Listener(listener) => Err(listener.clone()),
+        };
+        match object {
+            Ok(other) if Arc::ptr_eq(&other, channel) => return true,
+            Ok(other) => {
+                if !seen.insert(Arc::as_ptr(&other) as usize) {
+                    continue;
+                }
+                let locked = other.lock();
+                let queued = locked
+                    .endpoints
+                    .iter()
+                    .flat_map(|endpoint| endpoint.queue.iter())
+                    .flat_map(|message| message.handles.iter());
+                pending.extend(queued.filter_map(|transfer| match transfer {
+                    IpcTransfer::Ipc(handle) => Some(handle.clone()),
+                    IpcTransfer::File(_) => None,
+                }));
+            }
+            Err(listener) => {
+                if seen.insert(Arc::as_ptr(&listener) as usize) {
+                    pending.extend(listener.lock().pending.iter().cloned());
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Undo `take_current_handles` after a send failed.
+fn restore_current_handles(descs: &[IpcHandleDesc], handles: Vec<IpcTransfer>) {
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut released = Vec::new();
+    {
+        let mut tasks = TASKS.lock();
+        let task = tasks[current].as_mut().unwrap();
+        for (desc, handle) in descs.iter().zip(handles) {
+            let index = desc.index as usize;
+            match handle {
+                IpcTransfer::Ipc(handle) if desc.flags & IPC_HANDLE_MOVE != 0 => {
+                    task.ipc_handles[index] = Some(handle);
+                }
+                IpcTransfer::File(file) if desc.flags & IPC_HANDLE_MOVE != 0 => {
+                    task.files[index] = Some(file);
+                }
+                handle => released.push(handle),
+            }
+        }
+    }
+    release_transfers(released);
+}
+
+/// Install every handle of a received message into the current task, or
+/// none of them if the tables are too full.
+fn install_current_transfers(
+    handles: Vec<IpcTransfer>,
+) -> Result<Vec<(u32, usize)>, Vec<IpcTransfer>> {
+    let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut tasks = TASKS.lock();
+    let task = tasks[current].as_mut().unwrap();
+    let mut free_ipc = (0..task.ipc_handles.len()).filter(|&idx| task.ipc_handles[idx].is_none());
+    let mut free_files = (0..task.files.len()).filter(|&idx| task.files[idx].is_none());
+    let mut slots = Vec::with_capacity(handles.len());
+    for handle in &handles {
+        let slot = match handle {
+            IpcTransfer::Ipc(_) => free_ipc.next().map(|slot| (IPC_HANDLE_IPC, slot)),
+            IpcTransfer::File(_) => free_files.next().map(|slot| (IPC_HANDLE_FILE, slot)),
+        };
+        let Some(slot) = slot else {
+            return Err(handles);
+        };
+        slots.push(slot);
+    }
+    for (handle, &(_, slot)) in handles.into_iter().zip(slots.iter()) {
+        match handle {
+            IpcTransfer::Ipc(handle) => task.ipc_handles[slot] = Some(handle),
+            IpcTransfer::File(file) => task.files[slot] = Some(file),
+        }
+    }
+    Ok(slots)
+}
+
 fn install_current_pair(handle0: IpcHandle, handle1: IpcHandle) -> Option<(usize, usize)> {
     let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
     let mut tasks = TASKS.lock();
@@ -404,13 +1192,31 @@ fn close_handle_ref(handle: IpcHandle) {
     let locked = handle.lock();
     match &locked.object {
         IpcHandleObject::Channel { channel, side } => {
-            let mut locked = channel.lock();
-            if locked.refs[*side] != 0 {
-                locked.refs[*side] -= 1;
-                if locked.refs[*side] == 0 {
-                    close_side(&mut locked, *side);
+            let mut dropped = VecDeque::new();
+            let mut closed = false;
+            {
+                let mut locked = channel.lock();
+                if locked.refs[*side] != 0 {
+                    locked.refs[*side] -= 1;
+                    if locked.refs[*side] == 0 {
+                        dropped = close_side(&mut locked, *side);
+                        closed = true;
+                    }
                 }
             }
+            let peer_key = endpoint_key(channel, *side ^ 1);
+            let own_key = endpoint_key(channel, *side);
+            drop(locked);
+            if closed {
+                // blocked receivers on the other side now see end of stream,
+                // blocked senders to this side a broken pipe
+                wake_receivers(peer_key);
+                wake_senders(own_key);
+            }
+            // unread messages may carry handles to other channels
+            for message in dropped {
+                message.release();
+            }
         }
         IpcHandleObject::Listener(listener) => {
             let mut locked = listener.lock();
@@ -419,7 +1225,7 @@ fn close_handle_ref(handle: IpcHandle) {
                 if locked.refs == 0 {
                     let pending = core::mem::take(&mut locked.pending);
                     drop(locked);
-                    unregister_listener(listener);
+                    registry::unregister_listener(listener);
                     for pending_handle in pending {
                         close_handle_ref(pending_handle);
                     }
@@ -429,10 +1235,12 @@ fn close_handle_ref(handle: IpcHandle) {
     }
 }
 
-fn close_side(channel: &mut IpcChannel, side: usize) {
+/// Mark `side` closed and return the messages nobody will read any more.
+fn close_side(channel: &mut IpcChannel, side: usize) -> VecDeque<IpcMessage> {
     let endpoint = &mut channel.endpoints[side];
     endpoint.closed = true;
-    endpoint.queue.clear();
+    endpoint.queued = 0;
+    core::mem::take(&mut endpoint.queue)
 }
 
 fn new_channel_handle(channel: Arc<Mutex<IpcChannel>>, side: usize) -> IpcHandle {
@@ -447,20 +1255,3 @@ fn channel_from_handle_state(handle: &IpcHandleState) -> Option<&Arc<Mutex<IpcCh
         IpcHandleObject::Listener(_) => None,
     }
 }
-
-fn unregister_listener(listener: &Arc<Mutex<IpcListener>>) {
-    let mut named = NAMED_ENDPOINTS.lock();
-    if let Some(idx) = named
-        .iter()
-        .position(|(_, entry_listener)| Arc::ptr_eq(entry_listener, listener))
-    {
-        named.swap_remove(idx);
-    }
-}
-
-fn unregister_listener_by_name(name: &str) {
-    let mut named = NAMED_ENDPOINTS.lock();
-    if let Some(idx) = named.iter().position(|(entry_name, _)| entry_name == name) {
-        named.swap_remove(idx);
-    }
-}
@@ -0,0 +1,142 @@
+//! Named IPC services.
+//!
+//! Service names are hierarchical, `/`-separated paths such as `net/ppp0`.
+//! Every name records the PID that bound it and disappears when that task
+//! exits or its listener is closed, whichever comes first.
+//!
+//! Init (PID 1) may restrict a prefix to a set of executables. Once a prefix
+//! has rules, only those programs can bind names at or below it; prefixes
+//! without rules stay open to everyone.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::IpcListener;
+
+const INIT_PID: usize = 1;
+
+struct Service {
+    listener: Arc<Mutex<IpcListener>>,
+    owner: usize,
+}
+
+/// A registered service as shown in `/proc/ipc`.
+pub struct ServiceInfo {
+    pub name: String,
+    pub owner: usize,
+    pub pending: usize,
+}
+
+static SERVICES: Mutex<BTreeMap<String, Service>> = Mutex::new(BTreeMap::new());
+/// `(prefix, executable)` pairs allowed to bind below `prefix`.
+static POLICY: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Check that `name` is a relative path of non-empty components made of
+/// ASCII letters, digits, `.`, `_` and `-`.
+pub(super) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('/').all(|component| {
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && component
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte))
+        })
+}
+
+/// Whether `name` is `prefix` itself or lies below it.
+fn under(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn allowed(name: &str, exe: Option<&str>) -> bool {
+    let policy = POLICY.lock();
+    let mut rules = policy
+        .iter()
+        .filter(|(prefix, _)| under(name, prefix))
+        .peekable();
+    rules.peek().is_none() || rules.any(|(_, allowed)| Some(allowed.as_str()) == exe)
+}
+
+pub(super) fn register(
+    name: String,
+    listener: Arc<Mutex<IpcListener>>,
+    owner: usize,
+    exe: Option<&str>,
+) -> Result<(), isize> {
+    if !valid_name(&name) {
+        return Err(super::IPC_EINVAL);
+    }
+    if !allowed(&name, exe) {
+        return Err(super::IPC_EACCES);
+    }
+    let mut services = SERVICES.lock();
+    if services.contains_key(&name) {
+        return Err(super::IPC_EEXIST);
+    }
+    services.insert(name, Service { listener, owner });
+    Ok(())
+}
+
+pub(super) fn lookup(name: &str) -> Option<Arc<Mutex<IpcListener>>> {
+    SERVICES
+        .lock()
+        .get(name)
+        .map(|service| service.listener.clone())
+}
+
+pub(super) fn unregister_listener(listener: &Arc<Mutex<IpcListener>>) {
+    SERVICES
+        .lock()
+        .retain(|_, service| !Arc::ptr_eq(&service.listener, listener));
+}
+
+pub(super) fn unregister_name(name: &str) {
+    SERVICES.lock().remove(name);
+}
+
+/// Drop every name bound by `pid`. Called when the task exits, after its
+/// handle table has been released.
+pub fn release_owner(pid: usize) {
+    SERVICES.lock().retain(|_, service| service.owner != pid);
+}
+
+/// Only allow `exe` (among other programs named for the same prefix) to bind
+/// names at or below `prefix`.
+pub(super) fn restrict(caller: usize, prefix: String, exe: String) -> isize {
+    if caller != INIT_PID {
+        return super::IPC_EACCES;
+    }
+    if !valid_name(&prefix) {
+        return super::IPC_EINVAL;
+    }
+    let mut policy = POLICY.lock();
+    if !policy
+        .iter()
+        .any(|(rule_prefix, rule_exe)| *rule_prefix == prefix && *rule_exe == exe)
+    {
+        policy.push((prefix, exe));
+    }
+    super::IPC_OK
+}
+
+pub fn list() -> Vec<ServiceInfo> {
+    let services: Vec<_> = SERVICES
+        .lock()
+        .iter()
+        .map(|(name, service)| (name.clone(), service.owner, service.listener.clone()))
+        .collect();
+    services
+        .into_iter()
+        .map(|(name, owner, listener)| ServiceInfo {
+            name,
+            owner,
+            pending: listener.lock().pending.len(),
+        })
+        .collect()
+}
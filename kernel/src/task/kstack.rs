@@ -0,0 +1,138 @@
+//! Per-task kernel stacks with guard pages.
+//!
+//! Every task owns a kernel stack in a dedicated higher-half region, one slot
+//! per PID. Only the top of a slot is mapped; the rest stays unmapped, so a
+//! kernel path that runs off the end of its stack faults (and ends up in the
+//! double-fault handler) instead of overwriting a neighbour.
+//!
+//! The region is part of the kernel half shared by every address space (see
+//! `mm::paging`), so a stack mapped after a fork is visible no matter which
+//! page table happens to be active.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::addr::PhysAddr;
+use x86_64::structures::paging::mapper::OffsetPageTable;
+use x86_64::structures::paging::page::Page;
+use x86_64::structures::paging::page_table::{PageTable, PageTableFlags};
+use x86_64::structures::paging::{Mapper, PhysFrame, Size4KiB};
+
+use crate::mm::page_alloc::{
+    DLOSFrameAllocator, PAGE_SIZE, alloc_physical_page, dealloc_physical_page,
+};
+use crate::mm::phys_to_virt;
+use crate::task::process::ORIGINAL_KERNEL_CR3;
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+
+/// PML4 slot reserved for kernel stacks, shared by every page table.
+pub const PML4_INDEX: usize = 510;
+const REGION_BASE: u64 = 0xffff_ff00_0000_0000;
+const SLOT_SIZE: u64 = 2 * KERNEL_STACK_SIZE as u64;
+const SLOT_COUNT: u64 = (1 << 39) / SLOT_SIZE;
+
+/// Stacks of exited tasks. A task is still running on its own stack while
+/// `do_exit` switches away, so the pages are only released later.
+static RETIRED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+pub struct KernelStack {
+    pid: usize,
+}
+
+impl KernelStack {
+    pub fn new(pid: usize) -> Self {
+        assert!((pid as u64) < SLOT_COUNT, "task: out of kernel stack slots");
+        reap_retired();
+        let mut mapper = kernel_mapper();
+        let bottom = slot_base(pid) + SLOT_SIZE - KERNEL_STACK_SIZE as u64;
+        for offset in (0..KERNEL_STACK_SIZE as u64).step_by(PAGE_SIZE) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(bottom + offset));
+            let frame_pa = alloc_physical_page().expect("task: unable to allocate kernel stack");
+            unsafe {
+                mapper
+                    .map_to(
+                        page,
+                        PhysFrame::from_start_address(PhysAddr::new(frame_pa)).unwrap(),
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        &mut DLOSFrameAllocator,
+                    )
+                    .expect("task: kernel stack slot already mapped")
+                    .flush();
+            }
+        }
+        Self { pid }
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(slot_base(self.pid) + SLOT_SIZE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        RETIRED.lock().push(self.pid);
+    }
+}
+
+/// Check that the bootloader left the stack region empty.
+///
+/// Must run after `mm::paging::init_kernel_half`.
+pub fn init() {
+    let kernel_p4t = unsafe {
+        &*(phys_to_virt(ORIGINAL_KERNEL_CR3.0.start_address().as_u64()) as *const PageTable)
+    };
+    let pdpt =
+        unsafe { &*(phys_to_virt(kernel_p4t[PML4_INDEX].addr().as_u64()) as *const PageTable) };
+    assert!(
+        pdpt.iter().all(|entry| entry.is_unused()),
+        "task: PML4 slot {PML4_INDEX} is already used by the bootloader"
+    );
+}
+
+/// Release the stacks of tasks that have exited since the last call.
+///
+/// The caller must not be running on one of them, which holds for every
+/// caller other than `do_exit` of the task being reaped.
+pub fn reap_retired() {
+    let retired = core::mem::take(&mut *RETIRED.lock());
+    if retired.is_empty() {
+        return;
+    }
+    let mut mapper = kernel_mapper();
+    for pid in retired {
+        let bottom = slot_base(pid) + SLOT_SIZE - KERNEL_STACK_SIZE as u64;
+        for offset in (0..KERNEL_STACK_SIZE as u64).step_by(PAGE_SIZE) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(bottom + offset));
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                dealloc_physical_page(frame.start_address().as_u64());
+            }
+        }
+    }
+}
+
+/// Return the PID whose stack guard contains `addr`, if any.
+pub fn guard_owner(addr: u64) -> Option<usize> {
+    let offset = addr.checked_sub(REGION_BASE)?;
+    let pid = offset / SLOT_SIZE;
+    if pid >= SLOT_COUNT || offset % SLOT_SIZE >= SLOT_SIZE - KERNEL_STACK_SIZE as u64 {
+        return None;
+    }
+    Some(pid as usize)
+}
+
+fn slot_base(pid: usize) -> u64 {
+    REGION_BASE + pid as u64 * SLOT_SIZE
+}
+
+fn kernel_mapper() -> OffsetPageTable<'static> {
+    // Every PML4 references the same PDPT for this region, so mapping through
+    // the bootloader's table updates all address spaces at once.
+    unsafe {
+        OffsetPageTable::new(
+            &mut *(phys_to_virt(ORIGINAL_KERNEL_CR3.0.start_address().as_u64()) as *mut PageTable),
+            VirtAddr::new(phys_to_virt(0)),
+        )
+    }
+}
@@ -1,4 +1,5 @@
 pub mod ipc;
+pub mod kstack;
 pub mod process;
 pub mod sched;
 pub mod syscall;
@@ -14,12 +15,16 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::paging::frame::PhysFrame;
 use x86_64::structures::tss::TaskStateSegment;
 
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
 pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
-    let rsp0_pa = crate::mm::page_alloc::find_continuous_mem(16)
-        .expect("task: unable to reserve kernel stack")
-        + 0x10000; // 64k rsp0
-    tss.privilege_stack_table[0] = VirtAddr::new(crate::mm::phys_to_virt(rsp0_pa));
+    // rsp0 is pointed at the running task's own kernel stack by `set_kernel_stack`
+    let ist_pa = crate::mm::page_alloc::find_continuous_mem(4)
+        .expect("task: unable to reserve double fault stack")
+        + 0x4000; // 16k IST stack
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::new(crate::mm::phys_to_virt(ist_pa));
     tss
 });
 
@@ -46,7 +51,16 @@ pub fn reset_gdt() {
     }
 }
 
+/// Point rsp0 at the kernel stack that the next Ring3 -> Ring0 transition uses.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe {
+        (*Lazy::as_mut_ptr(&TSS)).privilege_stack_table[0] = top;
+    }
+}
+
 pub fn init() {
+    crate::mm::paging::init_kernel_half();
+    self::kstack::init();
     unsafe {
         let flags = Cr3::read().1;
         let new_cr3_va;
@@ -57,7 +71,9 @@ pub fn init() {
             } else {
                 tasks[0] = Some(self::process::Process::task_0());
             }
-            new_cr3_va = tasks[0].as_ref().unwrap().page_table.level_4_table() as *const _ as u64;
+            let task_0 = tasks[0].as_ref().unwrap();
+            set_kernel_stack(task_0.kernel_stack.top());
+            new_cr3_va = task_0.page_table.level_4_table() as *const _ as u64;
         }
         let new_cr3 =
             PhysFrame::from_start_address(PhysAddr::new(new_cr3_va - crate::mm::phys_to_virt(0)))
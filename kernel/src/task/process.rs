@@ -1,7 +1,8 @@
 use crate::mm::page_alloc::alloc_physical_page;
+use crate::mm::paging::{self, HUGE_PAGE_SIZE, PAGES_PER_HUGE_PAGE};
 use crate::mm::phys_to_virt;
 use crate::task::ipc::{self, IpcHandle};
-use alloc::borrow::ToOwned;
+use crate::task::kstack::{self, KernelStack};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -18,6 +19,7 @@ use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::frame::PhysFrame;
 use x86_64::structures::paging::mapper::OffsetPageTable;
 use x86_64::structures::paging::page::Page;
+use x86_64::structures::paging::page::Size2MiB;
 use x86_64::structures::paging::page::Size4KiB;
 use x86_64::structures::paging::page_table::PageTable;
 use x86_64::structures::paging::page_table::PageTableFlags;
@@ -51,8 +53,44 @@ pub struct ProcessContext {
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum WaitReason {
     WaitPid(usize),
-    // Additional wait reasons can be added here when kernel-visible blocking
-    // states beyond waitpid are introduced.
+    /// Waiting for a message on the IPC endpoint identified by `key`.
+    IpcRecv {
+        key: usize,
+        deadline: Option<usize>,
+    },
+    /// Waiting for a connection on the IPC listener identified by `key`.
+    IpcAccept {
+        key: usize,
+        deadline: Option<usize>,
+    },
+    /// Sent a request and waiting for the reply on the endpoint `key`.
+    IpcCall {
+        key: usize,
+        deadline: Option<usize>,
+    },
+    /// Waiting for queue space on the IPC endpoint or account `key`.
+    IpcSend {
+        key: usize,
+        deadline: Option<usize>,
+    },
+    /// Waiting until the file identified by `key`, e.g. a pipe end, can be
+    /// read or written.
+    File {
+        key: usize,
+    },
+}
+
+impl WaitReason {
+    /// The tick at which the wait gives up, if it does.
+    pub fn deadline(&self) -> Option<usize> {
+        match *self {
+            WaitReason::WaitPid(_) | WaitReason::File { .. } => None,
+            WaitReason::IpcRecv { deadline, .. }
+            | WaitReason::IpcAccept { deadline, .. }
+            | WaitReason::IpcCall { deadline, .. }
+            | WaitReason::IpcSend { deadline, .. } => deadline,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -61,6 +99,9 @@ pub enum ProcessState {
     Blocked(WaitReason),
 }
 
+/// The size of a process's file table.
+pub const MAX_FILES: usize = 64;
+
 pub struct Process<'a> {
     pub page_table: OffsetPageTable<'a>,
     pub context: ProcessContext,
@@ -69,10 +110,23 @@ pub struct Process<'a> {
     pub fs: VirtAddr,
     pub brk: u64,
     pub exe_path: Option<String>,
+    /// Writes to fd 0 go to fd 2, for a binary built when fd 0 was stderr.
+    pub legacy_stderr: bool,
+    /// Absolute and normalized, see `vfs::path`.
+    pub cwd: String,
     pub state: ProcessState,
-    pub files: [Option<Arc<Mutex<dyn crate::vfs::VfsFile>>>; 64],
+    pub files: [Option<Arc<crate::vfs::OpenFile>>; MAX_FILES],
     pub directories: [Option<Arc<Mutex<dyn crate::vfs::VfsDirHandle>>>; 64],
     pub ipc_handles: [Option<IpcHandle>; ipc::IPC_MAX_HANDLES],
+    /// Bytes this process has queued on IPC channels.
+    pub ipc_account: Arc<ipc::IpcAccount>,
+    /// The tick at which a blocked IPC wait gives up, kept while the system
+    /// call is restarted so each retry does not start the timeout over.
+    pub ipc_deadline: Option<usize>,
+    /// The number of the call whose reply the process waits for, so that a
+    /// restart does not send the request again.
+    pub ipc_call: Option<u64>,
+    pub kernel_stack: KernelStack,
 }
 
 pub static ORIGINAL_KERNEL_CR3: Lazy<(PhysFrame, Cr3Flags)> = Lazy::new(Cr3::read);
@@ -116,9 +170,10 @@ impl Process<'_> {
     pub fn task_0() -> Self {
         // PID 0 is reserved for the idle task. The scheduler relies on it as the
         // always-runnable fallback when no normal task can be selected.
-        let mut files = [const { None }; 64];
-        files[0] = crate::vfs::get_file("/dev/stderr").ok();
-        files[1] = crate::vfs::get_file("/dev/stdout").ok();
+        let mut files = [const { None }; MAX_FILES];
+        files[0] = crate::vfs::open("/dev/stdin", crate::vfs::O_RDONLY).ok();
+        files[1] = crate::vfs::open("/dev/stdout", crate::vfs::O_WRONLY).ok();
+        files[2] = crate::vfs::open("/dev/stderr", crate::vfs::O_WRONLY).ok();
         Process {
             page_table: Self::t0_p4_table(),
             context: ProcessContext::default(),
@@ -127,10 +182,16 @@ impl Process<'_> {
             fs: VirtAddr::new(0),
             brk: 0,
             exe_path: None,
+            legacy_stderr: false,
+            cwd: String::from("/"),
             state: ProcessState::Runnable,
             files,
             directories: [const { None }; 64],
             ipc_handles: [const { None }; ipc::IPC_MAX_HANDLES],
+            ipc_account: ipc::IpcAccount::new(),
+            ipc_deadline: None,
+            ipc_call: None,
+            kernel_stack: KernelStack::new(0),
         }
     }
 
@@ -138,61 +199,48 @@ impl Process<'_> {
         let p4t_pa = alloc_physical_page().unwrap();
         let p4t_va = phys_to_virt(p4t_pa);
         let p4t = unsafe { &mut *(p4t_va as *mut PageTable) };
-        let kernel_p4t = unsafe {
-            &mut *(phys_to_virt(ORIGINAL_KERNEL_CR3.0.start_address().as_u64()) as *mut PageTable)
-        };
-        Self::r_copy(kernel_p4t, p4t, 4, false, false);
+        p4t.zero();
+        paging::share_kernel_half(Self::kernel_p4_table(), p4t);
 
         unsafe { OffsetPageTable::new(p4t, x86_64::addr::VirtAddr::new_truncate(phys_to_virt(0))) }
     }
 
-    fn r_copy(
-        src_table: &mut PageTable,
-        dest_table: &mut PageTable,
-        level: u8,
-        copying_process: bool,
-        is_user_page: bool,
-    ) {
+    fn kernel_p4_table() -> &'static PageTable {
+        unsafe {
+            &*(phys_to_virt(ORIGINAL_KERNEL_CR3.0.start_address().as_u64()) as *const PageTable)
+        }
+    }
+
+    /// Copy the user mappings below `src_table` for a forked child, turning
+    /// every page into a CoW page in both address spaces.
+    fn r_copy(src_table: &mut PageTable, dest_table: &mut PageTable, level: u8) {
         // crate::println!("r_copy: src_table {:?} dest_table {:?} level {}",
         //          src_table as *const _, dest_table as *const _, level);
         dest_table.zero();
-        for (index, entry) in src_table.iter_mut().enumerate() {
+        let end = if level == 4 {
+            paging::KERNEL_PML4_START
+        } else {
+            512
+        };
+        for (index, entry) in src_table.iter_mut().enumerate().take(end) {
             if !entry.flags().contains(PageTableFlags::PRESENT) {
                 continue;
             }
             if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
                 let mut flags = entry.flags();
-                if is_user_page {
-                    crate::mm::page_alloc::page_incref(entry.addr().as_u64());
-                    if copying_process {
-                        flags.remove(PageTableFlags::WRITABLE);
-                        entry.set_flags(flags);
-                    }
-                }
-                flags.insert(PageTableFlags::USER_ACCESSIBLE);
+                crate::mm::page_alloc::page_incref(entry.addr().as_u64());
+                flags.remove(PageTableFlags::WRITABLE);
+                entry.set_flags(flags);
                 dest_table[index].set_addr(entry.addr(), flags);
                 continue;
             }
             let new_addr = entry.addr().as_u64();
-            if new_addr > (1 << 32) {
-                crate::println!(
-                    "[WARN] r_copy: ignoring level {level} page table at physical address 0x{:x}",
-                    new_addr
-                );
-                continue;
-            }
             let new_table_pa = alloc_physical_page().unwrap();
             let new_table_va = phys_to_virt(new_table_pa);
-            let mut flags = entry.flags();
-            flags.insert(PageTableFlags::USER_ACCESSIBLE);
-            dest_table[index].set_addr(PhysAddr::new(new_table_pa), flags);
+            dest_table[index].set_addr(PhysAddr::new(new_table_pa), entry.flags());
             let new_table = unsafe { &mut *(new_table_va as *mut PageTable) };
             let new_src = unsafe { &mut *(phys_to_virt(new_addr) as *mut PageTable) };
-            if level == 4 {
-                Self::r_copy(new_src, new_table, level - 1, copying_process, index < 256);
-            } else {
-                Self::r_copy(new_src, new_table, level - 1, copying_process, is_user_page);
-            }
+            Self::r_copy(new_src, new_table, level - 1);
         }
     }
 
@@ -200,7 +248,8 @@ impl Process<'_> {
         let p4t_pa = alloc_physical_page().unwrap();
         let p4t_va = phys_to_virt(p4t_pa);
         let p4t = unsafe { &mut *(p4t_va as *mut PageTable) };
-        Self::r_copy(self.page_table.level_4_table_mut(), p4t, 4, true, false);
+        Self::r_copy(self.page_table.level_4_table_mut(), p4t, 4);
+        paging::share_kernel_half(Self::kernel_p4_table(), p4t);
         let mut new_context = *context;
         new_context.rcx = 0;
         context.rcx = new_tid as u64;
@@ -214,16 +263,24 @@ impl Process<'_> {
             fs: VirtAddr::new(0),
             brk: self.brk,
             exe_path: self.exe_path.clone(),
+            legacy_stderr: self.legacy_stderr,
+            cwd: self.cwd.clone(),
             state: ProcessState::Runnable,
             files: self.files.clone(),
             directories: self.directories.clone(),
             ipc_handles: ipc::clone_handle_table(&self.ipc_handles),
+            ipc_account: ipc::IpcAccount::new(),
+            ipc_deadline: None,
+            ipc_call: None,
+            kernel_stack: KernelStack::new(new_tid),
         }
     }
 
+    /// Free the user half of the address space, and the PML4 itself unless
+    /// `user_only` is set. The kernel half is shared and never freed.
     pub fn free_page_tables(&mut self, user_only: bool) {
         let target_table = self.page_table.level_4_table_mut();
-        Self::r_free(target_table, 4, user_only, false);
+        Self::r_free(target_table, 4);
         if !user_only {
             crate::mm::page_alloc::dealloc_physical_page(
                 target_table as *const _ as u64 - phys_to_virt(0),
@@ -231,47 +288,46 @@ impl Process<'_> {
         }
     }
 
-    fn r_free(target_table: &mut PageTable, level: u8, user_only: bool, is_user_page: bool) {
-        let range = if level == 4 && user_only {
-            0..256
+    fn r_free(target_table: &mut PageTable, level: u8) {
+        let end = if level == 4 {
+            paging::KERNEL_PML4_START
         } else {
-            0..512
+            512
         };
-        for idx in range {
+        for idx in 0..end {
             let entry = &mut target_table[idx];
             if !entry.flags().contains(PageTableFlags::PRESENT) {
                 continue;
             }
             if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-                if !user_only || is_user_page {
-                    // when user_only is set,  only use page_decref on user pages
-                    let addr = entry.addr().as_u64();
-                    entry.set_unused();
-                    if is_user_page {
-                        crate::mm::page_alloc::page_decref(addr);
-                        if crate::mm::page_alloc::page_getref(addr) == 0 {
-                            //crate::println!("[DEBUG] will call dealloc_physical_page on 0x{:x}", addr);
-                            crate::mm::page_alloc::dealloc_physical_page(addr);
-                        }
+                let addr = entry.addr().as_u64();
+                entry.set_unused();
+                crate::mm::page_alloc::page_decref(addr);
+                if crate::mm::page_alloc::page_getref(addr) == 0 {
+                    //crate::println!("[DEBUG] will call dealloc_physical_page on 0x{:x}", addr);
+                    if level == 1 {
+                        crate::mm::page_alloc::dealloc_physical_page(addr);
+                    } else {
+                        // huge pages are refcounted through their head frame
+                        crate::mm::page_alloc::dealloc_continuous_mem(addr, 1 << (9 * (level - 1)));
                     }
                 }
                 continue;
             }
-            let target_is_user_page = if level == 4 { idx < 256 } else { is_user_page };
-            if !user_only || target_is_user_page {
-                let new_pa = entry.addr().as_u64();
-                let new_target = unsafe { &mut *(phys_to_virt(new_pa) as *mut PageTable) };
-                Self::r_free(new_target, level - 1, user_only, target_is_user_page);
-                entry.set_unused(); // fuck. i forgot this before
-                crate::mm::page_alloc::dealloc_physical_page(new_pa);
-            }
-            // fuck. the above set_unused() line was written here
+            let new_pa = entry.addr().as_u64();
+            let new_target = unsafe { &mut *(phys_to_virt(new_pa) as *mut PageTable) };
+            Self::r_free(new_target, level - 1);
+            entry.set_unused(); // fuck. i forgot this before
+            crate::mm::page_alloc::dealloc_physical_page(new_pa);
         }
     }
 }
 
 pub static TASKS: Mutex<Vec<Option<Process>>> = Mutex::new(Vec::new());
 
+/// Binaries built when fd 0 was stderr, which still print their errors there.
+const LEGACY_STDERR_BINARIES: [&str; 2] = ["/bin/lua", "/bin/pl_editor"];
+
 pub fn do_fork(context: &mut ProcessContext) {
     static NEXT_TID: AtomicUsize = AtomicUsize::new(0);
     let new_tid = NEXT_TID.fetch_add(1, Ordering::Relaxed) + 1;
@@ -287,12 +343,10 @@ pub fn do_fork(context: &mut ProcessContext) {
 }
 
 pub fn do_exec(args: &mut ProcessContext) {
-    let path = unsafe {
-        let slice = core::slice::from_raw_parts(args.rdi as *const _, args.rcx as usize);
-        core::str::from_utf8(slice).unwrap()
-    }
-    .to_owned();
-    if let Ok(elf_file_lock) = crate::vfs::get_file(&path) {
+    let Some(path) = super::syscall::user_path(args.rdi, args.rcx) else {
+        return;
+    };
+    if let Ok(elf_file_lock) = crate::vfs::get_executable(&path) {
         let mut elf_file = elf_file_lock.lock();
         let size = elf_file.size();
         let c_tid = super::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
@@ -303,6 +357,7 @@ pub fn do_exec(args: &mut ProcessContext) {
         current_task.fpu_state = FPU_INIT;
         current_task.fs = VirtAddr::zero();
         current_task.brk = 0;
+        current_task.legacy_stderr = LEGACY_STDERR_BINARIES.contains(&path.as_str());
         current_task.exe_path = Some(path);
         current_task.state = ProcessState::Runnable;
         let mut buf = alloc::vec![0u8; size];
@@ -315,10 +370,16 @@ pub fn do_exec(args: &mut ProcessContext) {
                     let end_va = VirtAddr::new_truncate(ph.p_vaddr + ph.p_memsz - 1);
                     current_task.brk = max(current_task.brk, ph.p_vaddr + ph.p_memsz);
                     // crate::println!("[DEBUG] sys_exec: {start_va:?} - {end_va:?}");
-                    for page in Page::range_inclusive(
-                        Page::<Size4KiB>::containing_address(start_va),
-                        Page::<Size4KiB>::containing_address(end_va),
-                    ) {
+                    let mut page = Page::<Size4KiB>::containing_address(start_va);
+                    while page.start_address() <= end_va {
+                        let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+                        if huge_page.start_address() == page.start_address()
+                            && end_va - page.start_address() >= HUGE_PAGE_SIZE as u64 - 1
+                            && paging::map_user_huge(&mut current_task.page_table, huge_page)
+                        {
+                            page += PAGES_PER_HUGE_PAGE as u64;
+                            continue;
+                        }
                         let allocated_pa = alloc_physical_page().unwrap();
                         unsafe {
                             let _ = current_task
@@ -340,6 +401,7 @@ pub fn do_exec(args: &mut ProcessContext) {
                                     crate::mm::page_alloc::dealloc_physical_page(allocated_pa);
                                 });
                         }
+                        page += 1;
                     }
                     let mut target_slice = unsafe {
                         core::slice::from_raw_parts_mut(
@@ -363,13 +425,19 @@ pub fn do_exec(args: &mut ProcessContext) {
 pub fn do_exit(args: &mut ProcessContext) {
     let c_tid = super::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
     // crate::println!("[DEBUG] task: process {c_tid} exited");
+    kstack::reap_retired();
+    let mut ipc_handles = [const { None }; ipc::IPC_MAX_HANDLES];
     {
         let mut tasks = TASKS.lock();
         if let Some(task) = tasks[c_tid].as_mut() {
-            ipc::release_handle_table(&mut task.ipc_handles);
+            core::mem::swap(&mut ipc_handles, &mut task.ipc_handles);
             task.free_page_tables(false);
         }
         tasks[c_tid] = None;
     }
+    // closing a channel wakes its peer, which needs the task list
+    ipc::release_handle_table(&mut ipc_handles);
+    // a listener shared with another task survives the table, its name not
+    ipc::registry::release_owner(c_tid);
     super::sched::schedule(args, true);
 }
@@ -1,4 +1,6 @@
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
 use x86_64::PhysAddr;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::PhysFrame;
@@ -10,6 +12,46 @@ use super::process::WaitReason;
 pub static CURRENT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
 pub static TOTAL_TICKS: AtomicUsize = AtomicUsize::new(0);
 const IDLE_TASK_ID: usize = 0;
+/// Length of the `int 0x80` instruction.
+pub const SYSCALL_INSN_LEN: u64 = 2;
+
+/// File wait keys whose waiters `schedule` has to wake, queued by code that
+/// may run with the task list locked.
+static DEFERRED_FILE_WAKES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Make every task blocked for a reason matching `woken` runnable again.
+///
+/// A woken task gets at least one tick of its time slice back, so it runs
+/// before the slices are refilled.
+pub fn wake(woken: impl Fn(&WaitReason) -> bool) {
+    let mut tasks = super::process::TASKS.lock();
+    for task in tasks.iter_mut().flatten() {
+        if let ProcessState::Blocked(reason) = task.state
+            && woken(&reason)
+        {
+            task.state = ProcessState::Runnable;
+            task.tm = task.tm.max(1);
+        }
+    }
+}
+
+/// Wake the tasks waiting for the file identified by `key`.
+pub fn wake_file(key: usize) {
+    wake(|reason| matches!(*reason, WaitReason::File { key: waiting } if waiting == key));
+}
+
+/// `wake_file` for callers that may hold the task list lock, e.g. the drop
+/// of a file. The waiters are woken at the next `schedule`.
+pub fn wake_file_later(key: usize) {
+    DEFERRED_FILE_WAKES.lock().push(key);
+}
+
+/// Block the current task and arrange for its `int 0x80` to run again once
+/// it is woken, which retries the system call.
+pub fn block_and_restart(context: &mut ProcessContext, reason: WaitReason) {
+    context.rip -= SYSCALL_INSN_LEN;
+    block_current(context, reason);
+}
 
 pub fn block_current(context: &mut ProcessContext, reason: WaitReason) {
     let current = CURRENT_TASK_ID.load(Ordering::Relaxed);
@@ -53,6 +95,7 @@ pub fn switch_to(
         }
         let nxt = tasks[next].as_ref().unwrap();
         *context = nxt.context;
+        super::set_kernel_stack(nxt.kernel_stack.top());
         x86_64::registers::model_specific::FsBase::write(nxt.fs);
         unsafe {
             core::arch::x86_64::_fxrstor64((&nxt.fpu_state) as *const _ as *const u8);
@@ -73,8 +116,16 @@ pub fn schedule(context: &mut super::process::ProcessContext, current_process_ex
     let mut max_tm = 0;
     let mut max_tid = None;
     let mut tasks = super::process::TASKS.lock();
+    let now = TOTAL_TICKS.load(Ordering::Relaxed);
+    let file_wakes = core::mem::take(&mut *DEFERRED_FILE_WAKES.lock());
     for tid in 0..tasks.len() {
         if tasks[tid].is_some() {
+            if let ProcessState::Blocked(WaitReason::File { key }) =
+                tasks[tid].as_ref().unwrap().state
+                && file_wakes.contains(&key)
+            {
+                tasks[tid].as_mut().unwrap().state = ProcessState::Runnable;
+            }
             let should_wake = matches!(
                 tasks[tid].as_ref().unwrap().state,
                 ProcessState::Blocked(WaitReason::WaitPid(pid))
@@ -83,6 +134,15 @@ pub fn schedule(context: &mut super::process::ProcessContext, current_process_ex
             if should_wake {
                 tasks[tid].as_mut().unwrap().state = ProcessState::Runnable;
             }
+            let timed_out = matches!(
+                tasks[tid].as_ref().unwrap().state,
+                ProcessState::Blocked(reason) if reason.deadline().is_some_and(|deadline| now >= deadline)
+            );
+            if timed_out {
+                let process = tasks[tid].as_mut().unwrap();
+                process.state = ProcessState::Runnable;
+                super::ipc::complete_timed_out(process);
+            }
             let process = tasks[tid].as_ref().unwrap();
             if tid != CURRENT_TASK_ID.load(Ordering::Relaxed)
                 && process.tm > max_tm
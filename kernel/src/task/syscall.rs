@@ -1,16 +1,26 @@
-use crate::blockdev::partition::ahci::AhciPartition;
-use crate::blockdev::partition::nvme::NvmePartition;
-use crate::blockdev::partition::usb::UsbPartition;
 use crate::println;
-use crate::task::process::ORIGINAL_KERNEL_CR3;
 use crate::task::process::ProcessContext as SyscallStackFrame;
-use crate::vfs::SeekFrom;
-use crate::vfs::mount;
+use crate::task::process::{ORIGINAL_KERNEL_CR3, WaitReason};
+use crate::task::sched;
+use crate::vfs::MountError;
+use crate::vfs::{
+    Metadata, O_CREAT, O_DIRECTORY, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, OpenFile, SeekFrom,
+    VfsDirHandle, VfsError, VfsFile,
+};
+use alloc::string::String;
+use alloc::sync::Arc;
 use core::arch::naked_asm;
 use core::sync::atomic::Ordering;
+use spin::Mutex;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::idt::InterruptStackFrame;
 
+/// What `sys_read` returns once redirected input is exhausted, as a terminal
+/// does for Ctrl-D.
+const EOT: u8 = 0x04;
+const STDIN_FD: u64 = 0;
+const STDERR_FD: u64 = 2;
+
 #[unsafe(naked)]
 pub extern "x86-interrupt" fn syscall_handler(_: InterruptStackFrame) {
     naked_asm!(
@@ -51,7 +61,7 @@ pub extern "x86-interrupt" fn syscall_handler(_: InterruptStackFrame) {
     )
 }
 
-const NUM_SYSCALLS: usize = 23;
+const NUM_SYSCALLS: usize = 43;
 
 const SYSCALL_TABLE: [fn(&mut SyscallStackFrame); NUM_SYSCALLS] = [
     sys_test,
@@ -77,6 +87,26 @@ const SYSCALL_TABLE: [fn(&mut SyscallStackFrame); NUM_SYSCALLS] = [
     sys_closedir,
     sys_ipc,
     sys_read3,
+    sys_pipe,
+    sys_dup2,
+    sys_umount,
+    sys_chdir,
+    sys_getcwd,
+    sys_stat,
+    sys_fstat,
+    sys_mkdir,
+    sys_rmdir,
+    sys_rename,
+    sys_truncate,
+    sys_open2,
+    sys_dup,
+    sys_fcntl,
+    sys_pread,
+    sys_pwrite,
+    sys_sync,
+    sys_readlink,
+    sys_symlink,
+    sys_lstat,
 ];
 
 unsafe extern "C" fn do_syscall(args: *mut SyscallStackFrame) {
@@ -102,16 +132,99 @@ pub fn sys_test(_: &mut SyscallStackFrame) {
     println!("test syscall");
 }
 
-pub fn sys_write(args: &mut SyscallStackFrame) {
-    let (fd, ptr, size) = (args.rdi, args.rsi, args.rcx);
-    // println!("[DEBUG] sys_write: to {fd} ptr 0x{ptr:x} size {size}");
+/// The path at `ptr`, `len` in user memory, made absolute against the
+/// current working directory. `None` if it is not UTF-8.
+pub(super) fn user_path(ptr: u64, len: u64) -> Option<String> {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    let path = core::str::from_utf8(bytes).ok()?;
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let tasks = crate::task::process::TASKS.lock();
+    let task = tasks[current].as_ref().unwrap();
+    Some(crate::vfs::path::resolve(&task.cwd, path))
+}
+
+/// The UTF-8 string at `ptr`, `len` in user memory, taken as it is.
+fn user_str<'a>(ptr: u64, len: u64) -> Option<&'a str> {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    core::str::from_utf8(bytes).ok()
+}
+
+/// Look up `fd` in the current task's file table.
+///
+/// The file is returned by reference so that it is used without the task
+/// list locked; reading or writing may wake other tasks.
+fn current_file(fd: u64) -> Option<Arc<OpenFile>> {
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let tasks = crate::task::process::TASKS.lock();
+    let task = tasks[current].as_ref().unwrap();
+    task.files.get(fd as usize)?.clone()
+}
+
+/// Put `file` into the first free slot of the current file table.
+fn install_file(file: Arc<OpenFile>) -> Option<usize> {
+    install_file_from(file, 0)
+}
+
+/// Put `file` into the first free slot at or above `lowest`.
+fn install_file_from(file: Arc<OpenFile>, lowest: usize) -> Option<usize> {
     let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
     let mut tasks = crate::task::process::TASKS.lock();
     let task = tasks[current].as_mut().unwrap();
-    let _ = task.files[fd as usize].as_ref().map(|file| {
-        file.lock()
-            .write_all(unsafe { core::slice::from_raw_parts(ptr as *const u8, size as usize) })
-    });
+    let fd = (lowest..task.files.len()).find(|&fd| task.files[fd].is_none())?;
+    task.files[fd] = Some(file);
+    Some(fd)
+}
+
+/// Write as much of `buf` as `file` takes now and return how much that was.
+/// Fails only if nothing was written.
+fn write_some(file: &mut dyn VfsFile, buf: &[u8]) -> Result<usize, VfsError> {
+    let mut written = 0;
+    while written < buf.len() {
+        match file.write(&buf[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(err) if written == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+    Ok(written)
+}
+
+pub fn sys_write(args: &mut SyscallStackFrame) {
+    let (mut fd, ptr, size) = (args.rdi, args.rsi, args.rcx);
+    // println!("[DEBUG] sys_write: to {fd} ptr 0x{ptr:x} size {size}");
+    if fd == STDIN_FD {
+        let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+        if crate::task::process::TASKS.lock()[current]
+            .as_ref()
+            .unwrap()
+            .legacy_stderr
+        {
+            fd = STDERR_FD;
+        }
+    }
+    let Some(open) = current_file(fd).filter(|open| open.writable()) else {
+        args.r10 = VfsError::BadDescriptor.errno() as u64;
+        return;
+    };
+    let mut file = open.file.lock();
+    if let Some(key) = file.write_blocks() {
+        drop(file);
+        if open.nonblocking() {
+            args.r10 = 0;
+        } else {
+            sched::block_and_restart(args, WaitReason::File { key });
+        }
+        return;
+    }
+    open.seek_for_write(&mut *file);
+    let buf = unsafe { core::slice::from_raw_parts(ptr as *const u8, size as usize) };
+    let written = write_some(&mut *file, buf);
+    open.update_offset(&mut *file);
+    args.r10 = match written {
+        Ok(written) => written as u64,
+        Err(err) => err.errno() as u64,
+    };
 }
 
 pub fn sys_fork(args: &mut SyscallStackFrame) {
@@ -127,8 +240,31 @@ pub fn sys_exit(args: &mut SyscallStackFrame) {
 }
 
 pub fn sys_read(args: &mut SyscallStackFrame) {
-    let res = crate::stdio::read_stdin().unwrap_or(0xff);
-    args.rcx = res as u64;
+    let Some(open) = current_file(STDIN_FD).filter(|open| open.readable()) else {
+        args.rcx = EOT as u64;
+        return;
+    };
+    let mut file = open.file.lock();
+    if let Some(key) = file.read_blocks() {
+        drop(file);
+        if open.nonblocking() {
+            args.rcx = 0xff;
+        } else {
+            sched::block_and_restart(args, WaitReason::File { key });
+        }
+        return;
+    }
+    let mut byte = [0];
+    open.seek_for_read(&mut *file);
+    let read = file.read(&mut byte);
+    open.update_offset(&mut *file);
+    // a terminal with nothing typed yet reports 0xff and the caller retries;
+    // anything else is at end of file
+    args.rcx = match read {
+        0 if file.is_terminal() => 0xff,
+        0 => EOT,
+        _ => byte[0],
+    } as u64;
 }
 
 pub fn sys_setfsbase(args: &mut SyscallStackFrame) {
@@ -195,51 +331,90 @@ pub fn sys_info(args: &mut SyscallStackFrame) {
     };
 }
 
+/// The open of the first binaries: `r10` says whether to create the file,
+/// and the file is always opened for reading and writing if the mount allows.
 pub fn sys_open(args: &mut SyscallStackFrame) {
-    let path = unsafe { core::str::from_raw_parts(args.rdi as *const u8, args.rcx as usize) };
-    let do_create = args.r10 != 0;
-    let tmp = if do_create {
-        crate::vfs::create_file_or_open_existing(path).ok()
+    let Some(path) = user_path(args.rdi, args.rcx) else {
+        args.rsi = u64::MAX;
+        return;
+    };
+    let flags = if args.r10 != 0 {
+        O_RDWR | O_CREAT
     } else {
-        crate::vfs::get_file(path).ok()
+        O_RDWR
     };
-    if let Some(file) = tmp {
-        let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
-        let mut tasks = crate::task::process::TASKS.lock();
-        let task = tasks[current].as_mut().unwrap();
-        if let Some((res, _)) = task.files.iter().enumerate().find(|x| x.1.is_none()) {
-            task.files[res] = Some(file);
-            args.rsi = res as u64;
+    let open = crate::vfs::open(&path, flags).or_else(|err| match err {
+        VfsError::ReadOnly if flags & O_CREAT == 0 => crate::vfs::open(&path, O_RDONLY),
+        err => Err(err),
+    });
+    args.rsi = open
+        .ok()
+        .and_then(install_file)
+        .map_or(u64::MAX, |fd| fd as u64);
+}
+
+/// Open the path at `rdi`, `rcx` with the `O_*` flags in `rsi` and return
+/// the file descriptor or a negative errno in `r10`. With `O_DIRECTORY` the
+/// descriptor is a directory handle as returned by `sys_opendir`.
+pub fn sys_open2(args: &mut SyscallStackFrame) {
+    let Some(path) = user_path(args.rdi, args.rcx) else {
+        args.r10 = VfsError::InvalidArgument.errno() as u64;
+        return;
+    };
+    let flags = args.rsi as usize;
+    let res = if flags & O_DIRECTORY != 0 {
+        if flags & !(O_DIRECTORY | O_NONBLOCK) != O_RDONLY {
+            Err(VfsError::InvalidArgument)
         } else {
-            args.rsi = u64::MAX;
+            crate::vfs::open_directory(&path)
+                .and_then(|directory| install_directory(directory).ok_or(VfsError::TooManyFiles))
         }
     } else {
-        args.rsi = u64::MAX;
-    }
+        crate::vfs::open(&path, flags)
+            .and_then(|open| install_file(open).ok_or(VfsError::TooManyFiles))
+    };
+    args.r10 = res.map_or_else(|err| err.errno(), |fd| fd as isize) as u64;
 }
 
 pub fn sys_read2(args: &mut SyscallStackFrame) {
     let buf = unsafe { core::slice::from_raw_parts_mut(args.rdi as *mut u8, args.rcx as usize) };
-    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
-    let mut tasks = crate::task::process::TASKS.lock();
-    let task = tasks[current].as_mut().unwrap();
-    task.files[args.rsi as usize]
-        .as_ref()
-        .unwrap()
-        .lock()
-        .read_exact(buf);
+    let Some(open) = current_file(args.rsi).filter(|open| open.readable()) else {
+        return;
+    };
+    let mut file = open.file.lock();
+    if let Some(key) = file.read_blocks() {
+        drop(file);
+        if !open.nonblocking() {
+            sched::block_and_restart(args, WaitReason::File { key });
+        }
+        return;
+    }
+    open.seek_for_read(&mut *file);
+    file.read_exact(buf);
+    open.update_offset(&mut *file);
 }
 
+/// Read up to `rcx` bytes; `r10` is the count, or `u64::MAX` if the file is
+/// not open for reading or a non-blocking read would block.
 pub fn sys_read3(args: &mut SyscallStackFrame) {
     let buf = unsafe { core::slice::from_raw_parts_mut(args.rdi as *mut u8, args.rcx as usize) };
-    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
-    let mut tasks = crate::task::process::TASKS.lock();
-    let task = tasks[current].as_mut().unwrap();
-    args.r10 = task.files[args.rsi as usize]
-        .as_ref()
-        .unwrap()
-        .lock()
-        .read(buf) as u64;
+    let Some(open) = current_file(args.rsi).filter(|open| open.readable()) else {
+        args.r10 = u64::MAX;
+        return;
+    };
+    let mut file = open.file.lock();
+    if let Some(key) = file.read_blocks() {
+        drop(file);
+        if open.nonblocking() {
+            args.r10 = u64::MAX;
+        } else {
+            sched::block_and_restart(args, WaitReason::File { key });
+        }
+        return;
+    }
+    open.seek_for_read(&mut *file);
+    args.r10 = file.read(buf) as u64;
+    open.update_offset(&mut *file);
 }
 
 pub fn sys_seek(args: &mut SyscallStackFrame) {
@@ -249,14 +424,7 @@ pub fn sys_seek(args: &mut SyscallStackFrame) {
         2 => SeekFrom::Start(args.rcx as usize),
         _ => return,
     };
-    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
-    let mut tasks = crate::task::process::TASKS.lock();
-    let task = tasks[current].as_mut().unwrap();
-    args.r10 = task.files[args.rsi as usize]
-        .as_ref()
-        .unwrap()
-        .lock()
-        .seek(pos) as u64;
+    args.r10 = current_file(args.rsi).map_or(u64::MAX, |open| open.seek(pos) as u64);
 }
 
 pub fn sys_close(args: &mut SyscallStackFrame) {
@@ -267,69 +435,67 @@ pub fn sys_close(args: &mut SyscallStackFrame) {
 }
 
 pub fn sys_remove(args: &mut SyscallStackFrame) {
-    let path = unsafe { core::str::from_raw_parts(args.rdi as *const u8, args.rcx as usize) };
-    crate::vfs::remove_file(path);
+    args.r10 = path_op(args.rdi, args.rcx, crate::vfs::remove_file);
+}
+
+/// Run `op` on the path at `ptr`, `len` and return 0 or a negative errno.
+fn path_op(ptr: u64, len: u64, op: impl FnOnce(&str) -> Result<(), VfsError>) -> u64 {
+    let res = user_path(ptr, len)
+        .ok_or(VfsError::InvalidArgument)
+        .and_then(|path| op(&path));
+    res.map_or_else(|err| err.errno(), |()| 0) as u64
 }
 
+/// Mount the filesystem on the device at `rsi`, `rdx` at `rdi`, `rcx`. The
+/// type at `r8`, `r9` is probed for if empty, and `r12`, `r13` are the
+/// options, as for `vfs::filesystems::mount`. For a type that needs no
+/// device, like `tmpfs`, the source is only a name.
 pub fn sys_mount(args: &mut SyscallStackFrame) {
-    let mountpoint = unsafe { core::str::from_raw_parts(args.rdi as *const u8, args.rcx as usize) };
-    match args.rsi {
-        0 => {
-            // 0 for AHCI
-            let block_device = crate::blockdev::ahci::AHCI.iter().nth(args.rdx as usize);
-            if let Some(block_device) = block_device
-                && let Ok(partition) = AhciPartition::new(block_device, args.r9 as usize)
-                && mount(Some(partition), mountpoint, crate::vfs::get_fat_fs).is_ok()
-            {
-                args.r10 = 0;
-            } else {
-                args.r10 = u64::MAX;
-            }
-        }
-        1 => {
-            let block_device = crate::blockdev::nvme::NVME
-                .iter()
-                .nth(args.rdx as usize)
-                .and_then(|device| device.into_iter().next());
-            if let Some(block_device) = block_device
-                && let Ok(partition) = NvmePartition::new(block_device, args.r9 as usize)
-                && mount(Some(partition), mountpoint, crate::vfs::get_fat_fs).is_ok()
-            {
-                args.r10 = 0;
-            } else {
-                args.r10 = u64::MAX;
-            }
-        }
-        2 => {
-            let block_device = crate::blockdev::usb::UsbBlockDevice::open(args.rdx as usize);
-            if let Some(block_device) = block_device
-                && let Ok(partition) = UsbPartition::new(block_device, args.r9 as usize)
-                && mount(Some(partition), mountpoint, crate::vfs::get_fat_fs).is_ok()
-            {
-                args.r10 = 0;
-            } else {
-                args.r10 = u64::MAX;
-            }
-        }
-        _ => args.r10 = u64::MAX,
-    }
+    let (Some(mountpoint), Some(source), Some(fs_type), Some(options)) = (
+        user_path(args.rdi, args.rcx),
+        user_str(args.rsi, args.rdx),
+        user_str(args.r8, args.r9),
+        user_str(args.r12, args.r13),
+    ) else {
+        args.r10 = MountError::InvalidArgument.errno() as u64;
+        return;
+    };
+    let fs_type = Some(fs_type).filter(|fs_type| !fs_type.is_empty());
+    let source = if crate::vfs::filesystems::needs_device(fs_type) {
+        user_path(args.rsi, args.rdx).unwrap_or_default()
+    } else {
+        String::from(source)
+    };
+    let res = crate::vfs::filesystems::mount(&source, fs_type, &mountpoint, options);
+    args.r10 = res.map_or_else(|err| err.errno(), |()| 0) as u64;
+}
+
+pub fn sys_umount(args: &mut SyscallStackFrame) {
+    let Some(mountpoint) = user_path(args.rdi, args.rcx) else {
+        args.r10 = MountError::InvalidArgument.errno() as u64;
+        return;
+    };
+    args.r10 = crate::vfs::umount(&mountpoint).map_or_else(|err| err.errno(), |()| 0) as u64;
 }
 
 pub fn sys_opendir(args: &mut SyscallStackFrame) {
-    let path = unsafe { core::str::from_raw_parts(args.rdi as *const u8, args.rcx as usize) };
-    if let Ok(directory) = crate::vfs::get_directory(path) {
-        let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
-        let mut tasks = crate::task::process::TASKS.lock();
-        let task = tasks[current].as_mut().unwrap();
-        if let Some((res, _)) = task.directories.iter().enumerate().find(|x| x.1.is_none()) {
-            task.directories[res] = Some(directory);
-            args.rsi = res as u64;
-        } else {
-            args.rsi = u64::MAX;
-        }
-    } else {
+    let Some(path) = user_path(args.rdi, args.rcx) else {
         args.rsi = u64::MAX;
-    }
+        return;
+    };
+    args.rsi = crate::vfs::get_directory(&path)
+        .ok()
+        .and_then(install_directory)
+        .map_or(u64::MAX, |fd| fd as u64);
+}
+
+fn install_directory(directory: Arc<Mutex<dyn VfsDirHandle>>) -> Option<usize> {
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut tasks = crate::task::process::TASKS.lock();
+    let task = tasks[current].as_mut().unwrap();
+    let fd = task.directories.iter().position(Option::is_none)?;
+    task.directories[fd] = Some(directory);
+    Some(fd)
 }
 
 pub fn sys_getdents(args: &mut SyscallStackFrame) {
@@ -355,3 +521,225 @@ pub fn sys_closedir(args: &mut SyscallStackFrame) {
 pub fn sys_ipc(args: &mut SyscallStackFrame) {
     crate::task::ipc::syscall(args);
 }
+
+pub fn sys_pipe(args: &mut SyscallStackFrame) {
+    let (reader, writer) = crate::vfs::pipe::new();
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut tasks = crate::task::process::TASKS.lock();
+    let task = tasks[current].as_mut().unwrap();
+    let mut free = (0..task.files.len()).filter(|&fd| task.files[fd].is_none());
+    if let (Some(read_fd), Some(write_fd)) = (free.next(), free.next()) {
+        task.files[read_fd] = Some(OpenFile::new(reader, O_RDONLY));
+        task.files[write_fd] = Some(OpenFile::new(writer, O_WRONLY));
+        args.rsi = read_fd as u64;
+        args.rdx = write_fd as u64;
+    } else {
+        // dropping the ends only queues their wakeups, so the lock is fine
+        args.rsi = u64::MAX;
+        args.rdx = u64::MAX;
+    }
+}
+
+/// Make `rdx` refer to the same open file description as `rsi`, closing
+/// what `rdx` referred to.
+pub fn sys_dup2(args: &mut SyscallStackFrame) {
+    let (old_fd, new_fd) = (args.rsi as usize, args.rdx as usize);
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut tasks = crate::task::process::TASKS.lock();
+    let task = tasks[current].as_mut().unwrap();
+    match task.files.get(old_fd).cloned().flatten() {
+        Some(file) if new_fd < task.files.len() => {
+            task.files[new_fd] = Some(file);
+            args.r10 = new_fd as u64;
+        }
+        _ => args.r10 = u64::MAX,
+    }
+}
+
+/// Make the lowest free file descriptor refer to the same open file
+/// description as `rsi`. Returns the new descriptor or a negative errno in
+/// `r10`.
+pub fn sys_dup(args: &mut SyscallStackFrame) {
+    args.r10 = dup_from(args.rsi, 0) as u64;
+}
+
+fn dup_from(fd: u64, lowest: usize) -> isize {
+    let Some(open) = current_file(fd) else {
+        return VfsError::BadDescriptor.errno();
+    };
+    match install_file_from(open, lowest) {
+        Some(new_fd) => new_fd as isize,
+        None if lowest >= crate::task::process::MAX_FILES => VfsError::InvalidArgument.errno(),
+        None => VfsError::TooManyFiles.errno(),
+    }
+}
+
+const F_DUPFD: u64 = 0;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+
+/// `F_DUPFD` duplicates `rsi` to the lowest free descriptor at or above
+/// `rdx`, `F_GETFL` returns the access mode and status flags and `F_SETFL`
+/// sets `O_APPEND` and `O_NONBLOCK` from `rdx`. The command is in `rdi`; the
+/// result or a negative errno is returned in `r10`.
+pub fn sys_fcntl(args: &mut SyscallStackFrame) {
+    let res = match args.rdi {
+        F_DUPFD => dup_from(args.rsi, args.rdx as usize),
+        F_GETFL | F_SETFL => match current_file(args.rsi) {
+            Some(open) if args.rdi == F_GETFL => open.flags() as isize,
+            Some(open) => {
+                open.set_status_flags(args.rdx as usize);
+                0
+            }
+            None => VfsError::BadDescriptor.errno(),
+        },
+        _ => VfsError::InvalidArgument.errno(),
+    };
+    args.r10 = res as u64;
+}
+
+/// Read up to `rcx` bytes into `rdi` from offset `rdx` of `rsi` without
+/// moving the offset of the file descriptor. Returns the count or a negative
+/// errno in `r10`.
+pub fn sys_pread(args: &mut SyscallStackFrame) {
+    let buf = unsafe { core::slice::from_raw_parts_mut(args.rdi as *mut u8, args.rcx as usize) };
+    let res = positional_file(args.rsi, OpenFile::readable)
+        .map(|open| open.file.lock().read_at(args.rdx as usize, buf));
+    args.r10 = res.map_or_else(|err| err.errno(), |read| read as isize) as u64;
+}
+
+/// Like `sys_pread`, for writing. `O_APPEND` is ignored.
+pub fn sys_pwrite(args: &mut SyscallStackFrame) {
+    let buf = unsafe { core::slice::from_raw_parts(args.rdi as *const u8, args.rcx as usize) };
+    let res = positional_file(args.rsi, OpenFile::writable)
+        .map(|open| open.file.lock().write_at(args.rdx as usize, buf));
+    args.r10 = res.map_or_else(|err| err.errno(), |written| written as isize) as u64;
+}
+
+/// Write every dirty cached block back to its device. Returns 0 or a
+/// negative errno in `r10`.
+pub fn sys_sync(args: &mut SyscallStackFrame) {
+    let res = crate::blockdev::cache::sync().map_or(VfsError::Io.errno(), |()| 0);
+    args.r10 = res as u64;
+}
+
+/// The file behind `fd` if it has an offset and `allowed` by its access mode.
+fn positional_file(fd: u64, allowed: fn(&OpenFile) -> bool) -> Result<Arc<OpenFile>, VfsError> {
+    let open = current_file(fd)
+        .filter(|open| allowed(open))
+        .ok_or(VfsError::BadDescriptor)?;
+    if open.seekable() {
+        Ok(open)
+    } else {
+        Err(VfsError::NotSeekable)
+    }
+}
+
+pub fn sys_chdir(args: &mut SyscallStackFrame) {
+    let Some(path) = user_path(args.rdi, args.rcx) else {
+        args.r10 = u64::MAX;
+        return;
+    };
+    if crate::vfs::get_directory(&path).is_err() {
+        args.r10 = u64::MAX;
+        return;
+    }
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let mut tasks = crate::task::process::TASKS.lock();
+    tasks[current].as_mut().unwrap().cwd = path;
+    args.r10 = 0;
+}
+
+/// Copy the working directory to the buffer at `rdi` of `rcx` bytes and
+/// return its length, or `u64::MAX` if it does not fit.
+pub fn sys_getcwd(args: &mut SyscallStackFrame) {
+    let buf = unsafe { core::slice::from_raw_parts_mut(args.rdi as *mut u8, args.rcx as usize) };
+    let current = crate::task::sched::CURRENT_TASK_ID.load(Ordering::Relaxed);
+    let tasks = crate::task::process::TASKS.lock();
+    let cwd = tasks[current].as_ref().unwrap().cwd.as_bytes();
+    args.r10 = match buf.get_mut(..cwd.len()) {
+        Some(dest) => {
+            dest.copy_from_slice(cwd);
+            cwd.len() as u64
+        }
+        None => u64::MAX,
+    };
+}
+
+/// Write the metadata of the path at `rdi`, `rcx` to the `Metadata` at `rsi`.
+pub fn sys_stat(args: &mut SyscallStackFrame) {
+    let metadata = user_path(args.rdi, args.rcx).and_then(|path| crate::vfs::metadata(&path).ok());
+    args.r10 = put_metadata(args.rsi, metadata);
+}
+
+/// Like `sys_stat`, but a symbolic link is described rather than followed.
+pub fn sys_lstat(args: &mut SyscallStackFrame) {
+    let metadata =
+        user_path(args.rdi, args.rcx).and_then(|path| crate::vfs::link_metadata(&path).ok());
+    args.r10 = put_metadata(args.rsi, metadata);
+}
+
+/// Copy the target of the symbolic link at `rdi`, `rcx` to the buffer at
+/// `rsi` of `rdx` bytes, cut to fit. Returns the length copied or a
+/// negative errno in `r10`.
+pub fn sys_readlink(args: &mut SyscallStackFrame) {
+    let buf = unsafe { core::slice::from_raw_parts_mut(args.rsi as *mut u8, args.rdx as usize) };
+    let res = user_path(args.rdi, args.rcx)
+        .ok_or(VfsError::InvalidArgument)
+        .and_then(|path| crate::vfs::read_link(&path))
+        .map(|target| {
+            let len = target.len().min(buf.len());
+            buf[..len].copy_from_slice(&target.as_bytes()[..len]);
+            len as isize
+        });
+    args.r10 = res.unwrap_or_else(VfsError::errno) as u64;
+}
+
+/// Create a symbolic link at `rsi`, `rdx` to the target at `rdi`, `rcx`,
+/// which is stored as given.
+pub fn sys_symlink(args: &mut SyscallStackFrame) {
+    let Some(target) = user_str(args.rdi, args.rcx) else {
+        args.r10 = VfsError::InvalidArgument.errno() as u64;
+        return;
+    };
+    args.r10 = path_op(args.rsi, args.rdx, |path| crate::vfs::symlink(target, path));
+}
+
+/// Write the metadata of file descriptor `rsi` to the `Metadata` at `rdi`.
+pub fn sys_fstat(args: &mut SyscallStackFrame) {
+    let metadata = current_file(args.rsi).map(|open| open.file.lock().metadata());
+    args.r10 = put_metadata(args.rdi, metadata);
+}
+
+fn put_metadata(ptr: u64, metadata: Option<Metadata>) -> u64 {
+    match metadata {
+        Some(metadata) => {
+            unsafe { (ptr as *mut Metadata).write_unaligned(metadata) };
+            0
+        }
+        None => u64::MAX,
+    }
+}
+
+pub fn sys_mkdir(args: &mut SyscallStackFrame) {
+    args.r10 = path_op(args.rdi, args.rcx, crate::vfs::create_directory);
+}
+
+pub fn sys_rmdir(args: &mut SyscallStackFrame) {
+    args.r10 = path_op(args.rdi, args.rcx, crate::vfs::remove_directory);
+}
+
+/// Rename the path at `rdi`, `rcx` to the one at `rsi`, `rdx`.
+pub fn sys_rename(args: &mut SyscallStackFrame) {
+    let Some(to) = user_path(args.rsi, args.rdx) else {
+        args.r10 = VfsError::InvalidArgument.errno() as u64;
+        return;
+    };
+    args.r10 = path_op(args.rdi, args.rcx, |from| crate::vfs::rename(from, &to));
+}
+
+/// Set the length of the file at `rdi`, `rcx` to `rsi`.
+pub fn sys_truncate(args: &mut SyscallStackFrame) {
+    let len = args.rsi;
+    args.r10 = path_op(args.rdi, args.rcx, |path| crate::vfs::truncate(path, len));
+}
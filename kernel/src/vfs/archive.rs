@@ -0,0 +1,213 @@
+//! The initrd as a newc CPIO or ustar archive.
+//!
+//! Such an initrd is unpacked into a tmpfs, so the root filesystem can be
+//! written to as with a FAT initrd. Only files and directories are kept;
+//! other entries, like symbolic links and devices, are left out. Paths too
+//! long for a ustar header are read from GNU long name entries and pax
+//! extended headers.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{VfsDirectory, VfsError};
+use crate::blockdev::ramdisk::RamDisk;
+
+const CPIO_MAGIC: &[u8] = b"07070";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const CPIO_S_IFMT: u32 = 0o170000;
+const CPIO_S_IFDIR: u32 = 0o040000;
+const CPIO_S_IFREG: u32 = 0o100000;
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+enum Kind {
+    File,
+    Directory,
+}
+
+struct Entry<'a> {
+    /// As in the archive, relative to its root.
+    path: String,
+    kind: Kind,
+    data: &'a [u8],
+}
+
+/// Whether `data` starts like one of the archives read here.
+pub(super) fn is_archive(data: &[u8]) -> bool {
+    is_cpio(data) || is_tar(data)
+}
+
+fn is_cpio(data: &[u8]) -> bool {
+    // 070701 without and 070702 with checksums
+    data.len() >= CPIO_HEADER_SIZE && data.starts_with(CPIO_MAGIC) && matches!(data[5], b'1' | b'2')
+}
+
+fn is_tar(data: &[u8]) -> bool {
+    data.get(257..262) == Some(TAR_MAGIC)
+}
+
+/// Unpack the archive in `data` into a new tmpfs.
+pub(super) fn unpack(data: &[u8]) -> Result<Arc<dyn VfsDirectory>, ()> {
+    let entries = if is_cpio(data) {
+        cpio_entries(data)?
+    } else {
+        tar_entries(data)?
+    };
+    let fs = super::tmpfs::get_fs(None::<RamDisk>)?;
+    for entry in entries {
+        let path = super::path::normalize(&format!("/{}", entry.path));
+        if path == "/" {
+            continue;
+        }
+        // archives need not list every directory before what is in it
+        for (end, _) in path.match_indices('/').skip(1) {
+            create_directory(&*fs, &path[..end])?;
+        }
+        match entry.kind {
+            Kind::Directory => create_directory(&*fs, &path)?,
+            Kind::File => fs
+                .create_file_or_open_existing(&path)?
+                .lock()
+                .write_all(entry.data),
+        }
+    }
+    Ok(fs)
+}
+
+fn create_directory(fs: &dyn VfsDirectory, path: &str) -> Result<(), ()> {
+    match fs.create_directory(path) {
+        Ok(()) | Err(VfsError::AlreadyExists) => Ok(()),
+        Err(_) => Err(()),
+    }
+}
+
+fn cpio_entries(data: &[u8]) -> Result<Vec<Entry<'_>>, ()> {
+    let field = |header: &[u8], index: usize| {
+        let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+        core::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(())
+    };
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = data.get(offset..offset + CPIO_HEADER_SIZE).ok_or(())?;
+        if !is_cpio(header) {
+            return Err(());
+        }
+        let mode = field(header, 1)?;
+        let size = field(header, 6)? as usize;
+        let name_size = field(header, 11)? as usize;
+        let name_start = offset + CPIO_HEADER_SIZE;
+        // the name is followed by a NUL
+        let name = data
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(())?;
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let file_data = data.get(data_start..data_start + size).ok_or(())?;
+        let kind = match mode & CPIO_S_IFMT {
+            CPIO_S_IFDIR => Some(Kind::Directory),
+            CPIO_S_IFREG => Some(Kind::File),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            entries.push(Entry {
+                path: String::from(name),
+                kind,
+                data: file_data,
+            });
+        }
+        offset = (data_start + size).next_multiple_of(4);
+    }
+}
+
+fn tar_entries(data: &[u8]) -> Result<Vec<Entry<'_>>, ()> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // from a GNU long name or pax extended header, for the entry after it
+    let mut long_path = None;
+    // the archive ends with two zero blocks, or just the end of the data
+    while let Some(header) = data.get(offset..offset + TAR_BLOCK) {
+        if header.iter().all(|&c| c == 0) {
+            break;
+        }
+        if !is_tar(header) {
+            return Err(());
+        }
+        let size = tar_field(&header[124..136])?.trim_matches(' ');
+        let size = usize::from_str_radix(size, 8).map_err(|_| ())?;
+        let data_start = offset + TAR_BLOCK;
+        let file_data = data.get(data_start..data_start + size).ok_or(())?;
+        let (name, prefix) = (tar_field(&header[..100])?, tar_field(&header[345..500])?);
+        let kind = match header[156] {
+            b'0' | 0 => Some(Kind::File),
+            b'5' => Some(Kind::Directory),
+            b'L' => {
+                long_path = Some(String::from(tar_field(file_data)?));
+                None
+            }
+            b'x' => {
+                long_path = pax_path(file_data)?.or(long_path);
+                None
+            }
+            // links, devices and the like are skipped, with any long name
+            // they had
+            _ => {
+                long_path = None;
+                None
+            }
+        };
+        if let Some(kind) = kind {
+            // a long path is split at a `/` between the two fields
+            let path = long_path.take().unwrap_or_else(|| {
+                if prefix.is_empty() {
+                    String::from(name)
+                } else {
+                    format!("{prefix}/{name}")
+                }
+            });
+            entries.push(Entry {
+                path,
+                kind,
+                data: file_data,
+            });
+        }
+        offset = data_start + size.next_multiple_of(TAR_BLOCK);
+    }
+    Ok(entries)
+}
+
+/// The `path` in the records of a pax extended header, which are lines
+/// like `<length> <key>=<value>`, the length counting the whole line.
+fn pax_path(mut records: &[u8]) -> Result<Option<String>, ()> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|&c| c == b' ').ok_or(())?;
+        let len = core::str::from_utf8(&records[..space]).map_err(|_| ())?;
+        let len = len.parse::<usize>().map_err(|_| ())?;
+        let record = records
+            .get(space + 1..len)
+            .and_then(|record| record.strip_suffix(b"\n"))
+            .ok_or(())?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from(core::str::from_utf8(value).map_err(|_| ())?));
+        }
+        records = &records[len..];
+    }
+    Ok(path)
+}
+
+/// A text field of a tar header, which ends at the first NUL if any.
+fn tar_field(field: &[u8]) -> Result<&str, ()> {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| ())
+}
@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use spin::Mutex;
 
-use crate::vfs::{SeekFrom, VfsFile};
+use crate::vfs::{SeekFrom, VfsError, VfsFile};
 
 struct CmdlineDevice {
     pos: usize,
@@ -23,8 +23,8 @@ impl VfsFile for CmdlineDevice {
         len
     }
 
-    fn write(&mut self, _buf: &[u8]) -> usize {
-        0
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
     }
 
     fn seek(&mut self, pos: SeekFrom) -> usize {
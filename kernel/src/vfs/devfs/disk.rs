@@ -1,17 +1,18 @@
 use alloc::sync::Arc;
 use spin::Mutex;
 
+use crate::blockdev::partition;
 use crate::vfs::VfsFile;
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
-    let Some(number) = path.strip_prefix("/disk") else {
+    let (disk, part) = partition::split_node_name(path.strip_prefix('/').ok_or(())?);
+    let Some(number) = disk.strip_prefix("disk") else {
         return Err(());
     };
 
-    Ok(Arc::new(Mutex::new(
-        crate::blockdev::ahci::AHCI
-            .iter()
-            .nth(number.parse().map_err(|_| ())?)
-            .ok_or(())?,
-    )))
+    let device = crate::blockdev::ahci::AHCI
+        .iter()
+        .nth(number.parse().map_err(|_| ())?)
+        .ok_or(())?;
+    partition::open_node(device, disk, part)
 }
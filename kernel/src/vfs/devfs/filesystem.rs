@@ -1,8 +1,9 @@
 use alloc::sync::Arc;
 use alloc::vec;
+use alloc::vec::Vec;
 use spin::Mutex;
 
-use crate::vfs::{DirEntry, SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsFile};
+use crate::vfs::{DirEntry, SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsError, VfsFile};
 
 pub(super) struct DevFileSystem;
 
@@ -16,6 +17,8 @@ impl VfsDirectory for DevFileSystem {
             Ok(file)
         } else if let Ok(file) = super::initrd::open(path) {
             Ok(file)
+        } else if let Ok(file) = super::stdin::open(path) {
+            Ok(file)
         } else if let Ok(file) = super::stdout::open(path) {
             Ok(file)
         } else if let Ok(file) = super::stderr::open(path) {
@@ -42,6 +45,7 @@ impl VfsDirectory for DevFileSystem {
 
         let mut entries = vec![
             DirEntry::new(false, "initrd"),
+            DirEntry::new(false, "stdin"),
             DirEntry::new(false, "stdout"),
             DirEntry::new(false, "stderr"),
             DirEntry::new(false, "tty"),
@@ -51,21 +55,28 @@ impl VfsDirectory for DevFileSystem {
             DirEntry::new(false, "power"),
         ];
 
+        let mut disks = Vec::new();
         for (idx, _) in crate::blockdev::ahci::AHCI.iter().enumerate() {
-            entries.push(DirEntry::new(false, &alloc::format!("disk{idx}")));
+            disks.push(alloc::format!("disk{idx}"));
         }
 
         for (device_idx, device) in crate::blockdev::nvme::NVME.iter().enumerate() {
             for namespace_idx in 0..device.len() {
-                entries.push(DirEntry::new(
-                    false,
-                    &alloc::format!("nvme{device_idx}-{namespace_idx}"),
-                ));
+                disks.push(alloc::format!("nvme{device_idx}-{namespace_idx}"));
             }
         }
 
         for (id, _) in crate::blockdev::usb::online_devices() {
-            entries.push(DirEntry::new(false, &crate::blockdev::usb::name(id)));
+            disks.push(crate::blockdev::usb::name(id));
+        }
+
+        // every disk is followed by its partitions
+        let partitions = crate::blockdev::partition::table::list(None);
+        for disk in disks {
+            entries.push(DirEntry::new(false, &disk));
+            for partition in partitions.iter().filter(|partition| partition.disk == disk) {
+                entries.push(DirEntry::new(false, &partition.name()));
+            }
         }
 
         Ok(Arc::new(Mutex::new(SnapshotDirectory::new(entries))))
@@ -75,7 +86,28 @@ impl VfsDirectory for DevFileSystem {
         self.file(path)
     }
 
-    fn remove(&self, _path: &str) -> bool {
-        false
+    // the entries are generated by the kernel and cannot be changed
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn create_directory(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn remove_directory(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn truncate(&self, _path: &str, _len: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "devfs"
     }
 }
@@ -7,6 +7,7 @@ mod pcspk;
 mod power;
 mod serial;
 mod stderr;
+mod stdin;
 mod stdout;
 mod tty;
 
@@ -1,18 +1,18 @@
 use alloc::sync::Arc;
 use spin::Mutex;
 
+use crate::blockdev::partition;
 use crate::vfs::VfsFile;
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
-    if !path.starts_with("/nvme") {
+    let (disk, part) = partition::split_node_name(path.strip_prefix('/').ok_or(())?);
+    let Some(name) = disk.strip_prefix("nvme") else {
         return Err(());
-    }
+    };
 
-    let res = path.find('-').ok_or(())?;
-    let device = path[5..res].parse::<usize>().map_err(|_| ())?;
-    let namespace = path[(res + 1)..].parse::<usize>().map_err(|_| ())?;
-    Ok(Arc::new(Mutex::new({
-        let v = crate::blockdev::nvme::NVME.iter().nth(device).ok_or(())?;
-        v[namespace].clone()
-    })))
+    let res = name.find('-').ok_or(())?;
+    let device = name[..res].parse::<usize>().map_err(|_| ())?;
+    let namespace = name[(res + 1)..].parse::<usize>().map_err(|_| ())?;
+    let v = crate::blockdev::nvme::NVME.iter().nth(device).ok_or(())?;
+    partition::open_node(v.get(namespace).ok_or(())?.clone(), disk, part)
 }
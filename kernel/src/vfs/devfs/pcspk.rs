@@ -2,7 +2,7 @@ use alloc::sync::Arc;
 use spin::Mutex;
 
 use crate::sound::pcspk;
-use crate::vfs::{SeekFrom, VfsFile};
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
 
 struct PcspkDevice;
 
@@ -15,22 +15,26 @@ impl VfsFile for PcspkDevice {
         0
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
         if let Ok(s) = core::str::from_utf8(buf) {
             if s.trim() == "stop" {
                 pcspk::stop_sound()
             } else if let Ok(freq) = s.trim().parse() {
                 pcspk::play_sound(freq)
             }
-            buf.len()
+            Ok(buf.len())
         } else {
-            0
+            Err(VfsError::InvalidArgument)
         }
     }
 
     fn seek(&mut self, _pos: SeekFrom) -> usize {
         0
     }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::char_device()
+    }
 }
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
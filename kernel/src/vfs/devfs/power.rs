@@ -1,8 +1,9 @@
 use alloc::sync::Arc;
 use spin::Mutex;
 
+use crate::blockdev::cache;
 use crate::power;
-use crate::vfs::{SeekFrom, VfsFile};
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
 
 struct PowerDevice;
 
@@ -15,22 +16,30 @@ impl VfsFile for PowerDevice {
         0
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
         if let Ok(s) = core::str::from_utf8(buf) {
+            if s.trim() == "poweroff" || s.trim() == "reboot" {
+                // the writes still in the block cache would be lost
+                let _ = cache::sync();
+            }
             if s.trim() == "poweroff" {
                 power::poweroff();
             } else if s.trim() == "reboot" {
                 power::reboot();
             }
-            buf.len()
+            Ok(buf.len())
         } else {
-            0
+            Err(VfsError::InvalidArgument)
         }
     }
 
     fn seek(&mut self, _pos: SeekFrom) -> usize {
         0
     }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::char_device()
+    }
 }
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
@@ -2,7 +2,7 @@ use alloc::sync::Arc;
 use core::sync::atomic::Ordering;
 use spin::Mutex;
 
-use crate::vfs::{SeekFrom, VfsFile};
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
 
 struct SerialDevice;
 
@@ -28,17 +28,25 @@ impl VfsFile for SerialDevice {
         count
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
         if !crate::console::serial::SERIAL_OK.load(Ordering::Relaxed) {
-            return buf.len();
+            return Ok(buf.len());
         }
         crate::console::serial::write_bytes(buf);
-        buf.len()
+        Ok(buf.len())
     }
 
     fn seek(&mut self, _pos: SeekFrom) -> usize {
         0
     }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::char_device()
+    }
 }
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use spin::Mutex;
 
-use crate::vfs::{SeekFrom, VfsFile};
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
 
 struct StderrDevice;
 
@@ -14,14 +14,22 @@ impl VfsFile for StderrDevice {
         0
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
         crate::console::write_err(buf);
-        buf.len()
+        Ok(buf.len())
     }
 
     fn seek(&mut self, _pos: SeekFrom) -> usize {
         0
     }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::char_device()
+    }
 }
 
 pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
@@ -0,0 +1,51 @@
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
+
+/// Console input, read from the TTY and/or serial line depending on `stdio=`.
+struct StdinDevice;
+
+impl VfsFile for StdinDevice {
+    fn size(&mut self) -> usize {
+        0
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match crate::stdio::read_stdin() {
+                Some(b) => {
+                    buf[count] = b;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> usize {
+        0
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::char_device()
+    }
+}
+
+pub(super) fn open(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
+    if path == "/stdin" {
+        Ok(Arc::new(Mutex::new(StdinDevice)))
+    } else {
+        Err(())
+    }
+}
@@ -0,0 +1,1332 @@
+//! The ext2 filesystem.
+//!
+//! Filesystems of revision 0 and 1 are supported as `mkfs.ext2` makes them.
+//! Those with incompatible features other than file types in directory
+//! entries are refused; those with unknown read-only compatible features,
+//! or that were not unmounted cleanly, can only be read.
+//!
+//! Paths given to this filesystem contain no symbolic links but perhaps the
+//! last component: the VFS replaces them before a path gets here.
+//!
+//! An inode whose last name is removed while files are open on it keeps its
+//! blocks until the last of them is closed.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use fatfs::ReadWriteSeek;
+use spin::Mutex;
+
+use super::{
+    ATTR_READ_ONLY, DIRENT_NAME_CAP, DirEntry, KIND_BLOCK_DEVICE, KIND_CHAR_DEVICE, KIND_DIRECTORY,
+    KIND_FILE, KIND_PIPE, KIND_SYMLINK, Metadata, SeekFrom, SnapshotDirectory, VfsDirHandle,
+    VfsDirectory, VfsError, VfsFile,
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT: u32 = 2;
+
+/// Superblock fields by byte offset.
+const SB_INODES_COUNT: usize = 0;
+const SB_BLOCKS_COUNT: usize = 4;
+const SB_FREE_BLOCKS_COUNT: usize = 12;
+const SB_FREE_INODES_COUNT: usize = 16;
+const SB_FIRST_DATA_BLOCK: usize = 20;
+const SB_LOG_BLOCK_SIZE: usize = 24;
+const SB_BLOCKS_PER_GROUP: usize = 32;
+const SB_INODES_PER_GROUP: usize = 40;
+const SB_WTIME: usize = 48;
+const SB_MAGIC: usize = 56;
+const SB_STATE: usize = 58;
+const SB_REV_LEVEL: usize = 76;
+const SB_FIRST_INO: usize = 84;
+const SB_INODE_SIZE: usize = 88;
+const SB_FEATURE_INCOMPAT: usize = 96;
+const SB_FEATURE_RO_COMPAT: usize = 100;
+
+/// Revision 0 has fixed inodes.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+const STATE_VALID: u16 = 1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+/// Group descriptor fields by byte offset.
+const GROUP_DESC_SIZE: usize = 32;
+const BG_BLOCK_BITMAP: usize = 0;
+const BG_INODE_BITMAP: usize = 4;
+const BG_INODE_TABLE: usize = 8;
+const BG_FREE_BLOCKS_COUNT: usize = 12;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFLNK: u16 = 0xa000;
+const S_IFREG: u16 = 0x8000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFIFO: u16 = 0x1000;
+
+/// The directory is indexed by a hash tree, which is not kept up to date
+/// here; clearing the flag makes Linux fall back to a linear search.
+const INDEX_FL: u32 = 0x1000;
+
+/// File types in directory entries.
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const DIRECT_BLOCKS: u64 = 12;
+/// Symbolic links shorter than this keep their target in the inode.
+const FAST_SYMLINK_MAX: usize = 60;
+
+fn get16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn get32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn put16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], at: usize, value: u32) {
+    buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// An inode as stored on disk. Only the first 128 bytes are interpreted;
+/// the rest is written back as it was read.
+struct Inode(Vec<u8>);
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        get16(&self.0, 0)
+    }
+
+    fn kind(&self) -> u16 {
+        self.mode() & S_IFMT
+    }
+
+    fn is_dir(&self) -> bool {
+        self.kind() == S_IFDIR
+    }
+
+    fn size(&self) -> u64 {
+        let high = if self.kind() == S_IFREG {
+            get32(&self.0, 108) as u64
+        } else {
+            0
+        };
+        get32(&self.0, 4) as u64 | high << 32
+    }
+
+    fn set_size(&mut self, size: u64) {
+        put32(&mut self.0, 4, size as u32);
+        if self.kind() == S_IFREG {
+            put32(&mut self.0, 108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        get16(&self.0, 26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        put16(&mut self.0, 26, links);
+    }
+
+    /// The blocks in use, in 512-byte sectors.
+    fn sectors(&self) -> u32 {
+        get32(&self.0, 28)
+    }
+
+    fn add_sectors(&mut self, delta: i32) {
+        let sectors = self.sectors().wrapping_add_signed(delta);
+        put32(&mut self.0, 28, sectors);
+    }
+
+    fn flags(&self) -> u32 {
+        get32(&self.0, 32)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        put32(&mut self.0, 32, flags);
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        get32(&self.0, 40 + slot * 4)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        put32(&mut self.0, 40 + slot * 4, block);
+    }
+
+    /// Whether this is a symbolic link that keeps its target in `i_block`.
+    fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let acl_sectors = if get32(&self.0, 104) != 0 {
+            block_size as u32 / 512
+        } else {
+            0
+        };
+        self.kind() == S_IFLNK && self.sectors() == acl_sectors
+    }
+
+    fn metadata(&self) -> Metadata {
+        let mut metadata = Metadata::file(self.size());
+        metadata.kind = match self.kind() {
+            S_IFDIR => KIND_DIRECTORY,
+            S_IFLNK => KIND_SYMLINK,
+            S_IFCHR => KIND_CHAR_DEVICE,
+            S_IFBLK => KIND_BLOCK_DEVICE,
+            S_IFIFO => KIND_PIPE,
+            _ => KIND_FILE,
+        };
+        if self.mode() & 0o222 == 0 {
+            metadata.attributes |= ATTR_READ_ONLY;
+        }
+        metadata.mode = (self.mode() & 0o7777) as u32;
+        metadata.uid = get16(&self.0, 2) as u32 | (get16(&self.0, 120) as u32) << 16;
+        metadata.gid = get16(&self.0, 24) as u32 | (get16(&self.0, 122) as u32) << 16;
+        metadata.accessed = get32(&self.0, 8) as u64;
+        metadata.modified = get32(&self.0, 16) as u64;
+        metadata
+    }
+}
+
+/// A name in a directory, and where its record is.
+struct Entry {
+    ino: u32,
+    file_type: u8,
+    name: Vec<u8>,
+    /// The device block holding the record and its offset there.
+    block: u32,
+    offset: usize,
+    /// The offset of the record before it in the same block.
+    prev: Option<usize>,
+}
+
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+struct Ext2<T: ReadWriteSeek> {
+    device: T,
+    /// The superblock as read, with the free counts kept up to date.
+    superblock: Vec<u8>,
+    groups: Vec<Group>,
+    block_size: usize,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    first_ino: u32,
+    /// Whether directory entries record the file type.
+    filetype: bool,
+    large_file: bool,
+    read_only: bool,
+    /// Whether the superblock is marked as in use, which is undone when the
+    /// filesystem is dropped.
+    marked: bool,
+    /// How many files are open on each inode.
+    open: BTreeMap<u32, usize>,
+}
+
+impl<T: ReadWriteSeek> Ext2<T> {
+    fn new(mut device: T) -> Result<Self, VfsError> {
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        read(&mut device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        let log_block_size = get32(&superblock, SB_LOG_BLOCK_SIZE);
+        if get16(&superblock, SB_MAGIC) != MAGIC || log_block_size > 6 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let block_size = 1024 << log_block_size;
+        let (inode_size, first_ino, incompat, ro_compat) = if get32(&superblock, SB_REV_LEVEL) == 0
+        {
+            (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO, 0, 0)
+        } else {
+            (
+                get16(&superblock, SB_INODE_SIZE) as usize,
+                get32(&superblock, SB_FIRST_INO),
+                get32(&superblock, SB_FEATURE_INCOMPAT),
+                get32(&superblock, SB_FEATURE_RO_COMPAT),
+            )
+        };
+        if incompat & !INCOMPAT_FILETYPE != 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+        {
+            return Err(VfsError::Unsupported);
+        }
+        let blocks_per_group = get32(&superblock, SB_BLOCKS_PER_GROUP);
+        let inodes_per_group = get32(&superblock, SB_INODES_PER_GROUP);
+        let first_data_block = get32(&superblock, SB_FIRST_DATA_BLOCK);
+        let blocks = get32(&superblock, SB_BLOCKS_COUNT);
+        // a group's bitmaps are one block each
+        let bits_per_block = 8 * block_size as u32;
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks_per_group > bits_per_block
+            || inodes_per_group > bits_per_block
+            || blocks <= first_data_block
+        {
+            return Err(VfsError::InvalidArgument);
+        }
+        let group_count = (blocks - first_data_block).div_ceil(blocks_per_group) as usize;
+        let inodes = get32(&superblock, SB_INODES_COUNT);
+        if inodes.div_ceil(inodes_per_group) as usize != group_count {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut descriptors = vec![0; group_count * GROUP_DESC_SIZE];
+        let table = (first_data_block as u64 + 1) * block_size as u64;
+        read(&mut device, table, &mut descriptors)?;
+        let groups = descriptors
+            .chunks(GROUP_DESC_SIZE)
+            .map(|desc| Group {
+                block_bitmap: get32(desc, BG_BLOCK_BITMAP),
+                inode_bitmap: get32(desc, BG_INODE_BITMAP),
+                inode_table: get32(desc, BG_INODE_TABLE),
+                free_blocks: get16(desc, BG_FREE_BLOCKS_COUNT),
+                free_inodes: get16(desc, BG_FREE_BLOCKS_COUNT + 2),
+                used_dirs: get16(desc, BG_FREE_BLOCKS_COUNT + 4),
+            })
+            .collect();
+        let clean = get16(&superblock, SB_STATE) == STATE_VALID;
+        Ok(Self {
+            device,
+            superblock,
+            groups,
+            block_size,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_ino,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: !clean || ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0,
+            marked: false,
+            open: BTreeMap::new(),
+        })
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), VfsError> {
+        read(&mut self.device, offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), VfsError> {
+        self.device
+            .seek(fatfs::SeekFrom::Start(offset))
+            .map_err(|_| VfsError::Io)?;
+        self.device.write_all(buf).map_err(|_| VfsError::Io)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Vec<u8>, VfsError> {
+        let mut data = vec![0; self.block_size];
+        self.read(self.block_offset(block), &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> Result<(), VfsError> {
+        self.write(self.block_offset(block), data)
+    }
+
+    /// Check that the filesystem may be changed, and mark it as in use the
+    /// first time so that a crash before it is dropped gets it checked.
+    fn modify(&mut self) -> Result<(), VfsError> {
+        if self.read_only {
+            return Err(VfsError::ReadOnly);
+        }
+        if !self.marked {
+            put16(&mut self.superblock, SB_STATE, 0);
+            self.write_superblock()?;
+            self.marked = true;
+        }
+        Ok(())
+    }
+
+    fn write_superblock(&mut self) -> Result<(), VfsError> {
+        let superblock = core::mem::take(&mut self.superblock);
+        let res = self.write(SUPERBLOCK_OFFSET, &superblock);
+        self.superblock = superblock;
+        res
+    }
+
+    /// There is no clock; the last write time of the superblock stands in
+    /// for it, so that times set here are at least not zero.
+    fn now(&self) -> u32 {
+        get32(&self.superblock, SB_WTIME).max(1)
+    }
+
+    fn write_group(&mut self, group: usize) -> Result<(), VfsError> {
+        let first_data_block = get32(&self.superblock, SB_FIRST_DATA_BLOCK);
+        let offset = self.block_offset(first_data_block + 1)
+            + (group * GROUP_DESC_SIZE + BG_FREE_BLOCKS_COUNT) as u64;
+        let desc = &self.groups[group];
+        let mut counts = [0; 6];
+        put16(&mut counts, 0, desc.free_blocks);
+        put16(&mut counts, 2, desc.free_inodes);
+        put16(&mut counts, 4, desc.used_dirs);
+        self.write(offset, &counts)
+    }
+
+    fn adjust_superblock(&mut self, at: usize, delta: i32) -> Result<(), VfsError> {
+        let count = get32(&self.superblock, at).wrapping_add_signed(delta);
+        put32(&mut self.superblock, at, count);
+        self.write_superblock()
+    }
+
+    /// Set the first clear bit below `count` in the bitmap in `block`.
+    fn take_bit(&mut self, block: u32, count: u32) -> Result<Option<u32>, VfsError> {
+        let mut bitmap = self.read_block(block)?;
+        if count as usize > bitmap.len() * 8 {
+            return Err(VfsError::Io);
+        }
+        let Some(bit) = (0..count).find(|&bit| bitmap[bit as usize / 8] & 1 << (bit % 8) == 0)
+        else {
+            return Ok(None);
+        };
+        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(block, &bitmap)?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&mut self, block: u32, bit: u32) -> Result<(), VfsError> {
+        let offset = self.block_offset(block) + bit as u64 / 8;
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        byte[0] &= !(1 << (bit % 8));
+        self.write(offset, &byte)
+    }
+
+    /// Allocate a zeroed block, preferably in the group of inode `ino`.
+    fn allocate_block(&mut self, ino: u32) -> Result<u32, VfsError> {
+        let first_data_block = get32(&self.superblock, SB_FIRST_DATA_BLOCK);
+        let blocks = get32(&self.superblock, SB_BLOCKS_COUNT);
+        let goal = ((ino - 1) / self.inodes_per_group) as usize;
+        for i in 0..self.groups.len() {
+            let group = (goal + i) % self.groups.len();
+            if self.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let start = first_data_block + group as u32 * self.blocks_per_group;
+            let count = min(self.blocks_per_group, blocks - start);
+            let Some(bit) = self.take_bit(self.groups[group].block_bitmap, count)? else {
+                continue;
+            };
+            self.groups[group].free_blocks -= 1;
+            self.write_group(group)?;
+            self.adjust_superblock(SB_FREE_BLOCKS_COUNT, -1)?;
+            let block = start + bit;
+            self.write_block(block, &vec![0; self.block_size])?;
+            return Ok(block);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), VfsError> {
+        let first_data_block = get32(&self.superblock, SB_FIRST_DATA_BLOCK);
+        let index = block.checked_sub(first_data_block).ok_or(VfsError::Io)?;
+        let group = (index / self.blocks_per_group) as usize;
+        let bit = index % self.blocks_per_group;
+        let bitmap = self.groups.get(group).ok_or(VfsError::Io)?.block_bitmap;
+        self.clear_bit(bitmap, bit)?;
+        self.groups[group].free_blocks += 1;
+        self.write_group(group)?;
+        self.adjust_superblock(SB_FREE_BLOCKS_COUNT, 1)
+    }
+
+    /// Allocate an inode, preferably in the group of inode `near`.
+    fn allocate_inode(&mut self, near: u32, dir: bool) -> Result<u32, VfsError> {
+        let goal = ((near - 1) / self.inodes_per_group) as usize;
+        for i in 0..self.groups.len() {
+            let group = (goal + i) % self.groups.len();
+            if self.groups[group].free_inodes == 0 {
+                continue;
+            }
+            let bitmap = self.groups[group].inode_bitmap;
+            let Some(bit) = self.take_bit(bitmap, self.inodes_per_group)? else {
+                continue;
+            };
+            let ino = group as u32 * self.inodes_per_group + bit + 1;
+            if ino < self.first_ino {
+                // the reserved inodes are marked in use by mkfs
+                return Err(VfsError::Io);
+            }
+            self.groups[group].free_inodes -= 1;
+            if dir {
+                self.groups[group].used_dirs += 1;
+            }
+            self.write_group(group)?;
+            self.adjust_superblock(SB_FREE_INODES_COUNT, -1)?;
+            return Ok(ino);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&mut self, ino: u32, dir: bool) -> Result<(), VfsError> {
+        let index = ino.checked_sub(1).ok_or(VfsError::Io)?;
+        let group = (index / self.inodes_per_group) as usize;
+        let bit = index % self.inodes_per_group;
+        let bitmap = self.groups.get(group).ok_or(VfsError::Io)?.inode_bitmap;
+        self.clear_bit(bitmap, bit)?;
+        self.groups[group].free_inodes += 1;
+        if dir {
+            self.groups[group].used_dirs -= 1;
+        }
+        self.write_group(group)?;
+        self.adjust_superblock(SB_FREE_INODES_COUNT, 1)
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, VfsError> {
+        if ino == 0 || ino > get32(&self.superblock, SB_INODES_COUNT) {
+            return Err(VfsError::Io);
+        }
+        let group = self
+            .groups
+            .get(((ino - 1) / self.inodes_per_group) as usize)
+            .ok_or(VfsError::Io)?;
+        let index = (ino - 1) % self.inodes_per_group;
+        Ok(self.block_offset(group.inode_table) + index as u64 * self.inode_size as u64)
+    }
+
+    fn read_inode(&mut self, ino: u32) -> Result<Inode, VfsError> {
+        let mut raw = vec![0; self.inode_size];
+        self.read(self.inode_offset(ino)?, &mut raw)?;
+        Ok(Inode(raw))
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), VfsError> {
+        self.write(self.inode_offset(ino)?, &inode.0)
+    }
+
+    /// A new inode with `mode`, one link and no blocks.
+    fn new_inode(&self, mode: u16) -> Inode {
+        let mut inode = Inode(vec![0; self.inode_size]);
+        put16(&mut inode.0, 0, mode);
+        let now = self.now();
+        for at in [8, 12, 16] {
+            put32(&mut inode.0, at, now);
+        }
+        inode.set_links(1);
+        inode
+    }
+
+    /// The device block holding block `index` of inode `ino`, or 0 for a
+    /// hole. With `allocate`, a hole is filled, along with the indirect
+    /// blocks leading to it; `inode` must then be written back even if this
+    /// fails.
+    fn map(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        index: u64,
+        allocate: bool,
+    ) -> Result<u32, VfsError> {
+        let per_block = self.block_size as u64 / 4;
+        let (slot, path) = if index < DIRECT_BLOCKS {
+            (index as usize, Vec::new())
+        } else if index - DIRECT_BLOCKS < per_block {
+            (12, vec![index - DIRECT_BLOCKS])
+        } else if index - DIRECT_BLOCKS - per_block < per_block * per_block {
+            let index = index - DIRECT_BLOCKS - per_block;
+            (13, vec![index / per_block, index % per_block])
+        } else if index - DIRECT_BLOCKS - per_block - per_block * per_block
+            < per_block * per_block * per_block
+        {
+            let index = index - DIRECT_BLOCKS - per_block - per_block * per_block;
+            (
+                14,
+                vec![
+                    index / per_block / per_block,
+                    index / per_block % per_block,
+                    index % per_block,
+                ],
+            )
+        } else {
+            return Err(VfsError::InvalidArgument);
+        };
+        let sectors = (self.block_size / 512) as i32;
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !allocate {
+                return Ok(0);
+            }
+            block = self.allocate_block(ino)?;
+            inode.set_block(slot, block);
+            inode.add_sectors(sectors);
+        }
+        for offset in path {
+            let at = self.block_offset(block) + offset * 4;
+            let mut pointer = [0; 4];
+            self.read(at, &mut pointer)?;
+            let mut next = u32::from_le_bytes(pointer);
+            if next == 0 {
+                if !allocate {
+                    return Ok(0);
+                }
+                next = self.allocate_block(ino)?;
+                inode.add_sectors(sectors);
+                self.write(at, &next.to_le_bytes())?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Free the blocks of `inode` from block `first` of the file on.
+    fn free_from(&mut self, inode: &mut Inode, first: u64) -> Result<(), VfsError> {
+        for slot in first..DIRECT_BLOCKS {
+            let block = inode.block(slot as usize);
+            if block != 0 {
+                self.release(inode, block)?;
+                inode.set_block(slot as usize, 0);
+            }
+        }
+        let per_block = self.block_size as u64 / 4;
+        let mut base = DIRECT_BLOCKS;
+        for (slot, depth) in [(12, 1), (13, 2), (14, 3)] {
+            let span = per_block.pow(depth);
+            let block = inode.block(slot);
+            if block != 0
+                && base + span > first
+                && self.free_tree(inode, block, depth, base, first)?
+            {
+                inode.set_block(slot, 0);
+            }
+            base += span;
+        }
+        Ok(())
+    }
+
+    /// Free the blocks of file blocks `first` and later in the tree under
+    /// `block`, which has `depth` levels of indirect blocks and starts at
+    /// file block `base`. Returns whether `block` itself was freed.
+    fn free_tree(
+        &mut self,
+        inode: &mut Inode,
+        block: u32,
+        depth: u32,
+        base: u64,
+        first: u64,
+    ) -> Result<bool, VfsError> {
+        if depth == 0 {
+            self.release(inode, block)?;
+            return Ok(true);
+        }
+        let span = (self.block_size as u64 / 4).pow(depth - 1);
+        let mut pointers = self.read_block(block)?;
+        let (mut used, mut changed) = (false, false);
+        for (i, child_base) in (0..self.block_size / 4).map(|i| (i, base + i as u64 * span)) {
+            let child = get32(&pointers, i * 4);
+            if child == 0 {
+                continue;
+            }
+            if child_base + span <= first
+                || !self.free_tree(inode, child, depth - 1, child_base, first)?
+            {
+                used = true;
+                continue;
+            }
+            put32(&mut pointers, i * 4, 0);
+            changed = true;
+        }
+        if !used {
+            self.release(inode, block)?;
+            return Ok(true);
+        }
+        if changed {
+            self.write_block(block, &pointers)?;
+        }
+        Ok(false)
+    }
+
+    fn release(&mut self, inode: &mut Inode, block: u32) -> Result<(), VfsError> {
+        self.free_block(block)?;
+        inode.add_sectors(-((self.block_size / 512) as i32));
+        Ok(())
+    }
+
+    /// Free inode `ino`, which has no names left, with its blocks.
+    fn release_inode(&mut self, ino: u32, mut inode: Inode) -> Result<(), VfsError> {
+        if !inode.is_fast_symlink(self.block_size) {
+            self.free_from(&mut inode, 0)?;
+        }
+        inode.set_links(0);
+        // e2fsck wants a deletion time on every free inode
+        put32(&mut inode.0, 20, self.now());
+        self.write_inode(ino, &inode)?;
+        self.free_inode(ino, inode.is_dir())
+    }
+
+    /// Drop a link to inode `ino`, freeing it once no name and no open file
+    /// is left.
+    fn unlink(&mut self, ino: u32) -> Result<(), VfsError> {
+        let mut inode = self.read_inode(ino)?;
+        inode.set_links(inode.links().saturating_sub(1));
+        if inode.links() == 0 && !self.open.contains_key(&ino) {
+            self.release_inode(ino, inode)
+        } else {
+            self.write_inode(ino, &inode)
+        }
+    }
+
+    fn adjust_links(&mut self, ino: u32, delta: i16) -> Result<(), VfsError> {
+        let mut inode = self.read_inode(ino)?;
+        inode.set_links(inode.links().wrapping_add_signed(delta));
+        self.write_inode(ino, &inode)
+    }
+
+    /// Every name in directory `ino`, `.` and `..` included.
+    fn entries(&mut self, ino: u32) -> Result<Vec<Entry>, VfsError> {
+        let mut dir = self.read_inode(ino)?;
+        if !dir.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        for index in 0..dir.size() / self.block_size as u64 {
+            let block = self.map(ino, &mut dir, index, false)?;
+            if block == 0 {
+                continue;
+            }
+            let data = self.read_block(block)?;
+            let (mut offset, mut prev) = (0, None);
+            while offset + 8 <= self.block_size {
+                let rec_len = get16(&data, offset + 4) as usize;
+                let name_len = data[offset + 6] as usize;
+                if rec_len < 8 || offset + rec_len > self.block_size || 8 + name_len > rec_len {
+                    return Err(VfsError::Io);
+                }
+                let entry_ino = get32(&data, offset);
+                if entry_ino != 0 {
+                    entries.push(Entry {
+                        ino: entry_ino,
+                        file_type: if self.filetype { data[offset + 7] } else { 0 },
+                        name: data[offset + 8..offset + 8 + name_len].to_vec(),
+                        block,
+                        offset,
+                        prev,
+                    });
+                }
+                prev = Some(offset);
+                offset += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn entry(&mut self, dir: u32, name: &str) -> Result<Entry, VfsError> {
+        self.entries(dir)?
+            .into_iter()
+            .find(|entry| entry.name == name.as_bytes())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, VfsError> {
+        let mut ino = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = self.entry(ino, name)?.ino;
+        }
+        Ok(ino)
+    }
+
+    /// The directory holding `path` and the last component of `path`.
+    fn parent<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str), VfsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidArgument)?;
+        if name.len() >= DIRENT_NAME_CAP {
+            return Err(VfsError::NameTooLong);
+        }
+        let dir = self.lookup(parent)?;
+        if !self.read_inode(dir)?.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok((dir, name))
+    }
+
+    /// Like `parent`, but fails if `path` exists.
+    fn new_name<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str), VfsError> {
+        let (dir, name) = self.parent(path)?;
+        match self.entry(dir, name) {
+            Ok(_) => Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => Ok((dir, name)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn put_record(
+        &self,
+        data: &mut [u8],
+        at: usize,
+        rec_len: usize,
+        ino: u32,
+        name: &str,
+        file_type: u8,
+    ) {
+        put32(data, at, ino);
+        put16(data, at + 4, rec_len as u16);
+        data[at + 6] = name.len() as u8;
+        data[at + 7] = if self.filetype { file_type } else { 0 };
+        data[at + 8..at + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// Add `name` for inode `ino` to directory `dir`, growing it by a block
+    /// if no record has room to spare.
+    fn add_entry(&mut self, dir: u32, name: &str, ino: u32, file_type: u8) -> Result<(), VfsError> {
+        let needed = record_len(name.len());
+        let mut inode = self.read_inode(dir)?;
+        let blocks = inode.size() / self.block_size as u64;
+        for index in 0..blocks {
+            let block = self.map(dir, &mut inode, index, false)?;
+            if block == 0 {
+                continue;
+            }
+            let mut data = self.read_block(block)?;
+            let mut offset = 0;
+            while offset + 8 <= self.block_size {
+                let rec_len = get16(&data, offset + 4) as usize;
+                if rec_len < 8 || offset + rec_len > self.block_size {
+                    return Err(VfsError::Io);
+                }
+                let used = if get32(&data, offset) == 0 {
+                    0
+                } else {
+                    record_len(data[offset + 6] as usize)
+                };
+                if rec_len >= used + needed {
+                    if used != 0 {
+                        put16(&mut data, offset + 4, used as u16);
+                    }
+                    self.put_record(
+                        &mut data,
+                        offset + used,
+                        rec_len - used,
+                        ino,
+                        name,
+                        file_type,
+                    );
+                    self.write_block(block, &data)?;
+                    return self.unindex(dir);
+                }
+                offset += rec_len;
+            }
+        }
+        let block = self.map(dir, &mut inode, blocks, true);
+        let res = block.and_then(|block| {
+            let mut data = vec![0; self.block_size];
+            self.put_record(&mut data, 0, self.block_size, ino, name, file_type);
+            self.write_block(block, &data)?;
+            inode.set_size((blocks + 1) * self.block_size as u64);
+            inode.set_flags(inode.flags() & !INDEX_FL);
+            Ok(())
+        });
+        self.write_inode(dir, &inode)?;
+        res
+    }
+
+    /// Remove the record of `entry` from directory `dir`.
+    fn remove_entry(&mut self, dir: u32, entry: &Entry) -> Result<(), VfsError> {
+        let mut data = self.read_block(entry.block)?;
+        match entry.prev {
+            Some(prev) => {
+                let rec_len = get16(&data, prev + 4) + get16(&data, entry.offset + 4);
+                put16(&mut data, prev + 4, rec_len);
+            }
+            None => put32(&mut data, entry.offset, 0),
+        }
+        self.write_block(entry.block, &data)?;
+        self.unindex(dir)
+    }
+
+    fn unindex(&mut self, dir: u32) -> Result<(), VfsError> {
+        let mut inode = self.read_inode(dir)?;
+        if inode.flags() & INDEX_FL != 0 {
+            inode.set_flags(inode.flags() & !INDEX_FL);
+            self.write_inode(dir, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Create inode `inode` under `name` in `dir`.
+    fn create(
+        &mut self,
+        dir: u32,
+        name: &str,
+        inode: Inode,
+        file_type: u8,
+    ) -> Result<u32, VfsError> {
+        let ino = self.allocate_inode(dir, inode.is_dir())?;
+        self.write_inode(ino, &inode)?;
+        if let Err(err) = self.add_entry(dir, name, ino, file_type) {
+            self.release_inode(ino, inode)?;
+            return Err(err);
+        }
+        Ok(ino)
+    }
+
+    /// Set the size of regular file `ino`, freeing the blocks past the end.
+    fn resize(&mut self, ino: u32, inode: &mut Inode, size: u64) -> Result<(), VfsError> {
+        if !self.large_file && size > i32::MAX as u64 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let block_size = self.block_size as u64;
+        if size < inode.size() {
+            self.free_from(inode, size.div_ceil(block_size))?;
+            // zero the cut-off tail so that growing the file again reads zeroes
+            if !size.is_multiple_of(block_size) {
+                let block = self.map(ino, inode, size / block_size, false)?;
+                if block != 0 {
+                    let tail = vec![0; (block_size - size % block_size) as usize];
+                    self.write(self.block_offset(block) + size % block_size, &tail)?;
+                }
+            }
+        }
+        inode.set_size(size);
+        Ok(())
+    }
+
+    fn read_file(&mut self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let mut inode = self.read_inode(ino)?;
+        let len = min(buf.len() as u64, inode.size().saturating_sub(offset)) as usize;
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % block_size;
+            let take = min(len - done, (block_size - within) as usize);
+            let block = self.map(ino, &mut inode, pos / block_size, false)?;
+            if block == 0 {
+                buf[done..done + take].fill(0);
+            } else {
+                self.read(
+                    self.block_offset(block) + within,
+                    &mut buf[done..done + take],
+                )?;
+            }
+            done += take;
+        }
+        Ok(len)
+    }
+
+    /// Write `buf` at `offset` of file `ino`. Returns how much was written
+    /// before the first error, if any.
+    /// Write `buf` at `offset` of the file and return how much was written.
+    /// Fails only if nothing could be.
+    fn write_file(&mut self, ino: u32, offset: u64, buf: &[u8]) -> Result<usize, VfsError> {
+        self.modify()?;
+        let mut inode = self.read_inode(ino)?;
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        let mut error = None;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % block_size;
+            let take = min(buf.len() - done, (block_size - within) as usize);
+            if !self.large_file && pos + take as u64 > i32::MAX as u64 {
+                error = Some(VfsError::InvalidArgument);
+                break;
+            }
+            let written = self
+                .map(ino, &mut inode, pos / block_size, true)
+                .and_then(|block| {
+                    self.write(self.block_offset(block) + within, &buf[done..done + take])
+                });
+            if let Err(err) = written {
+                error = Some(err);
+                break;
+            }
+            done += take;
+        }
+        if offset + done as u64 > inode.size() {
+            inode.set_size(offset + done as u64);
+        }
+        self.write_inode(ino, &inode)?;
+        match error {
+            Some(err) if done == 0 => Err(err),
+            _ => Ok(done),
+        }
+    }
+
+    fn read_link(&mut self, ino: u32) -> Result<String, VfsError> {
+        let mut inode = self.read_inode(ino)?;
+        if inode.kind() != S_IFLNK {
+            return Err(VfsError::InvalidArgument);
+        }
+        let size = inode.size() as usize;
+        let target = if inode.is_fast_symlink(self.block_size) {
+            inode.0[40..40 + min(size, FAST_SYMLINK_MAX)].to_vec()
+        } else {
+            // a slow symlink keeps its target in one block, which a corrupt
+            // inode may leave unmapped; block 0 is the boot block
+            if size > self.block_size {
+                return Err(VfsError::Io);
+            }
+            let block = self.map(ino, &mut inode, 0, false)?;
+            if block == 0 {
+                return Err(VfsError::Io);
+            }
+            let mut target = vec![0; size];
+            self.read(self.block_offset(block), &mut target)?;
+            target
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn open(&mut self, ino: u32) {
+        *self.open.entry(ino).or_default() += 1;
+    }
+
+    /// Forget a file open on `ino`, and free the inode if it was the last
+    /// one and the inode has no names left.
+    fn close(&mut self, ino: u32) -> Result<(), VfsError> {
+        let count = self.open.entry(ino).or_default();
+        *count -= 1;
+        if *count != 0 {
+            return Ok(());
+        }
+        self.open.remove(&ino);
+        let inode = self.read_inode(ino)?;
+        if inode.links() == 0 {
+            self.release_inode(ino, inode)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: ReadWriteSeek> Drop for Ext2<T> {
+    fn drop(&mut self) {
+        if self.marked {
+            put16(&mut self.superblock, SB_STATE, STATE_VALID);
+            let _ = self.write_superblock();
+            let _ = self.device.flush();
+        }
+    }
+}
+
+fn read<T: ReadWriteSeek>(device: &mut T, offset: u64, buf: &mut [u8]) -> Result<(), VfsError> {
+    device
+        .seek(fatfs::SeekFrom::Start(offset))
+        .map_err(|_| VfsError::Io)?;
+    device.read_exact(buf).map_err(|_| VfsError::Io)
+}
+
+/// The length of a directory record for a name of `name_len` bytes.
+fn record_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// Whether `device` holds an ext2 filesystem. The position of `device` is
+/// left anywhere.
+pub fn probe<T: ReadWriteSeek>(device: &mut T) -> bool {
+    let mut magic = [0; 2];
+    read(device, SUPERBLOCK_OFFSET + SB_MAGIC as u64, &mut magic).is_ok()
+        && u16::from_le_bytes(magic) == MAGIC
+}
+
+pub struct Ext2FileSystem<T: ReadWriteSeek> {
+    inner: Mutex<Ext2<T>>,
+}
+
+pub fn get_fs<T>(device: Option<T>) -> Result<Arc<dyn VfsDirectory>, ()>
+where
+    T: ReadWriteSeek + Send + 'static,
+{
+    let ext2 = Ext2::new(device.ok_or(())?).map_err(|_| ())?;
+    Ok(Arc::new(Ext2FileSystem {
+        inner: Mutex::new(ext2),
+    }))
+}
+
+impl<T: ReadWriteSeek + Send> Ext2FileSystem<T> {
+    fn open(&self, fs: &mut Ext2<T>, ino: u32) -> Arc<Mutex<dyn VfsFile + '_>> {
+        fs.open(ino);
+        Arc::new(Mutex::new(Ext2File {
+            fs: self,
+            ino,
+            pos: 0,
+        }))
+    }
+}
+
+impl<T: ReadWriteSeek + Send> VfsDirectory for Ext2FileSystem<T> {
+    fn file(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
+        let mut fs = self.inner.lock();
+        let ino = fs.lookup(path).map_err(|_| ())?;
+        if fs.read_inode(ino).map_err(|_| ())?.kind() != S_IFREG {
+            return Err(());
+        }
+        Ok(self.open(&mut fs, ino))
+    }
+
+    fn directory(&self, path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle + '_>>, ()> {
+        let mut fs = self.inner.lock();
+        let ino = fs.lookup(path).map_err(|_| ())?;
+        let mut entries = Vec::new();
+        for entry in fs.entries(ino).map_err(|_| ())? {
+            if entry.name == b"." || entry.name == b".." {
+                continue;
+            }
+            let is_dir = if fs.filetype {
+                entry.file_type == FT_DIR
+            } else {
+                fs.read_inode(entry.ino).is_ok_and(|inode| inode.is_dir())
+            };
+            entries.push(DirEntry::new(is_dir, &String::from_utf8_lossy(&entry.name)));
+        }
+        Ok(Arc::new(Mutex::new(SnapshotDirectory::new(entries))))
+    }
+
+    fn create_file_or_open_existing(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
+        let mut fs = self.inner.lock();
+        let (dir, name) = fs.parent(path).map_err(|_| ())?;
+        let ino = match fs.entry(dir, name) {
+            Ok(entry) => entry.ino,
+            Err(VfsError::NotFound) => {
+                fs.modify().map_err(|_| ())?;
+                let inode = fs.new_inode(S_IFREG | 0o644);
+                fs.create(dir, name, inode, FT_REG_FILE).map_err(|_| ())?
+            }
+            Err(_) => return Err(()),
+        };
+        if fs.read_inode(ino).map_err(|_| ())?.kind() != S_IFREG {
+            return Err(());
+        }
+        Ok(self.open(&mut fs, ino))
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        let (dir, name) = fs.parent(path)?;
+        let entry = fs.entry(dir, name)?;
+        if fs.read_inode(entry.ino)?.is_dir() {
+            return Err(VfsError::IsADirectory);
+        }
+        fs.remove_entry(dir, &entry)?;
+        fs.unlink(entry.ino)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        let (parent, name) = fs.new_name(path)?;
+        let ino = fs.allocate_inode(parent, true)?;
+        let mut inode = fs.new_inode(S_IFDIR | 0o755);
+        inode.set_links(2);
+        let block = match fs.map(ino, &mut inode, 0, true) {
+            Ok(block) => block,
+            Err(err) => {
+                fs.release_inode(ino, inode)?;
+                return Err(err);
+            }
+        };
+        let block_size = fs.block_size;
+        let mut data = vec![0; block_size];
+        fs.put_record(&mut data, 0, 12, ino, ".", FT_DIR);
+        fs.put_record(&mut data, 12, block_size - 12, parent, "..", FT_DIR);
+        inode.set_size(block_size as u64);
+        let created = fs
+            .write_block(block, &data)
+            .and_then(|()| fs.write_inode(ino, &inode))
+            .and_then(|()| fs.add_entry(parent, name, ino, FT_DIR));
+        if let Err(err) = created {
+            // gives the block back along with the inode
+            fs.release_inode(ino, inode)?;
+            return Err(err);
+        }
+        fs.adjust_links(parent, 1)
+    }
+
+    fn remove_directory(&self, path: &str) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        let (parent, name) = fs.parent(path)?;
+        let entry = fs.entry(parent, name)?;
+        let inode = fs.read_inode(entry.ino)?;
+        if !inode.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        if fs
+            .entries(entry.ino)?
+            .iter()
+            .any(|child| child.name != b"." && child.name != b"..")
+        {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        fs.remove_entry(parent, &entry)?;
+        fs.adjust_links(parent, -1)?;
+        fs.release_inode(entry.ino, inode)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        let (from_dir, from_name) = fs.parent(from)?;
+        let entry = fs.entry(from_dir, from_name)?;
+        let (to_dir, to_name) = fs.new_name(to)?;
+        fs.add_entry(to_dir, to_name, entry.ino, entry.file_type)?;
+        // adding may have split the record before the old one
+        let entry = fs.entry(from_dir, from_name)?;
+        fs.remove_entry(from_dir, &entry)?;
+        if from_dir != to_dir && fs.read_inode(entry.ino)?.is_dir() {
+            let parent = fs.entry(entry.ino, "..")?;
+            let at = fs.block_offset(parent.block) + parent.offset as u64;
+            fs.write(at, &to_dir.to_le_bytes())?;
+            fs.adjust_links(from_dir, -1)?;
+            fs.adjust_links(to_dir, 1)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, path: &str, len: u64) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        let ino = fs.lookup(path)?;
+        let mut inode = fs.read_inode(ino)?;
+        match inode.kind() {
+            S_IFREG => {}
+            S_IFDIR => return Err(VfsError::IsADirectory),
+            _ => return Err(VfsError::InvalidArgument),
+        }
+        let res = fs.resize(ino, &mut inode, len);
+        fs.write_inode(ino, &inode)?;
+        res
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn file_id(&self, path: &str) -> Option<u64> {
+        self.inner.lock().lookup(path).ok().map(u64::from)
+    }
+
+    fn symlinks(&self) -> bool {
+        true
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, ()> {
+        let mut fs = self.inner.lock();
+        let ino = fs.lookup(path).map_err(|_| ())?;
+        Ok(fs.read_inode(ino).map_err(|_| ())?.metadata())
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, VfsError> {
+        let mut fs = self.inner.lock();
+        let ino = fs.lookup(path)?;
+        fs.read_link(ino)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> Result<(), VfsError> {
+        let mut fs = self.inner.lock();
+        fs.modify()?;
+        if target.len() >= fs.block_size {
+            return Err(VfsError::NameTooLong);
+        }
+        let (dir, name) = fs.new_name(path)?;
+        let ino = fs.allocate_inode(dir, false)?;
+        let mut inode = fs.new_inode(S_IFLNK | 0o777);
+        inode.set_size(target.len() as u64);
+        let stored = if target.len() < FAST_SYMLINK_MAX {
+            inode.0[40..40 + target.len()].copy_from_slice(target.as_bytes());
+            Ok(())
+        } else {
+            fs.map(ino, &mut inode, 0, true).and_then(|block| {
+                let at = fs.block_offset(block);
+                fs.write(at, target.as_bytes())
+            })
+        };
+        if let Err(err) = stored.and_then(|()| fs.write_inode(ino, &inode)) {
+            fs.release_inode(ino, inode)?;
+            return Err(err);
+        }
+        if let Err(err) = fs.add_entry(dir, name, ino, FT_SYMLINK) {
+            fs.release_inode(ino, inode)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+struct Ext2File<'a, T: ReadWriteSeek> {
+    fs: &'a Ext2FileSystem<T>,
+    ino: u32,
+    pos: usize,
+}
+
+impl<T: ReadWriteSeek + Send> VfsFile for Ext2File<'_, T> {
+    fn size(&mut self) -> usize {
+        let mut fs = self.fs.inner.lock();
+        fs.read_inode(self.ino)
+            .map_or(0, |inode| inode.size() as usize)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let read = self.read_at(self.pos, buf);
+        self.pos += read;
+        read
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let written = self
+            .fs
+            .inner
+            .lock()
+            .write_file(self.ino, self.pos as u64, buf)?;
+        self.pos += written;
+        Ok(written)
+    }
+
+    /// Seeking past the end is allowed; a write there leaves a hole.
+    fn seek(&mut self, pos: SeekFrom) -> usize {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        if let Some(new_pos) = new_pos {
+            self.pos = new_pos;
+        }
+        self.pos
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        let mut fs = self.fs.inner.lock();
+        fs.read_inode(self.ino)
+            .map_or(Metadata::file(0), |inode| inode.metadata())
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut fs = self.fs.inner.lock();
+        fs.read_file(self.ino, offset as u64, buf).unwrap_or(0)
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> usize {
+        self.fs
+            .inner
+            .lock()
+            .write_file(self.ino, offset as u64, buf)
+            .unwrap_or(0)
+    }
+}
+
+impl<T: ReadWriteSeek> Drop for Ext2File<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.fs.inner.lock().close(self.ino);
+    }
+}
@@ -1,7 +1,12 @@
-use super::{DirEntry, SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsFile};
+use super::{
+    ATTR_ARCHIVE, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM, DirEntry, KIND_DIRECTORY, Metadata,
+    SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsError, VfsFile,
+};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use fatfs::{FileSystem, FsOptions, ReadWriteSeek};
+use fatfs::{
+    DefaultTimeProvider, FileAttributes, FileSystem, FsOptions, LossyOemCpConverter, ReadWriteSeek,
+};
 use spin::Mutex;
 
 pub fn get_fs<T>(device: Option<T>) -> Result<Arc<dyn VfsDirectory>, ()>
@@ -13,21 +18,38 @@ where
     Ok(Arc::new(WrappedFileSystem(filesystem)))
 }
 
+/// Whether `device` starts with a FAT boot sector. The position of `device`
+/// is left anywhere.
+pub fn probe<T: ReadWriteSeek>(device: &mut T) -> bool {
+    let mut sector = [0u8; 512];
+    if device.seek(fatfs::SeekFrom::Start(0)).is_err() || device.read_exact(&mut sector).is_err() {
+        return false;
+    }
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    let fats = sector[16];
+    sector[510..] == [0x55, 0xaa]
+        && matches!(sector[0], 0xeb | 0xe9)
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && fats != 0
+}
+
 pub struct WrappedFileSystem<T: ReadWriteSeek>(FileSystem<T>);
 
 unsafe impl<T: ReadWriteSeek> Sync for WrappedFileSystem<T> {}
 
-pub struct WrappedFile<'a, T: ReadWriteSeek, TP, OCC>(fatfs::File<'a, T, TP, OCC>);
+/// An open file and the metadata of its directory entry, which `fatfs::File`
+/// does not keep.
+pub struct WrappedFile<'a, T: ReadWriteSeek, TP, OCC>(fatfs::File<'a, T, TP, OCC>, Metadata);
 
 unsafe impl<'a, T: ReadWriteSeek, TP, OCC> Send for WrappedFile<'a, T, TP, OCC> {}
 
 impl<T: fatfs::ReadWriteSeek + Send> VfsDirectory for WrappedFileSystem<T> {
     fn file(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
-        self.0
-            .root_dir()
-            .open_file(path)
-            .map_err(|_| ())
-            .map(move |x| Arc::new(Mutex::new(WrappedFile(x))) as _)
+        let file = self.0.root_dir().open_file(path).map_err(|_| ())?;
+        let metadata = self.metadata(path).unwrap_or(Metadata::file(0));
+        Ok(Arc::new(Mutex::new(WrappedFile(file, metadata))))
     }
 
     fn directory(&self, path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle + '_>>, ()> {
@@ -45,16 +67,149 @@ impl<T: fatfs::ReadWriteSeek + Send> VfsDirectory for WrappedFileSystem<T> {
     }
 
     fn create_file_or_open_existing(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
-        self.0
-            .root_dir()
-            .create_file(path)
-            .map_err(|_| ())
-            .map(move |x| Arc::new(Mutex::new(WrappedFile(x))) as _)
+        let file = self.0.root_dir().create_file(path).map_err(|_| ())?;
+        let metadata = self.metadata(path).unwrap_or(Metadata::file(0));
+        Ok(Arc::new(Mutex::new(WrappedFile(file, metadata))))
     }
 
-    fn remove(&self, path: &str) -> bool {
-        self.0.root_dir().remove(path).is_ok()
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        if self.metadata(path).map_err(|()| VfsError::NotFound)?.kind == KIND_DIRECTORY {
+            return Err(VfsError::IsADirectory);
+        }
+        self.0.root_dir().remove(path).map_err(fat_error)
     }
+
+    fn create_directory(&self, path: &str) -> Result<(), VfsError> {
+        // fatfs opens the directory if it exists already
+        if self.metadata(path).is_ok() {
+            return Err(VfsError::AlreadyExists);
+        }
+        self.0.root_dir().create_dir(path).map_err(fat_error)?;
+        Ok(())
+    }
+
+    fn remove_directory(&self, path: &str) -> Result<(), VfsError> {
+        if self.metadata(path).map_err(|()| VfsError::NotFound)?.kind != KIND_DIRECTORY {
+            return Err(VfsError::NotADirectory);
+        }
+        self.0.root_dir().remove(path).map_err(fat_error)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        let root = self.0.root_dir();
+        root.rename(from, &root, to).map_err(fat_error)
+    }
+
+    fn truncate(&self, path: &str, len: u64) -> Result<(), VfsError> {
+        use fatfs::{Seek, SeekFrom, Write};
+        let metadata = self.metadata(path).map_err(|()| VfsError::NotFound)?;
+        if metadata.kind == KIND_DIRECTORY {
+            return Err(VfsError::IsADirectory);
+        }
+        let mut file = self.0.root_dir().open_file(path).map_err(fat_error)?;
+        if len <= metadata.size {
+            file.seek(SeekFrom::Start(len)).map_err(fat_error)?;
+            return file.truncate().map_err(fat_error);
+        }
+        file.seek(SeekFrom::End(0)).map_err(fat_error)?;
+        let zeroes = [0u8; 512];
+        let mut remaining = len - metadata.size;
+        while remaining > 0 {
+            let chunk = remaining.min(zeroes.len() as u64) as usize;
+            file.write_all(&zeroes[..chunk]).map_err(fat_error)?;
+            remaining -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "fat"
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, ()> {
+        let Some((parent, name)) = path.trim_end_matches('/').rsplit_once('/') else {
+            return Ok(Metadata::directory());
+        };
+        if name.is_empty() {
+            return Ok(Metadata::directory());
+        }
+        let dir = if parent.is_empty() {
+            self.0.root_dir()
+        } else {
+            self.0.root_dir().open_dir(parent).map_err(|_| ())?
+        };
+        for entry in dir.iter() {
+            let entry = entry.map_err(|_| ())?;
+            // fatfs matches both the long and the 8.3 name, ignoring case
+            if entry.file_name().eq_ignore_ascii_case(name)
+                || entry.short_file_name().eq_ignore_ascii_case(name)
+            {
+                return Ok(entry_metadata(&entry));
+            }
+        }
+        Err(())
+    }
+}
+
+fn fat_error<E>(err: fatfs::Error<E>) -> VfsError {
+    match err {
+        fatfs::Error::NotFound => VfsError::NotFound,
+        fatfs::Error::AlreadyExists => VfsError::AlreadyExists,
+        fatfs::Error::DirectoryIsNotEmpty => VfsError::DirectoryNotEmpty,
+        fatfs::Error::NotEnoughSpace => VfsError::NoSpace,
+        fatfs::Error::InvalidInput
+        | fatfs::Error::InvalidFileNameLength
+        | fatfs::Error::UnsupportedFileNameCharacter => VfsError::InvalidArgument,
+        _ => VfsError::Io,
+    }
+}
+
+fn entry_metadata<T: ReadWriteSeek>(
+    entry: &fatfs::DirEntry<'_, T, DefaultTimeProvider, LossyOemCpConverter>,
+) -> Metadata {
+    let mut metadata = if entry.is_dir() {
+        Metadata::directory()
+    } else {
+        Metadata::file(entry.len())
+    };
+    let attributes = entry.attributes();
+    for (fat, vfs) in [
+        (FileAttributes::READ_ONLY, ATTR_READ_ONLY),
+        (FileAttributes::HIDDEN, ATTR_HIDDEN),
+        (FileAttributes::SYSTEM, ATTR_SYSTEM),
+        (FileAttributes::ARCHIVE, ATTR_ARCHIVE),
+    ] {
+        if attributes.contains(fat) {
+            metadata.attributes |= vfs;
+        }
+    }
+    metadata.created = unix_time(entry.created());
+    metadata.modified = unix_time(entry.modified());
+    metadata.accessed = unix_days(entry.accessed()) * 86400;
+    metadata
+}
+
+fn unix_time(time: fatfs::DateTime) -> u64 {
+    let days = unix_days(time.date);
+    if days == 0 {
+        return 0;
+    }
+    days * 86400 + time.time.hour as u64 * 3600 + time.time.min as u64 * 60 + time.time.sec as u64
+}
+
+/// Days from 1970-01-01 to `date`, or 0 for a date FAT leaves unset.
+fn unix_days(date: fatfs::Date) -> u64 {
+    if date.month == 0 || date.day == 0 {
+        return 0;
+    }
+    // days_from_civil from http://howardhinnant.github.io/date_algorithms.html
+    let (month, day) = (date.month as u64, date.day as u64);
+    let year = date.year as u64 - (month <= 2) as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
 }
 
 impl<T: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC> VfsFile
@@ -76,9 +231,12 @@ impl<T: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC> VfsFile
         self.0.read(buf).unwrap_or(0)
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
         use fatfs::Write;
-        self.0.write(buf).unwrap_or(0)
+        self.0.write(buf).map_err(|err| match err {
+            fatfs::Error::NotEnoughSpace => VfsError::NoSpace,
+            _ => VfsError::Io,
+        })
     }
 
     fn seek(&mut self, pos: crate::vfs::SeekFrom) -> usize {
@@ -89,6 +247,20 @@ impl<T: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC> VfsFile
                 crate::vfs::SeekFrom::Current(x) => fatfs::SeekFrom::Current(x as i64),
                 crate::vfs::SeekFrom::Start(x) => fatfs::SeekFrom::Start(x as u64),
             })
+            .or_else(|_| self.0.seek(fatfs::SeekFrom::Current(0)))
             .unwrap_or(0) as usize
     }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata {
+            size: self.size() as u64,
+            ..self.1
+        }
+    }
+    /// fatfs writes the directory entry of the file back and then flushes
+    /// the device below it.
+    fn flush(&mut self) -> Result<(), ()> {
+        use fatfs::Write;
+        self.0.flush().map_err(|_| ())
+    }
 }
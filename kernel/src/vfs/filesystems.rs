@@ -0,0 +1,198 @@
+//! The filesystem drivers `mount` can pick from.
+//!
+//! A filesystem on a device is mounted by the path of the device, usually a
+//! devfs node like `/dev/usb0p1`, so every driver works on any block device,
+//! partition or even a disk image in a file. Without a type, each driver
+//! that reads from a device probes it in turn.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::mount::{MOUNT_NO_EXEC, MOUNT_READ_ONLY, MountError};
+use super::{SeekFrom, VfsDirectory, VfsFile};
+
+struct FsType {
+    name: &'static str,
+    /// Whether the filesystem is read from a device; if not, the source of
+    /// the mount is just a name for `/proc/mounts`.
+    needs_device: bool,
+    probe: fn(&mut FileDevice) -> bool,
+    /// Make the filesystem, given the mount options that are the driver's
+    /// own rather than generic ones like `ro`.
+    #[allow(clippy::type_complexity)]
+    get_fs: fn(Option<FileDevice>, &[&str]) -> Result<Arc<dyn VfsDirectory>, MountError>,
+}
+
+/// In the order they probe a device. ext2 comes before FAT, whose boot
+/// sector would not be in the way of an ext2 superblock.
+static FS_TYPES: &[FsType] = &[
+    FsType {
+        name: "ext2",
+        needs_device: true,
+        probe: super::ext2::probe,
+        get_fs: |device, options| without_options(super::ext2::get_fs, device, options),
+    },
+    FsType {
+        name: "fat",
+        needs_device: true,
+        probe: super::fat::probe,
+        get_fs: |device, options| without_options(super::fat::get_fs, device, options),
+    },
+    FsType {
+        name: "tmpfs",
+        needs_device: false,
+        probe: |_| false,
+        get_fs: |_, options| super::tmpfs::get_fs_with_options(options),
+    },
+];
+
+/// `get_fs` of a driver that has no options of its own.
+#[allow(clippy::type_complexity)]
+fn without_options(
+    get_fs: fn(Option<FileDevice>) -> Result<Arc<dyn VfsDirectory>, ()>,
+    device: Option<FileDevice>,
+    options: &[&str],
+) -> Result<Arc<dyn VfsDirectory>, MountError> {
+    if !options.is_empty() {
+        return Err(MountError::InvalidArgument);
+    }
+    get_fs(device).map_err(|()| MountError::BadFilesystem)
+}
+
+/// A file the filesystem drivers read and write through, as they do with a
+/// block device.
+struct FileDevice(Arc<Mutex<dyn VfsFile>>);
+
+impl fatfs::IoBase for FileDevice {
+    type Error = ();
+}
+
+impl fatfs::Read for FileDevice {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.0.lock().read(buf))
+    }
+}
+
+impl fatfs::Write for FileDevice {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.lock().write(buf).map_err(|_| ())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().flush()
+    }
+}
+
+impl fatfs::Seek for FileDevice {
+    fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, Self::Error> {
+        let mut file = self.0.lock();
+        // `VfsFile::seek` stays where it is on failure, so compare with
+        // where it should have gone
+        let target = match pos {
+            fatfs::SeekFrom::Start(offset) => usize::try_from(offset).ok(),
+            fatfs::SeekFrom::End(offset) => file.size().checked_add_signed(offset as isize),
+            fatfs::SeekFrom::Current(offset) => file
+                .seek(SeekFrom::Current(0))
+                .checked_add_signed(offset as isize),
+        }
+        .ok_or(())?;
+        if file.seek(SeekFrom::Start(target)) == target {
+            Ok(target as u64)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Whether a filesystem of type `fs_type`, or one found by probing for
+/// `None`, is read from a device. Otherwise the source of the mount is just
+/// a name, not a path.
+pub fn needs_device(fs_type: Option<&str>) -> bool {
+    fs_type.is_none_or(|name| {
+        FS_TYPES
+            .iter()
+            .find(|fs_type| fs_type.name == name)
+            .is_none_or(|fs_type| fs_type.needs_device)
+    })
+}
+
+/// Mount the filesystem on the device at the absolute path `source` at
+/// `path`, of type `fs_type` or whichever driver recognizes it. `options` is
+/// a comma-separated list of `ro`, `rw`, `noexec`, `exec` and `defaults`,
+/// and of the driver's own options, like `size=` for tmpfs.
+pub fn mount(
+    source: &str,
+    fs_type: Option<&str>,
+    path: &str,
+    options: &str,
+) -> Result<(), MountError> {
+    let (flags, fs_options) = parse_options(options);
+    let filesystem = match fs_type {
+        Some(name) => {
+            let fs_type = FS_TYPES
+                .iter()
+                .find(|fs_type| fs_type.name == name)
+                .ok_or(MountError::NoDevice)?;
+            let device = if fs_type.needs_device {
+                Some(open_device(source)?)
+            } else {
+                None
+            };
+            (fs_type.get_fs)(device, &fs_options)
+        }
+        None => {
+            let mut device = open_device(source)?;
+            let fs_type = FS_TYPES
+                .iter()
+                .filter(|fs_type| fs_type.needs_device)
+                .find(|fs_type| (fs_type.probe)(&mut device))
+                .ok_or(MountError::BadFilesystem)?;
+            fatfs::Seek::seek(&mut device, fatfs::SeekFrom::Start(0))
+                .map_err(|()| MountError::NoDevice)?;
+            (fs_type.get_fs)(Some(device), &fs_options)
+        }
+    }?;
+    super::mount::add(source, path, flags, filesystem)
+}
+
+fn open_device(source: &str) -> Result<FileDevice, MountError> {
+    super::get_file(source)
+        .map(FileDevice)
+        .map_err(|()| MountError::NoDevice)
+}
+
+/// The mount flags from the generic options, and the other options, which
+/// are left to the driver.
+fn parse_options(options: &str) -> (usize, Vec<&str>) {
+    let mut flags = 0;
+    let mut fs_options = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option {
+            "defaults" => {}
+            "rw" => flags &= !MOUNT_READ_ONLY,
+            "ro" => flags |= MOUNT_READ_ONLY,
+            "exec" => flags &= !MOUNT_NO_EXEC,
+            "noexec" => flags |= MOUNT_NO_EXEC,
+            _ => fs_options.push(option),
+        }
+    }
+    (flags, fs_options)
+}
+
+/// The contents of `/proc/filesystems`: one driver per line, marked `nodev`
+/// if it does not read from a device.
+pub(super) fn list() -> String {
+    let mut data = String::new();
+    for fs_type in FS_TYPES {
+        data.push_str(if fs_type.needs_device {
+            "\t"
+        } else {
+            "nodev\t"
+        });
+        data.push_str(fs_type.name);
+        data.push('\n');
+    }
+    data
+}
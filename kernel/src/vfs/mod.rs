@@ -1,19 +1,32 @@
+mod archive;
 mod devfs;
+mod ext2;
 mod fat;
+pub mod filesystems;
+mod mount;
+mod open_file;
+mod page_cache;
+pub mod path;
+pub mod pipe;
 mod procfs;
+mod tmpfs;
 
-pub use fat::get_fs as get_fat_fs;
+pub use mount::{MOUNT_NO_EXEC, MOUNT_READ_ONLY, MountError};
+pub use open_file::{
+    O_ACCMODE, O_APPEND, O_CREAT, O_DIRECTORY, O_EXCL, O_NONBLOCK, O_RDONLY, O_RDWR, O_TRUNC,
+    O_WRONLY, OpenFile, open, open_directory,
+};
 
 use crate::blockdev::ramdisk::RamDisk;
 use crate::cmdline;
 use crate::println;
-use alloc::borrow::ToOwned;
+use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use limine::module::InternalModule;
 use limine::request::ModulesRequest;
-use spin::{Lazy, Mutex};
+use spin::Mutex;
 
 #[used]
 #[unsafe(link_section = ".requests")]
@@ -24,20 +37,138 @@ pub fn has_cmdline_flag(flag: &str) -> bool {
     cmdline::has_cmdline_flag(flag)
 }
 
-static MOUNT_TABLE: Lazy<Vec<(String, Arc<dyn VfsDirectory + 'static>)>> = Lazy::new(Vec::new);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// The filesystem or its mount is read-only.
+    ReadOnly,
+    /// A rename between two filesystems.
+    CrossDevice,
+    /// The path is a mount point.
+    Busy,
+    NoSpace,
+    InvalidArgument,
+    /// The filesystem does not support the operation.
+    Unsupported,
+    Io,
+    /// The file table of the process is full.
+    TooManyFiles,
+    /// The file descriptor is not open.
+    BadDescriptor,
+    /// The file is a pipe or character device, which has no offset.
+    NotSeekable,
+    /// A path component is longer than a `DirEntry` can hold.
+    NameTooLong,
+    /// Symbolic links nest too deep or form a loop.
+    TooManyLinks,
+    /// A write to a pipe nobody reads any more.
+    BrokenPipe,
+}
+
+impl VfsError {
+    /// The negative errno returned by the syscalls.
+    pub fn errno(self) -> isize {
+        match self {
+            VfsError::Unsupported => -1,
+            VfsError::NotFound => -2,
+            VfsError::Io => -5,
+            VfsError::BadDescriptor => -9,
+            VfsError::Busy => -16,
+            VfsError::AlreadyExists => -17,
+            VfsError::CrossDevice => -18,
+            VfsError::NotADirectory => -20,
+            VfsError::IsADirectory => -21,
+            VfsError::InvalidArgument => -22,
+            VfsError::TooManyFiles => -24,
+            VfsError::NoSpace => -28,
+            VfsError::BrokenPipe => -32,
+            VfsError::NotSeekable => -29,
+            VfsError::ReadOnly => -30,
+            VfsError::NameTooLong => -36,
+            VfsError::DirectoryNotEmpty => -39,
+            VfsError::TooManyLinks => -40,
+        }
+    }
+}
 
 pub trait VfsDirectory: Send + Sync {
     fn file(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()>;
     fn directory(&self, path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle + '_>>, ()>;
     fn create_file_or_open_existing(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()>;
-    fn remove(&self, path: &str) -> bool;
+    /// Remove the file at `path`; directories need `remove_directory`.
+    fn remove(&self, path: &str) -> Result<(), VfsError>;
+    fn create_directory(&self, path: &str) -> Result<(), VfsError>;
+    /// Remove the empty directory at `path`.
+    fn remove_directory(&self, path: &str) -> Result<(), VfsError>;
+    /// Move `from` to `to`, both on this filesystem. `to` must not exist.
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError>;
+    /// Cut the file at `path` to `len` bytes, or extend it with zeroes.
+    fn truncate(&self, path: &str, len: u64) -> Result<(), VfsError>;
+    /// The name of the filesystem type, as shown in `/proc/mounts`.
+    fn fs_type(&self) -> &'static str;
+    /// The number of the file at `path`, the same under all of its names,
+    /// like an inode number. File contents are read through the page cache
+    /// only where there is one, which is only worth it for filesystems on
+    /// block devices.
+    fn file_id(&self, _path: &str) -> Option<u64> {
+        None
+    }
+    /// Whether the filesystem can hold symbolic links, which path lookup
+    /// then looks for in every component.
+    fn symlinks(&self) -> bool {
+        false
+    }
+    /// The target of the symbolic link at `path`.
+    fn read_link(&self, _path: &str) -> Result<String, VfsError> {
+        Err(VfsError::InvalidArgument)
+    }
+    /// Create a symbolic link at `path` pointing to `target`.
+    fn symlink(&self, _target: &str, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+    /// The metadata of `path`; a symbolic link is not followed.
+    fn metadata(&self, path: &str) -> Result<Metadata, ()> {
+        if let Ok(file) = self.file(path) {
+            Ok(file.lock().metadata())
+        } else {
+            self.directory(path).map(|_| Metadata::directory())
+        }
+    }
 }
 
 pub trait VfsFile: Send {
     fn size(&mut self) -> usize;
     fn read(&mut self, buf: &mut [u8]) -> usize;
-    fn write(&mut self, buf: &[u8]) -> usize;
+    /// Write from the cursor on and return how much was written, which is 0
+    /// when nothing fits now, e.g. into a full pipe.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError>;
     fn seek(&mut self, pos: SeekFrom) -> usize;
+    /// The key to wait on with `WaitReason::File` if a `read` cannot make
+    /// progress yet. `None` means a read returns now, possibly at end of file.
+    fn read_blocks(&mut self) -> Option<usize> {
+        None
+    }
+    /// Like `read_blocks`, for `write`.
+    fn write_blocks(&mut self) -> Option<usize> {
+        None
+    }
+    /// Whether this file is a terminal, whose reads return nothing while no
+    /// input has arrived yet rather than at end of file.
+    fn is_terminal(&self) -> bool {
+        false
+    }
+    fn metadata(&mut self) -> Metadata {
+        Metadata::file(self.size() as u64)
+    }
+    /// Write anything held back for the file, like dirty cached blocks of a
+    /// disk, through to where it is stored.
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
     fn read_exact(&mut self, buf: &mut [u8]) {
         let mut buf2 = buf;
         while !buf2.is_empty() {
@@ -51,10 +182,114 @@ pub trait VfsFile: Send {
         let mut buf2 = buf;
         while !buf2.is_empty() {
             match self.write(buf2) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => buf2 = &buf2[n..],
+            }
+        }
+    }
+    /// Read as much of `buf` as there is from `offset` on. The cursor is
+    /// left anywhere.
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+        if self.seek(SeekFrom::Start(offset)) != offset {
+            return 0;
+        }
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read(&mut buf[read..]) {
                 0 => break,
-                n => buf2 = &buf2[n..],
+                n => read += n,
+            }
+        }
+        read
+    }
+    /// Like `read_at`, for writing.
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> usize {
+        if self.seek(SeekFrom::Start(offset)) != offset {
+            return 0;
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            match self.write(&buf[written..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => written += n,
             }
         }
+        written
+    }
+}
+
+pub const KIND_FILE: u32 = 1;
+pub const KIND_DIRECTORY: u32 = 2;
+pub const KIND_CHAR_DEVICE: u32 = 3;
+pub const KIND_BLOCK_DEVICE: u32 = 4;
+pub const KIND_PIPE: u32 = 5;
+pub const KIND_SYMLINK: u32 = 6;
+
+pub const ATTR_READ_ONLY: u32 = 1 << 0;
+pub const ATTR_HIDDEN: u32 = 1 << 1;
+pub const ATTR_SYSTEM: u32 = 1 << 2;
+pub const ATTR_ARCHIVE: u32 = 1 << 3;
+
+/// What `stat` and `fstat` return. Times are seconds since the Unix epoch,
+/// or 0 if the filesystem does not record them. `block_size` and
+/// `block_count` are only set for block devices. `mode` holds the Unix
+/// permission bits, and is 0 with `uid` and `gid` on filesystems without
+/// them.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct Metadata {
+    pub kind: u32,
+    pub attributes: u32,
+    pub size: u64,
+    pub block_size: u64,
+    pub block_count: u64,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Metadata {
+    pub fn file(size: u64) -> Self {
+        Self {
+            kind: KIND_FILE,
+            size,
+            ..Self::default()
+        }
+    }
+
+    pub fn directory() -> Self {
+        Self {
+            kind: KIND_DIRECTORY,
+            ..Self::default()
+        }
+    }
+
+    pub fn char_device() -> Self {
+        Self {
+            kind: KIND_CHAR_DEVICE,
+            ..Self::default()
+        }
+    }
+
+    pub fn block_device(block_size: u64, block_count: u64) -> Self {
+        Self {
+            kind: KIND_BLOCK_DEVICE,
+            size: block_size * block_count,
+            block_size,
+            block_count,
+            ..Self::default()
+        }
+    }
+
+    pub fn pipe(queued: u64) -> Self {
+        Self {
+            kind: KIND_PIPE,
+            size: queued,
+            ..Self::default()
+        }
     }
 }
 
@@ -126,66 +361,183 @@ pub fn init() {
         data.as_ptr(),
         data.len()
     );
-    let disk = RamDisk::with_addr_and_size(data.as_ptr() as *mut u8, data.len() as u64);
-    Lazy::force(&MOUNT_TABLE);
-    mount(Some(disk), "/", self::fat::get_fs).expect("failed to mount initrd");
-    mount(None::<RamDisk>, "/dev/", self::devfs::get_fs).expect("failed to mount devfs");
-    mount(None::<RamDisk>, "/proc/", self::procfs::get_fs).expect("failed to mount procfs");
+    if archive::is_archive(data) {
+        let root = archive::unpack(data).expect("failed to unpack initrd");
+        mount::add("initrd", "/", 0, root).expect("failed to mount initrd");
+    } else {
+        let disk = RamDisk::with_addr_and_size(data.as_ptr() as *mut u8, data.len() as u64);
+        mount(Some(disk), "initrd", "/", 0, self::fat::get_fs).expect("failed to mount initrd");
+    }
+    mount(None::<RamDisk>, "devfs", "/dev", 0, self::devfs::get_fs).expect("failed to mount devfs");
+    mount(None::<RamDisk>, "procfs", "/proc", 0, self::procfs::get_fs)
+        .expect("failed to mount procfs");
+    mount(None::<RamDisk>, "tmpfs", "/tmp", 0, self::tmpfs::get_fs).expect("failed to mount tmpfs");
 }
 
+/// Mount the filesystem `fs` finds on `device` at `path`. `source` names the
+/// device in `/proc/mounts`.
 #[allow(clippy::type_complexity)]
 pub fn mount<T>(
     device: Option<T>,
+    source: &str,
     path: &str,
+    flags: usize,
     fs: fn(Option<T>) -> Result<Arc<dyn VfsDirectory>, ()>,
-) -> Result<(), ()>
+) -> Result<(), MountError>
 where
     T: fatfs::ReadWriteSeek,
 {
-    let filesystem = fs(device)?;
-    unsafe {
-        (*Lazy::as_mut_ptr(&MOUNT_TABLE)).push((path.to_owned(), filesystem));
+    let filesystem = fs(device).map_err(|()| MountError::BadFilesystem)?;
+    mount::add(source, path, flags, filesystem)
+}
+
+/// Detach the filesystem mounted at `path`. Fails while files or directories
+/// on it are open or other filesystems are mounted below it.
+pub fn umount(path: &str) -> Result<(), MountError> {
+    mount::remove(path)
+}
+
+/// How many symbolic links one lookup follows before giving up.
+const MAX_LINKS: usize = 16;
+
+/// Normalize an absolute path and replace the symbolic links in it by their
+/// targets, in the last component only if `follow_last`. Relative paths must
+/// have been resolved with `path::resolve` first.
+///
+/// Like the rest of a path, `..` is resolved lexically before the links in
+/// front of it are looked at.
+fn absolute(path: &str, follow_last: bool) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidArgument);
     }
-    Ok(())
+    let mut path = path::normalize(path);
+    let mut links = 0;
+    let mut end = 0;
+    while end < path.len() {
+        end = path[end + 1..]
+            .find('/')
+            .map_or(path.len(), |i| end + 1 + i);
+        if end == path.len() && !follow_last {
+            break;
+        }
+        let Some((mount, relative)) = mount::resolve(&path[..end]) else {
+            break;
+        };
+        if !mount.symlinks() {
+            continue;
+        }
+        let Ok(target) = mount.read_link(relative) else {
+            continue;
+        };
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(VfsError::TooManyLinks);
+        }
+        // a relative target starts in the directory holding the link
+        let dir = &path[..path[..end].rfind('/').unwrap()];
+        let dir = if dir.is_empty() { "/" } else { dir };
+        path = path::resolve(dir, &format!("{target}/{}", &path[end..]));
+        end = 0;
+    }
+    Ok(path)
+}
+
+/// Find the mount `path` lives on and call `op` with the path relative to
+/// it. Symbolic links are followed but in the last component, unless
+/// `follow_last`.
+fn on_mount<R>(
+    path: &str,
+    follow_last: bool,
+    op: impl FnOnce(&Arc<mount::Mount>, &str) -> Result<R, VfsError>,
+) -> Result<R, VfsError> {
+    let path = absolute(path, follow_last)?;
+    let (mount, path) = mount::resolve(&path).ok_or(VfsError::NotFound)?;
+    op(&mount, path)
 }
 
 pub fn get_file(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
-    for fs in MOUNT_TABLE.iter() {
-        if path.starts_with(&fs.0)
-            && let Ok(res) = fs.1.file(&path[(fs.0.len() - 1)..])
-        {
-            return Ok(res);
+    on_mount(path, true, |mount, path| {
+        mount.file(path).map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
+}
+
+/// Like `get_file`, but fails on filesystems mounted `noexec`.
+pub fn get_executable(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
+    on_mount(path, true, |mount, path| {
+        if mount.no_exec() {
+            return Err(VfsError::InvalidArgument);
         }
-    }
-    Err(())
+        mount.file(path).map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, ()> {
+    on_mount(path, true, |mount, path| {
+        mount.metadata(path).map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
+}
+
+/// Like `metadata`, but describes a symbolic link rather than its target.
+pub fn link_metadata(path: &str) -> Result<Metadata, ()> {
+    on_mount(path, false, |mount, path| {
+        mount.metadata(path).map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
 }
 
 pub fn get_directory(path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle>>, ()> {
-    for fs in MOUNT_TABLE.iter() {
-        if path.starts_with(&fs.0)
-            && let Ok(res) = fs.1.directory(&path[(fs.0.len() - 1)..])
-        {
-            return Ok(res);
-        }
-    }
-    Err(())
+    on_mount(path, true, |mount, path| {
+        mount.directory(path).map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
 }
 
 pub fn create_file_or_open_existing(path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
-    for fs in MOUNT_TABLE.iter() {
-        if path.starts_with(&fs.0)
-            && let Ok(res) = fs.1.create_file_or_open_existing(&path[(fs.0.len() - 1)..])
-        {
-            return Ok(res);
-        }
-    }
-    Err(())
+    on_mount(path, true, |mount, path| {
+        mount
+            .create_file_or_open_existing(path)
+            .map_err(|()| VfsError::NotFound)
+    })
+    .map_err(|_| ())
 }
 
-pub fn remove_file(path: &str) {
-    for fs in MOUNT_TABLE.iter() {
-        if path.starts_with(&fs.0) && fs.1.remove(&path[(fs.0.len() - 1)..]) {
-            break;
+pub fn remove_file(path: &str) -> Result<(), VfsError> {
+    on_mount(path, false, |mount, path| mount.remove(path))
+}
+
+pub fn create_directory(path: &str) -> Result<(), VfsError> {
+    on_mount(path, false, |mount, path| mount.create_directory(path))
+}
+
+pub fn remove_directory(path: &str) -> Result<(), VfsError> {
+    on_mount(path, false, |mount, path| mount.remove_directory(path))
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), VfsError> {
+    let to = absolute(to, false)?;
+    let (to_mount, to) = mount::resolve(&to).ok_or(VfsError::NotFound)?;
+    on_mount(from, false, |mount, from| {
+        if !Arc::ptr_eq(mount, &to_mount) {
+            return Err(VfsError::CrossDevice);
         }
-    }
+        mount.rename(from, to)
+    })
+}
+
+pub fn truncate(path: &str, len: u64) -> Result<(), VfsError> {
+    on_mount(path, true, |mount, path| mount.truncate(path, len))
+}
+
+/// The target of the symbolic link at `path`.
+pub fn read_link(path: &str) -> Result<String, VfsError> {
+    on_mount(path, false, |mount, path| mount.read_link(path))
+}
+
+/// Create a symbolic link at `path` pointing to `target`, which is stored
+/// as it is and resolved when the link is followed.
+pub fn symlink(target: &str, path: &str) -> Result<(), VfsError> {
+    on_mount(path, false, |mount, path| mount.symlink(target, path))
 }
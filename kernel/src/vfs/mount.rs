@@ -0,0 +1,416 @@
+//! The mount table.
+//!
+//! Every path is resolved against the mount with the longest matching mount
+//! point, and only that filesystem is asked for it: a path that does not
+//! exist under `/dev` never falls through to the root filesystem.
+//!
+//! Files and directories opened through a mount hold a reference to it, so
+//! a filesystem stays alive until its last file is closed and `umount`
+//! refuses to detach a mount that is still in use.
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::page_cache;
+use super::{
+    ATTR_READ_ONLY, DirEntry, Metadata, SeekFrom, VfsDirHandle, VfsDirectory, VfsError, VfsFile,
+};
+
+/// Refuse every write to the filesystem.
+pub const MOUNT_READ_ONLY: usize = 1 << 0;
+/// Refuse to execute programs from the filesystem.
+pub const MOUNT_NO_EXEC: usize = 1 << 1;
+const MOUNT_FLAGS: usize = MOUNT_READ_ONLY | MOUNT_NO_EXEC;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountError {
+    /// The mount point is not an absolute path, or unknown flags were given.
+    InvalidArgument,
+    /// Something is mounted on the mount point already, or the mount is in
+    /// use by open files or other mounts.
+    Busy,
+    /// Nothing is mounted on the path.
+    NotMounted,
+    /// The device or partition does not exist.
+    NoDevice,
+    /// The device does not hold a filesystem of the requested type.
+    BadFilesystem,
+}
+
+impl MountError {
+    /// The negative errno returned by the mount syscalls.
+    pub fn errno(self) -> isize {
+        match self {
+            MountError::InvalidArgument | MountError::NotMounted => -22,
+            MountError::Busy => -16,
+            MountError::NoDevice => -19,
+            MountError::BadFilesystem => -5,
+        }
+    }
+}
+
+pub(super) struct Mount {
+    /// Normalized mount point: absolute, without a trailing `/` except for
+    /// the root.
+    path: String,
+    source: String,
+    flags: usize,
+    fs: Arc<dyn VfsDirectory>,
+}
+
+impl Mount {
+    pub(super) fn read_only(&self) -> bool {
+        self.flags & MOUNT_READ_ONLY != 0
+    }
+
+    pub(super) fn no_exec(&self) -> bool {
+        self.flags & MOUNT_NO_EXEC != 0
+    }
+
+    /// The part of `path` below the mount point, starting with `/`, or `None`
+    /// if `path` is not on this mount.
+    fn relative<'p>(&self, path: &'p str) -> Option<&'p str> {
+        if self.path == "/" {
+            return path.starts_with('/').then_some(path);
+        }
+        let rest = path.strip_prefix(self.path.as_str())?;
+        if rest.is_empty() {
+            Some("/")
+        } else {
+            rest.starts_with('/').then_some(rest)
+        }
+    }
+
+    pub(super) fn file(self: &Arc<Self>, path: &str) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
+        let file = self.fs.file(path)?;
+        Ok(self.pin_file(file, path))
+    }
+
+    pub(super) fn create_file_or_open_existing(
+        self: &Arc<Self>,
+        path: &str,
+    ) -> Result<Arc<Mutex<dyn VfsFile>>, ()> {
+        if self.read_only() {
+            return Err(());
+        }
+        let file = self.fs.create_file_or_open_existing(path)?;
+        Ok(self.pin_file(file, path))
+    }
+
+    pub(super) fn directory(
+        self: &Arc<Self>,
+        path: &str,
+    ) -> Result<Arc<Mutex<dyn VfsDirHandle>>, ()> {
+        let directory = self.fs.directory(path)?;
+        // SAFETY: the handle borrows `self.fs`, which the `MountedDirectory`
+        // keeps alive and drops only after the handle
+        let directory = unsafe {
+            core::mem::transmute::<
+                Arc<Mutex<dyn VfsDirHandle + '_>>,
+                Arc<Mutex<dyn VfsDirHandle + 'static>>,
+            >(directory)
+        };
+        Ok(Arc::new(Mutex::new(MountedDirectory {
+            directory,
+            _mount: self.clone(),
+        })))
+    }
+
+    pub(super) fn metadata(&self, path: &str) -> Result<Metadata, ()> {
+        let metadata = self.fs.metadata(path)?;
+        Ok(self.restrict(metadata))
+    }
+
+    /// Mark `metadata` of a file on a read-only mount as read-only.
+    fn restrict(&self, mut metadata: Metadata) -> Metadata {
+        if self.read_only() {
+            metadata.attributes |= ATTR_READ_ONLY;
+        }
+        metadata
+    }
+
+    /// Check that `path` may be changed: the mount is writable and `path`
+    /// is not its root, which belongs to the mount point.
+    fn writable(&self, path: &str) -> Result<(), VfsError> {
+        if self.read_only() {
+            Err(VfsError::ReadOnly)
+        } else if path == "/" {
+            Err(VfsError::Busy)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn remove(&self, path: &str) -> Result<(), VfsError> {
+        self.writable(path)?;
+        // the number may be given to a new file once this one is gone
+        if let Some(id) = self.fs.file_id(path) {
+            page_cache::invalidate(self.id(), id);
+        }
+        self.fs.remove(path)
+    }
+
+    pub(super) fn create_directory(&self, path: &str) -> Result<(), VfsError> {
+        self.writable(path)?;
+        self.fs.create_directory(path)
+    }
+
+    pub(super) fn remove_directory(&self, path: &str) -> Result<(), VfsError> {
+        self.writable(path)?;
+        if self.has_mounts_below(path) {
+            return Err(VfsError::Busy);
+        }
+        self.fs.remove_directory(path)
+    }
+
+    pub(super) fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        self.writable(from)?;
+        self.writable(to)?;
+        if self.has_mounts_below(from) {
+            return Err(VfsError::Busy);
+        }
+        // a directory cannot be moved into its own subtree
+        if to
+            .strip_prefix(from)
+            .is_some_and(|rest| rest.starts_with('/'))
+        {
+            return Err(VfsError::InvalidArgument);
+        }
+        if let Some(id) = self.fs.file_id(to) {
+            page_cache::invalidate(self.id(), id);
+        }
+        self.fs.rename(from, to)
+    }
+
+    pub(super) fn truncate(&self, path: &str, len: u64) -> Result<(), VfsError> {
+        self.writable(path)?;
+        let res = self.fs.truncate(path, len);
+        if let Some(id) = self.fs.file_id(path) {
+            page_cache::invalidate(self.id(), id);
+        }
+        res
+    }
+
+    pub(super) fn symlinks(&self) -> bool {
+        self.fs.symlinks()
+    }
+
+    pub(super) fn read_link(&self, path: &str) -> Result<String, VfsError> {
+        self.fs.read_link(path)
+    }
+
+    pub(super) fn symlink(&self, target: &str, path: &str) -> Result<(), VfsError> {
+        self.writable(path)?;
+        self.fs.symlink(target, path)
+    }
+
+    /// Identifies the mount in the page cache.
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Whether another filesystem is mounted on `path` of this one or below.
+    fn has_mounts_below(&self, path: &str) -> bool {
+        let path = if self.path == "/" {
+            String::from(path)
+        } else {
+            format!("{}{path}", self.path)
+        };
+        MOUNTS
+            .lock()
+            .iter()
+            .any(|mount| is_below(&mount.path, &path))
+    }
+
+    fn pin_file(
+        self: &Arc<Self>,
+        file: Arc<Mutex<dyn VfsFile + '_>>,
+        path: &str,
+    ) -> Arc<Mutex<dyn VfsFile>> {
+        // SAFETY: as in `directory`
+        let file = unsafe {
+            core::mem::transmute::<Arc<Mutex<dyn VfsFile + '_>>, Arc<Mutex<dyn VfsFile + 'static>>>(
+                file,
+            )
+        };
+        let file: Arc<Mutex<dyn VfsFile>> = Arc::new(Mutex::new(MountedFile {
+            file,
+            mount: self.clone(),
+        }));
+        match self.fs.file_id(path) {
+            Some(id) => page_cache::cached(file, self.id(), id),
+            None => file,
+        }
+    }
+}
+
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+
+/// Whether the normalized `path` is `dir` or lies below it.
+fn is_below(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Turn `path` into a mount point: `/a/b/` and `/a/./b` both become `/a/b`.
+fn normalize(path: &str) -> Result<String, MountError> {
+    if !path.starts_with('/') {
+        return Err(MountError::InvalidArgument);
+    }
+    Ok(super::path::normalize(path))
+}
+
+pub(super) fn add(
+    source: &str,
+    path: &str,
+    flags: usize,
+    fs: Arc<dyn VfsDirectory>,
+) -> Result<(), MountError> {
+    if flags & !MOUNT_FLAGS != 0 {
+        return Err(MountError::InvalidArgument);
+    }
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(MountError::Busy);
+    }
+    mounts.push(Arc::new(Mount {
+        path,
+        source: source.to_owned(),
+        flags,
+        fs,
+    }));
+    Ok(())
+}
+
+pub(super) fn remove(path: &str) -> Result<(), MountError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(MountError::NotMounted)?;
+    let mount = &mounts[index];
+    let has_children = mounts
+        .iter()
+        .any(|other| other.path != path && is_below(&other.path, &path));
+    // the table's reference is the only one unless files are open
+    if path == "/" || has_children || Arc::strong_count(mount) != 1 {
+        return Err(MountError::Busy);
+    }
+    let mount = mounts.remove(index);
+    drop(mounts);
+    page_cache::forget_mount(mount.id());
+    // a FAT filesystem flushes itself to its device here
+    drop(mount);
+    // the mount is gone either way, and a failed write-back cannot be
+    // retried from here
+    let _ = crate::blockdev::cache::sync();
+    Ok(())
+}
+
+/// The mount `path` lives on, and the path relative to its mount point.
+pub(super) fn resolve(path: &str) -> Option<(Arc<Mount>, &str)> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| Some((mount, mount.relative(path)?)))
+        .max_by_key(|(mount, _)| mount.path.len())
+        .map(|(mount, relative)| (mount.clone(), relative))
+}
+
+/// The contents of `/proc/mounts`: one line per mount with its source, mount
+/// point, filesystem type and options.
+pub(super) fn list() -> String {
+    let mut data = String::from("SOURCE MOUNTPOINT TYPE OPTIONS\n");
+    for mount in MOUNTS.lock().iter() {
+        let mut options = String::from(if mount.read_only() { "ro" } else { "rw" });
+        if mount.no_exec() {
+            options.push_str(",noexec");
+        }
+        data.push_str(&format!(
+            "{} {} {} {}\n",
+            mount.source,
+            mount.path,
+            mount.fs.fs_type(),
+            options
+        ));
+    }
+    data
+}
+
+struct MountedFile {
+    // declared first so it is dropped before the filesystem it borrows
+    file: Arc<Mutex<dyn VfsFile>>,
+    mount: Arc<Mount>,
+}
+
+impl VfsFile for MountedFile {
+    fn size(&mut self) -> usize {
+        self.file.lock().size()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.file.lock().read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        if self.mount.read_only() {
+            return Err(VfsError::ReadOnly);
+        }
+        self.file.lock().write(buf)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> usize {
+        self.file.lock().seek(pos)
+    }
+
+    fn read_blocks(&mut self) -> Option<usize> {
+        self.file.lock().read_blocks()
+    }
+
+    fn write_blocks(&mut self) -> Option<usize> {
+        self.file.lock().write_blocks()
+    }
+
+    fn is_terminal(&self) -> bool {
+        self.file.lock().is_terminal()
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        let metadata = self.file.lock().metadata();
+        self.mount.restrict(metadata)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.file.lock().flush()
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+        self.file.lock().read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> usize {
+        if self.mount.read_only() {
+            return 0;
+        }
+        self.file.lock().write_at(offset, buf)
+    }
+}
+
+struct MountedDirectory {
+    directory: Arc<Mutex<dyn VfsDirHandle>>,
+    _mount: Arc<Mount>,
+}
+
+impl VfsDirHandle for MountedDirectory {
+    fn getdents(&mut self, buf: &mut [DirEntry]) -> usize {
+        self.directory.lock().getdents(buf)
+    }
+}
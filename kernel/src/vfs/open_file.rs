@@ -0,0 +1,178 @@
+//! Opening files with POSIX-like flags.
+//!
+//! Every `open` creates an open file description holding the file, the
+//! access mode, the status flags and the offset. File descriptors point to
+//! descriptions: `dup` and `fork` share one, and with it the offset, while
+//! two opens of the same path read and write independently. The description
+//! is freed when the last descriptor referring to it is closed.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{
+    KIND_CHAR_DEVICE, KIND_DIRECTORY, KIND_FILE, KIND_PIPE, SeekFrom, VfsDirHandle, VfsError,
+    VfsFile, on_mount,
+};
+
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+/// Create the file if it does not exist.
+pub const O_CREAT: usize = 0x40;
+/// With `O_CREAT`, fail if the file exists.
+pub const O_EXCL: usize = 0x80;
+/// Cut a regular file opened for writing to length 0.
+pub const O_TRUNC: usize = 0x200;
+/// Write at the end of the file, wherever the offset is.
+pub const O_APPEND: usize = 0x400;
+/// Fail reads and writes that would block instead of waiting.
+pub const O_NONBLOCK: usize = 0x800;
+/// Open a directory for `getdents`; fail if the path is not one.
+pub const O_DIRECTORY: usize = 0x10000;
+
+const O_ALL: usize = O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_NONBLOCK | O_DIRECTORY;
+/// The flags `set_status_flags` may change after the open.
+const O_STATUS: usize = O_APPEND | O_NONBLOCK;
+
+/// An open file description.
+pub struct OpenFile {
+    pub file: Arc<Mutex<dyn VfsFile>>,
+    flags: AtomicUsize,
+    /// Where the next read or write starts. Only used with `file` locked.
+    offset: AtomicUsize,
+    /// Pipes and character devices have no offset.
+    stream: bool,
+}
+
+impl OpenFile {
+    pub fn new(file: Arc<Mutex<dyn VfsFile>>, flags: usize) -> Arc<Self> {
+        let kind = file.lock().metadata().kind;
+        Arc::new(Self {
+            file,
+            flags: AtomicUsize::new(flags & !(O_CREAT | O_EXCL | O_TRUNC)),
+            offset: AtomicUsize::new(0),
+            stream: kind == KIND_PIPE || kind == KIND_CHAR_DEVICE,
+        })
+    }
+
+    /// The access mode and the status flags.
+    pub fn flags(&self) -> usize {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    /// Replace the status flags with those of `flags`; the access mode and
+    /// all other flags are ignored.
+    pub fn set_status_flags(&self, flags: usize) {
+        let kept = self.flags() & !O_STATUS;
+        self.flags.store(kept | flags & O_STATUS, Ordering::Relaxed);
+    }
+
+    pub fn readable(&self) -> bool {
+        self.flags() & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags() & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn append(&self) -> bool {
+        self.flags() & O_APPEND != 0
+    }
+
+    pub fn nonblocking(&self) -> bool {
+        self.flags() & O_NONBLOCK != 0
+    }
+
+    /// Whether the file has an offset, so that `seek`, `pread` and `pwrite`
+    /// make sense.
+    pub fn seekable(&self) -> bool {
+        !self.stream
+    }
+
+    /// Move the cursor of the locked `file` to where the next read starts.
+    pub fn seek_for_read(&self, file: &mut dyn VfsFile) {
+        if !self.stream {
+            file.seek(SeekFrom::Start(self.offset.load(Ordering::Relaxed)));
+        }
+    }
+
+    /// Like `seek_for_read`, but to the end of the file with `O_APPEND`.
+    pub fn seek_for_write(&self, file: &mut dyn VfsFile) {
+        if self.stream {
+            return;
+        }
+        let offset = if self.append() {
+            file.seek(SeekFrom::End(0))
+        } else {
+            file.seek(SeekFrom::Start(self.offset.load(Ordering::Relaxed)))
+        };
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    /// Remember where a read or write left the cursor of the locked `file`.
+    pub fn update_offset(&self, file: &mut dyn VfsFile) {
+        if !self.stream {
+            let offset = file.seek(SeekFrom::Current(0));
+            self.offset.store(offset, Ordering::Relaxed);
+        }
+    }
+
+    /// Move the offset and return the new one. A seek that fails leaves it
+    /// where it was.
+    pub fn seek(&self, pos: SeekFrom) -> usize {
+        if self.stream {
+            return 0;
+        }
+        let mut file = self.file.lock();
+        self.seek_for_read(&mut *file);
+        let offset = file.seek(pos);
+        self.offset.store(offset, Ordering::Relaxed);
+        offset
+    }
+}
+
+/// Open the file at the absolute `path`, following symbolic links.
+/// Directories need `O_DIRECTORY` and `open_directory`.
+pub fn open(path: &str, flags: usize) -> Result<Arc<OpenFile>, VfsError> {
+    let access = flags & O_ACCMODE;
+    if flags & !O_ALL != 0 || access == O_ACCMODE || flags & O_DIRECTORY != 0 {
+        return Err(VfsError::InvalidArgument);
+    }
+    // an exclusive create does not follow a symbolic link in its place
+    let follow_last = flags & (O_CREAT | O_EXCL) != O_CREAT | O_EXCL;
+    on_mount(path, follow_last, |mount, path| {
+        let writes = access != O_RDONLY || flags & (O_CREAT | O_TRUNC) != 0;
+        if writes && mount.read_only() {
+            return Err(VfsError::ReadOnly);
+        }
+        let file = match mount.metadata(path) {
+            Ok(metadata) if metadata.kind == KIND_DIRECTORY => return Err(VfsError::IsADirectory),
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => {
+                return Err(VfsError::AlreadyExists);
+            }
+            Ok(metadata) => {
+                // devices ignore O_TRUNC
+                if flags & O_TRUNC != 0 && access != O_RDONLY && metadata.kind == KIND_FILE {
+                    mount.truncate(path, 0)?;
+                }
+                mount.file(path)
+            }
+            Err(()) if flags & O_CREAT != 0 => mount.create_file_or_open_existing(path),
+            Err(()) => return Err(VfsError::NotFound),
+        };
+        Ok(OpenFile::new(file.map_err(|()| VfsError::Io)?, flags))
+    })
+}
+
+/// Open the directory at the absolute `path` for `getdents`.
+pub fn open_directory(path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle>>, VfsError> {
+    on_mount(path, true, |mount, path| {
+        let metadata = mount.metadata(path).map_err(|()| VfsError::NotFound)?;
+        if metadata.kind != KIND_DIRECTORY {
+            return Err(VfsError::NotADirectory);
+        }
+        mount.directory(path).map_err(|()| VfsError::Io)
+    })
+}
@@ -0,0 +1,228 @@
+//! The page cache for file contents.
+//!
+//! Files on filesystems that number them, like ext2 with its inodes, are
+//! read a page at a time through the cache, which is shared by all opens of
+//! the same file on the same mount, whatever name it was opened by. Writes
+//! go to the file first and then drop the pages they touched, so the cache
+//! never holds anything the file does not; dirty data is the business of
+//! the block cache below the filesystem.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Lazy, Mutex};
+
+use super::{Metadata, SeekFrom, VfsError, VfsFile};
+
+pub const PAGE_SIZE: usize = 4096;
+/// How many pages the cache holds.
+pub const CACHE_PAGES: usize = 256;
+
+struct Page {
+    data: Box<[u8; PAGE_SIZE]>,
+    /// How much of `data` is file contents; only the last page is short.
+    len: usize,
+    last_use: u64,
+}
+
+/// A file by mount and by the number its filesystem gives it.
+type FileKey = (usize, u64);
+
+struct PageCache {
+    /// Pages by file and index.
+    pages: BTreeMap<(FileKey, usize), Page>,
+    /// Pages by the time of their last use, oldest first.
+    lru: BTreeMap<u64, (FileKey, usize)>,
+    clock: u64,
+    /// Counts the times pages were dropped, so that a page read from its
+    /// file meanwhile is known to be stale.
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+static CACHE: Lazy<Mutex<PageCache>> = Lazy::new(|| {
+    Mutex::new(PageCache {
+        pages: BTreeMap::new(),
+        lru: BTreeMap::new(),
+        clock: 0,
+        generation: 0,
+        hits: 0,
+        misses: 0,
+    })
+});
+
+impl PageCache {
+    /// Drop the pages from `first` up to `last` of the files from `from` up
+    /// to `to`.
+    fn drop_pages(&mut self, (from, first): (FileKey, usize), (to, last): (FileKey, usize)) {
+        self.generation += 1;
+        let keys: Vec<_> = self
+            .pages
+            .range((from, first)..=(to, last))
+            .map(|(key, page)| (*key, page.last_use))
+            .collect();
+        for (key, last_use) in keys {
+            self.pages.remove(&key);
+            self.lru.remove(&last_use);
+        }
+    }
+
+    /// Drop the pages of `file` from index `first` on.
+    fn drop_file(&mut self, file: FileKey, first: usize) {
+        self.drop_pages((file, first), (file, usize::MAX));
+    }
+}
+
+/// Wrap `file`, which the filesystem of the mount identified by `mount`
+/// numbers `id`, so that it is read through the cache.
+pub(super) fn cached(
+    file: Arc<Mutex<dyn VfsFile>>,
+    mount: usize,
+    id: u64,
+) -> Arc<Mutex<dyn VfsFile>> {
+    Arc::new(Mutex::new(CachedFile {
+        file,
+        key: (mount, id),
+        pos: 0,
+    }))
+}
+
+/// Drop the pages of file `id` on `mount` after it was changed without going
+/// through a cached file, or removed.
+pub(super) fn invalidate(mount: usize, id: u64) {
+    CACHE.lock().drop_file((mount, id), 0);
+}
+
+/// Drop the pages of every file on `mount`, which goes away.
+pub(super) fn forget_mount(mount: usize) {
+    CACHE
+        .lock()
+        .drop_pages(((mount, 0), 0), ((mount, u64::MAX), usize::MAX));
+}
+
+/// The line of `/proc/cache` for the page cache.
+pub(super) fn stats() -> String {
+    let cache = CACHE.lock();
+    format!(
+        "page {} {} {} {} 0 0\n",
+        cache.hits,
+        cache.misses,
+        cache.pages.len(),
+        CACHE_PAGES
+    )
+}
+
+struct CachedFile {
+    file: Arc<Mutex<dyn VfsFile>>,
+    key: FileKey,
+    pos: usize,
+}
+
+impl CachedFile {
+    /// Copy from page `index` at `within` into `buf`, reading the page from
+    /// the file on a miss. The cache is not locked while the file is read.
+    fn read_page(&mut self, index: usize, within: usize, buf: &mut [u8]) -> usize {
+        let key = (self.key, index);
+        let generation = {
+            let mut cache = CACHE.lock();
+            cache.clock += 1;
+            let now = cache.clock;
+            if let Some(page) = cache.pages.get_mut(&key) {
+                let last_use = core::mem::replace(&mut page.last_use, now);
+                let len = buf.len().min(page.len.saturating_sub(within));
+                buf[..len].copy_from_slice(&page.data[within..within + len]);
+                cache.hits += 1;
+                cache.lru.remove(&last_use);
+                cache.lru.insert(now, key);
+                return len;
+            }
+            cache.misses += 1;
+            cache.generation
+        };
+        let mut data = Box::new([0; PAGE_SIZE]);
+        let len = {
+            let mut file = self.file.lock();
+            if file.seek(SeekFrom::Start(index * PAGE_SIZE)) != index * PAGE_SIZE {
+                return 0;
+            }
+            let size = file.size();
+            let len = PAGE_SIZE.min(size.saturating_sub(index * PAGE_SIZE));
+            file.read_exact(&mut data[..len]);
+            len
+        };
+        if len == 0 {
+            return 0;
+        }
+        let copied = buf.len().min(len.saturating_sub(within));
+        buf[..copied].copy_from_slice(&data[within..within + copied]);
+        let mut cache = CACHE.lock();
+        // a write may have dropped pages since the file was read
+        if cache.generation != generation || cache.pages.contains_key(&key) {
+            return copied;
+        }
+        while cache.pages.len() >= CACHE_PAGES {
+            let Some((_, oldest)) = cache.lru.pop_first() else {
+                break;
+            };
+            cache.pages.remove(&oldest);
+        }
+        cache.clock += 1;
+        let now = cache.clock;
+        cache.pages.insert(
+            key,
+            Page {
+                data,
+                len,
+                last_use: now,
+            },
+        );
+        cache.lru.insert(now, key);
+        copied
+    }
+}
+
+impl VfsFile for CachedFile {
+    fn size(&mut self) -> usize {
+        self.file.lock().size()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let (index, within) = (self.pos / PAGE_SIZE, self.pos % PAGE_SIZE);
+        let len = buf.len().min(PAGE_SIZE - within);
+        let read = self.read_page(index, within, &mut buf[..len]);
+        self.pos += read;
+        read
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let written = {
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(self.pos));
+            file.write(buf)?
+        };
+        // the page with the old end of file grows too, so drop everything
+        // from the first page written on
+        CACHE.lock().drop_file(self.key, self.pos / PAGE_SIZE);
+        self.pos += written;
+        Ok(written)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> usize {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(self.pos));
+        self.pos = file.seek(pos);
+        self.pos
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        self.file.lock().metadata()
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        self.file.lock().flush()
+    }
+}
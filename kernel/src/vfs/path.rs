@@ -0,0 +1,60 @@
+//! Lexical path resolution.
+//!
+//! Paths are normalized before the mount table sees them, so `..` may lead
+//! out of a mounted filesystem into the one it is mounted on.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Resolve `path` against the working directory `cwd`, which must be
+/// absolute and normalized, unless `path` is absolute itself.
+pub fn resolve(cwd: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        normalize(path)
+    } else {
+        let mut joined = String::with_capacity(cwd.len() + 1 + path.len());
+        joined.push_str(cwd);
+        joined.push('/');
+        joined.push_str(path);
+        normalize(&joined)
+    }
+}
+
+/// Normalize an absolute path: drop empty and `.` components, let `..`
+/// remove the component before it and strip the trailing `/`. `/..` is `/`.
+pub fn normalize(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return String::from("/");
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    normalized
+}
+
+pub fn test() {
+    assert_eq!(normalize("/"), "/");
+    assert_eq!(normalize(""), "/");
+    assert_eq!(normalize("/.."), "/");
+    assert_eq!(normalize("/a/b/"), "/a/b");
+    assert_eq!(normalize("//a///b/./c/.."), "/a/b");
+    assert_eq!(resolve("/home", "a/../.."), "/");
+    assert_eq!(resolve("/home", "a/b/"), "/home/a/b");
+    assert_eq!(resolve("/home", ""), "/home");
+    assert_eq!(resolve("/home", "."), "/home");
+    assert_eq!(resolve("/home", "/etc/../bin"), "/bin");
+    assert_eq!(resolve("/", ".."), "/");
+    crate::println!("[INFO] vfs: path self-test passed");
+}
@@ -0,0 +1,144 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end, each of
+//! which is a `VfsFile`. Reading an empty pipe and writing a full one block
+//! the caller; reading an empty pipe without writers returns end of file and
+//! writing to a pipe without readers fails.
+//!
+//! An end is closed when the last reference to its file goes away, which may
+//! happen with the task list locked, so closing only schedules the wakeup.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+use spin::Mutex;
+
+use crate::task::sched;
+use crate::vfs::{Metadata, SeekFrom, VfsError, VfsFile};
+
+pub const PIPE_CAPACITY: usize = 4096;
+
+type PipeEnd = Arc<Mutex<dyn VfsFile>>;
+
+struct Pipe {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct PipeReader(Arc<Mutex<Pipe>>);
+
+struct PipeWriter(Arc<Mutex<Pipe>>);
+
+/// Wait key of the tasks reading from `pipe`; writers wait on the next one.
+fn read_key(pipe: &Arc<Mutex<Pipe>>) -> usize {
+    Arc::as_ptr(pipe) as usize
+}
+
+fn write_key(pipe: &Arc<Mutex<Pipe>>) -> usize {
+    read_key(pipe) + 1
+}
+
+/// Create a pipe and return its read and write ends.
+pub fn new() -> (PipeEnd, PipeEnd) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+        readers: 1,
+        writers: 1,
+    }));
+    (
+        Arc::new(Mutex::new(PipeReader(pipe.clone()))),
+        Arc::new(Mutex::new(PipeWriter(pipe))),
+    )
+}
+
+impl VfsFile for PipeReader {
+    fn size(&mut self) -> usize {
+        self.0.lock().buffer.len()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = {
+            let mut pipe = self.0.lock();
+            let count = min(buf.len(), pipe.buffer.len());
+            for (dest, byte) in buf.iter_mut().zip(pipe.buffer.drain(..count)) {
+                *dest = byte;
+            }
+            count
+        };
+        if count != 0 {
+            sched::wake_file(write_key(&self.0));
+        }
+        count
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::BadDescriptor)
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> usize {
+        0
+    }
+
+    fn read_blocks(&mut self) -> Option<usize> {
+        let pipe = self.0.lock();
+        (pipe.buffer.is_empty() && pipe.writers != 0).then(|| read_key(&self.0))
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::pipe(self.0.lock().buffer.len() as u64)
+    }
+}
+
+impl VfsFile for PipeWriter {
+    fn size(&mut self) -> usize {
+        self.0.lock().buffer.len()
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let count = {
+            let mut pipe = self.0.lock();
+            if pipe.readers == 0 {
+                return Err(VfsError::BrokenPipe);
+            }
+            let count = min(buf.len(), PIPE_CAPACITY - pipe.buffer.len());
+            pipe.buffer.extend(&buf[..count]);
+            count
+        };
+        if count != 0 {
+            sched::wake_file(read_key(&self.0));
+        }
+        Ok(count)
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> usize {
+        0
+    }
+
+    fn write_blocks(&mut self) -> Option<usize> {
+        let pipe = self.0.lock();
+        (pipe.buffer.len() == PIPE_CAPACITY && pipe.readers != 0).then(|| write_key(&self.0))
+    }
+
+    fn metadata(&mut self) -> Metadata {
+        Metadata::pipe(self.0.lock().buffer.len() as u64)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.lock().readers -= 1;
+        sched::wake_file_later(write_key(&self.0));
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.lock().writers -= 1;
+        sched::wake_file_later(read_key(&self.0));
+    }
+}
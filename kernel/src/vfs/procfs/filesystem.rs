@@ -4,7 +4,9 @@ use alloc::sync::Arc;
 use alloc::vec;
 use spin::Mutex;
 
-use crate::vfs::{DirEntry, SeekFrom, SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsFile};
+use crate::vfs::{
+    DirEntry, SeekFrom, SnapshotDirectory, VfsDirHandle, VfsDirectory, VfsError, VfsFile,
+};
 
 pub(super) struct ProcFileSystem;
 
@@ -28,6 +30,36 @@ impl VfsDirectory for ProcFileSystem {
                 pos: 0,
             })));
         }
+        if path == "/cache" {
+            return Ok(Arc::new(Mutex::new(ProcTextFile {
+                data: cache_stats(),
+                pos: 0,
+            })));
+        }
+        if path == "/mounts" {
+            return Ok(Arc::new(Mutex::new(ProcTextFile {
+                data: crate::vfs::mount::list(),
+                pos: 0,
+            })));
+        }
+        if path == "/filesystems" {
+            return Ok(Arc::new(Mutex::new(ProcTextFile {
+                data: crate::vfs::filesystems::list(),
+                pos: 0,
+            })));
+        }
+        if path == "/partitions" {
+            return Ok(Arc::new(Mutex::new(ProcTextFile {
+                data: partitions(),
+                pos: 0,
+            })));
+        }
+        if path == "/ipc" {
+            return Ok(Arc::new(Mutex::new(ProcTextFile {
+                data: ipc_services(),
+                pos: 0,
+            })));
+        }
 
         let (pid, file_name) = split_process_file_path(path)?;
         if file_name != "exe" {
@@ -45,7 +77,14 @@ impl VfsDirectory for ProcFileSystem {
 
     fn directory(&self, path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle + '_>>, ()> {
         if path == "/" || path.is_empty() {
-            let mut entries = vec![DirEntry::new(false, "cmdline")];
+            let mut entries = vec![
+                DirEntry::new(false, "cache"),
+                DirEntry::new(false, "cmdline"),
+                DirEntry::new(false, "filesystems"),
+                DirEntry::new(false, "ipc"),
+                DirEntry::new(false, "mounts"),
+                DirEntry::new(false, "partitions"),
+            ];
             let tasks = crate::task::process::TASKS.lock();
             for (pid, task) in tasks.iter().enumerate() {
                 if task.is_some() {
@@ -73,8 +112,29 @@ impl VfsDirectory for ProcFileSystem {
         Err(())
     }
 
-    fn remove(&self, _path: &str) -> bool {
-        false
+    // the entries are generated by the kernel and cannot be changed
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn create_directory(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn remove_directory(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn truncate(&self, _path: &str, _len: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "procfs"
     }
 }
 
@@ -94,8 +154,8 @@ impl VfsFile for ProcTextFile {
         len
     }
 
-    fn write(&mut self, _buf: &[u8]) -> usize {
-        0
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
     }
 
     fn seek(&mut self, pos: SeekFrom) -> usize {
@@ -110,6 +170,46 @@ impl VfsFile for ProcTextFile {
     }
 }
 
+/// Hits, misses and fill of the block and page caches.
+fn cache_stats() -> String {
+    let mut data = String::from("CACHE HITS MISSES ENTRIES CAPACITY DIRTY WRITEBACKS\n");
+    data.push_str(&crate::blockdev::cache::stats());
+    data.push_str(&crate::vfs::page_cache::stats());
+    data
+}
+
+/// One line per partition: its devfs node, where it is on the disk in bytes,
+/// its type and unique GUIDs (or MBR system ID and made-up ID) and its GPT
+/// name.
+fn partitions() -> String {
+    let mut data = String::from("NAME START SIZE TYPE UUID LABEL\n");
+    for partition in crate::blockdev::partition::table::list(None) {
+        data.push_str(&format!(
+            "{} {} {} {} {} {}\n",
+            partition.name(),
+            partition.start,
+            partition.end - partition.start,
+            partition.type_name(),
+            partition.uuid(),
+            partition.label()
+        ));
+    }
+    data
+}
+
+/// One line per registered IPC service: name, owner PID and the number of
+/// connections waiting to be accepted.
+fn ipc_services() -> String {
+    let mut data = String::from("NAME OWNER PENDING\n");
+    for service in crate::task::ipc::registry::list() {
+        data.push_str(&format!(
+            "{} {} {}\n",
+            service.name, service.owner, service.pending
+        ));
+    }
+    data
+}
+
 fn split_process_dir_path(path: &str) -> Result<usize, ()> {
     let trimmed = path.trim_matches('/');
     if trimmed.is_empty() || trimmed.contains('/') {
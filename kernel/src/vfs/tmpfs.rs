@@ -0,0 +1,393 @@
+//! An in-memory filesystem.
+//!
+//! The tree of names is kept under one lock; the contents of every file live
+//! in their own, shared by the files opened on it. Removing a file only
+//! unlinks the name, so files that are still open keep their data until the
+//! last one is closed.
+//!
+//! File contents are stored in chunks that are allocated when first written:
+//! seeking past the end and writing leaves a hole that reads as zeroes. The
+//! `size=` mount option limits how much memory the chunks of a mount take.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::vfs::mount::MountError;
+use crate::vfs::{
+    DIRENT_NAME_CAP, DirEntry, Metadata, SeekFrom, SnapshotDirectory, VfsDirHandle, VfsDirectory,
+    VfsError, VfsFile,
+};
+
+const CHUNK_SIZE: usize = 4096;
+
+/// The bytes the chunks of one mount take, and how many they may.
+struct Usage {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl Usage {
+    /// Account for one more chunk, unless that goes over the limit.
+    fn charge(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(CHUNK_SIZE)
+                    .filter(|&used| used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, chunks: usize) {
+        self.used.fetch_sub(chunks * CHUNK_SIZE, Ordering::Relaxed);
+    }
+}
+
+struct FileData {
+    /// Chunks by index; missing ones are holes.
+    chunks: BTreeMap<usize, Box<[u8; CHUNK_SIZE]>>,
+    size: usize,
+    usage: Arc<Usage>,
+}
+
+impl FileData {
+    fn new(usage: Arc<Usage>) -> Self {
+        Self {
+            chunks: BTreeMap::new(),
+            size: 0,
+            usage,
+        }
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = min(buf.len(), self.size.saturating_sub(offset));
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let within = pos % CHUNK_SIZE;
+            let take = min(len - done, CHUNK_SIZE - within);
+            match self.chunks.get(&(pos / CHUNK_SIZE)) {
+                Some(chunk) => {
+                    buf[done..done + take].copy_from_slice(&chunk[within..within + take])
+                }
+                None => buf[done..done + take].fill(0),
+            }
+            done += take;
+        }
+        len
+    }
+
+    /// Writes as much of `buf` as the limit of the mount allows. Nothing is
+    /// written if the file would end past `usize::MAX`.
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<usize, VfsError> {
+        if offset.checked_add(buf.len()).is_none() {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let within = pos % CHUNK_SIZE;
+            let take = min(buf.len() - done, CHUNK_SIZE - within);
+            let index = pos / CHUNK_SIZE;
+            if !self.chunks.contains_key(&index) {
+                if !self.usage.charge() {
+                    break;
+                }
+                self.chunks.insert(index, Box::new([0; CHUNK_SIZE]));
+            }
+            let chunk = self.chunks.get_mut(&index).unwrap();
+            chunk[within..within + take].copy_from_slice(&buf[done..done + take]);
+            done += take;
+        }
+        if done != 0 {
+            self.size = self.size.max(offset + done);
+        } else if !buf.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        Ok(done)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        let chunks = self.chunks.len();
+        self.chunks
+            .retain(|&index, _| index.saturating_mul(CHUNK_SIZE) < len);
+        self.usage.release(chunks - self.chunks.len());
+        // zero the cut-off tail so that growing the file again reads zeroes
+        if let Some(chunk) = self.chunks.get_mut(&(len / CHUNK_SIZE)) {
+            chunk[len % CHUNK_SIZE..].fill(0);
+        }
+        self.size = len;
+    }
+}
+
+impl Drop for FileData {
+    fn drop(&mut self) {
+        self.usage.release(self.chunks.len());
+    }
+}
+
+enum Node {
+    File(Arc<Mutex<FileData>>),
+    Directory(Directory),
+}
+
+#[derive(Default)]
+struct Directory {
+    entries: BTreeMap<String, Node>,
+}
+
+impl Directory {
+    /// The directory at `path`, which is relative to this one.
+    fn directory(&mut self, path: &str) -> Result<&mut Directory, VfsError> {
+        let mut directory = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            directory = match directory.entries.get_mut(name) {
+                Some(Node::Directory(child)) => child,
+                Some(Node::File(_)) => return Err(VfsError::NotADirectory),
+                None => return Err(VfsError::NotFound),
+            };
+        }
+        Ok(directory)
+    }
+
+    fn node(&mut self, path: &str) -> Result<&mut Node, VfsError> {
+        let (parent, name) = split(path)?;
+        self.directory(parent)?
+            .entries
+            .get_mut(name)
+            .ok_or(VfsError::NotFound)
+    }
+}
+
+/// Split `path` into its parent and its last component. The root has
+/// neither.
+fn split(path: &str) -> Result<(&str, &str), VfsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidArgument)?;
+    if name.len() >= DIRENT_NAME_CAP {
+        return Err(VfsError::NameTooLong);
+    }
+    Ok((parent, name))
+}
+
+pub(super) struct TmpFileSystem {
+    root: Mutex<Directory>,
+    usage: Arc<Usage>,
+}
+
+pub fn get_fs<T>(_device: Option<T>) -> Result<Arc<dyn VfsDirectory>, ()>
+where
+    T: fatfs::ReadWriteSeek,
+{
+    Ok(Arc::new(TmpFileSystem::new(usize::MAX)))
+}
+
+/// A tmpfs mounted with `options`, of which there is only `size=`: the
+/// limit in bytes, optionally with a `k`, `m` or `g` suffix.
+pub fn get_fs_with_options(options: &[&str]) -> Result<Arc<dyn VfsDirectory>, MountError> {
+    let mut limit = usize::MAX;
+    for option in options {
+        limit = option
+            .strip_prefix("size=")
+            .and_then(parse_size)
+            .ok_or(MountError::InvalidArgument)?;
+    }
+    Ok(Arc::new(TmpFileSystem::new(limit)))
+}
+
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'k' | b'K' => (&size[..size.len() - 1], 10),
+        b'm' | b'M' => (&size[..size.len() - 1], 20),
+        b'g' | b'G' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+impl TmpFileSystem {
+    fn new(limit: usize) -> Self {
+        TmpFileSystem {
+            root: Mutex::new(Directory::default()),
+            usage: Arc::new(Usage {
+                used: AtomicUsize::new(0),
+                limit,
+            }),
+        }
+    }
+
+    fn open(data: Arc<Mutex<FileData>>) -> Arc<Mutex<dyn VfsFile>> {
+        Arc::new(Mutex::new(TmpFile { data, pos: 0 }))
+    }
+}
+
+impl VfsDirectory for TmpFileSystem {
+    fn file(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
+        match self.root.lock().node(path) {
+            Ok(Node::File(data)) => Ok(Self::open(data.clone())),
+            _ => Err(()),
+        }
+    }
+
+    fn directory(&self, path: &str) -> Result<Arc<Mutex<dyn VfsDirHandle + '_>>, ()> {
+        let mut root = self.root.lock();
+        let directory = root.directory(path).map_err(|_| ())?;
+        let entries = directory
+            .entries
+            .iter()
+            .map(|(name, node)| DirEntry::new(matches!(node, Node::Directory(_)), name))
+            .collect();
+        Ok(Arc::new(Mutex::new(SnapshotDirectory::new(entries))))
+    }
+
+    fn create_file_or_open_existing(&self, path: &str) -> Result<Arc<Mutex<dyn VfsFile + '_>>, ()> {
+        let (parent, name) = split(path).map_err(|_| ())?;
+        let mut root = self.root.lock();
+        let directory = root.directory(parent).map_err(|_| ())?;
+        let node = directory
+            .entries
+            .entry(name.to_owned())
+            .or_insert_with(|| Node::File(Arc::new(Mutex::new(FileData::new(self.usage.clone())))));
+        match node {
+            Node::File(data) => Ok(Self::open(data.clone())),
+            Node::Directory(_) => Err(()),
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        let (parent, name) = split(path)?;
+        let mut root = self.root.lock();
+        let directory = root.directory(parent)?;
+        match directory.entries.get(name) {
+            Some(Node::File(_)) => {
+                directory.entries.remove(name);
+                Ok(())
+            }
+            Some(Node::Directory(_)) => Err(VfsError::IsADirectory),
+            None => Err(VfsError::NotFound),
+        }
+    }
+
+    fn create_directory(&self, path: &str) -> Result<(), VfsError> {
+        let (parent, name) = split(path)?;
+        let mut root = self.root.lock();
+        let directory = root.directory(parent)?;
+        if directory.entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        directory
+            .entries
+            .insert(name.to_owned(), Node::Directory(Directory::default()));
+        Ok(())
+    }
+
+    fn remove_directory(&self, path: &str) -> Result<(), VfsError> {
+        let (parent, name) = split(path)?;
+        let mut root = self.root.lock();
+        let directory = root.directory(parent)?;
+        match directory.entries.get(name) {
+            Some(Node::Directory(child)) if !child.entries.is_empty() => {
+                Err(VfsError::DirectoryNotEmpty)
+            }
+            Some(Node::Directory(_)) => {
+                directory.entries.remove(name);
+                Ok(())
+            }
+            Some(Node::File(_)) => Err(VfsError::NotADirectory),
+            None => Err(VfsError::NotFound),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        let (from_parent, from_name) = split(from)?;
+        let (to_parent, to_name) = split(to)?;
+        let mut root = self.root.lock();
+        root.node(from)?;
+        if root.directory(to_parent)?.entries.contains_key(to_name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let node = root
+            .directory(from_parent)?
+            .entries
+            .remove(from_name)
+            .ok_or(VfsError::NotFound)?;
+        root.directory(to_parent)?
+            .entries
+            .insert(to_name.to_owned(), node);
+        Ok(())
+    }
+
+    fn truncate(&self, path: &str, len: u64) -> Result<(), VfsError> {
+        match self.root.lock().node(path)? {
+            Node::File(data) => {
+                data.lock().truncate(len as usize);
+                Ok(())
+            }
+            Node::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn fs_type(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, ()> {
+        let mut root = self.root.lock();
+        if root.directory(path).is_ok() {
+            return Ok(Metadata::directory());
+        }
+        match root.node(path) {
+            Ok(Node::File(data)) => Ok(Metadata::file(data.lock().size as u64)),
+            _ => Err(()),
+        }
+    }
+}
+
+struct TmpFile {
+    data: Arc<Mutex<FileData>>,
+    pos: usize,
+}
+
+impl VfsFile for TmpFile {
+    fn size(&mut self) -> usize {
+        self.data.lock().size
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let read = self.data.lock().read(self.pos, buf);
+        self.pos += read;
+        read
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let written = self.data.lock().write(self.pos, buf)?;
+        self.pos += written;
+        Ok(written)
+    }
+
+    /// Seeking past the end is allowed; a write there leaves a hole.
+    fn seek(&mut self, pos: SeekFrom) -> usize {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        if let Some(new_pos) = new_pos {
+            self.pos = new_pos;
+        }
+        self.pos
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> usize {
+        self.data.lock().read(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> usize {
+        self.data.lock().write(offset, buf).unwrap_or(0)
+    }
+}
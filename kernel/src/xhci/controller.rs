@@ -13,7 +13,8 @@ use super::usb::{
     SetupRequest, SupportedProtocol, get_descriptor, parse_configuration, parse_msc_bot_interface,
     set_configuration, set_idle, supported_protocol, usb2_max_packet,
 };
-use crate::mm::dma::{DmaBuffer, MmioMapping};
+use crate::mm::dma::{DmaBuffer, DmaDevice};
+use crate::mm::mmio::{self, CacheMode, MmioRegion};
 use crate::pcie::enumrate::{Bdf, MemoryBar, PCIConfigSpace, decode_memory_bar, doit};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, fence};
 
@@ -182,10 +183,11 @@ const _: () = {
 };
 
 /// Resources remain owned by this object for as long as the controller runs.
-/// There is no IOMMU in this release, so every address handed to xHCI is a
-/// physical address from the contiguous DMA allocator.
+/// Every address handed to xHCI is the device address of a `DmaBuffer`
+/// allocated for `dma`, so it stays valid with an IOMMU enabled.
 struct ControllerResources {
-    _mapping: MmioMapping,
+    _mapping: MmioRegion,
+    dma: DmaDevice,
     dcbaa: DmaBuffer,
     _scratchpad_array: Option<DmaBuffer>,
     _scratchpads: alloc::vec::Vec<DmaBuffer>,
@@ -213,36 +215,36 @@ struct ControllerResources {
 }
 
 impl ControllerResources {
-    fn new(mapping: MmioMapping, hcs2: u32) -> Result<Self, InitError> {
+    fn new(mapping: MmioRegion, dma: DmaDevice, hcs2: u32) -> Result<Self, InitError> {
         let scratch_count = (((hcs2 >> 27) & 0x1f) << 5 | ((hcs2 >> 21) & 0x1f)) as usize;
-        let dcbaa = DmaBuffer::new(256 * 8, 64).map_err(|_| InitError::Allocation)?;
+        let dcbaa = DmaBuffer::new(dma, 256 * 8, 64).map_err(|_| InitError::Allocation)?;
         let scratchpad_array = if scratch_count == 0 {
             None
         } else {
-            Some(DmaBuffer::new(scratch_count * 8, 64).map_err(|_| InitError::Allocation)?)
+            Some(DmaBuffer::new(dma, scratch_count * 8, 64).map_err(|_| InitError::Allocation)?)
         };
         let mut scratchpads = alloc::vec::Vec::new();
         for index in 0..scratch_count {
-            let page = DmaBuffer::new(4096, 4096).map_err(|_| InitError::Allocation)?;
+            let page = DmaBuffer::new(dma, 4096, 4096).map_err(|_| InitError::Allocation)?;
             unsafe {
                 core::ptr::write_volatile(
                     (scratchpad_array.as_ref().unwrap().as_ptr() as *mut u64).add(index),
-                    page.physical_address(),
+                    page.device_address(),
                 );
             }
             scratchpads.push(page);
         }
-        let command_ring = DmaBuffer::new(4096, 64).map_err(|_| InitError::Allocation)?;
+        let command_ring = DmaBuffer::new(dma, 4096, 64).map_err(|_| InitError::Allocation)?;
         let event_ring =
-            DmaBuffer::new(EVENT_RING_ENTRIES * 16, 64).map_err(|_| InitError::Allocation)?;
-        let erst = DmaBuffer::new(core::mem::size_of::<ErstEntry>(), 64)
+            DmaBuffer::new(dma, EVENT_RING_ENTRIES * 16, 64).map_err(|_| InitError::Allocation)?;
+        let erst = DmaBuffer::new(dma, core::mem::size_of::<ErstEntry>(), 64)
             .map_err(|_| InitError::Allocation)?;
         unsafe {
             let command = command_ring.as_ptr() as *mut Trb;
             core::ptr::write_volatile(
                 command.add(COMMAND_RING_ENTRIES - 1),
                 Trb {
-                    parameter: command_ring.physical_address(),
+                    parameter: command_ring.device_address(),
                     control: TRB_TYPE_LINK | TRB_TC | TRB_CYCLE,
                     ..Trb::default()
                 },
@@ -251,18 +253,19 @@ impl ControllerResources {
             core::ptr::write_volatile(
                 entry,
                 ErstEntry {
-                    base: event_ring.physical_address(),
+                    base: event_ring.device_address(),
                     size: EVENT_RING_ENTRIES as u16,
                     _reserved: 0,
                     _reserved2: 0,
                 },
             );
             if let Some(array) = scratchpad_array.as_ref() {
-                core::ptr::write_volatile(dcbaa.as_ptr() as *mut u64, array.physical_address());
+                core::ptr::write_volatile(dcbaa.as_ptr() as *mut u64, array.device_address());
             }
         }
         Ok(Self {
             _mapping: mapping,
+            dma,
             dcbaa,
             _scratchpad_array: scratchpad_array,
             _scratchpads: scratchpads,
@@ -302,17 +305,17 @@ impl ControllerResources {
         let doorbell = unsafe { register.read32(regs::DBOFF) } as usize & !3;
         let op = cap_len;
         unsafe {
-            register.write64(op + regs::DCBAAP, self.dcbaa.physical_address());
-            register.write64(op + regs::CRCR, self.command_ring.physical_address() | 1);
+            register.write64(op + regs::DCBAAP, self.dcbaa.device_address());
+            register.write64(op + regs::CRCR, self.command_ring.device_address() | 1);
             register.write32(op + regs::CONFIG, max_slots as u32);
             register.write32(runtime + regs::RT_INTR0 + regs::ERSTSZ, 1);
             register.write64(
                 runtime + regs::RT_INTR0 + regs::ERSTBA,
-                self.erst.physical_address(),
+                self.erst.device_address(),
             );
             register.write64(
                 runtime + regs::RT_INTR0 + regs::ERDP,
-                self.event_ring.physical_address(),
+                self.event_ring.device_address(),
             );
             // Keep event production enabled; events are still consumed by the
             // bounded polling path rather than an interrupt handler.
@@ -381,7 +384,7 @@ impl ControllerResources {
             unsafe {
                 register.write64(
                     self.runtime + regs::RT_INTR0 + regs::ERDP,
-                    (self.event_ring.physical_address() + (self.event_consumer * 16) as u64)
+                    (self.event_ring.device_address() + (self.event_consumer * 16) as u64)
                         | regs::ERDP_EHB,
                 );
                 let iman = register.read32(self.runtime + regs::RT_INTR0 + regs::IMAN);
@@ -440,7 +443,7 @@ impl ControllerResources {
             unsafe {
                 register.write64(
                     self.runtime + regs::RT_INTR0 + regs::ERDP,
-                    (self.event_ring.physical_address() + (self.event_consumer * 16) as u64)
+                    (self.event_ring.device_address() + (self.event_consumer * 16) as u64)
                         | regs::ERDP_EHB,
                 );
                 let iman = register.read32(self.runtime + regs::RT_INTR0 + regs::IMAN);
@@ -488,7 +491,7 @@ impl ControllerResources {
         unsafe {
             register.write64(
                 self.runtime + regs::RT_INTR0 + regs::ERDP,
-                (self.event_ring.physical_address() + (self.event_consumer * 16) as u64)
+                (self.event_ring.device_address() + (self.event_consumer * 16) as u64)
                     | regs::ERDP_EHB,
             );
             let iman = register.read32(self.runtime + regs::RT_INTR0 + regs::IMAN);
@@ -508,7 +511,7 @@ impl ControllerResources {
     ) {
         let index = hid.producer;
         let trb = Trb {
-            parameter: hid.report.physical_address(),
+            parameter: hid.report.device_address(),
             status: hid.endpoint.max_packet_size as u32,
             control: TRB_TYPE_NORMAL | (1 << 5) | hid.cycle as u32,
         };
@@ -526,7 +529,7 @@ impl ControllerResources {
                 core::ptr::write_volatile(
                     (hid.interrupt_ring.as_ptr() as *mut Trb).add(TRANSFER_RING_ENTRIES),
                     Trb {
-                        parameter: hid.interrupt_ring.physical_address(),
+                        parameter: hid.interrupt_ring.device_address(),
                         control: TRB_TYPE_LINK | TRB_TC | hid.cycle as u32,
                         ..Trb::default()
                     },
@@ -709,7 +712,7 @@ impl ControllerResources {
                 core::ptr::write_volatile(
                     (self.command_ring.as_ptr() as *mut Trb).add(COMMAND_RING_ENTRIES - 1),
                     Trb {
-                        parameter: self.command_ring.physical_address(),
+                        parameter: self.command_ring.device_address(),
                         control: TRB_TYPE_LINK | TRB_TC | self.command_cycle as u32,
                         ..Trb::default()
                     },
@@ -718,7 +721,7 @@ impl ControllerResources {
         }
         self.poll_command(
             register,
-            self.command_ring.physical_address() + (index * 16) as u64,
+            self.command_ring.device_address() + (index * 16) as u64,
         )
     }
 
@@ -1226,28 +1229,28 @@ impl ControllerResources {
             slot
         );
         let context_size = if self.context_64 { 64 } else { 32 };
-        let input = match DmaBuffer::new(context_size * 33, 64) {
+        let input = match DmaBuffer::new(self.dma, context_size * 33, 64) {
             Ok(buffer) => buffer,
             Err(_) => {
                 self.release_failed_slot(register, slot);
                 return Err(InitError::Allocation);
             }
         };
-        let output = match DmaBuffer::new(context_size * 32, 64) {
+        let output = match DmaBuffer::new(self.dma, context_size * 32, 64) {
             Ok(buffer) => buffer,
             Err(_) => {
                 self.release_failed_slot(register, slot);
                 return Err(InitError::Allocation);
             }
         };
-        let ep0 = match DmaBuffer::new(4096, 64) {
+        let ep0 = match DmaBuffer::new(self.dma, 4096, 64) {
             Ok(buffer) => buffer,
             Err(_) => {
                 self.release_failed_slot(register, slot);
                 return Err(InitError::Allocation);
             }
         };
-        let descriptor = match DmaBuffer::new(18, 64) {
+        let descriptor = match DmaBuffer::new(self.dma, 18, 64) {
             Ok(buffer) => buffer,
             Err(_) => {
                 self.release_failed_slot(register, slot);
@@ -1269,7 +1272,7 @@ impl ControllerResources {
             unsafe {
                 core::ptr::write_volatile(
                     (self.dcbaa.as_ptr() as *mut u64).add(slot as usize),
-                    output.physical_address(),
+                    output.device_address(),
                 );
                 core::ptr::write_volatile(input.as_ptr().add(4) as *mut u32, 0b11);
                 core::ptr::write_volatile(
@@ -1288,12 +1291,12 @@ impl ControllerResources {
                 );
                 core::ptr::write_volatile(
                     input.as_ptr().add(context_size * 2 + 8) as *mut u64,
-                    ep0.physical_address() | 1,
+                    ep0.device_address() | 1,
                 );
                 core::ptr::write_volatile(
                     (ep0.as_ptr() as *mut Trb).add(COMMAND_RING_ENTRIES - 1),
                     Trb {
-                        parameter: ep0.physical_address(),
+                        parameter: ep0.device_address(),
                         control: TRB_TYPE_LINK | TRB_TC | TRB_CYCLE,
                         ..Trb::default()
                     },
@@ -1305,7 +1308,7 @@ impl ControllerResources {
             if let Err(error) = self.submit_command(
                 register,
                 Trb {
-                    parameter: input.physical_address(),
+                    parameter: input.device_address(),
                     control: regs::TRB_TYPE_ADDRESS_DEVICE | (slot as u32) << 24,
                     ..Trb::default()
                 },
@@ -1337,7 +1340,7 @@ impl ControllerResources {
                 );
                 fail_published!(error);
             }
-            let config_header = match DmaBuffer::new(9, 64) {
+            let config_header = match DmaBuffer::new(self.dma, 9, 64) {
                 Ok(buffer) => buffer,
                 Err(_) => fail_published!(InitError::Allocation),
             };
@@ -1356,7 +1359,7 @@ impl ControllerResources {
             if !(9..=4096).contains(&total) {
                 fail_published!(InitError::Command(CompletionCode::TrbError));
             }
-            let configuration = match DmaBuffer::new(total, 64) {
+            let configuration = match DmaBuffer::new(self.dma, total, 64) {
                 Ok(buffer) => buffer,
                 Err(_) => fail_published!(InitError::Allocation),
             };
@@ -1428,7 +1431,7 @@ impl ControllerResources {
             ) {
                 fail_published!(error);
             }
-            let interrupt_ring = match DmaBuffer::new(4096, 64) {
+            let interrupt_ring = match DmaBuffer::new(self.dma, 4096, 64) {
                 Ok(buffer) => buffer,
                 Err(_) => fail_published!(InitError::Allocation),
             };
@@ -1449,7 +1452,7 @@ impl ControllerResources {
                 endpoint.interval,
                 endpoint.max_packet_size
             );
-            let report = match DmaBuffer::new(endpoint.max_packet_size as usize, 64) {
+            let report = match DmaBuffer::new(self.dma, endpoint.max_packet_size as usize, 64) {
                 Ok(buffer) => buffer,
                 Err(_) => {
                     self.release_or_retain_slot_resources(
@@ -1585,7 +1588,7 @@ impl ControllerResources {
                 core::ptr::write_volatile(
                     ptr.add(1),
                     Trb {
-                        parameter: buffer.physical_address(),
+                        parameter: buffer.device_address(),
                         status: data_len,
                         control: super::trb::TRB_TYPE_DATA_STAGE
                             | if data_in { 1 << 16 } else { 0 }
@@ -1609,7 +1612,7 @@ impl ControllerResources {
                 register.write32(self.doorbell + slot as usize * 4, 1);
                 let completion = self.poll_transfer(
                     register,
-                    ring.physical_address() + ((*producer + 2) * 16) as u64,
+                    ring.device_address() + ((*producer + 2) * 16) as u64,
                 );
                 *producer += trb_count;
                 return completion;
@@ -1627,7 +1630,7 @@ impl ControllerResources {
         }
         let completion = self.poll_transfer(
             register,
-            ring.physical_address() + ((*producer + 1) * 16) as u64,
+            ring.device_address() + ((*producer + 1) * 16) as u64,
         );
         *producer += trb_count;
         completion
@@ -1652,9 +1655,9 @@ impl ControllerResources {
             return Err(InitError::Command(CompletionCode::RingOverrun));
         }
         let in_direction = endpoint_address & 0x80 != 0;
-        let trb = bulk::normal_trb(buffer.physical_address(), len, in_direction, *cycle)
+        let trb = bulk::normal_trb(buffer.device_address(), len, in_direction, *cycle)
             .map_err(|_| InitError::Command(CompletionCode::TrbError))?;
-        let trb_address = ring.physical_address() + (*producer * 16) as u64;
+        let trb_address = ring.device_address() + (*producer * 16) as u64;
         unsafe { core::ptr::write_volatile((ring.as_ptr() as *mut Trb).add(*producer), trb) };
         fence(Ordering::SeqCst);
         unsafe {
@@ -1672,7 +1675,7 @@ impl ControllerResources {
                 core::ptr::write_volatile(
                     (ring.as_ptr() as *mut Trb).add(TRANSFER_RING_ENTRIES),
                     Trb {
-                        parameter: ring.physical_address(),
+                        parameter: ring.device_address(),
                         control: TRB_TYPE_LINK | TRB_TC | *cycle as u32,
                         ..Trb::default()
                     },
@@ -1756,11 +1759,11 @@ impl ControllerResources {
                 context.add(1),
                 (endpoint.max_packet_size as u32) << 16 | 3 << 1 | 7 << 3,
             );
-            core::ptr::write_volatile(context.add(2) as *mut u64, ring.physical_address() | 1);
+            core::ptr::write_volatile(context.add(2) as *mut u64, ring.device_address() | 1);
             core::ptr::write_volatile(
                 (ring.as_ptr() as *mut Trb).add(COMMAND_RING_ENTRIES - 1),
                 Trb {
-                    parameter: ring.physical_address(),
+                    parameter: ring.device_address(),
                     control: TRB_TYPE_LINK | TRB_TC | TRB_CYCLE,
                     ..Trb::default()
                 },
@@ -1770,14 +1773,14 @@ impl ControllerResources {
                 slot,
                 core::ptr::read_volatile(control),
                 core::ptr::read_volatile(slot_context) >> 27,
-                ring.physical_address() | 1,
+                ring.device_address() | 1,
             );
         }
         fence(Ordering::SeqCst);
         self.submit_command(
             register,
             Trb {
-                parameter: input.physical_address(),
+                parameter: input.device_address(),
                 control: regs::TRB_TYPE_CONFIGURE_ENDPOINT | (slot as u32) << 24,
                 ..Trb::default()
             },
@@ -1822,11 +1825,11 @@ impl ControllerResources {
                 context.add(1),
                 (endpoint.max_packet_size as u32) << 16 | endpoint_type << 3 | 3 << 1,
             );
-            core::ptr::write_volatile(context.add(2) as *mut u64, ring.physical_address() | 1);
+            core::ptr::write_volatile(context.add(2) as *mut u64, ring.device_address() | 1);
             core::ptr::write_volatile(
                 (ring.as_ptr() as *mut Trb).add(TRANSFER_RING_ENTRIES),
                 Trb {
-                    parameter: ring.physical_address(),
+                    parameter: ring.device_address(),
                     control: TRB_TYPE_LINK | TRB_TC | TRB_CYCLE,
                     ..Trb::default()
                 },
@@ -1836,7 +1839,7 @@ impl ControllerResources {
         self.submit_command(
             register,
             Trb {
-                parameter: input.physical_address(),
+                parameter: input.device_address(),
                 control: regs::TRB_TYPE_CONFIGURE_ENDPOINT | (slot as u32) << 24,
                 ..Trb::default()
             },
@@ -1861,11 +1864,12 @@ impl ControllerResources {
             set_configuration(endpoint.configuration_value),
             None,
         )?;
-        let bulk_in_ring = DmaBuffer::new(4096, 64).map_err(|_| InitError::Allocation)?;
-        let bulk_out_ring = DmaBuffer::new(4096, 64).map_err(|_| InitError::Allocation)?;
+        let bulk_in_ring = DmaBuffer::new(self.dma, 4096, 64).map_err(|_| InitError::Allocation)?;
+        let bulk_out_ring =
+            DmaBuffer::new(self.dma, 4096, 64).map_err(|_| InitError::Allocation)?;
         self.configure_bulk_endpoint(register, slot, input, endpoint.bulk_out, &bulk_out_ring)?;
         self.configure_bulk_endpoint(register, slot, input, endpoint.bulk_in, &bulk_in_ring)?;
-        let max_lun = DmaBuffer::new(1, 64).map_err(|_| InitError::Allocation)?;
+        let max_lun = DmaBuffer::new(self.dma, 1, 64).map_err(|_| InitError::Allocation)?;
         match self.control_transfer(
             register,
             slot,
@@ -1882,9 +1886,10 @@ impl ControllerResources {
             Err(InitError::Command(CompletionCode::StallError)) => {}
             Err(error) => return Err(error),
         }
-        let cbw = DmaBuffer::new(msc::CBW_LEN, 64).map_err(|_| InitError::Allocation)?;
-        let csw = DmaBuffer::new(msc::CSW_LEN, 64).map_err(|_| InitError::Allocation)?;
-        let data = DmaBuffer::new(msc::MAX_READ_BYTES, 64).map_err(|_| InitError::Allocation)?;
+        let cbw = DmaBuffer::new(self.dma, msc::CBW_LEN, 64).map_err(|_| InitError::Allocation)?;
+        let csw = DmaBuffer::new(self.dma, msc::CSW_LEN, 64).map_err(|_| InitError::Allocation)?;
+        let data =
+            DmaBuffer::new(self.dma, msc::MAX_READ_BYTES, 64).map_err(|_| InitError::Allocation)?;
         let mut in_producer = 0;
         let mut out_producer = 0;
         let mut in_cycle = true;
@@ -2256,7 +2261,7 @@ fn discover_one(bdf: Bdf, config: &PCIConfigSpace) -> ControllerState {
     unsafe {
         config.update_command(1 << 1 | 1 << 2, 0);
     }
-    let mapping = match unsafe { MmioMapping::map(address, length as usize) } {
+    let mapping = match unsafe { mmio::map(address, length as usize, CacheMode::Uncacheable) } {
         Ok(mapping) => mapping,
         Err(error) => {
             crate::println!(
@@ -2285,7 +2290,7 @@ fn discover_one(bdf: Bdf, config: &PCIConfigSpace) -> ControllerState {
             let max_slots =
                 (unsafe { regs::RegisterBlock::new(mapping_ptr).read32(regs::HCSPARAMS1) } & 0xff)
                     as u8;
-            match ControllerResources::new(mapping, hcs2)
+            match ControllerResources::new(mapping, DmaDevice { segment: 0, bdf }, hcs2)
                 .and_then(|resources| resources.start(mapping_ptr, cap_len, max_slots, context_64))
             {
                 Ok(resources) => {